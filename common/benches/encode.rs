@@ -0,0 +1,82 @@
+//! Encoding a typical console message, through an intermediate `Vec` as the codec used to and
+//! in place as it does now, and decoding a large command result.
+
+use bytes::{Bytes, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+use common::{
+    AsyncHeteroCodec, DefaultFormat, Envelope, ServerCodec, ServerMessage, UnityLogType, WireFormat,
+};
+
+fn console_output() -> Envelope<ServerMessage> {
+    Envelope::new(
+        42,
+        ServerMessage::UnityConsoleOutput {
+            log_type: UnityLogType::Log,
+            log: "Loaded scene 'Assets/Scenes/Main.unity' in 12.3 ms".to_string(),
+            stack_trace:
+                "UnityEngine.Debug:Log (object)\nSceneLoader:Load () (at Assets/SceneLoader.cs:27)\n"
+                    .to_string(),
+        },
+    )
+}
+
+fn encode(c: &mut Criterion) {
+    let msg = console_output();
+    let mut group = c.benchmark_group("encode console output");
+
+    group.bench_function("through a vec", |b| {
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .big_endian()
+            .new_codec();
+        let mut dst = BytesMut::new();
+        b.iter(|| {
+            dst.clear();
+            // Cloned like below, where the codec takes the message by value.
+            let bytes = DefaultFormat::serialize(&black_box(msg.clone())).unwrap();
+            codec.encode(Bytes::from(bytes), &mut dst).unwrap();
+        });
+    });
+
+    group.bench_function("in place", |b| {
+        let mut codec = ServerCodec::new();
+        let mut dst = BytesMut::new();
+        b.iter(|| {
+            dst.clear();
+            codec.encode(black_box(msg.clone()), &mut dst).unwrap();
+        });
+    });
+
+    group.finish();
+}
+
+fn command_result() -> Envelope<ServerMessage> {
+    Envelope::new(
+        42,
+        ServerMessage::CommandResult {
+            request_id: 1,
+            payload: vec![7; 1 << 20],
+            content_type: "application/octet-stream".to_string(),
+        },
+    )
+}
+
+fn decode(c: &mut Criterion) {
+    let mut codec = AsyncHeteroCodec::<Envelope<ServerMessage>, Envelope<ServerMessage>>::new();
+    let mut frame = BytesMut::new();
+    codec.encode(command_result(), &mut frame).unwrap();
+
+    c.bench_function("decode a 1 MB command result", |b| {
+        b.iter_batched(
+            // Copying the frame back in for every iteration isn't part of the measurement.
+            || frame.clone(),
+            |mut src| codec.decode(&mut src).unwrap().unwrap(),
+            BatchSize::LargeInput,
+        );
+    });
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
@@ -1,27 +1,240 @@
 use std::{
+    borrow::Cow,
+    fmt::{self, Display},
     io::{Read, Write},
     marker::PhantomData,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
 
 #[cfg(feature = "async")]
-use bytes::BytesMut;
+use bytes::{BufMut, BytesMut};
 
 #[cfg(feature = "async")]
-use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec, LengthDelimitedCodecError};
+
+/// Frames longer than this are refused by both codecs, so a corrupt length prefix can't make us
+/// allocate gigabytes.
+pub const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+#[cfg(not(any(feature = "wire-bincode", feature = "wire-json")))]
+compile_error!("either the `wire-bincode` or the `wire-json` feature must be enabled");
+
+/// Bumped whenever `ClientMessage`/`ServerMessage` change in a way older peers can't decode.
+pub const PROTOCOL_VERSION: u32 = 9;
 
 pub const MDNS_SERVICE_NAME: &str = "_unity-cli._tcp.local.";
 pub const PROJECT_PATH_PROP_KEY: &str = "project-path";
 pub const PROJECT_NAME_PROP_KEY: &str = "project-name";
 pub const UNITY_VERSION_PROP_KEY: &str = "unity-version";
+pub const SESSION_ID_PROP_KEY: &str = "session-id";
+pub const PROTOCOL_VERSION_PROP_KEY: &str = "protocol-version";
+/// Only advertised by sessions accepting TLS connections. See [`tls_fingerprint`].
+pub const TLS_FINGERPRINT_PROP_KEY: &str = "tls-sha256";
+/// When the session started, in milliseconds since the Unix epoch.
+pub const STARTED_AT_PROP_KEY: &str = "started-at";
+/// Every property a session advertises on its own. The extra ones given by Unity can't take
+/// these keys.
+pub const BUILTIN_PROP_KEYS: [&str; 7] = [
+    PROJECT_PATH_PROP_KEY,
+    PROJECT_NAME_PROP_KEY,
+    UNITY_VERSION_PROP_KEY,
+    SESSION_ID_PROP_KEY,
+    PROTOCOL_VERSION_PROP_KEY,
+    TLS_FINGERPRINT_PROP_KEY,
+    STARTED_AT_PROP_KEY,
+];
+
+/// Lowercase hex SHA-256 of a DER encoded certificate, as advertised under
+/// [`TLS_FINGERPRINT_PROP_KEY`].
+#[cfg(feature = "tls")]
+pub fn tls_fingerprint(cert_der: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(cert_der)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Full path of the Windows named pipe called `name`, which may already be a full path.
+pub fn named_pipe_path(name: &str) -> String {
+    if name.starts_with(r"\\") {
+        name.to_owned()
+    } else {
+        format!(r"\\.\pipe\{name}")
+    }
+}
+
+/// Whether `text` matches `pattern` as a whole, where `*` matches any run of characters and `?`
+/// any single one.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+    let text: Vec<_> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*` if the rest doesn't match: the `*`'s position, and how
+    // much of the text it swallowed.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, swallowed)) => {
+                    p = star + 1;
+                    t = swallowed + 1;
+                    backtrack = Some((star, swallowed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Whether `pattern` uses any of the wildcards understood by [`glob_matches`].
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// In human readable formats like JSON, every message is an object with a `type` field naming
+/// its variant, e.g. `{"type":"ListCommands","request_id":1}`. Compact formats like bincode
+/// can't decode tagged enums, so they keep serde's default representation.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(remote = "Self")]
 pub enum ClientMessage {
-    CommandRequest { cmd: String, args: Vec<String> },
+    CommandRequest {
+        /// Chosen by the client and echoed back in replies to this request.
+        request_id: u64,
+        cmd: String,
+        args: Vec<String>,
+        /// `key=value` arguments, in the order given. Empty for positional-only commands.
+        named_args: Vec<(String, String)>,
+        /// Where the client was invoked, for commands taking paths relative to it.
+        cwd: Option<String>,
+        /// `KEY=VALUE` environment for the command, like a build number. Empty if none was given.
+        env: Vec<(String, String)>,
+        /// Input for the command, like a snippet to evaluate. Input too long for one message is
+        /// sent ahead in `StdinChunk`s, and only its end is here.
+        stdin: Option<Vec<u8>>,
+    },
+    /// The start of a command's input, sent before the `CommandRequest` with the same id.
+    StdinChunk {
+        request_id: u64,
+        data: Vec<u8>,
+    },
+    ListCommands {
+        request_id: u64,
+    },
+    /// Asks Unity to stop the given command. Its `CommandFinished` is still sent.
+    CancelCommand {
+        request_id: u64,
+    },
+    /// Asks for up to the last `count` console messages that were sent to every connection, e.g.
+    /// those logged before this client connected.
+    RequestBacklog {
+        count: u32,
+    },
+    /// Sent first on every connection, before anything else is sent or the `Welcome` arrives.
+    Hello {
+        /// What the client can decompress, for the server to pick from.
+        compression: Vec<Compression>,
+    },
+    /// Asks Unity to do one of the things it can do on its own, which is answered like a
+    /// `CommandRequest`.
+    BuiltinRequest {
+        request_id: u64,
+        builtin: Builtin,
+        /// Where the client was invoked, like in a `CommandRequest`.
+        cwd: Option<String>,
+    },
+}
+
+/// What the editor does by itself, as opposed to the commands registered by the project. These
+/// have their own message so that a custom command of the same name is never run instead.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Builtin {
+    /// Enters play mode.
+    Play,
+    /// Pauses play mode, or resumes it if paused.
+    Pause,
+    /// Exits play mode.
+    StopPlay,
+    /// Builds the player, for the active target into the last used location unless told
+    /// otherwise.
+    Build {
+        /// E.g. `android`.
+        target: Option<String>,
+        path: Option<String>,
+    },
+    /// Compiles the project's scripts.
+    Compile {
+        /// `KEY=VALUE` environment to compile with, like a `CommandRequest`'s.
+        env: Vec<(String, String)>,
+    },
+}
+
+/// How frames are compressed once the handshake agreed on it. The `Hello` and the `Welcome`
+/// themselves never are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Requires the `zstd` feature.
+    Zstd,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Zstd's default, fast enough to keep up with bursts of console output.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+impl Compression {
+    /// What this build can decompress, to offer in a `Hello`.
+    pub fn supported() -> Vec<Self> {
+        let mut supported = vec![Self::None];
+        #[cfg(feature = "zstd")]
+        supported.push(Self::Zstd);
+        supported
+    }
+
+    fn compress(self, bytes: Vec<u8>) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Self::None => Ok(bytes),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Ok(zstd::bulk::compress(&bytes, ZSTD_LEVEL)?),
+            #[cfg(not(feature = "zstd"))]
+            Self::Zstd => Err(zstd_unsupported()),
+        }
+    }
+
+    /// Refuses to inflate `frame` beyond `max_len`, the limit of the frame itself.
+    fn decompress(self, frame: &[u8], max_len: usize) -> Result<Cow<'_, [u8]>, CodecError> {
+        match self {
+            Self::None => Ok(Cow::Borrowed(frame)),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Ok(Cow::Owned(zstd::bulk::decompress(frame, max_len)?)),
+            #[cfg(not(feature = "zstd"))]
+            Self::Zstd => Err(zstd_unsupported()),
+        }
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_unsupported() -> CodecError {
+    CodecError::Io(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "zstd compression requires the `zstd` feature",
+    ))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum UnityLogType {
     Error = 0,
     Assert = 1,
@@ -44,7 +257,9 @@ impl From<i32> for UnityLogType {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Tagged with a `type` field in human readable formats, like [`ClientMessage`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(remote = "Self")]
 pub enum ServerMessage {
     UnityConsoleOutput {
         log_type: UnityLogType,
@@ -59,118 +274,615 @@ pub enum ServerMessage {
     AssemblyReloaded,
     IsBusy,
     CommandFinished {
+        request_id: u64,
         is_success: bool,
         msg: Option<String>,
     },
+    Rejected {
+        reason: String,
+    },
+    /// Unity refused to run the command, e.g. because no such command is registered.
+    CommandRejected {
+        request_id: u64,
+        reason: String,
+    },
+    CommandList {
+        request_id: u64,
+        commands: Vec<String>,
+    },
+    /// Unity hasn't called back for a while although commands are pending, e.g. because the
+    /// editor froze.
+    EditorUnresponsive {
+        idle_secs: u64,
+    },
+    /// Output of a command that doesn't fit in `CommandFinished`, like JSON or binary data. The
+    /// command is still finished with `CommandFinished`.
+    CommandResult {
+        request_id: u64,
+        payload: Vec<u8>,
+        /// MIME type of `payload`, e.g. `application/json`.
+        content_type: String,
+    },
+    /// Part of a command's output that is too large for one `CommandResult`, like a build report.
+    /// Chunks are numbered from zero, and the command is still finished with `CommandFinished`.
+    ResultChunk {
+        request_id: u64,
+        seq: u32,
+        data: Vec<u8>,
+        is_last: bool,
+    },
+    /// Sent first on every accepted connection, so clients can tell a live server from one that
+    /// accepted the connection but will never answer.
+    Welcome {
+        /// The server's [`PROTOCOL_VERSION`], told here too for connections that skipped
+        /// discovery, like over a Unix socket.
+        protocol_version: u32,
+        /// What every later frame is compressed with, in both directions, picked from what the
+        /// client's `Hello` offered.
+        compression: Compression,
+    },
+    /// Something the server itself tells the client, like that console messages were dropped,
+    /// as opposed to what Unity logged.
+    Notice {
+        level: UnityLogType,
+        text: String,
+    },
+}
+
+// The tagged representations. Deriving them with `remote` makes the compiler check that they
+// have exactly the variants and fields of the messages they mirror.
+
+#[derive(Deserialize, Serialize)]
+#[serde(remote = "ClientMessage", tag = "type")]
+enum TaggedClientMessage {
+    CommandRequest {
+        request_id: u64,
+        cmd: String,
+        args: Vec<String>,
+        named_args: Vec<(String, String)>,
+        cwd: Option<String>,
+        env: Vec<(String, String)>,
+        stdin: Option<Vec<u8>>,
+    },
+    StdinChunk {
+        request_id: u64,
+        data: Vec<u8>,
+    },
+    ListCommands {
+        request_id: u64,
+    },
+    CancelCommand {
+        request_id: u64,
+    },
+    RequestBacklog {
+        count: u32,
+    },
+    Hello {
+        compression: Vec<Compression>,
+    },
+    BuiltinRequest {
+        request_id: u64,
+        builtin: Builtin,
+        cwd: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(remote = "ServerMessage", tag = "type")]
+enum TaggedServerMessage {
+    UnityConsoleOutput {
+        log_type: UnityLogType,
+        log: String,
+        stack_trace: String,
+    },
+    CompilationStarted,
+    Compiling,
+    CompilationFinished {},
+    AssemblyUnloaded,
+    AssemblyReloading,
+    AssemblyReloaded,
+    IsBusy,
+    CommandFinished {
+        request_id: u64,
+        is_success: bool,
+        msg: Option<String>,
+    },
+    Rejected {
+        reason: String,
+    },
+    CommandRejected {
+        request_id: u64,
+        reason: String,
+    },
+    CommandList {
+        request_id: u64,
+        commands: Vec<String>,
+    },
+    EditorUnresponsive {
+        idle_secs: u64,
+    },
+    CommandResult {
+        request_id: u64,
+        payload: Vec<u8>,
+        content_type: String,
+    },
+    ResultChunk {
+        request_id: u64,
+        seq: u32,
+        data: Vec<u8>,
+        is_last: bool,
+    },
+    Welcome {
+        protocol_version: u32,
+        compression: Compression,
+    },
+    Notice {
+        level: UnityLogType,
+        text: String,
+    },
+}
+
+/// Implements `Serialize` and `Deserialize` for `$msg` with `$tagged` in human readable formats,
+/// and with the inherent functions `#[serde(remote = "Self")]` derived on `$msg` otherwise.
+macro_rules! tag_if_human_readable {
+    ($msg:ident, $tagged:ident) => {
+        impl Serialize for $msg {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                if serializer.is_human_readable() {
+                    $tagged::serialize(self, serializer)
+                } else {
+                    $msg::serialize(self, serializer)
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $msg {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                if deserializer.is_human_readable() {
+                    $tagged::deserialize(deserializer)
+                } else {
+                    $msg::deserialize(deserializer)
+                }
+            }
+        }
+    };
+}
+
+tag_if_human_readable!(ClientMessage, TaggedClientMessage);
+tag_if_human_readable!(ServerMessage, TaggedServerMessage);
+
+impl ServerMessage {
+    /// The welcome of a server speaking this crate's [`PROTOCOL_VERSION`], leaving frames
+    /// uncompressed.
+    pub fn welcome() -> Self {
+        Self::Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            compression: Compression::None,
+        }
+    }
+}
+
+/// What both peers write: a message along with what every message is sent with, so that is
+/// added here instead of to each variant.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Envelope<T> {
+    /// Counts up from zero on every connection, in each direction. Server messages dropped for a
+    /// client that wasn't reading fast enough still take up their number, so a gap tells the
+    /// client how many it missed.
+    pub seq: u64,
+    /// When the message was sent, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub payload: T,
+}
+
+impl<T> Envelope<T> {
+    /// Puts `payload` in an envelope numbered `seq`, stamped with the current time.
+    pub fn new(seq: u64, payload: T) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        Self {
+            seq,
+            timestamp_ms,
+            payload,
+        }
+    }
+
+    pub fn into_payload(self) -> T {
+        self.payload
+    }
+}
+
+/// For a single message on its own, like a rejection sent right before closing.
+impl<T> From<T> for Envelope<T> {
+    fn from(payload: T) -> Self {
+        Self::new(0, payload)
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(std::io::Error),
+    Serde(Box<dyn std::error::Error + Send + Sync>),
+    FrameTooLarge,
+    VersionMismatch {
+        expected: u32,
+        found: u32,
+    },
+    /// The peer closed the connection, possibly in the middle of a message.
+    ConnectionClosed,
+}
+
+impl CodecError {
+    #[cfg(feature = "sync")]
+    fn from_read(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            Self::ConnectionClosed
+        } else {
+            Self::Io(e)
+        }
+    }
+
+    #[cfg(feature = "async")]
+    fn from_framing(e: std::io::Error) -> Self {
+        if e.get_ref()
+            .is_some_and(|inner| inner.is::<LengthDelimitedCodecError>())
+        {
+            Self::FrameTooLarge
+        } else {
+            Self::Io(e)
+        }
+    }
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "i/o error: {e}"),
+            Self::Serde(e) => write!(f, "malformed message: {e}"),
+            Self::FrameTooLarge => write!(f, "frame exceeds the maximum length"),
+            Self::VersionMismatch { expected, found } => write!(
+                f,
+                "protocol version mismatch: expected {expected}, found {found}"
+            ),
+            Self::ConnectionClosed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Serde(e) => Some(e.as_ref()),
+            Self::FrameTooLarge | Self::VersionMismatch { .. } | Self::ConnectionClosed => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CodecError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[cfg(feature = "wire-bincode")]
+impl From<bincode::Error> for CodecError {
+    fn from(e: bincode::Error) -> Self {
+        Self::Serde(e)
+    }
+}
+
+#[cfg(feature = "wire-json")]
+impl From<serde_json::Error> for CodecError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Serde(Box::new(e))
+    }
+}
+
+/// How messages are encoded inside their length prefixed frames.
+pub trait WireFormat {
+    fn serialize<T: Serialize>(item: &T) -> Result<Vec<u8>, CodecError>;
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError>;
+
+    /// Like [`serialize`](Self::serialize), but writes straight to `dst`.
+    fn serialize_into<T: Serialize, W: Write>(item: &T, mut dst: W) -> Result<(), CodecError> {
+        dst.write_all(&Self::serialize(item)?)?;
+        Ok(())
+    }
+}
+
+/// Compact, and the default.
+#[cfg(feature = "wire-bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "wire-bincode")]
+impl WireFormat for Bincode {
+    fn serialize<T: Serialize>(item: &T) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(item).map_err(CodecError::from)
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        bincode::deserialize(bytes).map_err(CodecError::from)
+    }
+
+    fn serialize_into<T: Serialize, W: Write>(item: &T, dst: W) -> Result<(), CodecError> {
+        bincode::serialize_into(dst, item).map_err(CodecError::from)
+    }
+}
+
+/// Readable on the wire, for debugging and for clients not written in Rust.
+#[cfg(feature = "wire-json")]
+pub struct Json;
+
+#[cfg(feature = "wire-json")]
+impl WireFormat for Json {
+    fn serialize<T: Serialize>(item: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(item).map_err(CodecError::from)
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(CodecError::from)
+    }
+
+    fn serialize_into<T: Serialize, W: Write>(item: &T, dst: W) -> Result<(), CodecError> {
+        serde_json::to_writer(dst, item).map_err(CodecError::from)
+    }
 }
 
+/// The format picked by the enabled `wire-*` features, which both peers must agree on.
+#[cfg(feature = "wire-json")]
+pub type DefaultFormat = Json;
+#[cfg(all(feature = "wire-bincode", not(feature = "wire-json")))]
+pub type DefaultFormat = Bincode;
+
 #[cfg(feature = "sync")]
-pub type ClientCodec = SyncHeteroCodec<ClientMessage, ServerMessage>;
+pub type ClientCodec = SyncHeteroCodec<Envelope<ClientMessage>, Envelope<ServerMessage>>;
 
 #[cfg(feature = "async")]
-pub type ServerCodec = AsyncHeteroCodec<ServerMessage, ClientMessage>;
+pub type ServerCodec = AsyncHeteroCodec<Envelope<ServerMessage>, Envelope<ClientMessage>>;
 
 #[cfg(feature = "sync")]
-pub struct SyncHeteroCodec<T, U> {
+pub struct SyncHeteroCodec<T, U, F = DefaultFormat> {
+    compression: Compression,
     _t: PhantomData<T>,
     _u: PhantomData<U>,
+    _f: PhantomData<F>,
 }
 
 #[cfg(feature = "sync")]
-impl<T, U> SyncHeteroCodec<T, U> {
+impl<T, U, F> SyncHeteroCodec<T, U, F> {
     pub fn new() -> Self {
         Self {
+            compression: Compression::None,
             _t: PhantomData::<_>,
             _u: PhantomData::<_>,
+            _f: PhantomData::<_>,
         }
     }
+
+    /// Compresses the frames written and decompresses those read from now on, once the
+    /// handshake picked `compression`.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
 }
 
 #[cfg(feature = "sync")]
-impl<T, U> Default for SyncHeteroCodec<T, U> {
+impl<T, U, F> Default for SyncHeteroCodec<T, U, F> {
     fn default() -> Self {
         Self::new()
     }
 }
 
 #[cfg(feature = "sync")]
-impl<T, U> SyncHeteroCodec<T, U>
+impl<T, U, F> SyncHeteroCodec<T, U, F>
 where
     T: Serialize,
     U: DeserializeOwned,
+    F: WireFormat,
 {
-    pub fn write<W: Write>(&self, item: &T, dst: &mut W) -> anyhow::Result<()> {
-        let bytes = bincode::serialize(item)?;
+    /// Returns how many bytes were written, the length prefix included.
+    pub fn write<W: Write>(&self, item: &T, dst: &mut W) -> Result<usize, CodecError> {
+        let bytes = self.compression.compress(F::serialize(item)?)?;
         dst.write_all(&(bytes.len() as u32).to_be_bytes())?;
-        dst.write_all(&bytes).map_err(anyhow::Error::new)
+        dst.write_all(&bytes)?;
+        Ok(4 + bytes.len())
+    }
+
+    /// Decodes the first whole message in `buf` and removes it from there. Returns `None` until
+    /// `buf` holds a whole message, for readers that can't block until it arrives.
+    pub fn read_buffered(&self, buf: &mut Vec<u8>) -> Result<Option<U>, CodecError> {
+        let Some(len_buf) = buf.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes([len_buf[0], len_buf[1], len_buf[2], len_buf[3]]) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(CodecError::FrameTooLarge);
+        }
+        let Some(payload) = buf.get(4..4 + len) else {
+            return Ok(None);
+        };
+        let item = F::deserialize(&self.compression.decompress(payload, MAX_FRAME_LEN)?)?;
+        buf.drain(..4 + len);
+        Ok(Some(item))
+    }
+
+    pub fn read<R: Read>(&self, src: &mut R) -> Result<U, CodecError> {
+        self.read_with_len(src).map(|(item, _)| item)
     }
 
-    pub fn read<R: Read>(&self, src: &mut R) -> anyhow::Result<U> {
+    /// Like [`read`](Self::read), but also returns how many bytes were read, the length prefix
+    /// included.
+    pub fn read_with_len<R: Read>(&self, src: &mut R) -> Result<(U, usize), CodecError> {
         let mut len_buf = [0_u8; 4];
-        src.read_exact(&mut len_buf)?;
+        src.read_exact(&mut len_buf)
+            .map_err(CodecError::from_read)?;
         let len = u32::from_be_bytes(len_buf) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(CodecError::FrameTooLarge);
+        }
         let mut buf = vec![0_u8; len];
-        src.read_exact(&mut buf)?;
-        bincode::deserialize(&buf).map_err(anyhow::Error::new)
+        src.read_exact(&mut buf).map_err(CodecError::from_read)?;
+        let item = F::deserialize(&self.compression.decompress(&buf, MAX_FRAME_LEN)?)?;
+        Ok((item, 4 + len))
     }
 }
 
 #[cfg(feature = "async")]
-pub struct AsyncHeteroCodec<T, U> {
+pub struct AsyncHeteroCodec<T, U, F = DefaultFormat> {
     inner: LengthDelimitedCodec,
+    /// How much room to make at once whenever a buffer runs out of it.
+    buffer_capacity: usize,
+    compression: Compression,
     _t: PhantomData<T>,
     _u: PhantomData<U>,
+    _f: PhantomData<F>,
 }
 
 #[cfg(feature = "async")]
-impl<T, U> AsyncHeteroCodec<T, U> {
+impl<T, U, F> AsyncHeteroCodec<T, U, F> {
     pub fn new() -> Self {
-        Self {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> AsyncHeteroCodecBuilder<T, U, F> {
+        AsyncHeteroCodecBuilder {
+            buffer_capacity: 0,
+            max_frame_length: MAX_FRAME_LEN,
+            _t: PhantomData::<_>,
+            _u: PhantomData::<_>,
+            _f: PhantomData::<_>,
+        }
+    }
+
+    /// Like [`SyncHeteroCodec::set_compression`].
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Makes room for at least `needed` more bytes in `buf`, and for `buffer_capacity` of them
+    /// if it has to grow anyway, so that a burst of small messages doesn't reallocate it again
+    /// and again.
+    fn reserve(&self, buf: &mut BytesMut, needed: usize) {
+        if buf.capacity() - buf.len() < needed {
+            buf.reserve(needed.max(self.buffer_capacity));
+        }
+    }
+}
+
+/// Configures an [`AsyncHeteroCodec`], e.g. to stream lots of console output.
+#[cfg(feature = "async")]
+pub struct AsyncHeteroCodecBuilder<T, U, F = DefaultFormat> {
+    buffer_capacity: usize,
+    max_frame_length: usize,
+    _t: PhantomData<T>,
+    _u: PhantomData<U>,
+    _f: PhantomData<F>,
+}
+
+#[cfg(feature = "async")]
+impl<T, U, F> AsyncHeteroCodecBuilder<T, U, F> {
+    /// How many bytes to make room for whenever the read or write buffer is full. Zero, the
+    /// default, only makes room for the message at hand.
+    pub fn buffer_capacity(mut self, capacity: usize) -> Self {
+        self.buffer_capacity = capacity;
+        self
+    }
+
+    /// Frames longer than this are refused. Defaults to [`MAX_FRAME_LEN`].
+    pub fn max_frame_length(mut self, len: usize) -> Self {
+        self.max_frame_length = len;
+        self
+    }
+
+    pub fn build(self) -> AsyncHeteroCodec<T, U, F> {
+        AsyncHeteroCodec {
             inner: LengthDelimitedCodec::builder()
                 .length_field_type::<u32>()
+                .max_frame_length(self.max_frame_length)
                 .big_endian()
                 .new_codec(),
+            buffer_capacity: self.buffer_capacity,
+            compression: Compression::None,
             _t: PhantomData::<_>,
             _u: PhantomData::<_>,
+            _f: PhantomData::<_>,
         }
     }
 }
 
 #[cfg(feature = "async")]
-impl<T, U> Default for AsyncHeteroCodec<T, U> {
+impl<T, U, F> Default for AsyncHeteroCodec<T, U, F> {
     fn default() -> Self {
         Self::new()
     }
 }
 
 #[cfg(feature = "async")]
-impl<T, U> Encoder<T> for AsyncHeteroCodec<T, U>
+impl<T, U, F> Encoder<T> for AsyncHeteroCodec<T, U, F>
 where
     T: Serialize,
+    F: WireFormat,
 {
-    type Error = anyhow::Error;
+    type Error = CodecError;
 
     fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let bytes = bincode::serialize(&item)?;
-        self.inner
-            .encode(bytes.into(), dst)
-            .map_err(anyhow::Error::new)
+        if self.compression != Compression::None {
+            let bytes = self.compression.compress(F::serialize(&item)?)?;
+            if bytes.len() > self.inner.max_frame_length() {
+                return Err(CodecError::FrameTooLarge);
+            }
+            self.reserve(dst, 4 + bytes.len());
+            dst.put_u32(bytes.len() as u32);
+            dst.put_slice(&bytes);
+            return Ok(());
+        }
+
+        // Most messages are small console logs, so they are serialized in place after a
+        // placeholder for their length, instead of into a `Vec` that is then copied over.
+        let start = dst.len();
+        self.reserve(dst, 4);
+        dst.put_u32(0);
+        if let Err(e) = F::serialize_into(&item, (&mut *dst).writer()) {
+            dst.truncate(start);
+            return Err(e);
+        }
+        let len = dst.len() - start - 4;
+        if len > self.inner.max_frame_length() {
+            dst.truncate(start);
+            return Err(CodecError::FrameTooLarge);
+        }
+        dst[start..start + 4].copy_from_slice(&(len as u32).to_be_bytes());
+        Ok(())
     }
 }
 
 #[cfg(feature = "async")]
-impl<T, U> Decoder for AsyncHeteroCodec<T, U>
+impl<T, U, F> Decoder for AsyncHeteroCodec<T, U, F>
 where
     U: DeserializeOwned,
+    F: WireFormat,
 {
     type Item = U;
-    type Error = anyhow::Error;
+    type Error = CodecError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        self.inner
-            .decode(src)?
-            .map(|bytes| bincode::deserialize(&bytes).map_err(anyhow::Error::new))
-            .transpose()
+        // The frame is split off `src` without copying, and deserialized straight from there, so
+        // reading through a `Buf` wouldn't save anything.
+        let compression = self.compression;
+        let max_len = self.inner.max_frame_length();
+        let item = self
+            .inner
+            .decode(src)
+            .map_err(CodecError::from_framing)?
+            .map(|bytes| F::deserialize(&compression.decompress(&bytes, max_len)?))
+            .transpose()?;
+        if self.buffer_capacity > 0 {
+            // Room for the next reads, which would otherwise grow the buffer bit by bit.
+            self.reserve(src, 1);
+        }
+        Ok(item)
     }
 }
 
@@ -192,25 +904,31 @@ mod tests {
             let handle = tokio::task::spawn_blocking(move || {
                 let mut read = std::net::TcpStream::connect(format!("127.0.0.1:{}", port))?;
                 let codec = ClientCodec::new();
-                let msg = codec.read(&mut read)?;
-                anyhow::Result::<ServerMessage>::Ok(msg)
+                let envelope = codec.read(&mut read)?;
+                anyhow::Result::<Envelope<ServerMessage>>::Ok(envelope)
             });
 
             let finish_msg = Some("Test message. 🤓\n".repeat(100));
 
             let (stream, _) = listener.accept().await?;
             let mut write = FramedWrite::new(stream, ServerCodec::new());
-            write
-                .send(ServerMessage::CommandFinished {
+            let sent = Envelope::new(
+                7,
+                ServerMessage::CommandFinished {
+                    request_id: 1,
                     is_success: true,
                     msg: finish_msg.clone(),
-                })
-                .await?;
+                },
+            );
+            let timestamp_ms = sent.timestamp_ms;
+            write.send(sent).await?;
 
-            let msg = handle.await??;
+            let envelope = handle.await??;
 
+            assert_eq!(7, envelope.seq);
+            assert_eq!(timestamp_ms, envelope.timestamp_ms);
             assert!(
-                matches!(msg, ServerMessage::CommandFinished { is_success, msg } if is_success && msg == finish_msg)
+                matches!(envelope.payload, ServerMessage::CommandFinished { request_id: 1, is_success, msg } if is_success && msg == finish_msg)
             );
 
             anyhow::Result::<()>::Ok(())
@@ -231,23 +949,29 @@ mod tests {
             let args = vec!["--bar".to_string(), "42".to_string()];
 
             let msg = ClientMessage::CommandRequest {
+                request_id: 42,
                 cmd: cmd.clone(),
                 args: args.clone(),
+                named_args: vec![],
+                cwd: None,
+                env: vec![],
+                stdin: None,
             };
 
             let handle = tokio::task::spawn_blocking(move || {
                 let (mut stream, _) = listener.accept()?;
                 let codec = ClientCodec::new();
-                codec.write(&msg, &mut stream)?;
+                codec.write(&Envelope::new(3, msg), &mut stream)?;
                 anyhow::Result::<()>::Ok(())
             });
 
             let stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port)).await?;
             let mut read = FramedRead::new(stream, ServerCodec::new());
-            let msg = read.next().await.expect("No msg received!")?;
+            let envelope = read.next().await.expect("No msg received!")?;
 
+            assert_eq!(3, envelope.seq);
             assert!(
-                matches!(msg, ClientMessage::CommandRequest { cmd: cmd1, args: args1 } if cmd1 == cmd && args1 == args)
+                matches!(envelope.payload, ClientMessage::CommandRequest { request_id: 42, cmd: cmd1, args: args1, .. } if cmd1 == cmd && args1 == args)
             );
 
             handle.await??;
@@ -259,4 +983,590 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn oversized_frame_is_reported() {
+        let mut src = BytesMut::from(&u32::MAX.to_be_bytes()[..]);
+
+        assert!(matches!(
+            ServerCodec::new().decode(&mut src),
+            Err(CodecError::FrameTooLarge)
+        ));
+    }
+
+    #[test]
+    fn sync_oversized_frame_is_reported() {
+        let mut src = std::io::Cursor::new(u32::MAX.to_be_bytes());
+
+        assert!(matches!(
+            ClientCodec::new().read(&mut src),
+            Err(CodecError::FrameTooLarge)
+        ));
+    }
+
+    #[test]
+    fn sync_read_reassembles_split_frame() -> anyhow::Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+
+        let handle = std::thread::spawn(move || -> anyhow::Result<()> {
+            let (mut stream, _) = listener.accept()?;
+            let mut frame = Vec::new();
+            SyncHeteroCodec::<Envelope<ServerMessage>, ()>::new().write(
+                &Envelope::from(ServerMessage::CommandFinished {
+                    request_id: 1,
+                    is_success: true,
+                    msg: Some("done".to_string()),
+                }),
+                &mut frame,
+            )?;
+            stream.write_all(&frame[..4])?;
+            stream.flush()?;
+            std::thread::sleep(Duration::from_millis(50));
+            stream.write_all(&frame[4..])?;
+            Ok(())
+        });
+
+        let mut read = std::net::TcpStream::connect(("127.0.0.1", port))?;
+        let msg = ClientCodec::new().read(&mut read)?.payload;
+        handle.join().unwrap()?;
+
+        assert!(matches!(
+            msg,
+            ServerMessage::CommandFinished { is_success: true, msg: Some(ref msg), .. } if msg == "done"
+        ));
+        assert!(matches!(
+            ClientCodec::new().read(&mut read),
+            Err(CodecError::ConnectionClosed)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_payload_is_reported() {
+        let mut src = std::io::Cursor::new(vec![0, 0, 0, 1, 0xff]);
+
+        assert!(matches!(
+            ClientCodec::new().read(&mut src),
+            Err(CodecError::Serde(_))
+        ));
+    }
+
+    /// Frames `msg` with `F`, and checks that only the body differs between formats.
+    fn round_trip<F: WireFormat>(msg: &ServerMessage) -> ServerMessage {
+        let mut frame = Vec::new();
+        SyncHeteroCodec::<ServerMessage, ServerMessage, F>::new()
+            .write(msg, &mut frame)
+            .unwrap();
+        let len = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize;
+        assert_eq!(frame.len() - 4, len);
+
+        let read = SyncHeteroCodec::<ServerMessage, ServerMessage, F>::new()
+            .read(&mut std::io::Cursor::new(&frame))
+            .unwrap();
+        let mut buf = BytesMut::from(&frame[..]);
+        let decoded = AsyncHeteroCodec::<ServerMessage, ServerMessage, F>::new()
+            .decode(&mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(format!("{read:?}"), format!("{decoded:?}"));
+        read
+    }
+
+    fn sample_message() -> ServerMessage {
+        ServerMessage::UnityConsoleOutput {
+            log_type: UnityLogType::Warning,
+            log: "Missing reference in \"Main\" 🤔".to_string(),
+            stack_trace: "at Foo.cs:42\n".to_string(),
+        }
+    }
+
+    #[test]
+    fn bincode_round_trip() {
+        assert!(matches!(
+            round_trip::<Bincode>(&sample_message()),
+            ServerMessage::UnityConsoleOutput { log_type: UnityLogType::Warning, ref log, .. }
+                if log == "Missing reference in \"Main\" 🤔"
+        ));
+    }
+
+    #[cfg(feature = "wire-json")]
+    #[test]
+    fn json_round_trip() {
+        assert!(matches!(
+            round_trip::<Json>(&sample_message()),
+            ServerMessage::UnityConsoleOutput { log_type: UnityLogType::Warning, ref log, .. }
+                if log == "Missing reference in \"Main\" 🤔"
+        ));
+
+        let mut frame = Vec::new();
+        SyncHeteroCodec::<ServerMessage, (), Json>::new()
+            .write(&ServerMessage::IsBusy, &mut frame)
+            .unwrap();
+        assert_eq!(br#"{"type":"IsBusy"}"#, &frame[4..]);
+    }
+
+    fn every_client_message() -> Vec<ClientMessage> {
+        vec![
+            ClientMessage::CommandRequest {
+                request_id: 1,
+                cmd: "build".to_string(),
+                args: vec!["--fast".to_string()],
+                named_args: vec![("target".to_string(), "android".to_string())],
+                cwd: None,
+                env: vec![("BUILD_NUMBER".to_string(), "42".to_string())],
+                stdin: Some(b"end".to_vec()),
+            },
+            ClientMessage::StdinChunk {
+                request_id: 1,
+                data: b"start".to_vec(),
+            },
+            ClientMessage::ListCommands { request_id: 2 },
+            ClientMessage::CancelCommand { request_id: 3 },
+            ClientMessage::RequestBacklog { count: 4 },
+            ClientMessage::Hello {
+                compression: vec![Compression::None, Compression::Zstd],
+            },
+            ClientMessage::BuiltinRequest {
+                request_id: 5,
+                builtin: Builtin::Pause,
+                cwd: Some("/home/me".to_string()),
+            },
+            ClientMessage::BuiltinRequest {
+                request_id: 6,
+                builtin: Builtin::Build {
+                    target: Some("android".to_string()),
+                    path: None,
+                },
+                cwd: None,
+            },
+            ClientMessage::BuiltinRequest {
+                request_id: 7,
+                builtin: Builtin::Compile {
+                    env: vec![("CI".to_string(), "1".to_string())],
+                },
+                cwd: None,
+            },
+        ]
+    }
+
+    fn every_server_message() -> Vec<ServerMessage> {
+        vec![
+            sample_message(),
+            ServerMessage::CompilationStarted,
+            ServerMessage::Compiling,
+            ServerMessage::CompilationFinished {},
+            ServerMessage::AssemblyUnloaded,
+            ServerMessage::AssemblyReloading,
+            ServerMessage::AssemblyReloaded,
+            ServerMessage::IsBusy,
+            ServerMessage::CommandFinished {
+                request_id: 1,
+                is_success: true,
+                msg: Some("done".to_string()),
+            },
+            ServerMessage::Rejected {
+                reason: "busy".to_string(),
+            },
+            ServerMessage::CommandRejected {
+                request_id: 1,
+                reason: "unknown".to_string(),
+            },
+            ServerMessage::CommandList {
+                request_id: 2,
+                commands: vec!["build".to_string()],
+            },
+            ServerMessage::EditorUnresponsive { idle_secs: 30 },
+            ServerMessage::CommandResult {
+                request_id: 3,
+                payload: b"{}".to_vec(),
+                content_type: "application/json".to_string(),
+            },
+            ServerMessage::ResultChunk {
+                request_id: 4,
+                seq: 0,
+                data: vec![1, 2],
+                is_last: true,
+            },
+            ServerMessage::Welcome {
+                protocol_version: 1,
+                compression: Compression::None,
+            },
+            ServerMessage::Notice {
+                level: UnityLogType::Warning,
+                text: "3 console messages were dropped".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn json_messages_are_tagged_with_their_type() {
+        let client_json: Vec<_> = every_client_message()
+            .iter()
+            .map(|msg| serde_json::to_string(msg).unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                r#"{"type":"CommandRequest","request_id":1,"cmd":"build","args":["--fast"],"named_args":[["target","android"]],"cwd":null,"env":[["BUILD_NUMBER","42"]],"stdin":[101,110,100]}"#,
+                r#"{"type":"StdinChunk","request_id":1,"data":[115,116,97,114,116]}"#,
+                r#"{"type":"ListCommands","request_id":2}"#,
+                r#"{"type":"CancelCommand","request_id":3}"#,
+                r#"{"type":"RequestBacklog","count":4}"#,
+                r#"{"type":"Hello","compression":["None","Zstd"]}"#,
+                r#"{"type":"BuiltinRequest","request_id":5,"builtin":"Pause","cwd":"/home/me"}"#,
+                r#"{"type":"BuiltinRequest","request_id":6,"builtin":{"Build":{"target":"android","path":null}},"cwd":null}"#,
+                r#"{"type":"BuiltinRequest","request_id":7,"builtin":{"Compile":{"env":[["CI","1"]]}},"cwd":null}"#,
+            ],
+            client_json
+        );
+
+        let server_json: Vec<_> = every_server_message()
+            .iter()
+            .map(|msg| serde_json::to_string(msg).unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                r#"{"type":"UnityConsoleOutput","log_type":"Warning","log":"Missing reference in \"Main\" 🤔","stack_trace":"at Foo.cs:42\n"}"#,
+                r#"{"type":"CompilationStarted"}"#,
+                r#"{"type":"Compiling"}"#,
+                r#"{"type":"CompilationFinished"}"#,
+                r#"{"type":"AssemblyUnloaded"}"#,
+                r#"{"type":"AssemblyReloading"}"#,
+                r#"{"type":"AssemblyReloaded"}"#,
+                r#"{"type":"IsBusy"}"#,
+                r#"{"type":"CommandFinished","request_id":1,"is_success":true,"msg":"done"}"#,
+                r#"{"type":"Rejected","reason":"busy"}"#,
+                r#"{"type":"CommandRejected","request_id":1,"reason":"unknown"}"#,
+                r#"{"type":"CommandList","request_id":2,"commands":["build"]}"#,
+                r#"{"type":"EditorUnresponsive","idle_secs":30}"#,
+                r#"{"type":"CommandResult","request_id":3,"payload":[123,125],"content_type":"application/json"}"#,
+                r#"{"type":"ResultChunk","request_id":4,"seq":0,"data":[1,2],"is_last":true}"#,
+                r#"{"type":"Welcome","protocol_version":1,"compression":"None"}"#,
+                r#"{"type":"Notice","level":"Warning","text":"3 console messages were dropped"}"#,
+            ],
+            server_json
+        );
+
+        for json in client_json {
+            let msg: ClientMessage = serde_json::from_str(&json).unwrap();
+            assert_eq!(json, serde_json::to_string(&msg).unwrap());
+        }
+        for json in server_json {
+            let msg: ServerMessage = serde_json::from_str(&json).unwrap();
+            assert_eq!(json, serde_json::to_string(&msg).unwrap());
+        }
+
+        let envelope = Envelope {
+            seq: 5,
+            timestamp_ms: 1_700_000_000_000,
+            payload: ServerMessage::IsBusy,
+        };
+        assert_eq!(
+            r#"{"seq":5,"timestamp_ms":1700000000000,"payload":{"type":"IsBusy"}}"#,
+            serde_json::to_string(&envelope).unwrap()
+        );
+    }
+
+    #[test]
+    fn buffer_capacity_saves_reallocations() {
+        type Codec = AsyncHeteroCodec<ServerMessage, ServerMessage>;
+
+        fn reallocations(mut codec: Codec) -> usize {
+            let mut dst = BytesMut::new();
+            let mut reallocations = 0;
+            for _ in 0..10_000 {
+                let capacity = dst.capacity();
+                codec.encode(sample_message(), &mut dst).unwrap();
+                if dst.capacity() != capacity {
+                    reallocations += 1;
+                }
+            }
+            let mut decoded = 0;
+            while codec.decode(&mut dst).unwrap().is_some() {
+                decoded += 1;
+            }
+            assert_eq!(10_000, decoded);
+            reallocations
+        }
+
+        let by_default = reallocations(Codec::default());
+        let with_capacity = reallocations(Codec::builder().buffer_capacity(1 << 20).build());
+        assert!(
+            with_capacity < by_default,
+            "{with_capacity} reallocations with a capacity, {by_default} without"
+        );
+    }
+
+    #[test]
+    fn frames_are_decoded_in_place() {
+        let msg = ServerMessage::CommandResult {
+            request_id: 1,
+            payload: vec![7; 1 << 20],
+            content_type: "application/octet-stream".to_string(),
+        };
+        let mut codec = AsyncHeteroCodec::<ServerMessage, ServerMessage>::new();
+        let mut src = BytesMut::new();
+        codec.encode(msg, &mut src).unwrap();
+        let buffer = src.as_ptr_range();
+
+        let frame = codec.inner.decode(&mut src).unwrap().unwrap();
+        assert!(buffer.contains(&frame.as_ptr()));
+        assert!(matches!(
+            DefaultFormat::deserialize::<ServerMessage>(&frame).unwrap(),
+            ServerMessage::CommandResult { payload, .. } if payload.len() == 1 << 20
+        ));
+    }
+
+    #[test]
+    fn max_frame_length_is_configurable() {
+        let mut codec = ServerCodec::builder().max_frame_length(16).build();
+
+        assert!(matches!(
+            codec.encode(Envelope::from(sample_message()), &mut BytesMut::new()),
+            Err(CodecError::FrameTooLarge)
+        ));
+    }
+
+    #[test]
+    fn bincode_round_trips_every_variant() {
+        for msg in every_server_message() {
+            assert_eq!(
+                format!("{msg:?}"),
+                format!("{:?}", round_trip::<Bincode>(&msg))
+            );
+        }
+        for msg in every_client_message() {
+            let bytes = Bincode::serialize(&msg).unwrap();
+            let decoded: ClientMessage = Bincode::deserialize(&bytes).unwrap();
+            assert_eq!(format!("{msg:?}"), format!("{decoded:?}"));
+        }
+    }
+
+    /// Any change to how a message is laid out on the wire changes these bytes, and must come
+    /// with a bump of [`PROTOCOL_VERSION`].
+    #[cfg(feature = "wire-bincode")]
+    #[test]
+    fn wire_layout_is_pinned_to_the_protocol_version() {
+        fn encode<T: Serialize>(payload: T, dst: &mut Vec<u8>) {
+            let envelope = Envelope {
+                seq: 1,
+                timestamp_ms: 2,
+                payload,
+            };
+            Bincode::serialize_into(&envelope, dst).unwrap();
+        }
+        let mut bytes = Vec::new();
+        every_client_message()
+            .into_iter()
+            .for_each(|msg| encode(msg, &mut bytes));
+        every_server_message()
+            .into_iter()
+            .for_each(|msg| encode(msg, &mut bytes));
+
+        // 64-bit FNV-1a, which is plenty to notice a change without depending on `tls`.
+        let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        });
+        assert_eq!(
+            (9, 0x535a_e520_ffbf_943c),
+            (PROTOCOL_VERSION, hash),
+            "{hash:#018x}"
+        );
+    }
+
+    #[test]
+    fn envelopes_round_trip_between_codecs() {
+        let sent: Vec<_> = (0..)
+            .zip(every_server_message())
+            .map(|(seq, msg)| Envelope::new(seq, msg))
+            .collect();
+        let mut frames = Vec::new();
+        let sync = SyncHeteroCodec::<Envelope<ServerMessage>, ()>::new();
+        for envelope in &sent {
+            sync.write(envelope, &mut frames).unwrap();
+        }
+
+        let mut codec = AsyncHeteroCodec::<(), Envelope<ServerMessage>>::new();
+        let mut buf = BytesMut::from(&frames[..]);
+        for envelope in &sent {
+            let decoded = codec.decode(&mut buf).unwrap().unwrap();
+            assert_eq!(envelope.seq, decoded.seq);
+            assert_eq!(envelope.timestamp_ms, decoded.timestamp_ms);
+            assert_eq!(
+                format!("{:?}", envelope.payload),
+                format!("{:?}", decoded.payload)
+            );
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn zstd_frames_round_trip_between_codecs() {
+        let sent = Envelope::new(1, sample_message());
+
+        let mut sync = SyncHeteroCodec::<Envelope<ServerMessage>, Envelope<ServerMessage>>::new();
+        sync.set_compression(Compression::Zstd);
+        let mut codec = AsyncHeteroCodec::<Envelope<ServerMessage>, Envelope<ServerMessage>>::new();
+        codec.set_compression(Compression::Zstd);
+
+        let mut frames = Vec::new();
+        sync.write(&sent, &mut frames).unwrap();
+        let decoded = codec
+            .decode(&mut BytesMut::from(&frames[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(format!("{sent:?}"), format!("{decoded:?}"));
+
+        let mut buf = BytesMut::new();
+        codec.encode(decoded, &mut buf).unwrap();
+        assert_eq!(frames, buf);
+        let decoded = sync.read(&mut &buf[..]).unwrap();
+        assert_eq!(format!("{sent:?}"), format!("{decoded:?}"));
+    }
+
+    #[test]
+    fn zstd_frames_inflate_no_further_than_the_max_frame_length() {
+        let sent = Envelope::new(
+            1,
+            ClientMessage::CommandRequest {
+                request_id: 1,
+                cmd: "echo".to_owned(),
+                args: vec!["a".repeat(4096)],
+                named_args: vec![],
+                cwd: None,
+                env: vec![],
+                stdin: None,
+            },
+        );
+        let mut sync = SyncHeteroCodec::<Envelope<ClientMessage>, ()>::new();
+        sync.set_compression(Compression::Zstd);
+        let mut frames = Vec::new();
+        sync.write(&sent, &mut frames).unwrap();
+        assert!(frames.len() < 1024);
+
+        let mut codec = ServerCodec::builder().max_frame_length(1024).build();
+        codec.set_compression(Compression::Zstd);
+        assert!(codec.decode(&mut BytesMut::from(&frames[..])).is_err());
+    }
+
+    #[test]
+    fn stdin_reaches_the_server_intact() {
+        let stdin: Vec<u8> = (0..=255).collect();
+        let mut frames = Vec::new();
+        ClientCodec::new()
+            .write(
+                &Envelope::new(
+                    0,
+                    ClientMessage::StdinChunk {
+                        request_id: 7,
+                        data: stdin[..100].to_vec(),
+                    },
+                ),
+                &mut frames,
+            )
+            .unwrap();
+        ClientCodec::new()
+            .write(
+                &Envelope::new(
+                    1,
+                    ClientMessage::CommandRequest {
+                        request_id: 7,
+                        cmd: "eval".to_string(),
+                        args: vec![],
+                        named_args: vec![],
+                        cwd: None,
+                        env: vec![],
+                        stdin: Some(stdin[100..].to_vec()),
+                    },
+                ),
+                &mut frames,
+            )
+            .unwrap();
+
+        let mut codec = ServerCodec::new();
+        let mut buf = BytesMut::from(&frames[..]);
+        let mut received = Vec::new();
+        while let Some(envelope) = codec.decode(&mut buf).unwrap() {
+            match envelope.payload {
+                ClientMessage::StdinChunk { data, .. } => received.extend(data),
+                ClientMessage::CommandRequest {
+                    stdin: Some(data), ..
+                } => received.extend(data),
+                msg => panic!("unexpected message: {msg:?}"),
+            }
+        }
+        assert_eq!(stdin, received);
+    }
+
+    #[test]
+    fn named_pipe_path_prefixes_bare_names() {
+        assert_eq!(r"\\.\pipe\ucli-game", named_pipe_path("ucli-game"));
+        assert_eq!(
+            r"\\.\pipe\ucli-game",
+            named_pipe_path(r"\\.\pipe\ucli-game")
+        );
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_matches("build*", "build"));
+        assert!(glob_matches("build*", "build-android"));
+        assert!(glob_matches("*-android", "build-android"));
+        assert!(glob_matches("b?ild", "build"));
+        assert!(glob_matches("*a*b*", "xxaxxbxx"));
+        assert!(!glob_matches("build", "build-android"));
+        assert!(!glob_matches("b?ild", "bld"));
+        assert!(!glob_matches("*-ios", "build-android"));
+    }
+
+    #[test]
+    fn tls_fingerprint_is_lowercase_hex_sha256() {
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            tls_fingerprint(&[])
+        );
+    }
+
+    #[test]
+    fn buffered_read_waits_for_whole_message() {
+        let codec = SyncHeteroCodec::<ServerMessage, ServerMessage>::new();
+        let mut bytes = Vec::new();
+        codec.write(&ServerMessage::IsBusy, &mut bytes).unwrap();
+        codec
+            .write(&ServerMessage::AssemblyReloaded, &mut bytes)
+            .unwrap();
+
+        let mut buf = bytes[..3].to_vec();
+        assert!(codec.read_buffered(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&bytes[3..]);
+        assert!(matches!(
+            codec.read_buffered(&mut buf),
+            Ok(Some(ServerMessage::IsBusy))
+        ));
+        assert!(matches!(
+            codec.read_buffered(&mut buf),
+            Ok(Some(ServerMessage::AssemblyReloaded))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn sync_codec_counts_the_bytes_it_moves() {
+        let codec = SyncHeteroCodec::<ServerMessage, ServerMessage>::new();
+        let msg = ServerMessage::UnityConsoleOutput {
+            log_type: UnityLogType::Log,
+            log: "Hello".repeat(100),
+            stack_trace: String::new(),
+        };
+        let payload_len = DefaultFormat::serialize(&msg).unwrap().len();
+
+        let mut bytes = Vec::new();
+        assert_eq!(4 + payload_len, codec.write(&msg, &mut bytes).unwrap());
+        assert_eq!(4 + payload_len, bytes.len());
+
+        let (read, read_len) = codec.read_with_len(&mut bytes.as_slice()).unwrap();
+        assert!(matches!(read, ServerMessage::UnityConsoleOutput { log, .. } if log.len() == 500));
+        assert_eq!(4 + payload_len, read_len);
+    }
 }
@@ -0,0 +1,68 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+use tracing::info;
+use uuid::Uuid;
+
+/// Records who ran which command and how it ended, to answer "who ran what" on a shared editor.
+///
+/// Every event is emitted through `tracing` with the `audit` target, and also appended to a file
+/// if one was given, one `key=value` line per event.
+#[derive(Default)]
+pub(crate) struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    pub(crate) fn command_requested(&self, uuid: Uuid, request_id: u64, cmd: &str, args: usize) {
+        info!(target: "audit", %uuid, request_id, %cmd, args, "command requested.");
+        self.append(format_args!(
+            "event=requested uuid={uuid} request_id={request_id} cmd={cmd:?} args={args}"
+        ));
+    }
+
+    /// Denied by the server's command policy, so Unity never saw it.
+    pub(crate) fn command_denied(&self, uuid: Uuid, request_id: u64, cmd: &str, reason: &str) {
+        info!(target: "audit", %uuid, request_id, %cmd, %reason, "command denied.");
+        self.append(format_args!(
+            "event=denied uuid={uuid} request_id={request_id} cmd={cmd:?} reason={reason:?}"
+        ));
+    }
+
+    pub(crate) fn command_rejected(&self, uuid: Uuid, request_id: u64, reason: &str) {
+        info!(target: "audit", %uuid, request_id, %reason, "command rejected by unity.");
+        self.append(format_args!(
+            "event=rejected uuid={uuid} request_id={request_id} reason={reason:?}"
+        ));
+    }
+
+    pub(crate) fn command_finished(&self, uuid: Uuid, request_id: u64, is_success: bool) {
+        info!(target: "audit", %uuid, request_id, is_success, "command finished.");
+        self.append(format_args!(
+            "event=finished uuid={uuid} request_id={request_id} is_success={is_success}"
+        ));
+    }
+
+    fn append(&self, event: std::fmt::Arguments) {
+        let Some(ref file) = self.file else {
+            return;
+        };
+        let unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        // Failing to audit shouldn't fail the command.
+        let _ = writeln!(file.lock(), "unix_ms={unix_ms} {event}");
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use parking_lot::Mutex;
+
+use common::ServerMessage;
+
+/// The most recent console output sent to every connection, for clients that connect later and
+/// ask what they missed.
+pub(crate) struct LogBacklog {
+    logs: Mutex<VecDeque<ServerMessage>>,
+    capacity: usize,
+    /// Whether a message was ever forgotten to make room, or not kept at all.
+    has_forgotten: AtomicBool,
+}
+
+impl LogBacklog {
+    /// Keeps up to `capacity` messages. `0` keeps none.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            logs: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            has_forgotten: AtomicBool::new(false),
+        }
+    }
+
+    /// Keeps `msg`, forgetting the oldest message if the backlog is full.
+    pub(crate) fn push(&self, msg: ServerMessage) {
+        if self.capacity == 0 {
+            self.has_forgotten.store(true, Ordering::Relaxed);
+            return;
+        }
+        let mut logs = self.logs.lock();
+        if logs.len() >= self.capacity {
+            logs.pop_front();
+            self.has_forgotten.store(true, Ordering::Relaxed);
+        }
+        logs.push_back(msg);
+    }
+
+    /// Up to the last `count` messages, oldest first.
+    pub(crate) fn recent(&self, count: usize) -> Vec<ServerMessage> {
+        let logs = self.logs.lock();
+        logs.iter()
+            .skip(logs.len().saturating_sub(count))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether asking for more than [`LogBacklog::recent`] returns misses messages, rather than
+    /// there being no more.
+    pub(crate) fn has_forgotten(&self) -> bool {
+        self.has_forgotten.load(Ordering::Relaxed)
+    }
+}
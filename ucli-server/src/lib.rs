@@ -1,202 +1,1039 @@
 use std::{
+    collections::HashMap,
     ffi::{CStr, CString},
-    net::SocketAddr,
+    net::{Ipv4Addr, SocketAddr},
     os::raw::c_char,
-    sync::{Arc, OnceLock},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    task::Poll,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::Context;
 use dashmap::DashMap;
 use futures::{SinkExt, StreamExt};
 use gethostname::gethostname;
-use mdns_sd::{IPMulticastTTLOption, ServiceDaemon, ServiceInfo};
+use mdns_sd::ServiceInfo;
+use parking_lot::{Mutex as SyncMutex, RwLock as SyncRwLock};
 use socket2::{Domain, Socket, Type};
 use tokio::{
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener,
-    },
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
     runtime::Builder,
-    sync::RwLock,
+    sync::mpsc::error::TrySendError,
 };
-use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{error, info, info_span, trace, Instrument};
+use tokio_util::{
+    codec::{FramedRead, FramedWrite},
+    sync::CancellationToken,
+};
+use tracing::{error, info, info_span, trace, warn, Instrument};
 use uuid::Uuid;
 
 use common::{
-    ClientMessage, ServerCodec, ServerMessage, PROJECT_NAME_PROP_KEY, PROJECT_PATH_PROP_KEY,
+    Builtin, ClientMessage, Compression, Envelope, ServerCodec, ServerMessage, UnityLogType,
+    BUILTIN_PROP_KEYS, PROJECT_NAME_PROP_KEY, PROJECT_PATH_PROP_KEY, PROTOCOL_VERSION,
+    PROTOCOL_VERSION_PROP_KEY, SESSION_ID_PROP_KEY, STARTED_AT_PROP_KEY, TLS_FINGERPRINT_PROP_KEY,
     UNITY_VERSION_PROP_KEY,
 };
 
+mod audit;
+mod backlog;
+mod metrics;
+mod outbox;
+mod policy;
+mod rate_limit;
+mod registrar;
+#[cfg(feature = "test-support")]
+pub mod testing;
+#[cfg(feature = "tls")]
+mod tls;
+
+use audit::AuditLog;
+use backlog::LogBacklog;
+use metrics::Metrics;
+use outbox::{Outbox, OUTBOX_CAPACITY};
+use policy::CommandPolicy;
+use rate_limit::RateLimiter;
+use registrar::{Advertisement, MdnsRegistrar, Registrar};
+
+/// Tunables for [`run`]. Passing a null pointer to `run` is the same as passing
+/// `ServerOptions::default()`.
+#[repr(C)]
+#[derive(Clone)]
+pub struct ServerOptions {
+    /// How many messages from Unity may wait to be routed to the clients. Once it is full,
+    /// further console output is dropped until there is room again, while `CommandFinished`
+    /// blocks the caller instead of being dropped. `0` means the default of 1024.
+    pub message_queue_capacity: u32,
+    /// TCP port to listen on. `0` lets the OS pick a free one.
+    pub port: u16,
+    /// Connections beyond this many are sent `ServerMessage::Rejected` and closed. `0` means
+    /// no limit.
+    pub max_connections: u32,
+    /// Path to a PEM encoded certificate chain, starting with the server's own certificate.
+    /// When it is set along with `tls_key_path`, the server listens on every interface and only
+    /// speaks TLS. Otherwise it listens on loopback in plaintext. Requires the `tls` feature.
+    pub tls_cert_path: *const c_char,
+    /// Path to the PEM encoded private key of `tls_cert_path`.
+    pub tls_key_path: *const c_char,
+    /// Clients are sent `ServerMessage::EditorUnresponsive` once Unity hasn't called any of the
+    /// `on_*` functions for this long while commands are pending. `0` disables the check.
+    pub unresponsive_timeout_ms: u32,
+    /// TCP port on loopback serving Prometheus metrics over HTTP. `0` disables the endpoint.
+    /// Requires the `metrics` feature.
+    pub metrics_port: u16,
+    /// Path of a Unix domain socket to listen on instead of a TCP port. The socket isn't
+    /// advertised over mDNS, so clients must be given the path. Only supported on Unix.
+    pub unix_socket_path: *const c_char,
+    /// Name of a Windows named pipe to listen on instead of a TCP port, e.g. `ucli-<session>`
+    /// for `\\.\pipe\ucli-<session>`. Like `unix_socket_path`, it isn't advertised over mDNS.
+    /// Only supported on Windows.
+    pub pipe_name: *const c_char,
+    /// mDNS instance name to advertise, i.e. what clients pass to `--session`. Characters other
+    /// than ASCII letters, digits, `-` and `_` are replaced with `-`, and it is cut to 63 bytes.
+    /// Null picks a random name on every `run`.
+    pub session_name: *const c_char,
+    /// Connections are closed once nothing was sent either way for this long, e.g. because the
+    /// client's machine went to sleep. `0` disables the timeout.
+    pub idle_timeout_ms: u32,
+    /// Comma separated patterns of the commands clients may run, where `*` matches any run of
+    /// characters and `?` any single one, e.g. `build*,test`. Null allows every command.
+    pub allowed_commands: *const c_char,
+    /// Comma separated patterns of the commands clients may not run, like `allowed_commands`.
+    /// These win over the allowed ones.
+    pub denied_commands: *const c_char,
+    /// File to append a line to for every command requested and finished, with who requested
+    /// it. The same events are always logged with the `audit` target. Null only logs them.
+    pub audit_log_path: *const c_char,
+    /// Called from the server thread whenever a client connects, e.g. to list them in the editor.
+    pub client_connected_callback: Option<UnityConnectionCallback>,
+    /// Called from the server thread whenever a client that was connected goes away.
+    pub client_disconnected_callback: Option<UnityConnectionCallback>,
+    /// How many of the latest console messages sent to every connection are kept for clients
+    /// sending `ClientMessage::RequestBacklog`. `0` keeps none.
+    pub log_backlog_capacity: u32,
+    /// How many console messages per second are forwarded to clients, with bursts of as many.
+    /// The ones beyond are dropped, and clients are told how many about once a second. `0` means
+    /// no limit.
+    pub console_log_rate_limit: u32,
+    /// Keys of extra properties to advertise over mDNS, e.g. the target platform, for clients to
+    /// filter on with `--match-prop`. The keys of the built-in properties can't be used.
+    pub extra_property_keys: *const *const c_char,
+    /// Values of `extra_property_keys`, in the same order.
+    pub extra_property_values: *const *const c_char,
+    /// How many keys and values there are.
+    pub extra_properties_len: u32,
+    /// IPv4 address to also listen on in plaintext, on the same port as loopback, e.g. the LAN
+    /// address a CI runner connects to. Unlike loopback, anyone on that network can connect, so
+    /// prefer TLS, with which this is ignored as every interface is listened on already. Null
+    /// only listens on loopback.
+    pub lan_address: *const c_char,
+    /// Command requests with more arguments than this, named ones and environment variables
+    /// included, are failed instead of being forwarded to Unity. `0` means no limit.
+    pub max_command_args: u32,
+    /// Like `max_command_args`, but for the total length in bytes of those arguments, keys
+    /// included. `0` means no limit.
+    pub max_command_args_len: u32,
+    /// Whether to warn, naming the client it was for, about every string from Unity that isn't
+    /// valid UTF-8. Either way, their invalid bytes are replaced with U+FFFD and they are counted
+    /// in `stats_json`.
+    pub strict_utf8: bool,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            message_queue_capacity: 1024,
+            port: 0,
+            max_connections: 0,
+            tls_cert_path: std::ptr::null(),
+            tls_key_path: std::ptr::null(),
+            unresponsive_timeout_ms: 10_000,
+            metrics_port: 0,
+            unix_socket_path: std::ptr::null(),
+            pipe_name: std::ptr::null(),
+            session_name: std::ptr::null(),
+            idle_timeout_ms: 0,
+            allowed_commands: std::ptr::null(),
+            denied_commands: std::ptr::null(),
+            audit_log_path: std::ptr::null(),
+            client_connected_callback: None,
+            client_disconnected_callback: None,
+            log_backlog_capacity: 256,
+            console_log_rate_limit: 0,
+            extra_property_keys: std::ptr::null(),
+            extra_property_values: std::ptr::null(),
+            extra_properties_len: 0,
+            lan_address: std::ptr::null(),
+            max_command_args: 0,
+            max_command_args_len: 0,
+            strict_utf8: false,
+        }
+    }
+}
+
 struct Instance {
     stop_tx: tokio::sync::mpsc::Sender<()>,
-    unity_msg_send: tokio::sync::mpsc::UnboundedSender<(Uuid, ServerMessage)>,
+    unity_msg_send: tokio::sync::mpsc::Sender<(Uuid, ServerMessage)>,
+    dropped_console_msgs: AtomicU64,
+    activity: Arc<UnityActivity>,
+    metrics: Arc<Metrics>,
+    audit: Arc<AuditLog>,
+    backlog: Arc<LogBacklog>,
+    rate_limiter: RateLimiter,
+    /// The TCP port listened on, `0` until it is bound or when listening on a local transport.
+    port: Arc<AtomicU16>,
+    started_at: Instant,
+    /// Cancelled by `stop`, which ends every connection right away instead of when its socket
+    /// closes.
+    shutdown: CancellationToken,
+    strict_utf8: bool,
+    /// What it was started with, to tell whether a later `run` asks for anything else.
+    config: RunConfig,
+}
+
+/// The inputs of `run` that shape a server, as read from Unity but not yet checked, so that a
+/// later `run` can be compared against them before anything else. The callbacks aren't, since
+/// they are only put back after a domain reload.
+#[derive(Debug, PartialEq)]
+struct RunConfig {
+    project_path: String,
+    project_name: String,
+    unity_version: String,
+    message_queue_capacity: u32,
+    port: u16,
+    max_connections: u32,
+    tls_cert_path: Option<String>,
+    tls_key_path: Option<String>,
+    unresponsive_timeout_ms: u32,
+    metrics_port: u16,
+    unix_socket_path: Option<String>,
+    pipe_name: Option<String>,
+    session_name: Option<String>,
+    idle_timeout_ms: u32,
+    allowed_commands: String,
+    denied_commands: String,
+    audit_log_path: Option<String>,
+    log_backlog_capacity: u32,
+    console_log_rate_limit: u32,
+    extra_properties: Vec<(String, String)>,
+    lan_address: Option<String>,
+    max_command_args: u32,
+    max_command_args_len: u32,
+    strict_utf8: bool,
+}
+
+impl RunConfig {
+    fn read(
+        project_path: *const c_char,
+        project_name: *const c_char,
+        unity_version: *const c_char,
+        options: &ServerOptions,
+    ) -> Self {
+        let optional = |ptr: *const c_char| (!ptr.is_null()).then(|| c_char_to_str(ptr));
+        let message_queue_capacity = match options.message_queue_capacity {
+            0 => ServerOptions::default().message_queue_capacity,
+            capacity => capacity,
+        };
+        Self {
+            project_path: canonical_project_path(c_char_to_str(project_path)),
+            project_name: c_char_to_str(project_name),
+            unity_version: c_char_to_str(unity_version),
+            message_queue_capacity,
+            port: options.port,
+            max_connections: options.max_connections,
+            tls_cert_path: optional(options.tls_cert_path),
+            tls_key_path: optional(options.tls_key_path),
+            unresponsive_timeout_ms: options.unresponsive_timeout_ms,
+            metrics_port: options.metrics_port,
+            unix_socket_path: optional(options.unix_socket_path),
+            pipe_name: optional(options.pipe_name),
+            session_name: optional(options.session_name),
+            idle_timeout_ms: options.idle_timeout_ms,
+            allowed_commands: c_char_to_str(options.allowed_commands),
+            denied_commands: c_char_to_str(options.denied_commands),
+            audit_log_path: optional(options.audit_log_path),
+            log_backlog_capacity: options.log_backlog_capacity,
+            console_log_rate_limit: options.console_log_rate_limit,
+            extra_properties: read_extra_properties(options),
+            lan_address: optional(options.lan_address),
+            max_command_args: options.max_command_args,
+            max_command_args_len: options.max_command_args_len,
+            strict_utf8: options.strict_utf8,
+        }
+    }
+}
+
+impl Instance {
+    /// Queues a message that must reach the client, unlike console output which is dropped when
+    /// the queue is full.
+    fn send_reliably(&self, uuid: Uuid, msg: ServerMessage) {
+        match self.unity_msg_send.try_send((uuid, msg)) {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(item)) => match tokio::runtime::Handle::try_current() {
+                // Called back from a command the server sent, where blocking would panic.
+                Ok(runtime) => {
+                    let unity_msg_send = self.unity_msg_send.clone();
+                    runtime.spawn(async move {
+                        let _ = unity_msg_send.send(item).await;
+                    });
+                }
+                Err(_) => {
+                    let _ = self.unity_msg_send.blocking_send(item);
+                }
+            },
+        }
+    }
+
+    /// Reads a string Unity passed for the client `uuid`, like [`c_char_to_str`], but counts the
+    /// ones that weren't valid UTF-8 and warns about them in strict mode.
+    fn unity_str(&self, ptr: *const c_char, uuid: Uuid, what: &'static str) -> String {
+        if ptr.is_null() {
+            return String::new();
+        }
+        let s = unsafe { CStr::from_ptr(ptr) };
+        match s.to_str() {
+            Ok(s) => s.to_owned(),
+            Err(e) => {
+                self.metrics.invalid_utf8_string();
+                if self.strict_utf8 {
+                    warn!(
+                        %uuid,
+                        what,
+                        valid_up_to = e.valid_up_to(),
+                        "string from unity isn't valid UTF-8, replacing the invalid bytes."
+                    );
+                }
+                s.to_string_lossy().into_owned()
+            }
+        }
+    }
+}
+
+/// Shared between the FFI entry points and the watchdog noticing a frozen editor.
+struct UnityActivity {
+    last_seen: SyncMutex<Instant>,
+    pending_commands: AtomicUsize,
+}
+
+impl UnityActivity {
+    fn new() -> Self {
+        Self {
+            last_seen: SyncMutex::new(Instant::now()),
+            pending_commands: AtomicUsize::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_seen.lock() = Instant::now();
+    }
+
+    fn command_sent(&self) {
+        // Unity may have been idle for long, which doesn't count against it.
+        self.touch();
+        self.pending_commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn command_done(&self) {
+        let _ = self
+            .pending_commands
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    /// How long Unity has been silent while commands are pending, if any are.
+    fn stalled_for(&self) -> Option<Duration> {
+        if self.pending_commands.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        Some(self.last_seen.lock().elapsed())
+    }
+}
+
+// Not an async lock, so that Unity may call back into the FFI from within the callbacks the
+// runtime invokes.
+static INSTANCE: OnceLock<SyncRwLock<Option<Instance>>> = OnceLock::new();
+
+fn instance() -> &'static SyncRwLock<Option<Instance>> {
+    INSTANCE.get_or_init(|| SyncRwLock::new(None))
+}
+
+static SESSION_ID: OnceLock<Uuid> = OnceLock::new();
+
+/// Identifies this editor process. Unlike the generated instance name, it survives `stop`/`run`
+/// cycles (e.g. domain reloads), so clients can find "the same" editor again.
+fn session_id() -> Uuid {
+    *SESSION_ID.get_or_init(Uuid::new_v4)
+}
+
+/// Takes the next name of `names`, or makes one up from a UUID if it ran out, so that starting
+/// never fails for want of a name.
+fn session_name_from(mut names: impl Iterator<Item = String>) -> String {
+    names.next().unwrap_or_else(|| {
+        warn!("failed to generate a session name, falling back to a UUID.");
+        format!("ucli-{}", Uuid::new_v4())
+    })
 }
 
-static INSTANCE: OnceLock<RwLock<Option<Instance>>> = OnceLock::new();
+/// `(uuid_hi, uuid_lo, request_id, cmd, args, args_len, named_arg_keys, named_arg_values,
+/// named_args_len, cwd, env_keys, env_values, env_len, stdin, stdin_len)`. The same uuid and
+/// request id must be handed back when replying to the command. `cwd` is where the client was
+/// invoked, or null if it didn't tell. `env` is the environment the client asked the command to
+/// run with. `stdin` is the input piped to the client, or null if it wasn't asked to send any.
+/// What the editor does by itself comes as a `cmd` starting with [`BUILTIN_COMMAND_PREFIX`].
+type UnityCommandCallback = extern "C" fn(
+    u64,
+    u64,
+    u64,
+    *const c_char,
+    *const *const c_char,
+    i32,
+    *const *const c_char,
+    *const *const c_char,
+    i32,
+    *const c_char,
+    *const *const c_char,
+    *const *const c_char,
+    i32,
+    *const u8,
+    i32,
+);
+
+/// `(uuid_hi, uuid_lo, request_id)`. Answered with [`on_command_list`].
+type UnityListCommandsCallback = extern "C" fn(u64, u64, u64);
+
+/// `(uuid_hi, uuid_lo, request_id)` of the command to cancel. The command must still be finished
+/// with [`on_command_finish`].
+type UnityCancelCommandCallback = extern "C" fn(u64, u64, u64);
+
+/// `(uuid_hi, uuid_lo)` of a client connecting or disconnecting, the same uuid its commands come
+/// with.
+type UnityConnectionCallback = extern "C" fn(u64, u64);
 
-fn instance() -> &'static RwLock<Option<Instance>> {
-    INSTANCE.get_or_init(|| RwLock::new(None))
+struct UnityCommand {
+    uuid: Uuid,
+    request_id: u64,
+    cmd: String,
+    args: Vec<String>,
+    named_args: Vec<(String, String)>,
+    cwd: Option<String>,
+    env: Vec<(String, String)>,
+    stdin: Option<Vec<u8>>,
 }
 
-type UnityCommandCallback = extern "C" fn(u64, u64, *const c_char, *const *const c_char, i32);
+/// Unity is asked to run a [`Builtin`] as a command named after it with this prefix, e.g.
+/// `ucli:play`. Clients can't request custom commands starting with it.
+const BUILTIN_COMMAND_PREFIX: &str = "ucli:";
+
+impl UnityCommand {
+    fn builtin(uuid: Uuid, request_id: u64, builtin: Builtin, cwd: Option<String>) -> Self {
+        let (name, named_args, env) = match builtin {
+            Builtin::Play => ("play", vec![], vec![]),
+            Builtin::Pause => ("pause", vec![], vec![]),
+            Builtin::StopPlay => ("stop-play", vec![], vec![]),
+            Builtin::Build { target, path } => {
+                let named_args = [
+                    target.map(|target| ("target".to_owned(), target)),
+                    path.map(|path| ("path".to_owned(), path)),
+                ];
+                ("build", named_args.into_iter().flatten().collect(), vec![])
+            }
+            Builtin::Compile { env } => ("compile", vec![], env),
+        };
+        Self {
+            uuid,
+            request_id,
+            cmd: format!("{BUILTIN_COMMAND_PREFIX}{name}"),
+            args: vec![],
+            named_args,
+            cwd,
+            env,
+            stdin: None,
+        }
+    }
+}
+
+enum UnityRequest {
+    Command(UnityCommand),
+    ListCommands { uuid: Uuid, request_id: u64 },
+    CancelCommand { uuid: Uuid, request_id: u64 },
+}
 
 struct UnityState {
     cmd_cb: UnityCommandCallback,
+    list_cmds_cb: UnityListCommandsCallback,
+    cancel_cmd_cb: UnityCancelCommandCallback,
+    client_connected_cb: Option<UnityConnectionCallback>,
+    client_disconnected_cb: Option<UnityConnectionCallback>,
+}
+
+// Like `INSTANCE`, not an async lock, as it is also read where awaiting isn't possible.
+static UNITY_STATE: OnceLock<SyncRwLock<Option<UnityState>>> = OnceLock::new();
+
+fn unity_state() -> &'static SyncRwLock<Option<UnityState>> {
+    UNITY_STATE.get_or_init(|| SyncRwLock::new(None))
+}
+
+/// Tells Unity about a client connecting or disconnecting, if it asked to know.
+fn notify_connection(
+    uuid: Uuid,
+    callback: impl FnOnce(&UnityState) -> Option<UnityConnectionCallback>,
+) {
+    if let Some(callback) = unity_state().read().as_ref().and_then(callback) {
+        let (uuid_hi, uuid_lo) = uuid.as_u64_pair();
+        callback(uuid_hi, uuid_lo);
+    }
+}
+
+static ADVERTISED_NAME: OnceLock<SyncRwLock<Option<CString>>> = OnceLock::new();
+
+fn advertised_name_slot() -> &'static SyncRwLock<Option<CString>> {
+    ADVERTISED_NAME.get_or_init(|| SyncRwLock::new(None))
 }
 
-static UNITY_STATE: OnceLock<RwLock<Option<UnityState>>> = OnceLock::new();
+static LAST_ERROR: OnceLock<SyncRwLock<Option<CString>>> = OnceLock::new();
+
+fn last_error_slot() -> &'static SyncRwLock<Option<CString>> {
+    LAST_ERROR.get_or_init(|| SyncRwLock::new(None))
+}
 
-fn unity_state() -> &'static RwLock<Option<UnityState>> {
-    UNITY_STATE.get_or_init(|| RwLock::new(None))
+fn set_last_error(msg: String) {
+    *last_error_slot().write() = Some(CString::new(msg.replace('\0', "")).unwrap_or_default());
 }
 
+/// Null pointers, which a buggy caller may pass, are read as an empty string.
 #[inline(always)]
 fn c_char_to_str(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
     unsafe {
         let s = CStr::from_ptr(ptr);
         s.to_string_lossy().into_owned()
     }
 }
 
+#[inline(always)]
+fn read_server_options(ptr: *const ServerOptions) -> ServerOptions {
+    unsafe { ptr.as_ref() }.map_or_else(ServerOptions::default, Clone::clone)
+}
+
+/// Null arrays, like null strings, are read as empty.
+fn read_extra_properties(options: &ServerOptions) -> Vec<(String, String)> {
+    if options.extra_property_keys.is_null() || options.extra_property_values.is_null() {
+        return Vec::new();
+    }
+    let len = options.extra_properties_len as usize;
+    let (keys, values) = unsafe {
+        (
+            std::slice::from_raw_parts(options.extra_property_keys, len),
+            std::slice::from_raw_parts(options.extra_property_values, len),
+        )
+    };
+    keys.iter()
+        .zip(values)
+        .map(|(key, value)| (c_char_to_str(*key), c_char_to_str(*value)))
+        .collect()
+}
+
+/// Starts the server on a background thread. `options` may be null; see [`ServerOptions`].
+///
+/// Calling it again while the server runs only puts back the callbacks forgotten by
+/// [`on_csharp_assembly_unload`], e.g. after a domain reload, telling clients the assemblies were
+/// reloaded, and sets [`last_error`] to say the new options were ignored if they differ from the
+/// running server's.
 #[no_mangle]
 pub extern "C" fn run(
     project_path: *const c_char,
     project_name: *const c_char,
     unity_version: *const c_char,
     command_callback: UnityCommandCallback,
+    list_commands_callback: UnityListCommandsCallback,
+    cancel_command_callback: UnityCancelCommandCallback,
+    options: *const ServerOptions,
+) {
+    run_with(
+        Hooks::default(),
+        project_path,
+        project_name,
+        unity_version,
+        command_callback,
+        list_commands_callback,
+        cancel_command_callback,
+        options,
+    );
+}
+
+/// What the server reaches for besides its sockets and Unity, which the tests fake.
+struct Hooks {
+    registrar: Box<dyn Registrar>,
+    /// Where names come from for an instance that wasn't given a session name.
+    session_names: Box<dyn Iterator<Item = String>>,
+    accepts: Box<dyn AcceptSource>,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            registrar: Box::new(MdnsRegistrar),
+            session_names: Box::new(names::Generator::default()),
+            accepts: Box::new(OsAccepts),
+        }
+    }
+}
+
+/// Like [`run`], with `hooks` in place of mDNS, the name generator and the OS's accepts.
+#[allow(clippy::too_many_arguments)]
+fn run_with(
+    hooks: Hooks,
+    project_path: *const c_char,
+    project_name: *const c_char,
+    unity_version: *const c_char,
+    command_callback: UnityCommandCallback,
+    list_commands_callback: UnityListCommandsCallback,
+    cancel_command_callback: UnityCancelCommandCallback,
+    options: *const ServerOptions,
 ) {
-    *unity_state().blocking_write() = Some(UnityState {
+    let Hooks {
+        registrar,
+        session_names,
+        mut accepts,
+    } = hooks;
+    let options = read_server_options(options);
+    let config = RunConfig::read(project_path, project_name, unity_version, &options);
+    let callbacks = UnityState {
         cmd_cb: command_callback,
-    });
+        list_cmds_cb: list_commands_callback,
+        cancel_cmd_cb: cancel_command_callback,
+        client_connected_cb: options.client_connected_callback,
+        client_disconnected_cb: options.client_disconnected_callback,
+    };
 
+    // Unity runs it again after every domain reload, which must leave the server as it is.
+    if let Some(running) = instance().read().as_ref() {
+        if running.config != config {
+            warn!("the server is already running, ignoring the new options.");
+            set_last_error(
+                "the server is already running, stop it first to apply new options".to_owned(),
+            );
+        }
+        let mut unity_state = unity_state().write();
+        if unity_state.is_none() {
+            *unity_state = Some(callbacks);
+            drop(unity_state);
+            running.send_reliably(Uuid::nil(), ServerMessage::AssemblyReloaded);
+        }
+        return;
+    }
+
+    *unity_state().write() = Some(callbacks);
+    *last_error_slot().write() = None;
+
+    let tls_paths = match (config.tls_cert_path.clone(), config.tls_key_path.clone()) {
+        (None, None) => None,
+        (Some(cert_path), Some(key_path)) => Some((cert_path, key_path)),
+        _ => {
+            set_last_error("`tls_cert_path` and `tls_key_path` must be set together".to_owned());
+            return;
+        }
+    };
+    let unix_socket_path = config.unix_socket_path.clone();
+    #[cfg(not(unix))]
+    if unix_socket_path.is_some() {
+        set_last_error("Unix domain sockets are only supported on Unix platforms".to_owned());
+        return;
+    }
+    if unix_socket_path.is_some() && tls_paths.is_some() {
+        set_last_error("`unix_socket_path` can't be combined with TLS".to_owned());
+        return;
+    }
+    let unix_socket_path = unix_socket_path.map(PathBuf::from);
+    let pipe_name = config.pipe_name.clone();
+    #[cfg(not(windows))]
+    if pipe_name.is_some() {
+        set_last_error("Named pipes are only supported on Windows".to_owned());
+        return;
+    }
+    if pipe_name.is_some() && tls_paths.is_some() {
+        set_last_error("`pipe_name` can't be combined with TLS".to_owned());
+        return;
+    }
+    let session_name = match config.session_name {
+        None => None,
+        Some(ref name) => match sanitize_session_name(name) {
+            Some(name) => Some(name),
+            None => {
+                set_last_error(format!("`{name}` can't be used as a session name"));
+                return;
+            }
+        },
+    };
+    let audit = match config.audit_log_path {
+        None => AuditLog::default(),
+        Some(ref path) => match AuditLog::open(std::path::Path::new(path)) {
+            Ok(audit) => audit,
+            Err(e) => {
+                set_last_error(format!("failed to open the audit log `{path}`: {e}"));
+                return;
+            }
+        },
+    };
+    let audit = Arc::new(audit);
+    let extra_properties = config.extra_properties.clone();
+    if let Some((key, _)) = extra_properties
+        .iter()
+        .find(|(key, _)| BUILTIN_PROP_KEYS.contains(&key.as_str()))
+    {
+        set_last_error(format!(
+            "`{key}` is a built-in property and can't be overridden"
+        ));
+        return;
+    }
+    let lan_ip = match config.lan_address {
+        None => None,
+        Some(ref address) => match address.parse::<Ipv4Addr>() {
+            Ok(ip) => Some(ip),
+            Err(e) => {
+                set_last_error(format!(
+                    "`{address}` isn't an IPv4 address to listen on: {e}"
+                ));
+                return;
+            }
+        },
+    };
+    let command_policy = Arc::new(CommandPolicy::new(
+        &config.allowed_commands,
+        &config.denied_commands,
+    ));
+    let project_path = config.project_path.clone();
+    let project_name = config.project_name.clone();
+    let unity_version = config.unity_version.clone();
+    // The options hold raw pointers, which can't be sent to the server thread.
+    let ServerOptions {
+        port,
+        max_connections,
+        unresponsive_timeout_ms,
+        metrics_port,
+        idle_timeout_ms,
+        log_backlog_capacity,
+        console_log_rate_limit,
+        max_command_args,
+        max_command_args_len,
+        strict_utf8,
+        ..
+    } = options;
     let (stop_tx, mut stop_rx) = tokio::sync::mpsc::channel(1);
-    let (unity_msg_tx, mut unity_msg_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (unity_msg_tx, mut unity_msg_rx) =
+        tokio::sync::mpsc::channel(config.message_queue_capacity as usize);
+
+    let is_advertised = unix_socket_path.is_none() && pipe_name.is_none();
+    let instance_name = session_name.unwrap_or_else(|| session_name_from(session_names));
+
+    let activity = Arc::new(UnityActivity::new());
+    let metrics = Arc::new(Metrics::default());
+    let backlog = Arc::new(LogBacklog::new(log_backlog_capacity as usize));
+    let bound_port = Arc::new(AtomicU16::new(0));
+    let shutdown = CancellationToken::new();
 
     {
-        let mut instance = instance().blocking_write();
+        let mut instance = instance().write();
         if instance.is_some() {
+            // Another `run` started it in the meantime.
             return;
         } else {
             *instance = Some(Instance {
                 stop_tx,
                 unity_msg_send: unity_msg_tx,
+                dropped_console_msgs: AtomicU64::new(0),
+                activity: activity.clone(),
+                metrics: metrics.clone(),
+                audit: audit.clone(),
+                backlog: backlog.clone(),
+                rate_limiter: RateLimiter::new(console_log_rate_limit, Instant::now()),
+                port: bound_port.clone(),
+                started_at: Instant::now(),
+                shutdown: shutdown.clone(),
+                strict_utf8,
+                config,
             });
         }
     }
+    if is_advertised {
+        // Generated and sanitized names never contain nul bytes.
+        *advertised_name_slot().write() = CString::new(instance_name.as_str()).ok();
+    }
 
     // TODO: tracing_appender support, configurability
-    tracing_subscriber::fmt()
+    // `run` can be called again after `stop`, so the subscriber may already be set.
+    let _ = tracing_subscriber::fmt()
         .with_max_level(tracing_subscriber::filter::LevelFilter::DEBUG)
         .with_file(true)
         .with_line_number(true)
         .with_thread_ids(true)
-        .init();
+        .try_init();
 
-    let project_path = c_char_to_str(project_path);
-    let project_name = c_char_to_str(project_name);
-    let unity_version = c_char_to_str(unity_version);
+    let session_id = session_id().to_string();
+    let protocol_version = PROTOCOL_VERSION.to_string();
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis())
+        .to_string();
 
     std::thread::spawn(move || {
         struct GlobalStatesGuard;
 
         impl Drop for GlobalStatesGuard {
             fn drop(&mut self) {
-                *instance().blocking_write() = None;
-                *unity_state().blocking_write() = None;
+                // Cleared first, as a new `run` may start as soon as the instance is gone.
+                *advertised_name_slot().write() = None;
+                *instance().write() = None;
+                *unity_state().write() = None;
             }
         }
 
         let _guard = GlobalStatesGuard;
 
-        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
-        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
-        let addr = addr.into();
-        socket.bind(&addr).unwrap();
-        socket.listen(128).unwrap();
-        socket.set_keepalive(true).unwrap();
-
-        let listener: std::net::TcpListener = socket.into();
-        listener.set_nonblocking(true).unwrap();
-        let port = listener.local_addr().unwrap().port();
-
-        let mdns_daemon = ServiceDaemon::new(IPMulticastTTLOption::NodeLocal).unwrap();
-        let service_type = common::MDNS_SERVICE_NAME;
-        let instance_name = names::Generator::default().next().unwrap();
-        let host_ipv4 = "";
-        let host_name = gethostname();
-        let properties = [
+        #[cfg(feature = "tls")]
+        let (tls_acceptor, tls_fingerprint) = match tls_paths {
+            Some((cert_path, key_path)) => match tls::load(&cert_path, &key_path) {
+                Ok(tls) => (Some(tls.acceptor), Some(tls.fingerprint)),
+                Err(e) => {
+                    error!(error = %e, "failed to load the TLS certificate!");
+                    set_last_error(format!("failed to load the TLS certificate: {e:#}"));
+                    return;
+                }
+            },
+            None => (None, None),
+        };
+        #[cfg(not(feature = "tls"))]
+        let tls_fingerprint: Option<String> = match tls_paths {
+            Some(_) => {
+                error!("TLS was requested, but this library was built without the `tls` feature!");
+                set_last_error(
+                    "TLS was requested, but this library was built without the `tls` feature"
+                        .to_owned(),
+                );
+                return;
+            }
+            None => None,
+        };
+
+        #[cfg(feature = "metrics")]
+        let metrics_listener = match metrics_port {
+            0 => None,
+            port => match bind_metrics(port) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    error!(error = %e, "failed to bind the metrics endpoint!");
+                    set_last_error(format!("failed to bind the metrics endpoint: {e}"));
+                    return;
+                }
+            },
+        };
+        #[cfg(not(feature = "metrics"))]
+        if metrics_port != 0 {
+            error!(
+                "metrics were requested, but this library was built without the `metrics` feature!"
+            );
+            set_last_error(
+                "metrics were requested, but this library was built without the `metrics` feature"
+                    .to_owned(),
+            );
+            return;
+        }
+
+        let properties: Vec<_> = [
             (PROJECT_PATH_PROP_KEY, &project_path),
             (PROJECT_NAME_PROP_KEY, &project_name),
             (UNITY_VERSION_PROP_KEY, &unity_version),
-        ];
-        let service_info = ServiceInfo::new(
-            service_type,
-            &instance_name,
-            host_name.to_string_lossy().as_ref(),
-            host_ipv4,
-            port,
-            &properties[..],
+            (SESSION_ID_PROP_KEY, &session_id),
+            (PROTOCOL_VERSION_PROP_KEY, &protocol_version),
+            (STARTED_AT_PROP_KEY, &started_at),
+        ]
+        .into_iter()
+        .chain(
+            tls_fingerprint
+                .as_ref()
+                .map(|f| (TLS_FINGERPRINT_PROP_KEY, f)),
+        )
+        .chain(
+            extra_properties
+                .iter()
+                .map(|(key, value)| (key.as_str(), value)),
         )
-        .unwrap()
-        .enable_addr_auto();
-        mdns_daemon
-            .register(service_info)
-            .expect("Failed to register our service");
+        .collect();
+        // Plaintext is only offered on loopback, and the LAN address if asked for.
+        let (bind_ip, lan_ip) = if tls_fingerprint.is_some() {
+            (Ipv4Addr::UNSPECIFIED, None)
+        } else {
+            (Ipv4Addr::LOCALHOST, lan_ip)
+        };
+        let rt = match Builder::new_multi_thread().enable_all().build() {
+            Ok(rt) => rt,
+            Err(e) => {
+                error!(error = %e, "failed to build the runtime!");
+                set_last_error(format!("failed to build the runtime: {e}"));
+                return;
+            }
+        };
+        // Clients find local transports through their path instead.
+        let tcp_listeners = if !is_advertised {
+            Vec::new()
+        } else {
+            match bind_tcp_listeners(bind_ip, port, lan_ip) {
+                Ok((listeners, port)) => {
+                    bound_port.store(port, Ordering::Relaxed);
+                    listeners
+                }
+                Err(e) => {
+                    error!(error = %e, "failed to start the server!");
+                    set_last_error(format!("failed to start the server: {e:#}"));
+                    return;
+                }
+            }
+        };
+        // Clients given the address can still connect, so this isn't worth stopping for.
+        let advertisement = match bound_port.load(Ordering::Relaxed) {
+            0 => None,
+            port => match advertise(&*registrar, port, lan_ip, &instance_name, &properties) {
+                Ok(advertisement) => Some(advertisement),
+                Err(e) => {
+                    warn!(error = %e, "failed to advertise the server, serving unadvertised.");
+                    set_last_error(format!("failed to advertise the server: {e:#}"));
+                    None
+                }
+            },
+        };
+        let socket_path = unix_socket_path.as_deref();
+        let pipe_name = pipe_name.as_deref();
 
-        let rt = Builder::new_multi_thread().enable_io().build().unwrap();
         rt.block_on(async move {
-            let listener = TcpListener::from_std(listener).unwrap();
-            let conns: Arc<DashMap<Uuid, tokio::sync::mpsc::Sender<ServerMessage>>> =
-                Arc::new(DashMap::new());
+            let listener = if tcp_listeners.is_empty() {
+                local_listener(socket_path, pipe_name)
+            } else {
+                tcp_listeners
+                    .into_iter()
+                    .map(TcpListener::from_std)
+                    .collect::<std::io::Result<_>>()
+                    .map(Listener::Tcp)
+            };
+            let listener = match listener {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(error = %e, "failed to register the listener to the runtime!");
+                    set_last_error(format!("failed to register the listener: {e}"));
+                    return;
+                }
+            };
+            let conns: Arc<DashMap<Uuid, Arc<Outbox>>> = Arc::new(DashMap::new());
             let conns2 = conns.clone();
             let conns3 = conns.clone();
+            let conns4 = conns.clone();
+            let activity2 = activity.clone();
+            let metrics2 = metrics.clone();
+            let metrics3 = metrics.clone();
+            let metrics4 = metrics.clone();
             let (cmd_tx, mut cmd_rx) = tokio::sync::mpsc::channel(10);
 
+            let config = ConnectionConfig {
+                max_connections: max_connections as usize,
+                idle_timeout: Duration::from_millis(idle_timeout_ms as u64),
+                command_policy,
+                audit: audit.clone(),
+                backlog,
+                shutdown,
+                max_command_args: max_command_args as usize,
+                max_command_args_len: max_command_args_len as usize,
+            };
             let accept_conn_loop = async move {
-                loop {
-                    match listener.accept().await {
-                        Ok((stream, _)) => {
-                            let (read, write) = stream.into_split();
-                            let read = FramedRead::new(read, ServerCodec::default());
-                            let write = FramedWrite::new(write, ServerCodec::default());
-                            let (msg_tx, msg_rx) = tokio::sync::mpsc::channel(8);
-                            let uuid = Uuid::new_v4();
-                            conns2.insert(uuid, msg_tx);
-                            let cmd_tx = cmd_tx.clone();
-                            let conns = conns3.clone();
-                            let on_finish = move || {
-                                conns.remove(&uuid);
-                            };
-
-                            tokio::spawn(async move {
-                                handle_read(read, uuid, cmd_tx)
-                                    .instrument(info_span!("handle_read", %uuid))
-                                    .await;
-                            });
-                            tokio::spawn(async move {
-                                handle_write(write, msg_rx, on_finish)
-                                    .instrument(info_span!("handle_write", %uuid))
-                                    .await;
-                            });
+                let mut backoff = AcceptBackoff::default();
+                match listener {
+                    Listener::Tcp(listeners) => loop {
+                        let accepted = match accepts.failure() {
+                            Some(e) => Err(e),
+                            None => accept_any(&listeners).await,
+                        };
+                        match accepted {
+                            Ok((stream, peer)) => {
+                                backoff.reset();
+                                let is_local = accepts.is_local(peer);
+                                #[cfg(feature = "tls")]
+                                if let Some(ref acceptor) = tls_acceptor {
+                                    let handshake = acceptor.accept(stream);
+                                    let conns = conns2.clone();
+                                    let cmd_tx = cmd_tx.clone();
+                                    let metrics = metrics2.clone();
+                                    let config = config.clone();
+                                    tokio::spawn(async move {
+                                        match handshake.await {
+                                            Ok(stream) => serve_connection(
+                                                stream, &conns, &cmd_tx, &metrics, &config,
+                                                is_local,
+                                            ),
+                                            Err(e) => warn!(error = %e, "TLS handshake failed!"),
+                                        }
+                                    });
+                                    continue;
+                                }
+
+                                serve_connection(
+                                    stream, &conns2, &cmd_tx, &metrics2, &config, is_local,
+                                );
+                            }
+                            Err(e) => backoff.wait(&e).await,
                         }
-                        Err(_e) => {}
-                    }
+                    },
+                    #[cfg(unix)]
+                    Listener::Unix(listener) => loop {
+                        let accepted = match accepts.failure() {
+                            Some(e) => Err(e),
+                            None => listener.accept().await,
+                        };
+                        match accepted {
+                            Ok((stream, _)) => {
+                                backoff.reset();
+                                serve_connection(
+                                    stream, &conns2, &cmd_tx, &metrics2, &config, true,
+                                );
+                            }
+                            Err(e) => backoff.wait(&e).await,
+                        }
+                    },
+                    #[cfg(windows)]
+                    Listener::Pipe { path, mut next } => loop {
+                        if let Err(e) = next.connect().await {
+                            warn!(error = %e, "failed to accept a pipe client!");
+                        }
+                        // A pipe instance serves a single client, so the next one must be ready
+                        // before handing this one over.
+                        let connected = match create_pipe(&path, false) {
+                            Ok(pipe) => std::mem::replace(&mut next, pipe),
+                            Err(e) => {
+                                error!(error = %e, "failed to create a pipe instance!");
+                                break;
+                            }
+                        };
+                        serve_connection(connected, &conns2, &cmd_tx, &metrics2, &config, true);
+                    },
                 }
             }
             .instrument(info_span!("accept_conn_loop"));
 
             let route_msg_from_unity_loop = async move {
-                loop {
-                    match unity_msg_rx.recv().await {
-                        Some((uuid, msg)) => {
-                            if let Some(msg_tx) = conns.get(&uuid) {
-                                if msg_tx.send(msg).await.is_err() {
-                                    break;
-                                }
+                // Never waits on a connection, so a client that stopped reading or went away
+                // only loses its own messages.
+                while let Some((uuid, msg)) = unity_msg_rx.recv().await {
+                    if uuid.is_nil() {
+                        for conn in conns.iter() {
+                            if conn.value().push(msg.clone()) {
+                                metrics4.console_log_dropped();
                             }
                         }
-                        None => {
-                            break;
+                    } else if let Some(outbox) = conns.get(&uuid) {
+                        if outbox.push(msg) {
+                            metrics4.console_log_dropped();
                         }
                     }
                 }
@@ -204,36 +1041,101 @@ pub extern "C" fn run(
             .instrument(info_span!("route_msg_from_unity_loop"));
 
             let send_cmd_to_unity_loop = async move {
+                // Finishes a command Unity never got to see, so its client doesn't wait forever.
+                let fail_command = |uuid: Uuid, request_id: u64, msg: String| {
+                    metrics3.command_failed();
+                    audit.command_finished(uuid, request_id, false);
+                    if let Some(outbox) = conns3.get(&uuid) {
+                        outbox.push(ServerMessage::CommandFinished {
+                            request_id,
+                            is_success: false,
+                            msg: Some(msg),
+                        });
+                    }
+                };
                 loop {
                     match cmd_rx.recv().await {
-                        Some((uuid, cmd, args)) => {
-                            if let Some(unity_state) = unity_state().read().await.as_ref() {
+                        Some(UnityRequest::Command(UnityCommand {
+                            uuid,
+                            request_id,
+                            cmd,
+                            args,
+                            named_args,
+                            cwd,
+                            env,
+                            stdin,
+                        })) => {
+                            let c_strings =
+                                match command_to_c_strings(cmd, args, named_args, cwd, env) {
+                                    Ok(c_strings) => c_strings,
+                                    Err(e) => {
+                                        error!(%uuid, error = %e, "invalid command request!");
+                                        fail_command(
+                                            uuid,
+                                            request_id,
+                                            format!(
+                                                "Command and arguments must not contain nul \
+                                                 bytes: {e}"
+                                            ),
+                                        );
+                                        continue;
+                                    }
+                                };
+
+                            if let Some(unity_state) = unity_state().read().as_ref() {
+                                activity.command_sent();
                                 let (uuid_hi, uuid_lo) = uuid.as_u64_pair();
-                                let cmd = CString::new(cmd).unwrap().into_raw();
-                                let args: Vec<_> = args
-                                    .iter()
-                                    .map(|s| {
-                                        CString::new(s.as_str()).unwrap().into_raw()
-                                            as *const c_char
-                                    })
-                                    .collect();
+                                let as_ptrs = |strings: &[CString]| -> Vec<_> {
+                                    strings.iter().map(|s| s.as_ptr()).collect()
+                                };
+                                let arg_ptrs = as_ptrs(&c_strings.args);
+                                let key_ptrs = as_ptrs(&c_strings.named_arg_keys);
+                                let value_ptrs = as_ptrs(&c_strings.named_arg_values);
+                                let env_key_ptrs = as_ptrs(&c_strings.env_keys);
+                                let env_value_ptrs = as_ptrs(&c_strings.env_values);
 
                                 // Send the command to Unity C# script
                                 (unity_state.cmd_cb)(
                                     uuid_hi,
                                     uuid_lo,
-                                    cmd,
-                                    args.as_ptr(),
-                                    args.len() as i32,
+                                    request_id,
+                                    c_strings.cmd.as_ptr(),
+                                    arg_ptrs.as_ptr(),
+                                    arg_ptrs.len() as i32,
+                                    key_ptrs.as_ptr(),
+                                    value_ptrs.as_ptr(),
+                                    key_ptrs.len() as i32,
+                                    c_strings
+                                        .cwd
+                                        .as_ref()
+                                        .map_or(std::ptr::null(), |cwd| cwd.as_ptr()),
+                                    env_key_ptrs.as_ptr(),
+                                    env_value_ptrs.as_ptr(),
+                                    env_key_ptrs.len() as i32,
+                                    stdin.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+                                    stdin.as_ref().map_or(0, |s| s.len() as i32),
                                 );
-
-                                // Free allocated strings
-                                unsafe {
-                                    drop(CString::from_raw(cmd));
-                                    for ptr in args {
-                                        drop(CString::from_raw(ptr as *mut c_char));
-                                    }
-                                }
+                            } else {
+                                // The assembly was unloaded, e.g. by a domain reload, and nothing
+                                // would ever finish the command.
+                                warn!(%uuid, request_id, "assembly unloaded, dropping command.");
+                                fail_command(
+                                    uuid,
+                                    request_id,
+                                    "editor reloading, command dropped".to_owned(),
+                                );
+                            }
+                        }
+                        Some(UnityRequest::ListCommands { uuid, request_id }) => {
+                            if let Some(unity_state) = unity_state().read().as_ref() {
+                                let (uuid_hi, uuid_lo) = uuid.as_u64_pair();
+                                (unity_state.list_cmds_cb)(uuid_hi, uuid_lo, request_id);
+                            }
+                        }
+                        Some(UnityRequest::CancelCommand { uuid, request_id }) => {
+                            if let Some(unity_state) = unity_state().read().as_ref() {
+                                let (uuid_hi, uuid_lo) = uuid.as_u64_pair();
+                                (unity_state.cancel_cmd_cb)(uuid_hi, uuid_lo, request_id);
                             }
                         }
                         None => {
@@ -244,30 +1146,664 @@ pub extern "C" fn run(
             }
             .instrument(info_span!("send_cmd_to_unity_loop"));
 
+            let unresponsive_timeout = Duration::from_millis(unresponsive_timeout_ms as u64);
+            let watch_unity_loop = async move {
+                if unresponsive_timeout.is_zero() {
+                    return std::future::pending::<()>().await;
+                }
+
+                let mut interval = tokio::time::interval(unresponsive_timeout / 2);
+                let mut reported = false;
+                loop {
+                    interval.tick().await;
+                    match activity2.stalled_for() {
+                        Some(idle) if idle >= unresponsive_timeout => {
+                            if !reported {
+                                warn!(?idle, "unity is not responding!");
+                                for conn in conns4.iter() {
+                                    conn.value().push(ServerMessage::EditorUnresponsive {
+                                        idle_secs: idle.as_secs(),
+                                    });
+                                }
+                                reported = true;
+                            }
+                        }
+                        _ => reported = false,
+                    }
+                }
+            }
+            .instrument(info_span!("watch_unity_loop"));
+
+            let serve_metrics_loop = async move {
+                #[cfg(feature = "metrics")]
+                if let Some(listener) = metrics_listener {
+                    return metrics::serve(listener, metrics).await;
+                }
+                std::future::pending::<()>().await
+            }
+            .instrument(info_span!("serve_metrics_loop"));
+
             tokio::select! {
                 _ = accept_conn_loop => {}
                 _ = route_msg_from_unity_loop => {}
                 _ = send_cmd_to_unity_loop => {}
+                _ = watch_unity_loop => {}
+                _ = serve_metrics_loop => {}
                 _ = stop_rx.recv() => {
                     info!("stopped from unity.");
                 }
             }
         });
+
+        if let Some(advertisement) = advertisement {
+            advertisement.withdraw();
+        }
+        if let Some(ref path) = unix_socket_path {
+            let _ = std::fs::remove_file(path);
+        }
     });
 }
 
-async fn handle_read(
-    mut read: FramedRead<OwnedReadHalf, ServerCodec>,
+enum Listener {
+    /// Every address listened on, all on the same port.
+    Tcp(Vec<TcpListener>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+    /// The instance waiting for the next client.
+    #[cfg(windows)]
+    Pipe {
+        path: String,
+        next: tokio::net::windows::named_pipe::NamedPipeServer,
+    },
+}
+
+/// Listens on the Unix socket or named pipe `run` was given, whichever the platform supports.
+fn local_listener(
+    socket_path: Option<&std::path::Path>,
+    pipe_name: Option<&str>,
+) -> std::io::Result<Listener> {
+    #[cfg(unix)]
+    if let Some(path) = socket_path {
+        return bind_unix_socket(path).map(Listener::Unix);
+    }
+    #[cfg(windows)]
+    if let Some(name) = pipe_name {
+        let path = common::named_pipe_path(name);
+        return create_pipe(&path, true).map(|next| Listener::Pipe { path, next });
+    }
+    let _ = (socket_path, pipe_name);
+    unreachable!("the server has nothing to listen on")
+}
+
+/// `first` makes creating the pipe fail if another server already owns the name.
+#[cfg(windows)]
+fn create_pipe(
+    path: &str,
+    first: bool,
+) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeServer> {
+    tokio::net::windows::named_pipe::ServerOptions::new()
+        .first_pipe_instance(first)
+        .reject_remote_clients(true)
+        .create(path)
+}
+
+/// Binds a socket only the current user may connect to, replacing the one a previous server
+/// may have left behind.
+#[cfg(unix)]
+fn bind_unix_socket(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// A command request in the form handed to Unity.
+struct CommandCStrings {
+    cmd: CString,
+    args: Vec<CString>,
+    named_arg_keys: Vec<CString>,
+    named_arg_values: Vec<CString>,
+    cwd: Option<CString>,
+    env_keys: Vec<CString>,
+    env_values: Vec<CString>,
+}
+
+/// Strings from clients were decoded as UTF-8 already, so only nul bytes keep them from reaching
+/// Unity intact.
+fn command_to_c_strings(
+    cmd: String,
+    args: Vec<String>,
+    named_args: Vec<(String, String)>,
+    cwd: Option<String>,
+    env: Vec<(String, String)>,
+) -> Result<CommandCStrings, std::ffi::NulError> {
+    fn to_c_strings(
+        strings: impl IntoIterator<Item = String>,
+    ) -> Result<Vec<CString>, std::ffi::NulError> {
+        strings.into_iter().map(CString::new).collect()
+    }
+
+    let (keys, values): (Vec<_>, Vec<_>) = named_args.into_iter().unzip();
+    let (env_keys, env_values): (Vec<_>, Vec<_>) = env.into_iter().unzip();
+    Ok(CommandCStrings {
+        cmd: CString::new(cmd)?,
+        args: to_c_strings(args)?,
+        named_arg_keys: to_c_strings(keys)?,
+        named_arg_values: to_c_strings(values)?,
+        cwd: cwd.map(CString::new).transpose()?,
+        env_keys: to_c_strings(env_keys)?,
+        env_values: to_c_strings(env_values)?,
+    })
+}
+
+/// Resolves `.`, `..` and symlinks in `path`, so that it matches what clients canonicalize their
+/// own path argument to. Paths that can't be resolved on this machine are advertised as given.
+fn canonical_project_path(path: String) -> String {
+    std::fs::canonicalize(&path)
+        .ok()
+        .and_then(|canonical| canonical.into_os_string().into_string().ok())
+        .unwrap_or(path)
+}
+
+/// The longest DNS label, which the instance name must fit in.
+const MAX_SESSION_NAME_LEN: usize = 63;
+
+/// Makes `name` safe to advertise, or returns `None` if nothing usable is left of it.
+fn sanitize_session_name(name: &str) -> Option<String> {
+    let name: String = name
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .take(MAX_SESSION_NAME_LEN)
+        .collect();
+    let name = name.trim_matches('-');
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
+/// Binds `ip` on `port`, then `lan_ip` on the port that was bound, so that the one advertised port
+/// reaches both. Returns the listeners and their port.
+fn bind_tcp_listeners(
+    ip: Ipv4Addr,
+    port: u16,
+    lan_ip: Option<Ipv4Addr>,
+) -> anyhow::Result<(Vec<std::net::TcpListener>, u16)> {
+    let listener = bind_tcp_listener(ip, port)?;
+    let port = listener.local_addr()?.port();
+    let mut listeners = vec![listener];
+    if let Some(lan_ip) = lan_ip {
+        listeners.push(
+            bind_tcp_listener(lan_ip, port)
+                .with_context(|| format!("failed to also listen on {lan_ip}:{port}"))?,
+        );
+    }
+    Ok((listeners, port))
+}
+
+/// Accepts the next client of whichever listener has one.
+async fn accept_any(
+    listeners: &[TcpListener],
+) -> std::io::Result<(tokio::net::TcpStream, SocketAddr)> {
+    std::future::poll_fn(|cx| {
+        for listener in listeners {
+            if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return Poll::Ready(accepted);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+fn bind_tcp_listener(ip: Ipv4Addr, port: u16) -> anyhow::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+    let addr = SocketAddr::from((ip, port)).into();
+    socket
+        .bind(&addr)
+        .context("failed to bind the listening socket")?;
+    socket.listen(128)?;
+    socket.set_keepalive(true)?;
+
+    let listener: std::net::TcpListener = socket.into();
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Registers `port` to `registrar`, on every address of this machine along with `lan_ip`.
+fn advertise(
+    registrar: &dyn Registrar,
+    port: u16,
+    lan_ip: Option<Ipv4Addr>,
+    instance_name: &str,
+    properties: &[(&str, &String)],
+) -> anyhow::Result<Box<dyn Advertisement>> {
+    let service_type = common::MDNS_SERVICE_NAME;
+    let host_ipv4 = lan_ip.map_or_else(String::new, |ip| ip.to_string());
+    let host_name = gethostname();
+    let service_info = ServiceInfo::new(
+        service_type,
+        instance_name,
+        host_name.to_string_lossy().as_ref(),
+        host_ipv4.as_str(),
+        port,
+        properties,
+    )?
+    .enable_addr_auto();
+    registrar
+        .register(service_info)
+        .context("failed to register our service")
+}
+
+#[cfg(feature = "metrics")]
+fn bind_metrics(port: u16) -> std::io::Result<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+#[derive(Clone)]
+struct ConnectionConfig {
+    /// `0` means no limit.
+    max_connections: usize,
+    /// Zero disables the timeout.
+    idle_timeout: Duration,
+    command_policy: Arc<CommandPolicy>,
+    audit: Arc<AuditLog>,
+    backlog: Arc<LogBacklog>,
+    shutdown: CancellationToken,
+    /// `0` means no limit, for this and the next.
+    max_command_args: usize,
+    max_command_args_len: usize,
+}
+
+impl ConnectionConfig {
+    /// Returns why a command with these arguments is too large to forward, if it is.
+    fn check_command_args(
+        &self,
+        args: &[String],
+        named_args: &[(String, String)],
+        env: &[(String, String)],
+    ) -> Result<(), String> {
+        let count = args.len() + named_args.len() + env.len();
+        if self.max_command_args != 0 && count > self.max_command_args {
+            return Err(format!(
+                "the command has {count} arguments, more than the {} this server accepts",
+                self.max_command_args
+            ));
+        }
+        let len = args.iter().map(String::len).sum::<usize>()
+            + named_args
+                .iter()
+                .chain(env)
+                .map(|(key, value)| key.len() + value.len())
+                .sum::<usize>();
+        if self.max_command_args_len != 0 && len > self.max_command_args_len {
+            return Err(format!(
+                "the command's arguments take {len} bytes, more than the {} this server accepts",
+                self.max_command_args_len
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The shortest and longest waits before accepting again after accepting failed.
+const ACCEPT_BACKOFF: (Duration, Duration) = (Duration::from_millis(10), Duration::from_secs(1));
+
+/// Accepting fails transiently, e.g. while the process is out of file descriptors, so failures
+/// are retried after a wait doubling up to [`ACCEPT_BACKOFF`]'s longest instead of ending the
+/// accept loop, and the server with it.
+#[derive(Default)]
+struct AcceptBackoff {
+    next: Option<Duration>,
+}
+
+impl AcceptBackoff {
+    async fn wait(&mut self, e: &std::io::Error) {
+        let delay = self.next.unwrap_or(ACCEPT_BACKOFF.0);
+        warn!(error = %e, ?delay, "failed to accept a client, retrying.");
+        self.next = Some((delay * 2).min(ACCEPT_BACKOFF.1));
+        tokio::time::sleep(delay).await;
+    }
+
+    fn reset(&mut self) {
+        self.next = None;
+    }
+}
+
+/// What the accept loop makes of its listeners. [`OsAccepts`] takes them at their word.
+trait AcceptSource: Send {
+    /// Fails the next accept with the returned error instead of waiting for a client, if any.
+    fn failure(&mut self) -> Option<std::io::Error> {
+        None
+    }
+
+    /// Whether the TCP client at `peer` runs on this machine.
+    fn is_local(&self, peer: SocketAddr) -> bool {
+        peer.ip().is_loopback()
+    }
+}
+
+struct OsAccepts;
+
+impl AcceptSource for OsAccepts {}
+
+/// Starts serving `stream`. A panic while doing so only loses this client, instead of ending the
+/// accept loop. `is_local` tells whether the client runs on this machine.
+fn serve_connection<S>(
+    stream: S,
+    conns: &Arc<DashMap<Uuid, Arc<Outbox>>>,
+    cmd_tx: &tokio::sync::mpsc::Sender<UnityRequest>,
+    metrics: &Arc<Metrics>,
+    config: &ConnectionConfig,
+    is_local: bool,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let started = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        start_connection(stream, conns, cmd_tx, metrics, config, is_local)
+    }));
+    if started.is_err() {
+        error!("panicked while starting to serve a client!");
+    }
+}
+
+/// Spawns the tasks serving a new connection, which is registered once the client said hello, or
+/// turns it away if the server is at capacity.
+fn start_connection<S>(
+    stream: S,
+    conns: &Arc<DashMap<Uuid, Arc<Outbox>>>,
+    cmd_tx: &tokio::sync::mpsc::Sender<UnityRequest>,
+    metrics: &Arc<Metrics>,
+    config: &ConnectionConfig,
+    is_local: bool,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let max_connections = config.max_connections;
+    if max_connections != 0 && conns.len() >= max_connections {
+        tokio::spawn(
+            reject_connection(
+                stream,
+                format!("The server is at capacity ({max_connections} connections)"),
+            )
+            .instrument(info_span!("reject_connection")),
+        );
+        return;
+    }
+
+    let (read, write) = tokio::io::split(stream);
+    let mut read = FramedRead::new(read, ServerCodec::default());
+    let write = FramedWrite::new(write, ServerCodec::default());
+    let cmd_tx = cmd_tx.clone();
+    let conns = conns.clone();
+    let metrics = metrics.clone();
+    let config = config.clone();
+    tokio::spawn(async move {
+        let offered = tokio::select! {
+            offered = read_hello(&mut read, config.idle_timeout) => offered,
+            _ = config.shutdown.cancelled() => None,
+        };
+        let Some(offered) = offered else {
+            return;
+        };
+        let compression = pick_compression(&offered, is_local);
+        read.decoder_mut().set_compression(compression);
+
+        let outbox = Arc::new(Outbox::new(OUTBOX_CAPACITY));
+        outbox.push(ServerMessage::Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            compression,
+        });
+        let uuid = Uuid::new_v4();
+        conns.insert(uuid, outbox.clone());
+        metrics.connection_opened();
+        let read_metrics = metrics.clone();
+        notify_connection(uuid, |state| state.client_connected_cb);
+        let on_finish = move || {
+            conns.remove(&uuid);
+            metrics.connection_closed();
+            notify_connection(uuid, |state| state.client_disconnected_cb);
+        };
+
+        let read_outbox = outbox.clone();
+        let write_shutdown = config.shutdown.clone();
+        let writer = tokio::spawn(async move {
+            tokio::select! {
+                _ = handle_write(write, outbox, on_finish)
+                    .instrument(info_span!("handle_write", %uuid)) => {}
+                _ = write_shutdown.cancelled() => {}
+            }
+        });
+        tokio::select! {
+            _ = handle_read(read, uuid, cmd_tx, read_metrics, &config, &read_outbox)
+                .instrument(info_span!("handle_read", %uuid)) => {}
+            _ = config.shutdown.cancelled() => {}
+        }
+        // The writer would only notice the connection is gone once writing fails, or never if the
+        // client stopped reading.
+        writer.abort();
+    });
+}
+
+/// Waits for the `Hello` every client starts with, and returns what compression it offered, or
+/// `None` if it sent something else, went away or stayed silent for `idle_timeout`.
+async fn read_hello<R: AsyncRead + Unpin>(
+    read: &mut FramedRead<R, ServerCodec>,
+    idle_timeout: Duration,
+) -> Option<Vec<Compression>> {
+    let next = if idle_timeout.is_zero() {
+        read.next().await
+    } else {
+        match tokio::time::timeout(idle_timeout, read.next()).await {
+            Ok(next) => next,
+            Err(_) => {
+                info!(?idle_timeout, "closing a connection that never said hello.");
+                return None;
+            }
+        }
+    };
+    match next.map(|envelope| envelope.map(Envelope::into_payload)) {
+        Some(Ok(ClientMessage::Hello { compression })) => Some(compression),
+        Some(Ok(_)) => {
+            warn!("client didn't start with a hello, closing connection!");
+            None
+        }
+        Some(Err(e)) => {
+            error!(error = %e, "failed to deserialize client message!");
+            None
+        }
+        None => {
+            trace!("stream closed before the hello.");
+            None
+        }
+    }
+}
+
+/// Only compresses frames for clients on other machines, where the bandwidth saved is worth the
+/// time spent compressing.
+fn pick_compression(offered: &[Compression], is_local: bool) -> Compression {
+    let zstd = Compression::Zstd;
+    if !is_local && offered.contains(&zstd) && Compression::supported().contains(&zstd) {
+        zstd
+    } else {
+        Compression::None
+    }
+}
+
+async fn reject_connection<S: AsyncWrite + Unpin>(stream: S, reason: String) {
+    let mut write = FramedWrite::new(stream, ServerCodec::default());
+    let rejection = Envelope::from(ServerMessage::Rejected { reason });
+    if let Err(e) = write.send(rejection).await {
+        error!(error = %e, "failed to send rejection!");
+    }
+}
+
+/// How much command input a connection may send ahead of its requests, so that a client can't
+/// make the editor buffer without bound.
+const MAX_STDIN_LEN: usize = 64 * 1024 * 1024;
+
+/// Audits `command` and hands it on to Unity, or fails it right away if it isn't allowed, is
+/// too large, or is a custom command under [`BUILTIN_COMMAND_PREFIX`].
+fn admit_command(
+    config: &ConnectionConfig,
+    metrics: &Metrics,
+    outbox: &Outbox,
+    command: UnityCommand,
+    is_builtin: bool,
+) -> Option<UnityRequest> {
+    let UnityCommand {
+        uuid,
+        request_id,
+        ref cmd,
+        ref args,
+        ref named_args,
+        ref env,
+        ..
+    } = command;
+    metrics.command_received();
+    config
+        .audit
+        .command_requested(uuid, request_id, cmd, args.len() + named_args.len());
+    let checked = if !is_builtin && cmd.starts_with(BUILTIN_COMMAND_PREFIX) {
+        Err(format!(
+            "commands starting with `{BUILTIN_COMMAND_PREFIX}` are reserved for built-in ones"
+        ))
+    } else {
+        Ok(())
+    };
+    let checked = checked
+        .and_then(|()| config.command_policy.check(cmd))
+        .and_then(|()| config.check_command_args(args, named_args, env));
+    if let Err(reason) = checked {
+        config.audit.command_denied(uuid, request_id, cmd, &reason);
+        metrics.command_failed();
+        outbox.push(ServerMessage::CommandFinished {
+            request_id,
+            is_success: false,
+            msg: Some(reason),
+        });
+        return None;
+    }
+    Some(UnityRequest::Command(command))
+}
+
+async fn handle_read<R: AsyncRead + Unpin>(
+    mut read: FramedRead<R, ServerCodec>,
     uuid: Uuid,
-    cmd_tx: tokio::sync::mpsc::Sender<(Uuid, String, Vec<String>)>,
+    cmd_tx: tokio::sync::mpsc::Sender<UnityRequest>,
+    metrics: Arc<Metrics>,
+    config: &ConnectionConfig,
+    outbox: &Outbox,
 ) {
+    let idle_timeout = config.idle_timeout;
+    let mut last_read = Instant::now();
+    // The input sent ahead of each request, until the request itself arrives.
+    let mut stdin_chunks: HashMap<u64, Vec<u8>> = HashMap::new();
     loop {
-        match read.next().await {
-            Some(Ok(ClientMessage::CommandRequest { cmd, args })) => {
-                if let Err(e) = cmd_tx.send((uuid, cmd, args)).await {
-                    error!(error = %e, "failed to send client command request through channel!");
+        let next = if idle_timeout.is_zero() {
+            read.next().await
+        } else {
+            // Output counts as traffic too, so that long running commands aren't cut off.
+            let last_traffic = last_read.max(outbox.last_written());
+            let deadline = tokio::time::Instant::from_std(last_traffic + idle_timeout);
+            match tokio::time::timeout_at(deadline, read.next()).await {
+                Ok(next) => next,
+                Err(_) if last_read.max(outbox.last_written()) == last_traffic => {
+                    info!(?idle_timeout, "closing idle connection.");
+                    return;
+                }
+                Err(_) => continue,
+            }
+        };
+        last_read = Instant::now();
+
+        let request = match next.map(|envelope| envelope.map(Envelope::into_payload)) {
+            Some(Ok(ClientMessage::CommandRequest {
+                request_id,
+                cmd,
+                args,
+                named_args,
+                cwd,
+                env,
+                stdin,
+            })) => {
+                let stdin = match (stdin_chunks.remove(&request_id), stdin) {
+                    (Some(mut start), Some(end)) => {
+                        start.extend(end);
+                        Some(start)
+                    }
+                    (start, end) => start.or(end),
+                };
+                let command = UnityCommand {
+                    uuid,
+                    request_id,
+                    cmd,
+                    args,
+                    named_args,
+                    cwd,
+                    env,
+                    stdin,
+                };
+                match admit_command(config, &metrics, outbox, command, false) {
+                    Some(request) => request,
+                    None => continue,
+                }
+            }
+            Some(Ok(ClientMessage::BuiltinRequest {
+                request_id,
+                builtin,
+                cwd,
+            })) => {
+                let command = UnityCommand::builtin(uuid, request_id, builtin, cwd);
+                match admit_command(config, &metrics, outbox, command, true) {
+                    Some(request) => request,
+                    None => continue,
+                }
+            }
+            Some(Ok(ClientMessage::StdinChunk { request_id, data })) => {
+                let buffered: usize = stdin_chunks.values().map(Vec::len).sum();
+                if buffered + data.len() > MAX_STDIN_LEN {
+                    error!(request_id, "command input too large, closing connection!");
                     break;
                 }
+                stdin_chunks.entry(request_id).or_default().extend(data);
+                continue;
+            }
+            Some(Ok(ClientMessage::ListCommands { request_id })) => {
+                UnityRequest::ListCommands { uuid, request_id }
+            }
+            Some(Ok(ClientMessage::CancelCommand { request_id })) => {
+                UnityRequest::CancelCommand { uuid, request_id }
+            }
+            Some(Ok(ClientMessage::Hello { .. })) => {
+                warn!("ignoring a hello sent after the handshake.");
+                continue;
+            }
+            Some(Ok(ClientMessage::RequestBacklog { count })) => {
+                let recent = config.backlog.recent(count as usize);
+                if recent.len() < count as usize && config.backlog.has_forgotten() {
+                    outbox.push(ServerMessage::Notice {
+                        level: UnityLogType::Log,
+                        text: format!(
+                            "Only the last {} console messages were kept, older ones are lost.",
+                            recent.len()
+                        ),
+                    });
+                }
+                for msg in recent {
+                    outbox.push(msg);
+                }
+                continue;
             }
             Some(Err(e)) => {
                 error!(error = %e, "failed to deserialize client message!");
@@ -277,15 +1813,21 @@ async fn handle_read(
                 trace!("stream closed.");
                 break;
             }
+        };
+
+        if let Err(e) = cmd_tx.send(request).await {
+            error!(error = %e, "failed to send client command request through channel!");
+            break;
         }
     }
 }
 
-async fn handle_write<F>(
-    mut write: FramedWrite<OwnedWriteHalf, ServerCodec>,
-    mut cmd_rx: tokio::sync::mpsc::Receiver<ServerMessage>,
+async fn handle_write<W, F>(
+    mut write: FramedWrite<W, ServerCodec>,
+    outbox: Arc<Outbox>,
     on_finish: F,
 ) where
+    W: AsyncWrite + Unpin,
     F: FnMut(),
 {
     struct ReleaseGuard<G>
@@ -307,33 +1849,125 @@ async fn handle_write<F>(
     let _guard = ReleaseGuard { on_finish };
 
     loop {
-        match cmd_rx.recv().await {
-            Some(msg) => {
-                if let Err(e) = write.send(msg).await {
-                    error!(error = %e, "failed to send server message!");
-                    break;
-                }
-            }
-            None => {
-                trace!("channel closed.");
-                break;
-            }
+        let envelope = outbox.pop().await;
+        let welcomed = match envelope.payload {
+            ServerMessage::Welcome { compression, .. } => Some(compression),
+            _ => None,
+        };
+        if let Err(e) = write.send(envelope).await {
+            error!(error = %e, "failed to send server message!");
+            break;
+        }
+        // Everything after the `Welcome` is compressed the way it told the client.
+        if let Some(compression) = welcomed {
+            write.encoder_mut().set_compression(compression);
         }
     }
 }
 
 #[no_mangle]
 pub extern "C" fn stop() {
-    if let Some(ref mut instance) = instance().blocking_write().as_mut() {
-        let _ = instance.stop_tx.blocking_send(());
+    if let Some(instance) = instance().read().as_ref() {
+        instance.shutdown.cancel();
+        // A full queue means a stop is already pending.
+        let _ = instance.stop_tx.try_send(());
     }
 }
 
 #[no_mangle]
 pub extern "C" fn is_running() -> bool {
-    instance().blocking_read().is_some()
+    instance().read().is_some()
 }
 
+/// Returns why the server last failed, or null if nothing failed since the last `run`. A server
+/// that couldn't advertise itself over mDNS sets it too, but keeps serving on its port.
+///
+/// The string is owned by this library and is only valid until the next `run` or failure, so
+/// copy it right away and never free it.
+#[no_mangle]
+pub extern "C" fn last_error() -> *const c_char {
+    last_error_slot()
+        .read()
+        .as_ref()
+        .map_or(std::ptr::null(), |e| e.as_ptr())
+}
+
+static STATS_JSON: OnceLock<SyncMutex<CString>> = OnceLock::new();
+
+/// Returns the counters of the running server as a JSON object, or null if it isn't running.
+/// They start from zero on every `run`.
+///
+/// Like [`last_error`], the string is owned by this library and is only valid until the next
+/// call, so copy it right away and never free it.
+#[no_mangle]
+pub extern "C" fn stats_json() -> *const c_char {
+    let Some(json) = instance()
+        .read()
+        .as_ref()
+        .map(|instance| instance.metrics.to_json())
+    else {
+        return std::ptr::null();
+    };
+    let mut slot = STATS_JSON
+        .get_or_init(|| SyncMutex::new(CString::default()))
+        .lock();
+    // Counter names and numbers never contain nul bytes.
+    *slot = CString::new(json).unwrap_or_default();
+    slot.as_ptr()
+}
+
+static STATUS_JSON: OnceLock<SyncMutex<CString>> = OnceLock::new();
+
+/// Returns what the running server is up to as a JSON object, or null if it isn't running: its
+/// `port` (`0` on local transports), `session_name` as [`session_name`] returns it, how many
+/// `connections` it has and its `uptime_secs`.
+///
+/// Like [`stats_json`], the string is only valid until the next call.
+#[no_mangle]
+pub extern "C" fn server_status_json() -> *const c_char {
+    let Some(json) = instance().read().as_ref().map(|instance| {
+        let session_name = advertised_name_slot()
+            .read()
+            .as_ref()
+            .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+        // Session names are sanitized down to letters, digits, `-` and `_`, which need no
+        // escaping.
+        format!(
+            "{{\"port\":{},\"session_name\":\"{session_name}\",\"connections\":{},\"uptime_secs\":{}}}",
+            instance.port.load(Ordering::Relaxed),
+            instance.metrics.active_connections(),
+            instance.started_at.elapsed().as_secs(),
+        )
+    }) else {
+        return std::ptr::null();
+    };
+    let mut slot = STATUS_JSON
+        .get_or_init(|| SyncMutex::new(CString::default()))
+        .lock();
+    *slot = CString::new(json).unwrap_or_default();
+    slot.as_ptr()
+}
+
+/// Returns the name the running server advertises over mDNS, i.e. what clients pass to
+/// `--session`. It is empty if the server isn't running or only listens on a local transport.
+///
+/// The string is owned by this library and is only valid until the server stops, so copy it
+/// right away and never free it.
+#[no_mangle]
+pub extern "C" fn session_name() -> *const c_char {
+    const EMPTY: &[u8] = b"\0";
+    advertised_name_slot()
+        .read()
+        .as_ref()
+        .map_or(EMPTY.as_ptr().cast(), |name| name.as_ptr())
+}
+
+/// Forwards a console log to the connection identified by `uuid_hi` and `uuid_lo`, or to every
+/// connection if both are zero, e.g. for logs that no command caused.
+///
+/// # Safety
+///
+/// `log` and `stack_trace` must each be null or point to a nul terminated string.
 #[no_mangle]
 pub unsafe extern "C" fn on_unity_console_log(
     uuid_hi: u64,
@@ -342,47 +1976,237 @@ pub unsafe extern "C" fn on_unity_console_log(
     log: *const c_char,
     stack_trace: *const c_char,
 ) -> bool {
-    if let Some(instance) = instance().blocking_read().as_ref() {
-        let log = c_char_to_str(log);
-        let stack_trace = c_char_to_str(stack_trace);
+    if let Some(instance) = instance().read().as_ref() {
+        instance.activity.touch();
+        instance.metrics.console_log(log_type.into());
+        let admission = instance.rate_limiter.admit(Instant::now());
+        if let Some(dropped) = admission.dropped {
+            let notice = ServerMessage::Notice {
+                level: UnityLogType::Warning,
+                text: format!(
+                    "{dropped} console messages were dropped to stay under the rate limit."
+                ),
+            };
+            let _ = instance.unity_msg_send.try_send((Uuid::nil(), notice));
+        }
+        if !admission.is_allowed {
+            instance.metrics.console_log_dropped();
+            return false;
+        }
+        let uuid = Uuid::from_u64_pair(uuid_hi, uuid_lo);
+        let log = instance.unity_str(log, uuid, "console log");
+        let stack_trace = instance.unity_str(stack_trace, uuid, "stack trace");
         let msg = ServerMessage::UnityConsoleOutput {
             log_type: log_type.into(),
             log,
             stack_trace,
         };
-        instance
-            .unity_msg_send
-            .send((Uuid::from_u64_pair(uuid_hi, uuid_lo), msg))
-            .is_ok()
+        if uuid.is_nil() {
+            instance.backlog.push(msg.clone());
+        }
+        match instance.unity_msg_send.try_send((uuid, msg)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                let dropped = instance
+                    .dropped_console_msgs
+                    .fetch_add(1, Ordering::Relaxed)
+                    + 1;
+                if dropped.is_power_of_two() {
+                    warn!(
+                        dropped,
+                        "message queue is full, dropping unity console output."
+                    );
+                }
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
     } else {
         false
     }
 }
 
+/// Finishes the command `request_id` of the client, with the uuid and request id it was called
+/// back with. `result` may be null.
 #[no_mangle]
 pub extern "C" fn on_command_finish(
     uuid_hi: u64,
     uuid_lo: u64,
+    request_id: u64,
     is_success: bool,
     result: *const c_char,
 ) {
-    if let Some(instance) = instance().blocking_read().as_ref() {
+    if let Some(instance) = instance().read().as_ref() {
+        instance.activity.touch();
+        instance.activity.command_done();
+        if is_success {
+            instance.metrics.command_succeeded();
+        } else {
+            instance.metrics.command_failed();
+        }
+        let uuid = Uuid::from_u64_pair(uuid_hi, uuid_lo);
         let result = if result.is_null() {
             None
         } else {
-            Some(c_char_to_str(result))
+            Some(instance.unity_str(result, uuid, "command result"))
         };
-        let _ = instance.unity_msg_send.send((
-            Uuid::from_u64_pair(uuid_hi, uuid_lo),
+        instance
+            .audit
+            .command_finished(uuid, request_id, is_success);
+        // Never dropped, as the client would wait for it forever.
+        instance.send_reliably(
+            uuid,
             ServerMessage::CommandFinished {
+                request_id,
                 is_success,
                 msg: result,
             },
-        ));
+        );
+    }
+}
+
+/// Tells the client that Unity won't run the command, e.g. because it isn't registered.
+#[no_mangle]
+pub extern "C" fn on_command_rejected(
+    uuid_hi: u64,
+    uuid_lo: u64,
+    request_id: u64,
+    reason: *const c_char,
+) {
+    if let Some(instance) = instance().read().as_ref() {
+        instance.activity.touch();
+        instance.activity.command_done();
+        instance.metrics.command_failed();
+        let uuid = Uuid::from_u64_pair(uuid_hi, uuid_lo);
+        let reason = instance.unity_str(reason, uuid, "rejection reason");
+        instance.audit.command_rejected(uuid, request_id, &reason);
+        instance.send_reliably(uuid, ServerMessage::CommandRejected { request_id, reason });
     }
 }
 
+/// Answers a list commands request with the names of the registered commands.
+///
+/// # Safety
+///
+/// `commands` must be null or point to `commands_len` pointers, each of them null or pointing to a
+/// nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn on_command_list(
+    uuid_hi: u64,
+    uuid_lo: u64,
+    request_id: u64,
+    commands: *const *const c_char,
+    commands_len: i32,
+) {
+    if let Some(instance) = instance().read().as_ref() {
+        instance.activity.touch();
+        let uuid = Uuid::from_u64_pair(uuid_hi, uuid_lo);
+        let commands = if commands.is_null() {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(commands, commands_len.max(0) as usize)
+                .iter()
+                .map(|ptr| instance.unity_str(*ptr, uuid, "command name"))
+                .collect()
+        };
+        instance.send_reliably(
+            uuid,
+            ServerMessage::CommandList {
+                request_id,
+                commands,
+            },
+        );
+    }
+}
+
+/// Sends a command's output that doesn't fit in its finish message, like JSON or binary data.
+/// The command must still be finished with `on_command_finish`.
+///
+/// # Safety
+///
+/// `payload` must be null or point to `payload_len` bytes, and `content_type` must be null or
+/// point to a nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn on_command_result(
+    uuid_hi: u64,
+    uuid_lo: u64,
+    request_id: u64,
+    payload: *const u8,
+    payload_len: i32,
+    content_type: *const c_char,
+) {
+    if let Some(instance) = instance().read().as_ref() {
+        instance.activity.touch();
+        let uuid = Uuid::from_u64_pair(uuid_hi, uuid_lo);
+        let payload = if payload.is_null() {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(payload, payload_len.max(0) as usize).to_vec()
+        };
+        instance.send_reliably(
+            uuid,
+            ServerMessage::CommandResult {
+                request_id,
+                payload,
+                content_type: instance.unity_str(content_type, uuid, "content type"),
+            },
+        );
+    }
+}
+
+/// Sends a part of a command's output that is too large for `on_command_result`. Chunks must be
+/// numbered from zero by `seq`, and the command must still be finished with `on_command_finish`
+/// after the last one.
+///
+/// # Safety
+///
+/// `data` must be null or point to `data_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn on_command_result_chunk(
+    uuid_hi: u64,
+    uuid_lo: u64,
+    request_id: u64,
+    seq: u32,
+    data: *const u8,
+    data_len: i32,
+    is_last: bool,
+) {
+    if let Some(instance) = instance().read().as_ref() {
+        instance.activity.touch();
+        let data = if data.is_null() {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(data, data_len.max(0) as usize).to_vec()
+        };
+        // A dropped chunk would break the whole result.
+        instance.send_reliably(
+            Uuid::from_u64_pair(uuid_hi, uuid_lo),
+            ServerMessage::ResultChunk {
+                request_id,
+                seq,
+                data,
+                is_last,
+            },
+        );
+    }
+}
+
+/// Lets the server know the editor is alive. Call it regularly, e.g. from
+/// `EditorApplication.update`, so that long running commands which don't log aren't mistaken for a
+/// frozen editor.
+#[no_mangle]
+pub extern "C" fn on_editor_heartbeat() {
+    if let Some(instance) = instance().read().as_ref() {
+        instance.activity.touch();
+    }
+}
+
+/// Forgets the callbacks, which are gone with the assemblies, until [`run`] is called again. The
+/// server and its connections outlive the reload, and clients are told it started.
 #[no_mangle]
 pub extern "C" fn on_csharp_assembly_unload() {
-    *unity_state().blocking_write() = None;
+    *unity_state().write() = None;
+    if let Some(instance) = instance().read().as_ref() {
+        instance.send_reliably(Uuid::nil(), ServerMessage::AssemblyReloading);
+    }
 }
@@ -0,0 +1,176 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use common::UnityLogType;
+
+/// Names of the [`UnityLogType`]s, indexed by their discriminant.
+const LOG_TYPE_NAMES: [&str; 6] = ["error", "assert", "warning", "log", "exception", "unknown"];
+
+/// Counters updated as requests and Unity's replies go through the server.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    commands_total: AtomicU64,
+    commands_succeeded: AtomicU64,
+    commands_failed: AtomicU64,
+    active_connections: AtomicU64,
+    console_logs: [AtomicU64; LOG_TYPE_NAMES.len()],
+    console_logs_dropped: AtomicU64,
+    invalid_utf8_strings: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn command_received(&self) {
+        self.commands_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn command_succeeded(&self) {
+        self.commands_succeeded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn command_failed(&self) {
+        self.commands_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn console_log(&self, log_type: UnityLogType) {
+        self.console_logs[log_type as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts console output dropped because a client wasn't reading fast enough, or to stay
+    /// under the rate limit.
+    pub(crate) fn console_log_dropped(&self) {
+        self.console_logs_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts strings from Unity that weren't valid UTF-8, and had their invalid bytes replaced.
+    pub(crate) fn invalid_utf8_string(&self) {
+        self.invalid_utf8_strings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        };
+        metric("ucli_commands_total", "counter", &self.commands_total);
+        metric(
+            "ucli_commands_succeeded_total",
+            "counter",
+            &self.commands_succeeded,
+        );
+        metric(
+            "ucli_commands_failed_total",
+            "counter",
+            &self.commands_failed,
+        );
+        metric("ucli_connections", "gauge", &self.active_connections);
+        metric(
+            "ucli_console_logs_dropped_total",
+            "counter",
+            &self.console_logs_dropped,
+        );
+        metric(
+            "ucli_invalid_utf8_strings_total",
+            "counter",
+            &self.invalid_utf8_strings,
+        );
+
+        let _ = writeln!(out, "# TYPE ucli_console_logs_total counter");
+        for (name, count) in LOG_TYPE_NAMES.iter().zip(&self.console_logs) {
+            let _ = writeln!(
+                out,
+                "ucli_console_logs_total{{type=\"{name}\"}} {}",
+                count.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+
+    /// Renders the counters as a JSON object, for the editor to display.
+    pub(crate) fn to_json(&self) -> String {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let console_logs: Vec<_> = LOG_TYPE_NAMES
+            .iter()
+            .zip(&self.console_logs)
+            .map(|(name, count)| format!("\"{name}\":{}", load(count)))
+            .collect();
+        format!(
+            "{{\"commands_received\":{},\"commands_succeeded\":{},\"commands_failed\":{},\
+             \"active_connections\":{},\"console_logs\":{{{}}},\"console_logs_dropped\":{},\
+             \"invalid_utf8_strings\":{}}}",
+            load(&self.commands_total),
+            load(&self.commands_succeeded),
+            load(&self.commands_failed),
+            load(&self.active_connections),
+            console_logs.join(","),
+            load(&self.console_logs_dropped),
+            load(&self.invalid_utf8_strings),
+        )
+    }
+}
+
+/// Answers every HTTP request on `listener` with the rendered metrics, whatever its path.
+#[cfg(feature = "metrics")]
+pub(crate) async fn serve(listener: std::net::TcpListener, metrics: std::sync::Arc<Metrics>) {
+    use tracing::{error, warn};
+
+    let listener = match tokio::net::TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(error = %e, "failed to register the metrics listener to the runtime!");
+            return;
+        }
+    };
+
+    let mut backoff = crate::AcceptBackoff::default();
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                backoff.wait(&e).await;
+                continue;
+            }
+        };
+        backoff.reset();
+        let body = metrics.render();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, body).await {
+                warn!(error = %e, "failed to serve the metrics!");
+            }
+        });
+    }
+}
+
+#[cfg(feature = "metrics")]
+async fn respond(mut stream: tokio::net::TcpStream, body: String) -> std::io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Scrapers send small requests, and which path they ask for doesn't matter.
+    let mut request = [0_u8; 1024];
+    let _ = stream.read(&mut request).await?;
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,86 @@
+use std::{collections::VecDeque, time::Instant};
+
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use common::{Envelope, ServerMessage};
+
+/// How many messages a connection may have waiting to be written before console output starts
+/// being dropped.
+pub(crate) const OUTBOX_CAPACITY: usize = 256;
+
+/// The messages waiting to be written to a connection.
+///
+/// Pushing never waits, so a client that stops reading can't hold up the others. Once the queue
+/// is full, the oldest console output is dropped to make room. Other messages, like
+/// `CommandFinished`, are never dropped since clients wait for them.
+///
+/// Every message is numbered as it is pushed, dropped or not, so clients can tell from the gaps
+/// how many they missed.
+pub(crate) struct Outbox {
+    queue: Mutex<Queue>,
+    pushed: Notify,
+    capacity: usize,
+    last_written: Mutex<Instant>,
+}
+
+#[derive(Default)]
+struct Queue {
+    envelopes: VecDeque<Envelope<ServerMessage>>,
+    next_seq: u64,
+}
+
+impl Outbox {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            queue: Mutex::new(Queue::default()),
+            pushed: Notify::new(),
+            capacity,
+            last_written: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Queues `msg`, and returns whether a console output had to be dropped for it.
+    pub(crate) fn push(&self, msg: ServerMessage) -> bool {
+        let mut queue = self.queue.lock();
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        let mut dropped = false;
+        if queue.envelopes.len() >= self.capacity {
+            if let Some(oldest) = queue
+                .envelopes
+                .iter()
+                .position(|e| is_droppable(&e.payload))
+            {
+                queue.envelopes.remove(oldest);
+                dropped = true;
+            } else if is_droppable(&msg) {
+                return true;
+            }
+        }
+        queue.envelopes.push_back(Envelope::new(seq, msg));
+        drop(queue);
+        self.pushed.notify_one();
+        dropped
+    }
+
+    /// Waits for the next message to write. Only one task may wait at a time.
+    pub(crate) async fn pop(&self) -> Envelope<ServerMessage> {
+        loop {
+            if let Some(envelope) = self.queue.lock().envelopes.pop_front() {
+                *self.last_written.lock() = Instant::now();
+                return envelope;
+            }
+            self.pushed.notified().await;
+        }
+    }
+
+    /// When the last message was taken to be written, or when the outbox was created.
+    pub(crate) fn last_written(&self) -> Instant {
+        *self.last_written.lock()
+    }
+}
+
+fn is_droppable(msg: &ServerMessage) -> bool {
+    matches!(msg, ServerMessage::UnityConsoleOutput { .. })
+}
@@ -0,0 +1,47 @@
+use common::glob_matches;
+
+/// Which commands clients may run, e.g. to keep a shared editor from being used for anything but
+/// builds.
+///
+/// Patterns are globs where `*` matches any run of characters and `?` any single one.
+#[derive(Default)]
+pub(crate) struct CommandPolicy {
+    allowed: Vec<String>,
+    denied: Vec<String>,
+}
+
+impl CommandPolicy {
+    /// Both lists are comma separated patterns. An empty allow list allows every command that
+    /// isn't denied.
+    pub(crate) fn new(allowed: &str, denied: &str) -> Self {
+        Self {
+            allowed: split_patterns(allowed),
+            denied: split_patterns(denied),
+        }
+    }
+
+    /// Returns why `cmd` may not be run, if it may not.
+    pub(crate) fn check(&self, cmd: &str) -> Result<(), String> {
+        if let Some(pattern) = self.denied.iter().find(|p| glob_matches(p, cmd)) {
+            return Err(format!(
+                "`{cmd}` is denied on this server by the pattern `{pattern}`"
+            ));
+        }
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|p| glob_matches(p, cmd)) {
+            return Err(format!(
+                "`{cmd}` isn't among the commands this server allows: {}",
+                self.allowed.join(", ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn split_patterns(patterns: &str) -> Vec<String> {
+    patterns
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
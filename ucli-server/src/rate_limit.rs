@@ -0,0 +1,80 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// How often at most clients are told how many console messages were dropped.
+const NOTICE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A token bucket limiting how many console messages per second reach the clients, so that a
+/// log storm doesn't flood them.
+pub(crate) struct RateLimiter {
+    /// Messages per second, and how many may be sent in a burst.
+    rate: u32,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+    /// Dropped since the clients were last told.
+    dropped: u64,
+    first_dropped_at: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Admission {
+    pub(crate) is_allowed: bool,
+    /// How many messages were dropped, when it's time to tell the clients.
+    pub(crate) dropped: Option<u64>,
+}
+
+impl RateLimiter {
+    /// `0` means no limit.
+    pub(crate) fn new(rate: u32, now: Instant) -> Self {
+        Self {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                refilled_at: now,
+                dropped: 0,
+                first_dropped_at: None,
+            }),
+        }
+    }
+
+    /// Takes a token for a message sent at `now`, if there is one left.
+    pub(crate) fn admit(&self, now: Instant) -> Admission {
+        if self.rate == 0 {
+            return Admission {
+                is_allowed: true,
+                dropped: None,
+            };
+        }
+
+        let rate = self.rate as f64;
+        let mut state = self.state.lock();
+        let elapsed = now.saturating_duration_since(state.refilled_at);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        state.refilled_at = now;
+
+        let is_allowed = state.tokens >= 1.0;
+        if is_allowed {
+            state.tokens -= 1.0;
+        } else {
+            state.dropped += 1;
+            state.first_dropped_at.get_or_insert(now);
+        }
+
+        let dropped = match state.first_dropped_at {
+            Some(since) if now.saturating_duration_since(since) >= NOTICE_INTERVAL => {
+                state.first_dropped_at = None;
+                Some(std::mem::take(&mut state.dropped))
+            }
+            _ => None,
+        };
+        Admission {
+            is_allowed,
+            dropped,
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use mdns_sd::{IPMulticastTTLOption, ServiceDaemon, ServiceInfo};
+use tracing::error;
+
+/// Advertises the server for clients to find. [`MdnsRegistrar`] is the one `run` uses.
+pub(crate) trait Registrar: Send {
+    /// Starts advertising `service`, until the returned advertisement is withdrawn.
+    fn register(&self, service: ServiceInfo) -> anyhow::Result<Box<dyn Advertisement>>;
+}
+
+/// A service being advertised by a [`Registrar`].
+pub(crate) trait Advertisement: Send {
+    /// Stops advertising the service.
+    fn withdraw(self: Box<Self>);
+}
+
+/// Advertises over mDNS, with a daemon of its own for every registration.
+pub(crate) struct MdnsRegistrar;
+
+impl Registrar for MdnsRegistrar {
+    fn register(&self, service: ServiceInfo) -> anyhow::Result<Box<dyn Advertisement>> {
+        let daemon = ServiceDaemon::new(IPMulticastTTLOption::NodeLocal)?;
+        let fullname = service.get_fullname().to_owned();
+        if let Err(e) = daemon.register(service) {
+            let _ = daemon.shutdown();
+            return Err(e.into());
+        }
+        Ok(Box::new(MdnsAdvertisement { daemon, fullname }))
+    }
+}
+
+struct MdnsAdvertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertisement for MdnsAdvertisement {
+    fn withdraw(self: Box<Self>) {
+        // Say goodbye so that browsers drop this session right away instead of waiting for the
+        // TTL to expire.
+        match self.daemon.unregister(&self.fullname) {
+            Ok(status_rx) => {
+                let _ = status_rx.recv_timeout(Duration::from_millis(500));
+            }
+            Err(e) => {
+                error!(error = %e, "failed to unregister our service!");
+            }
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
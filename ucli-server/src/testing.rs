@@ -0,0 +1,144 @@
+//! Helpers shared by the tests driving a server through its FFI.
+
+use std::{
+    ffi::c_char,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use mdns_sd::ServiceInfo;
+use parking_lot::Mutex;
+
+use common::PROJECT_PATH_PROP_KEY;
+
+use crate::{
+    registrar::{Advertisement, Registrar},
+    AcceptSource, Hooks, ServerOptions, UnityCancelCommandCallback, UnityCommandCallback,
+    UnityListCommandsCallback,
+};
+
+/// What a server started by [`run`] has in place of mDNS, the name generator and the OS's
+/// accepts. The default fakes behave like the real ones, but advertise to [`discover`] only.
+#[derive(Clone, Default)]
+pub struct Fakes {
+    /// Makes the registration fail, as if mDNS were unavailable.
+    pub fail_registration: bool,
+    /// Makes the name generator run out of names right away.
+    pub exhaust_session_names: bool,
+    /// How many of the first accepts fail, as if the process were out of file descriptors.
+    pub failing_accepts: u32,
+    /// Takes every TCP client for one on another machine, even over loopback.
+    pub remote_peers: bool,
+}
+
+/// Like [`crate::run`], with `fakes` in place of what the server reaches outside.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    fakes: Fakes,
+    project_path: *const c_char,
+    project_name: *const c_char,
+    unity_version: *const c_char,
+    command_callback: UnityCommandCallback,
+    list_commands_callback: UnityListCommandsCallback,
+    cancel_command_callback: UnityCancelCommandCallback,
+    options: *const ServerOptions,
+) {
+    let session_names: Box<dyn Iterator<Item = String>> = if fakes.exhaust_session_names {
+        Box::new(std::iter::empty())
+    } else {
+        Box::new(names::Generator::default())
+    };
+    let hooks = Hooks {
+        registrar: Box::new(FakeRegistrar {
+            fail: fakes.fail_registration,
+        }),
+        session_names,
+        accepts: Box::new(FakeAccepts {
+            failures: fakes.failing_accepts,
+            remote: fakes.remote_peers,
+        }),
+    };
+    crate::run_with(
+        hooks,
+        project_path,
+        project_name,
+        unity_version,
+        command_callback,
+        list_commands_callback,
+        cancel_command_callback,
+        options,
+    );
+}
+
+/// The services advertised by servers started by [`run`], in the order they were registered.
+static REGISTERED: Mutex<Vec<ServiceInfo>> = Mutex::new(Vec::new());
+
+struct FakeRegistrar {
+    fail: bool,
+}
+
+impl Registrar for FakeRegistrar {
+    fn register(&self, service: ServiceInfo) -> anyhow::Result<Box<dyn Advertisement>> {
+        if self.fail {
+            bail!("injected registration failure");
+        }
+        let fullname = service.get_fullname().to_owned();
+        REGISTERED.lock().push(service);
+        Ok(Box::new(FakeAdvertisement { fullname }))
+    }
+}
+
+struct FakeAdvertisement {
+    fullname: String,
+}
+
+impl Advertisement for FakeAdvertisement {
+    fn withdraw(self: Box<Self>) {
+        REGISTERED
+            .lock()
+            .retain(|service| service.get_fullname() != self.fullname);
+    }
+}
+
+struct FakeAccepts {
+    failures: u32,
+    remote: bool,
+}
+
+impl AcceptSource for FakeAccepts {
+    fn failure(&mut self) -> Option<std::io::Error> {
+        self.failures = self.failures.checked_sub(1)?;
+        Some(std::io::Error::other("injected accept failure"))
+    }
+
+    fn is_local(&self, peer: SocketAddr) -> bool {
+        !self.remote && peer.ip().is_loopback()
+    }
+}
+
+/// Waits until a server started by [`run`] advertises `project_path`, for at most `timeout`.
+pub fn discover(project_path: &str, timeout: Duration) -> anyhow::Result<ServiceInfo> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let found = REGISTERED
+            .lock()
+            .iter()
+            .find(|service| {
+                service.get_property_val_str(PROJECT_PATH_PROP_KEY) == Some(project_path)
+            })
+            .cloned();
+        if let Some(service) = found {
+            return Ok(service);
+        }
+        if Instant::now() >= deadline {
+            bail!("no server advertising `{project_path}` was found within {timeout:?}");
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Like [`discover`], but only returns the port to connect to.
+pub fn discover_port(project_path: &str, timeout: Duration) -> anyhow::Result<u16> {
+    discover(project_path, timeout).map(|info| info.get_port())
+}
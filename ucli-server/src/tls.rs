@@ -0,0 +1,55 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use rustls_pemfile::Item;
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+pub(crate) struct TlsSetup {
+    pub(crate) acceptor: TlsAcceptor,
+    /// Advertised so clients without a CA can pin our certificate.
+    pub(crate) fingerprint: String,
+}
+
+/// Loads the PEM encoded certificate chain and private key. The first certificate in the chain
+/// must be our own.
+pub(crate) fn load(cert_path: &str, key_path: &str) -> anyhow::Result<TlsSetup> {
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .with_context(|| format!("failed to parse the certificate `{cert_path}`"))?;
+    let Some(leaf) = certs.first() else {
+        return Err(anyhow!("no certificate found in `{cert_path}`"));
+    };
+    let fingerprint = common::tls_fingerprint(leaf);
+    let certs = certs.into_iter().map(Certificate).collect();
+
+    let mut key_file = open(key_path)?;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_file)
+            .with_context(|| format!("failed to parse the private key `{key_path}`"))?
+        {
+            Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key)) => {
+                break PrivateKey(key)
+            }
+            Some(_) => continue,
+            None => return Err(anyhow!("no private key found in `{key_path}`")),
+        }
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("invalid certificate or private key")?;
+
+    Ok(TlsSetup {
+        acceptor: TlsAcceptor::from(Arc::new(config)),
+        fingerprint,
+    })
+}
+
+fn open(path: &str) -> anyhow::Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("failed to open `{path}`"))?;
+    Ok(BufReader::new(file))
+}
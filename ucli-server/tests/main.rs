@@ -1,18 +1,21 @@
+// The callbacks below mirror the C# signatures, however many arguments those take.
+#![allow(clippy::too_many_arguments)]
+
 use std::{
     ffi::{c_char, CStr, CString},
     net::TcpStream,
-    sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use common::{
-    ClientCodec, ClientMessage, ServerMessage, PROJECT_NAME_PROP_KEY, PROJECT_PATH_PROP_KEY,
-    UNITY_VERSION_PROP_KEY,
+    Builtin, ClientCodec, ClientMessage, Compression, Envelope, ServerMessage,
+    PROJECT_NAME_PROP_KEY, STARTED_AT_PROP_KEY, UNITY_VERSION_PROP_KEY,
 };
-use mdns_sd::{ServiceDaemon, ServiceEvent};
-use parking_lot::{Condvar, Mutex};
+use parking_lot::Mutex;
+use ucli_server::testing::{discover, discover_port, Fakes};
 
-type Command = (u64, u64, String, Vec<String>);
+// The server is a process-wide singleton, so tests touching it must not overlap.
+static SERVER_LOCK: Mutex<()> = Mutex::new(());
 
 fn ptr_to_string(ptr: *const c_char) -> String {
     unsafe { CStr::from_ptr(ptr).to_string_lossy().to_string() }
@@ -22,117 +25,283 @@ fn str_to_ptr<T: AsRef<str>>(s: &T) -> *const c_char {
     CString::new(s.as_ref()).unwrap().into_raw()
 }
 
-#[test]
-fn general_use_case() {
-    const PROJECT_PATH: &str = "foo/bar/baz";
-    let project_path_cstr = CString::new(PROJECT_PATH).unwrap();
-    const PROJECT_NAME: &str = "My Unity Project";
-    let project_name_cstr = CString::new(PROJECT_NAME).unwrap();
-    const UNITY_VERSION: &str = "2023.5.30";
-    let unity_version_cstr = CString::new(UNITY_VERSION).unwrap();
+type CommandCallback = extern "C" fn(
+    u64,
+    u64,
+    u64,
+    *const c_char,
+    *const *const c_char,
+    i32,
+    *const *const c_char,
+    *const *const c_char,
+    i32,
+    *const c_char,
+    *const *const c_char,
+    *const *const c_char,
+    i32,
+    *const u8,
+    i32,
+);
 
-    static COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+extern "C" fn noop_cmd_cb(
+    _: u64,
+    _: u64,
+    _: u64,
+    _: *const c_char,
+    _: *const *const c_char,
+    _: i32,
+    _: *const *const c_char,
+    _: *const *const c_char,
+    _: i32,
+    _: *const c_char,
+    _: *const *const c_char,
+    _: *const *const c_char,
+    _: i32,
+    _: *const u8,
+    _: i32,
+) {
+}
 
-    extern "C" fn cmd_cb(
-        u1: u64,
-        u2: u64,
-        cmd: *const c_char,
-        args: *const *const c_char,
-        args_len: i32,
-    ) {
-        let cmd = ptr_to_string(cmd);
-        let args_len = args_len as usize;
-        let slice = unsafe { std::slice::from_raw_parts(args, args_len) };
-        let args = (0..args_len).map(|i| ptr_to_string(slice[i])).collect();
-        dbg!((&cmd, &args));
-        COMMANDS.lock().push((u1, u2, cmd, args));
+extern "C" fn noop_list_cmds_cb(_: u64, _: u64, _: u64) {}
+
+extern "C" fn noop_cancel_cmd_cb(_: u64, _: u64, _: u64) {}
+
+/// A command as Unity was asked to run it, recorded by [`recording_cmd_cb`].
+#[derive(Debug)]
+struct ReceivedCommand {
+    uuid: (u64, u64),
+    request_id: u64,
+    cmd: String,
+    args: Vec<String>,
+    named_args: Vec<(String, String)>,
+    cwd: Option<String>,
+    env: Vec<(String, String)>,
+    stdin: Option<Vec<u8>>,
+}
+
+static RECEIVED_COMMANDS: Mutex<Vec<ReceivedCommand>> = Mutex::new(Vec::new());
+
+fn ptr_to_strings(ptrs: *const *const c_char, len: i32) -> Vec<String> {
+    if ptrs.is_null() {
+        return Vec::new();
     }
+    let ptrs = unsafe { std::slice::from_raw_parts(ptrs, len as usize) };
+    ptrs.iter().map(|ptr| ptr_to_string(*ptr)).collect()
+}
+
+fn ptr_to_pairs(
+    keys: *const *const c_char,
+    values: *const *const c_char,
+    len: i32,
+) -> Vec<(String, String)> {
+    ptr_to_strings(keys, len)
+        .into_iter()
+        .zip(ptr_to_strings(values, len))
+        .collect()
+}
+
+/// Records every command it is called with, for [`received_commands`] to hand back.
+extern "C" fn recording_cmd_cb(
+    uuid_hi: u64,
+    uuid_lo: u64,
+    request_id: u64,
+    cmd: *const c_char,
+    args: *const *const c_char,
+    args_len: i32,
+    named_arg_keys: *const *const c_char,
+    named_arg_values: *const *const c_char,
+    named_args_len: i32,
+    cwd: *const c_char,
+    env_keys: *const *const c_char,
+    env_values: *const *const c_char,
+    env_len: i32,
+    stdin: *const u8,
+    stdin_len: i32,
+) {
+    RECEIVED_COMMANDS.lock().push(ReceivedCommand {
+        uuid: (uuid_hi, uuid_lo),
+        request_id,
+        cmd: ptr_to_string(cmd),
+        args: ptr_to_strings(args, args_len),
+        named_args: ptr_to_pairs(named_arg_keys, named_arg_values, named_args_len),
+        cwd: (!cwd.is_null()).then(|| ptr_to_string(cwd)),
+        env: ptr_to_pairs(env_keys, env_values, env_len),
+        stdin: (!stdin.is_null())
+            .then(|| unsafe { std::slice::from_raw_parts(stdin, stdin_len as usize) }.to_vec()),
+    });
+}
+
+/// Takes what [`recording_cmd_cb`] recorded since the server was started, or since the last
+/// call.
+fn received_commands() -> Vec<ReceivedCommand> {
+    std::mem::take(&mut *RECEIVED_COMMANDS.lock())
+}
+
+/// Like [`received_commands`], but only their names.
+fn received_command_names() -> Vec<String> {
+    received_commands().into_iter().map(|c| c.cmd).collect()
+}
 
-    COMMANDS.lock().clear();
+fn run_server(
+    project_path: &str,
+    cmd_cb: CommandCallback,
+    options: Option<&ucli_server::ServerOptions>,
+) {
+    run_faked_server(project_path, cmd_cb, options, Fakes::default());
+}
 
-    ucli_server::run(
-        project_path_cstr.into_raw(),
-        project_name_cstr.into_raw(),
-        unity_version_cstr.into_raw(),
+/// Like [`run_server`], but with `fakes` other than the default ones.
+fn run_faked_server(
+    project_path: &str,
+    cmd_cb: CommandCallback,
+    options: Option<&ucli_server::ServerOptions>,
+    fakes: Fakes,
+) {
+    run_server_with(
+        project_path,
         cmd_cb,
+        noop_list_cmds_cb,
+        noop_cancel_cmd_cb,
+        options,
+        fakes,
     );
+}
 
-    assert!(ucli_server::is_running());
+/// Like [`run_faked_server`], but with every callback given.
+fn run_server_with(
+    project_path: &str,
+    cmd_cb: CommandCallback,
+    list_cmds_cb: extern "C" fn(u64, u64, u64),
+    cancel_cmd_cb: extern "C" fn(u64, u64, u64),
+    options: Option<&ucli_server::ServerOptions>,
+    fakes: Fakes,
+) {
+    let project_path = CString::new(project_path).unwrap();
+    let project_name = CString::new("My Unity Project").unwrap();
+    let unity_version = CString::new("2023.5.30").unwrap();
 
-    fn discover_and_connect(port_cvar: Arc<(Mutex<Option<u16>>, Condvar)>) {
-        let mdns = ServiceDaemon::new(mdns_sd::IPMulticastTTLOption::NodeLocal).unwrap();
-        let receiver = mdns.browse(common::MDNS_SERVICE_NAME).unwrap();
-        while let Ok(event) = receiver.recv() {
-            dbg!(&event);
-            if let ServiceEvent::ServiceResolved(info) = event {
-                dbg!(&info);
-                if (
-                    info.get_property_val_str(PROJECT_PATH_PROP_KEY),
-                    info.get_property_val_str(PROJECT_NAME_PROP_KEY),
-                    info.get_property_val_str(UNITY_VERSION_PROP_KEY),
-                ) != (Some(PROJECT_PATH), Some(PROJECT_NAME), Some(UNITY_VERSION))
-                {
-                    continue;
-                }
-                dbg!(info.get_fullname());
-                let (lock, cvar) = &*port_cvar;
-                *lock.lock() = Some(info.get_port());
-                cvar.notify_one();
-            }
-        }
+    RECEIVED_COMMANDS.lock().clear();
+    ucli_server::testing::run(
+        fakes,
+        project_path.as_ptr(),
+        project_name.as_ptr(),
+        unity_version.as_ptr(),
+        cmd_cb,
+        list_cmds_cb,
+        cancel_cmd_cb,
+        options.map_or(std::ptr::null(), |o| o as *const _),
+    );
+}
+
+fn stop_server() {
+    ucli_server::stop();
+
+    let deadline = Instant::now() + Duration::from_millis(1000);
+    while ucli_server::is_running() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
     }
+}
 
-    let pair = Arc::new((Mutex::new(None), Condvar::new()));
-    let pair2 = pair.clone();
+/// Connects to the server advertised for `project_path`, past its welcome.
+fn connect(project_path: &str) -> TcpStream {
+    let mut conn = connect_unwelcomed(project_path);
+    expect_welcome(&mut conn);
+    conn
+}
 
-    std::thread::spawn(move || {
-        discover_and_connect(pair2);
-    });
+fn connect_unwelcomed(project_path: &str) -> TcpStream {
+    let port =
+        discover_port(project_path, Duration::from_millis(5000)).expect("Cannot find service!");
+    let conn = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    conn.set_read_timeout(Some(Duration::from_millis(1000)))
+        .unwrap();
+    conn
+}
 
-    let (port_a, cvar): &(
-        parking_lot::lock_api::Mutex<parking_lot::RawMutex, Option<u16>>,
-        Condvar,
-    ) = &pair;
-    let wait_timeout_result = cvar.wait_for(&mut port_a.lock(), Duration::from_millis(5000));
+/// Reads the next message, out of its envelope.
+fn read_msg(conn: &mut impl std::io::Read) -> Result<ServerMessage, common::CodecError> {
+    ClientCodec::default()
+        .read(conn)
+        .map(Envelope::into_payload)
+}
 
-    assert!(!wait_timeout_result.timed_out(), "Cannot find service!");
+/// Writes `msg` in an envelope, like a client's first message.
+fn write_msg(conn: &mut impl std::io::Write, msg: &ClientMessage) {
+    ClientCodec::default()
+        .write(&Envelope::from(msg.clone()), conn)
+        .unwrap();
+}
 
-    let pair = Arc::new((Mutex::new(None), Condvar::new()));
-    let pair2 = pair.clone();
+/// Says hello like a client without compression would, and expects the welcome in return.
+fn expect_welcome(conn: &mut (impl std::io::Read + std::io::Write)) {
+    write_msg(
+        conn,
+        &ClientMessage::Hello {
+            compression: vec![],
+        },
+    );
+    match read_msg(conn) {
+        Ok(ServerMessage::Welcome { .. }) => {}
+        other => panic!("expected a welcome, got {other:?}"),
+    }
+}
 
-    std::thread::spawn(move || {
-        discover_and_connect(pair2);
-    });
+#[test]
+fn general_use_case() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/baz";
+    const PROJECT_NAME: &str = "My Unity Project";
+    const UNITY_VERSION: &str = "2023.5.30";
 
-    let (port_b, cvar): &(
-        parking_lot::lock_api::Mutex<parking_lot::RawMutex, Option<u16>>,
-        Condvar,
-    ) = &pair;
-    let wait_timeout_result = cvar.wait_for(&mut port_a.lock(), Duration::from_millis(5000));
+    run_server(PROJECT_PATH, recording_cmd_cb, None);
 
-    assert!(!wait_timeout_result.timed_out(), "Cannot find service!");
+    assert!(ucli_server::is_running());
 
-    let port_a = port_a.lock().unwrap();
-    let port_b = port_b.lock().unwrap();
+    // Two separate browses must agree on the same server.
+    let info_a = discover(PROJECT_PATH, Duration::from_millis(5000)).unwrap();
+    let info_b = discover(PROJECT_PATH, Duration::from_millis(5000)).unwrap();
+    for info in [&info_a, &info_b] {
+        assert_eq!(
+            (
+                info.get_property_val_str(PROJECT_NAME_PROP_KEY),
+                info.get_property_val_str(UNITY_VERSION_PROP_KEY),
+            ),
+            (Some(PROJECT_NAME), Some(UNITY_VERSION))
+        );
+    }
+    let (port_a, port_b) = (info_a.get_port(), info_b.get_port());
 
     assert_eq!(port_a, port_b);
 
     let mut conn_a = TcpStream::connect(format!("127.0.0.1:{}", port_a)).unwrap();
-    let mut conn_b = TcpStream::connect(format!("127.0.0.1:{}", port_b)).unwrap();
+    let _conn_b = TcpStream::connect(format!("127.0.0.1:{}", port_b)).unwrap();
+    expect_welcome(&mut conn_a);
 
     let cmd = "foo".to_string();
     let args = vec!["bar".to_string(), "baz".to_string()];
     let msg = ClientMessage::CommandRequest {
+        request_id: 0,
         cmd: "foo".to_string(),
         args: vec!["bar".to_string(), "baz".to_string()],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
     };
-    ClientCodec::default().write(&msg, &mut conn_a).unwrap();
+    write_msg(&mut conn_a, &msg);
 
     std::thread::sleep(Duration::from_millis(100));
 
     assert!(ucli_server::is_running());
-    assert_eq!(1, COMMANDS.lock().len());
-    let (id_hi_a, id_lo_a, cmd_recvd, args_recvd) = COMMANDS.lock().remove(0);
+    let mut received = received_commands();
+    assert_eq!(1, received.len());
+    let ReceivedCommand {
+        uuid: (id_hi_a, id_lo_a),
+        cmd: cmd_recvd,
+        args: args_recvd,
+        ..
+    } = received.remove(0);
     assert_eq!((cmd_recvd, args_recvd), (cmd, args));
 
     let log_a = "log to connection A";
@@ -145,7 +314,7 @@ fn general_use_case() {
 
     std::thread::sleep(Duration::from_millis(100));
 
-    let msg = ClientCodec::default().read(&mut conn_a);
+    let msg = read_msg(&mut conn_a);
     match msg {
         Ok(ServerMessage::UnityConsoleOutput {
             log_type: _,
@@ -169,3 +338,1881 @@ fn general_use_case() {
 
     assert!(!ucli_server::is_running());
 }
+
+#[test]
+fn service_unregistered_on_stop() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/unregistered";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+
+    assert!(
+        discover_port(PROJECT_PATH, Duration::from_millis(5000)).is_ok(),
+        "Cannot find service!"
+    );
+
+    stop_server();
+
+    assert!(!ucli_server::is_running());
+    assert!(
+        discover_port(PROJECT_PATH, Duration::from_millis(1000)).is_err(),
+        "Service is still advertised after stop!"
+    );
+}
+
+#[test]
+fn bind_failure_is_reported() {
+    let _lock = SERVER_LOCK.lock();
+
+    let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let options = ucli_server::ServerOptions {
+        port: occupied.local_addr().unwrap().port(),
+        ..Default::default()
+    };
+    run_server("foo/bar/bind-failure", noop_cmd_cb, Some(&options));
+
+    let deadline = Instant::now() + Duration::from_millis(1000);
+    while ucli_server::is_running() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(!ucli_server::is_running());
+    let error = ucli_server::last_error();
+    assert!(!error.is_null());
+    assert!(ptr_to_string(error).contains("bind"));
+}
+
+#[test]
+fn registration_failure_keeps_serving() {
+    let _lock = SERVER_LOCK.lock();
+
+    let port = {
+        let free = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        free.local_addr().unwrap().port()
+    };
+    let options = ucli_server::ServerOptions {
+        port,
+        ..Default::default()
+    };
+    let fakes = Fakes {
+        fail_registration: true,
+        ..Default::default()
+    };
+    run_faked_server(
+        "foo/bar/registration-failure",
+        noop_cmd_cb,
+        Some(&options),
+        fakes,
+    );
+
+    let deadline = Instant::now() + Duration::from_millis(1000);
+    while ucli_server::last_error().is_null() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let error = ucli_server::last_error();
+    assert!(!error.is_null());
+    assert!(ptr_to_string(error).contains("register"));
+    assert!(ucli_server::is_running());
+
+    let mut conn = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    conn.set_read_timeout(Some(Duration::from_millis(1000)))
+        .unwrap();
+    expect_welcome(&mut conn);
+
+    stop_server();
+}
+
+#[test]
+fn exhausted_name_generator_falls_back_to_a_uuid() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/no-names";
+    let fakes = Fakes {
+        exhaust_session_names: true,
+        ..Default::default()
+    };
+    run_faked_server(PROJECT_PATH, noop_cmd_cb, None, fakes);
+
+    let session_name = ptr_to_string(ucli_server::session_name());
+    let uuid = session_name.strip_prefix("ucli-").expect(&session_name);
+    assert!(uuid::Uuid::parse_str(uuid).is_ok(), "{session_name}");
+    drop(connect(PROJECT_PATH));
+
+    stop_server();
+}
+
+#[test]
+fn running_twice_is_reported() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/run-twice";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+    let session_name = ptr_to_string(ucli_server::session_name());
+    assert!(ucli_server::last_error().is_null());
+
+    run_server("foo/bar/run-twice-again", noop_cmd_cb, None);
+
+    let error = ucli_server::last_error();
+    assert!(!error.is_null());
+    assert!(ptr_to_string(error).contains("already running"));
+    assert!(ucli_server::is_running());
+    assert_eq!(session_name, ptr_to_string(ucli_server::session_name()));
+    drop(connect(PROJECT_PATH));
+
+    stop_server();
+}
+
+#[test]
+fn running_again_with_the_same_options_is_not_reported() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/run-same-twice";
+    let options = ucli_server::ServerOptions {
+        max_connections: 4,
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+
+    assert!(ucli_server::last_error().is_null());
+    assert!(ucli_server::is_running());
+
+    stop_server();
+}
+
+#[test]
+fn running_again_has_no_side_effects() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/run-without-effects";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+
+    let audit_path = std::env::temp_dir().join(format!("ucli-unused-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&audit_path);
+    let audit_path_cstr = CString::new(audit_path.to_str().unwrap()).unwrap();
+    let options = ucli_server::ServerOptions {
+        audit_log_path: audit_path_cstr.as_ptr(),
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+
+    assert!(!ucli_server::last_error().is_null());
+    assert!(!audit_path.exists(), "the ignored audit log was opened");
+
+    stop_server();
+}
+
+#[test]
+fn connections_outlive_a_domain_reload() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/domain-reload";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    ucli_server::on_csharp_assembly_unload();
+    assert!(matches!(
+        read_msg(&mut conn),
+        Ok(ServerMessage::AssemblyReloading)
+    ));
+    // The reloaded assemblies start the server again, which only hands over their callbacks.
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+    assert!(matches!(
+        read_msg(&mut conn),
+        Ok(ServerMessage::AssemblyReloaded)
+    ));
+
+    // Without an unload first, it isn't a reload.
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+    assert!(read_msg(&mut conn).is_err());
+
+    stop_server();
+}
+
+// Other loopback addresses than 127.0.0.1 only work out of the box on Linux.
+#[cfg(target_os = "linux")]
+#[test]
+fn lan_address_is_listened_on_too() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/lan-address";
+    let lan_address = CString::new("127.0.0.2").unwrap();
+    let options = ucli_server::ServerOptions {
+        lan_address: lan_address.as_ptr(),
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+    let port =
+        discover_port(PROJECT_PATH, Duration::from_millis(5000)).expect("Cannot find service!");
+
+    for ip in ["127.0.0.1", "127.0.0.2"] {
+        let mut conn = TcpStream::connect((ip, port)).unwrap();
+        conn.set_read_timeout(Some(Duration::from_millis(1000)))
+            .unwrap();
+        expect_welcome(&mut conn);
+    }
+
+    stop_server();
+}
+
+#[test]
+fn unusable_lan_address_is_reported() {
+    let _lock = SERVER_LOCK.lock();
+
+    let lan_address = CString::new("my-laptop.local").unwrap();
+    let options = ucli_server::ServerOptions {
+        lan_address: lan_address.as_ptr(),
+        ..Default::default()
+    };
+    run_server("foo/bar/bad-lan-address", noop_cmd_cb, Some(&options));
+
+    assert!(!ucli_server::is_running());
+    let error = ucli_server::last_error();
+    assert!(!error.is_null());
+    assert!(ptr_to_string(error).contains("IPv4"));
+}
+
+#[test]
+fn tls_cert_without_key_is_reported() {
+    let _lock = SERVER_LOCK.lock();
+
+    let cert_path = CString::new("certs/server.pem").unwrap();
+    let options = ucli_server::ServerOptions {
+        tls_cert_path: cert_path.as_ptr(),
+        ..Default::default()
+    };
+    run_server("foo/bar/tls-cert-only", noop_cmd_cb, Some(&options));
+
+    assert!(!ucli_server::is_running());
+    let error = ucli_server::last_error();
+    assert!(!error.is_null());
+    assert!(ptr_to_string(error).contains("tls_key_path"));
+}
+
+#[test]
+fn session_name_is_sanitized() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/session-name";
+    let session_name = CString::new(" My Game (CI)! ").unwrap();
+    let options = ucli_server::ServerOptions {
+        session_name: session_name.as_ptr(),
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+
+    let info = discover(PROJECT_PATH, Duration::from_millis(5000)).expect("Cannot find service!");
+    assert_eq!(
+        format!("My-Game--CI.{}", common::MDNS_SERVICE_NAME),
+        info.get_fullname()
+    );
+
+    stop_server();
+}
+
+#[test]
+fn extra_properties_are_advertised() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/extra-properties";
+    let keys = [
+        CString::new("platform").unwrap(),
+        CString::new("license").unwrap(),
+    ];
+    let values = [
+        CString::new("Android").unwrap(),
+        CString::new("pro").unwrap(),
+    ];
+    let key_ptrs: Vec<_> = keys.iter().map(|k| k.as_ptr()).collect();
+    let value_ptrs: Vec<_> = values.iter().map(|v| v.as_ptr()).collect();
+    let options = ucli_server::ServerOptions {
+        extra_property_keys: key_ptrs.as_ptr(),
+        extra_property_values: value_ptrs.as_ptr(),
+        extra_properties_len: 2,
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+
+    let info = discover(PROJECT_PATH, Duration::from_millis(5000)).expect("Cannot find service!");
+    assert_eq!(Some("Android"), info.get_property_val_str("platform"));
+    assert_eq!(Some("pro"), info.get_property_val_str("license"));
+    assert_eq!(
+        Some("My Unity Project"),
+        info.get_property_val_str(PROJECT_NAME_PROP_KEY)
+    );
+
+    stop_server();
+}
+
+#[test]
+fn start_time_is_advertised() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/started-at";
+    let now_ms = || {
+        let since = std::time::UNIX_EPOCH.elapsed().unwrap();
+        since.as_millis() as u64
+    };
+    let before = now_ms();
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+
+    let info = discover(PROJECT_PATH, Duration::from_millis(5000)).expect("Cannot find service!");
+    let started_at: u64 = info
+        .get_property_val_str(STARTED_AT_PROP_KEY)
+        .and_then(|ms| ms.parse().ok())
+        .expect("no start time");
+    assert!((before..=now_ms()).contains(&started_at), "{started_at}");
+
+    stop_server();
+}
+
+#[test]
+fn built_in_properties_cant_be_overridden() {
+    let _lock = SERVER_LOCK.lock();
+
+    let key = CString::new(PROJECT_NAME_PROP_KEY).unwrap();
+    let value = CString::new("Someone Else's Project").unwrap();
+    let options = ucli_server::ServerOptions {
+        extra_property_keys: &key.as_ptr(),
+        extra_property_values: &value.as_ptr(),
+        extra_properties_len: 1,
+        ..Default::default()
+    };
+    run_server("foo/bar/overridden-property", noop_cmd_cb, Some(&options));
+
+    assert!(!ucli_server::is_running());
+    let error = ucli_server::last_error();
+    assert!(!error.is_null());
+    assert!(ptr_to_string(error).contains(PROJECT_NAME_PROP_KEY));
+}
+
+#[test]
+fn session_name_matches_advertised_name() {
+    let _lock = SERVER_LOCK.lock();
+
+    assert_eq!("", ptr_to_string(ucli_server::session_name()));
+
+    const PROJECT_PATH: &str = "foo/bar/session-name-accessor";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+
+    let info = discover(PROJECT_PATH, Duration::from_millis(5000)).expect("Cannot find service!");
+    let session_name = ptr_to_string(ucli_server::session_name());
+    assert_eq!(
+        format!("{session_name}.{}", common::MDNS_SERVICE_NAME),
+        info.get_fullname()
+    );
+
+    stop_server();
+    assert_eq!("", ptr_to_string(ucli_server::session_name()));
+}
+
+#[test]
+fn unusable_session_name_is_reported() {
+    let _lock = SERVER_LOCK.lock();
+
+    let session_name = CString::new("!!!").unwrap();
+    let options = ucli_server::ServerOptions {
+        session_name: session_name.as_ptr(),
+        ..Default::default()
+    };
+    run_server("foo/bar/bad-session-name", noop_cmd_cb, Some(&options));
+
+    assert!(!ucli_server::is_running());
+    let error = ucli_server::last_error();
+    assert!(!error.is_null());
+    assert!(ptr_to_string(error).contains("session name"));
+}
+
+#[test]
+fn project_path_is_canonicalized() {
+    let _lock = SERVER_LOCK.lock();
+
+    // Relative to the working directory, which is the package root for integration tests.
+    let dir_name = format!("ucli-test-project-{}", std::process::id());
+    std::fs::create_dir_all(&dir_name).unwrap();
+    let absolute = std::fs::canonicalize(&dir_name).unwrap();
+    let absolute = absolute.to_str().unwrap();
+
+    run_server(&format!("./{dir_name}/."), noop_cmd_cb, None);
+    let found = discover(absolute, Duration::from_millis(5000));
+    stop_server();
+
+    let _ = std::fs::remove_dir(&dir_name);
+    assert!(
+        found.is_ok(),
+        "`./{dir_name}/.` wasn't advertised as {absolute}"
+    );
+}
+
+#[test]
+fn null_strings_from_unity() {
+    let _lock = SERVER_LOCK.lock();
+
+    // Not through `run_server`, which only hands over real strings.
+    ucli_server::testing::run(
+        Fakes::default(),
+        std::ptr::null(),
+        std::ptr::null(),
+        std::ptr::null(),
+        noop_cmd_cb,
+        noop_list_cmds_cb,
+        noop_cancel_cmd_cb,
+        std::ptr::null(),
+    );
+
+    assert!(ucli_server::is_running());
+    unsafe {
+        ucli_server::on_unity_console_log(0, 0, 0, std::ptr::null(), std::ptr::null());
+    }
+    ucli_server::on_command_finish(0, 0, 0, false, std::ptr::null());
+
+    stop_server();
+
+    assert!(!ucli_server::is_running());
+}
+
+#[test]
+fn command_round_trip() {
+    let _lock = SERVER_LOCK.lock();
+
+    // Like a command Unity runs synchronously, logging and finishing from within the callback.
+    extern "C" fn cmd_cb(
+        u1: u64,
+        u2: u64,
+        request_id: u64,
+        cmd: *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const c_char,
+        _: *const *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const u8,
+        _: i32,
+    ) {
+        let log = CString::new(format!("running {}", ptr_to_string(cmd))).unwrap();
+        let result = CString::new("done").unwrap();
+        unsafe {
+            ucli_server::on_unity_console_log(u1, u2, 3, log.as_ptr(), std::ptr::null());
+        }
+        ucli_server::on_command_finish(u1, u2, request_id, true, result.as_ptr());
+    }
+
+    const PROJECT_PATH: &str = "foo/bar/round-trip";
+    run_server(PROJECT_PATH, cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    let msg = ClientMessage::CommandRequest {
+        request_id: 1,
+        cmd: "foo".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+
+    match read_msg(&mut conn) {
+        Ok(ServerMessage::UnityConsoleOutput { log, .. }) => assert_eq!("running foo", log),
+        other => panic!("unexpected message: {other:?}"),
+    }
+    match read_msg(&mut conn) {
+        Ok(ServerMessage::CommandFinished {
+            request_id,
+            is_success,
+            msg,
+        }) => {
+            assert_eq!(1, request_id);
+            assert!(is_success);
+            assert_eq!(Some("done"), msg.as_deref());
+        }
+        other => panic!("unexpected message: {other:?}"),
+    }
+
+    stop_server();
+    assert!(!ucli_server::is_running());
+}
+
+#[test]
+fn command_results_reach_the_client() {
+    let _lock = SERVER_LOCK.lock();
+
+    extern "C" fn cmd_cb(
+        u1: u64,
+        u2: u64,
+        request_id: u64,
+        _: *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const c_char,
+        _: *const *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const u8,
+        _: i32,
+    ) {
+        let payload = br#"{"scenes":2}"#;
+        let content_type = CString::new("application/json").unwrap();
+        unsafe {
+            ucli_server::on_command_result(
+                u1,
+                u2,
+                request_id,
+                payload.as_ptr(),
+                payload.len() as i32,
+                content_type.as_ptr(),
+            );
+        }
+        ucli_server::on_command_finish(u1, u2, request_id, true, std::ptr::null());
+    }
+
+    const PROJECT_PATH: &str = "foo/bar/result";
+    run_server(PROJECT_PATH, cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    let msg = ClientMessage::CommandRequest {
+        request_id: 7,
+        cmd: "list-scenes".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+
+    match read_msg(&mut conn) {
+        Ok(ServerMessage::CommandResult {
+            request_id,
+            payload,
+            content_type,
+        }) => {
+            assert_eq!(7, request_id);
+            assert_eq!(br#"{"scenes":2}"#, payload.as_slice());
+            assert_eq!("application/json", content_type);
+        }
+        other => panic!("unexpected message: {other:?}"),
+    }
+    assert!(matches!(
+        read_msg(&mut conn),
+        Ok(ServerMessage::CommandFinished {
+            is_success: true,
+            ..
+        })
+    ));
+
+    stop_server();
+}
+
+#[test]
+fn result_chunks_arrive_in_order() {
+    let _lock = SERVER_LOCK.lock();
+
+    extern "C" fn cmd_cb(
+        u1: u64,
+        u2: u64,
+        request_id: u64,
+        _: *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const c_char,
+        _: *const *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const u8,
+        _: i32,
+    ) {
+        let chunks: [&[u8]; 3] = [b"Build ", b"report", b""];
+        for (seq, chunk) in chunks.iter().enumerate() {
+            unsafe {
+                ucli_server::on_command_result_chunk(
+                    u1,
+                    u2,
+                    request_id,
+                    seq as u32,
+                    chunk.as_ptr(),
+                    chunk.len() as i32,
+                    seq == chunks.len() - 1,
+                );
+            }
+        }
+        ucli_server::on_command_finish(u1, u2, request_id, true, std::ptr::null());
+    }
+
+    const PROJECT_PATH: &str = "foo/bar/chunks";
+    run_server(PROJECT_PATH, cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    let msg = ClientMessage::CommandRequest {
+        request_id: 3,
+        cmd: "build-report".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+
+    let mut report = Vec::new();
+    for expected_seq in 0..3 {
+        match read_msg(&mut conn) {
+            Ok(ServerMessage::ResultChunk {
+                request_id,
+                seq,
+                data,
+                is_last,
+            }) => {
+                assert_eq!(3, request_id);
+                assert_eq!(expected_seq, seq);
+                assert_eq!(expected_seq == 2, is_last);
+                report.extend(data);
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+    assert_eq!(b"Build report", report.as_slice());
+    assert!(matches!(
+        read_msg(&mut conn),
+        Ok(ServerMessage::CommandFinished {
+            is_success: true,
+            ..
+        })
+    ));
+
+    stop_server();
+}
+
+#[test]
+fn late_clients_receive_the_backlog() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/backlog";
+    let options = ucli_server::ServerOptions {
+        log_backlog_capacity: 2,
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+    // Waits for the server to be up, so that the logs below are routed before the client that
+    // should only get them from the backlog connects.
+    drop(connect(PROJECT_PATH));
+
+    for (uuid_lo, log) in [(0, "first"), (0, "second"), (42, "private"), (0, "third")] {
+        let log = CString::new(log).unwrap();
+        unsafe {
+            ucli_server::on_unity_console_log(0, uuid_lo, 3, log.as_ptr(), std::ptr::null());
+        }
+    }
+    std::thread::sleep(Duration::from_millis(100));
+
+    let mut conn = connect(PROJECT_PATH);
+    write_msg(&mut conn, &ClientMessage::RequestBacklog { count: 10 });
+
+    match read_msg(&mut conn) {
+        Ok(ServerMessage::Notice { text, .. }) => assert!(text.contains("last 2 console messages")),
+        other => panic!("expected a notice, got {other:?}"),
+    }
+    for expected in ["second", "third"] {
+        match read_msg(&mut conn) {
+            Ok(ServerMessage::UnityConsoleOutput { log, .. }) => assert_eq!(expected, log),
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+    assert!(read_msg(&mut conn).is_err());
+
+    stop_server();
+}
+
+#[test]
+fn console_bursts_are_throttled() {
+    let _lock = SERVER_LOCK.lock();
+
+    fn log(text: &str) {
+        let text = CString::new(text).unwrap();
+        unsafe {
+            ucli_server::on_unity_console_log(0, 0, 3, text.as_ptr(), std::ptr::null());
+        }
+    }
+
+    const PROJECT_PATH: &str = "foo/bar/rate-limit";
+    let options = ucli_server::ServerOptions {
+        console_log_rate_limit: 5,
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+    let mut conn = connect(PROJECT_PATH);
+    std::thread::sleep(Duration::from_millis(100));
+
+    for _ in 0..50 {
+        log("spam");
+    }
+    // Past the notice interval, so the next message is preceded by the count of dropped ones.
+    std::thread::sleep(Duration::from_millis(1100));
+    log("calm");
+
+    let mut spam = 0;
+    let mut dropped = None;
+    loop {
+        match read_msg(&mut conn) {
+            Ok(ServerMessage::UnityConsoleOutput { log, .. }) if log == "spam" => spam += 1,
+            Ok(ServerMessage::UnityConsoleOutput { log, .. }) if log == "calm" => break,
+            Ok(ServerMessage::Notice { text, .. }) => {
+                dropped = text.split(' ').next().and_then(|n| n.parse::<u32>().ok());
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    assert!((5..10).contains(&spam), "{spam} messages got through");
+    assert_eq!(Some(50 - spam), dropped);
+
+    stop_server();
+}
+
+#[test]
+fn cwd_is_passed_to_unity() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/cwd";
+    run_server(PROJECT_PATH, recording_cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    for cwd in [Some("/home/me/game/Assets".to_string()), None] {
+        let msg = ClientMessage::CommandRequest {
+            request_id: 1,
+            cmd: "import".to_string(),
+            args: vec!["Textures/hero.png".to_string()],
+            named_args: vec![],
+            cwd,
+            env: vec![],
+            stdin: None,
+        };
+        write_msg(&mut conn, &msg);
+    }
+    std::thread::sleep(Duration::from_millis(100));
+
+    let cwds: Vec<_> = received_commands().into_iter().map(|c| c.cwd).collect();
+    assert_eq!(vec![Some("/home/me/game/Assets".to_string()), None], cwds);
+
+    stop_server();
+}
+
+#[test]
+fn env_is_passed_to_unity() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/env";
+    run_server(PROJECT_PATH, recording_cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    let env = vec![
+        ("BUILD_NUMBER".to_string(), "42".to_string()),
+        ("BRANCH".to_string(), "release/1.2".to_string()),
+    ];
+    for env in [env.clone(), vec![]] {
+        let msg = ClientMessage::CommandRequest {
+            request_id: 1,
+            cmd: "build".to_string(),
+            args: vec![],
+            named_args: vec![],
+            cwd: None,
+            env,
+            stdin: None,
+        };
+        write_msg(&mut conn, &msg);
+    }
+    std::thread::sleep(Duration::from_millis(100));
+
+    let envs: Vec<_> = received_commands().into_iter().map(|c| c.env).collect();
+    assert_eq!(vec![env, vec![]], envs);
+
+    stop_server();
+}
+
+#[test]
+fn stdin_is_passed_to_unity() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/stdin";
+    run_server(PROJECT_PATH, recording_cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    let request = |request_id, stdin| ClientMessage::CommandRequest {
+        request_id,
+        cmd: "eval".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin,
+    };
+    write_msg(
+        &mut conn,
+        &ClientMessage::StdinChunk {
+            request_id: 1,
+            data: b"Debug.Log(".to_vec(),
+        },
+    );
+    write_msg(&mut conn, &request(1, Some(b"42);".to_vec())));
+    write_msg(&mut conn, &request(2, Some(vec![])));
+    write_msg(&mut conn, &request(3, None));
+    std::thread::sleep(Duration::from_millis(100));
+
+    let stdins: Vec<_> = received_commands().into_iter().map(|c| c.stdin).collect();
+    assert_eq!(
+        vec![Some(b"Debug.Log(42);".to_vec()), Some(vec![]), None],
+        stdins
+    );
+
+    stop_server();
+}
+
+#[test]
+fn commands_during_a_reload_are_failed() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/reloading";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    ucli_server::on_csharp_assembly_unload();
+    assert!(matches!(
+        read_msg(&mut conn),
+        Ok(ServerMessage::AssemblyReloading)
+    ));
+    let msg = ClientMessage::CommandRequest {
+        request_id: 1,
+        cmd: "build".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+
+    match read_msg(&mut conn) {
+        Ok(ServerMessage::CommandFinished {
+            request_id: 1,
+            is_success: false,
+            msg: Some(msg),
+        }) => assert_eq!("editor reloading, command dropped", msg),
+        other => panic!("unexpected message: {other:?}"),
+    }
+
+    stop_server();
+}
+
+#[test]
+fn commands_are_audited() {
+    let _lock = SERVER_LOCK.lock();
+
+    extern "C" fn cmd_cb(
+        u1: u64,
+        u2: u64,
+        request_id: u64,
+        _: *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const c_char,
+        _: *const *const c_char,
+        _: *const *const c_char,
+        _: i32,
+        _: *const u8,
+        _: i32,
+    ) {
+        ucli_server::on_command_finish(u1, u2, request_id, true, std::ptr::null());
+    }
+
+    let audit_path = std::env::temp_dir().join(format!("ucli-audit-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&audit_path);
+    let audit_path_cstr = CString::new(audit_path.to_str().unwrap()).unwrap();
+    let options = ucli_server::ServerOptions {
+        audit_log_path: audit_path_cstr.as_ptr(),
+        ..Default::default()
+    };
+    const PROJECT_PATH: &str = "foo/bar/audit";
+    run_server(PROJECT_PATH, cmd_cb, Some(&options));
+    let mut conn = connect(PROJECT_PATH);
+
+    let msg = ClientMessage::CommandRequest {
+        request_id: 7,
+        cmd: "build".to_string(),
+        args: vec!["ios".to_string()],
+        named_args: vec![("dev".to_string(), "true".to_string())],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+    match read_msg(&mut conn) {
+        Ok(ServerMessage::CommandFinished { is_success, .. }) => assert!(is_success),
+        other => panic!("unexpected message: {other:?}"),
+    }
+    stop_server();
+
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    let _ = std::fs::remove_file(&audit_path);
+    let lines: Vec<_> = audit.lines().collect();
+    assert_eq!(2, lines.len(), "{audit}");
+    assert!(lines[0].contains("event=requested"), "{audit}");
+    assert!(
+        lines[0].contains("request_id=7 cmd=\"build\" args=2"),
+        "{audit}"
+    );
+    assert!(lines[1].contains("event=finished"), "{audit}");
+    assert!(
+        lines[1].ends_with("request_id=7 is_success=true"),
+        "{audit}"
+    );
+}
+
+#[test]
+fn interior_nul_in_args_is_rejected() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/interior-nul";
+    run_server(PROJECT_PATH, recording_cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    let msg = ClientMessage::CommandRequest {
+        request_id: 0,
+        cmd: "foo".to_string(),
+        args: vec!["bar\0baz".to_string()],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+
+    match read_msg(&mut conn) {
+        Ok(ServerMessage::CommandFinished {
+            is_success, msg, ..
+        }) => {
+            assert!(!is_success);
+            assert!(msg.is_some());
+        }
+        other => panic!("unexpected message: {other:?}"),
+    }
+    assert!(received_commands().is_empty());
+
+    stop_server();
+}
+
+#[test]
+fn clients_are_welcomed() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/welcome";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+
+    // Before the client asks for anything, so a silent server can be told apart from an idle one.
+    let mut conn = connect_unwelcomed(PROJECT_PATH);
+    expect_welcome(&mut conn);
+
+    stop_server();
+}
+
+#[test]
+fn only_remote_clients_get_compressed_frames() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/compression";
+    let denied = CString::new("deploy").unwrap();
+    let options = ucli_server::ServerOptions {
+        denied_commands: denied.as_ptr(),
+        ..Default::default()
+    };
+    for (remote, expected) in [(false, Compression::None), (true, Compression::Zstd)] {
+        let fakes = Fakes {
+            remote_peers: remote,
+            ..Default::default()
+        };
+        run_faked_server(PROJECT_PATH, noop_cmd_cb, Some(&options), fakes);
+        let mut conn = connect_unwelcomed(PROJECT_PATH);
+        write_msg(
+            &mut conn,
+            &ClientMessage::Hello {
+                compression: vec![Compression::None, Compression::Zstd],
+            },
+        );
+        match read_msg(&mut conn) {
+            Ok(ServerMessage::Welcome { compression, .. }) => assert_eq!(expected, compression),
+            other => panic!("expected a welcome, got {other:?}"),
+        }
+
+        // A denied command is refused right away, without bothering Unity.
+        let mut codec = ClientCodec::default();
+        codec.set_compression(expected);
+        let request = ClientMessage::CommandRequest {
+            request_id: 1,
+            cmd: "deploy".to_owned(),
+            args: vec![],
+            named_args: vec![],
+            cwd: None,
+            env: vec![],
+            stdin: None,
+        };
+        codec.write(&Envelope::new(1, request), &mut conn).unwrap();
+        match codec.read(&mut conn).map(Envelope::into_payload) {
+            Ok(ServerMessage::CommandFinished {
+                is_success: false, ..
+            }) => {}
+            other => panic!("unexpected message: {other:?}"),
+        }
+
+        stop_server();
+    }
+}
+
+#[test]
+fn failing_accepts_are_retried() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/accept-failures";
+    let fakes = Fakes {
+        failing_accepts: 3,
+        ..Default::default()
+    };
+    run_faked_server(PROJECT_PATH, noop_cmd_cb, None, fakes);
+
+    let _conn = connect(PROJECT_PATH);
+    assert!(ucli_server::is_running());
+    let _conn = connect(PROJECT_PATH);
+
+    stop_server();
+}
+
+#[test]
+fn connections_over_capacity_are_rejected() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/max-connections";
+    let options = ucli_server::ServerOptions {
+        max_connections: 1,
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+
+    let _conn_a = connect(PROJECT_PATH);
+    std::thread::sleep(Duration::from_millis(100));
+    let mut conn_b = connect_unwelcomed(PROJECT_PATH);
+
+    match read_msg(&mut conn_b) {
+        Ok(ServerMessage::Rejected { .. }) => {}
+        other => panic!("unexpected message: {other:?}"),
+    }
+
+    stop_server();
+}
+
+#[test]
+fn idle_connections_are_closed() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/idle-timeout";
+    let options = ucli_server::ServerOptions {
+        idle_timeout_ms: 200,
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+
+    let mut conn = connect(PROJECT_PATH);
+    match read_msg(&mut conn) {
+        Err(common::CodecError::ConnectionClosed) => {}
+        other => panic!("unexpected message: {other:?}"),
+    }
+    let stats = ptr_to_string(ucli_server::stats_json());
+    assert!(stats.contains("\"active_connections\":0,"), "{stats}");
+
+    stop_server();
+}
+
+/// Requests `cmd`, and returns the server's answer if it answered by itself.
+fn request_under_policy(conn: &mut TcpStream, cmd: &str) -> Option<ServerMessage> {
+    let msg = ClientMessage::CommandRequest {
+        request_id: 1,
+        cmd: cmd.to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(conn, &msg);
+    conn.set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    read_msg(conn).ok()
+}
+
+#[test]
+fn only_allowed_commands_are_forwarded() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/allowed-commands";
+    let allowed = CString::new("build*, test").unwrap();
+    let options = ucli_server::ServerOptions {
+        allowed_commands: allowed.as_ptr(),
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, recording_cmd_cb, Some(&options));
+    let mut conn = connect(PROJECT_PATH);
+
+    assert!(request_under_policy(&mut conn, "build-ios").is_none());
+    assert!(request_under_policy(&mut conn, "test").is_none());
+    match request_under_policy(&mut conn, "deploy") {
+        Some(ServerMessage::CommandFinished {
+            is_success: false,
+            msg: Some(msg),
+            ..
+        }) => assert!(msg.contains("deploy"), "{msg}"),
+        other => panic!("unexpected message: {other:?}"),
+    }
+    assert_eq!(vec!["build-ios", "test"], received_command_names());
+
+    stop_server();
+}
+
+#[test]
+fn denied_commands_are_not_forwarded() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/denied-commands";
+    let allowed = CString::new("*").unwrap();
+    let denied = CString::new("deploy-*,?ipe").unwrap();
+    let options = ucli_server::ServerOptions {
+        allowed_commands: allowed.as_ptr(),
+        denied_commands: denied.as_ptr(),
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, recording_cmd_cb, Some(&options));
+    let mut conn = connect(PROJECT_PATH);
+
+    for cmd in ["deploy-prod", "wipe"] {
+        match request_under_policy(&mut conn, cmd) {
+            Some(ServerMessage::CommandFinished {
+                is_success: false, ..
+            }) => {}
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+    assert!(request_under_policy(&mut conn, "deploy").is_none());
+    assert_eq!(vec!["deploy"], received_command_names());
+
+    stop_server();
+}
+
+#[test]
+fn builtins_are_forwarded_under_reserved_names() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/builtins";
+    run_server(PROJECT_PATH, recording_cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    let msg = ClientMessage::BuiltinRequest {
+        request_id: 1,
+        builtin: Builtin::StopPlay,
+        cwd: None,
+    };
+    write_msg(&mut conn, &msg);
+    match request_under_policy(&mut conn, "ucli:stop-play") {
+        Some(ServerMessage::CommandFinished {
+            is_success: false, ..
+        }) => {}
+        other => panic!("unexpected message: {other:?}"),
+    }
+    assert_eq!(vec!["ucli:stop-play"], received_command_names());
+
+    stop_server();
+}
+
+#[test]
+fn oversized_argument_lists_are_not_forwarded() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/oversized-args";
+    let options = ucli_server::ServerOptions {
+        max_command_args: 3,
+        max_command_args_len: 16,
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, recording_cmd_cb, Some(&options));
+    let mut conn = connect(PROJECT_PATH);
+    let mut request = |cmd: &str, args: &[&str], env: &[(&str, &str)]| {
+        let msg = ClientMessage::CommandRequest {
+            request_id: 1,
+            cmd: cmd.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            named_args: vec![],
+            cwd: None,
+            env: env
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            stdin: None,
+        };
+        write_msg(&mut conn, &msg);
+        conn.set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        read_msg(&mut conn).ok()
+    };
+
+    assert!(request("fits", &["a", "b"], &[("CI", "1")]).is_none());
+    for (cmd, args, env, reason) in [
+        (
+            "too-many",
+            &["a", "b", "c"][..],
+            &[("CI", "1")][..],
+            "4 arguments",
+        ),
+        (
+            "too-long",
+            &["0123456789abcdef", "!"][..],
+            &[][..],
+            "17 bytes",
+        ),
+    ] {
+        match request(cmd, args, env) {
+            Some(ServerMessage::CommandFinished {
+                is_success: false,
+                msg: Some(msg),
+                ..
+            }) => assert!(msg.contains(reason), "{msg}"),
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+    assert_eq!(vec!["fits"], received_command_names());
+
+    stop_server();
+}
+
+#[test]
+fn command_rejected_by_unity() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/command-rejected";
+    run_server(PROJECT_PATH, recording_cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    let msg = ClientMessage::CommandRequest {
+        request_id: 7,
+        cmd: "foo".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+    std::thread::sleep(Duration::from_millis(100));
+
+    let ReceivedCommand {
+        uuid: (uuid_hi, uuid_lo),
+        request_id,
+        ..
+    } = received_commands().pop().expect("No command received!");
+    assert_eq!(7, request_id);
+    let reason = CString::new("unknown command: foo").unwrap();
+    ucli_server::on_command_rejected(uuid_hi, uuid_lo, request_id, reason.as_ptr());
+
+    match read_msg(&mut conn) {
+        Ok(ServerMessage::CommandRejected { request_id, reason }) => {
+            assert_eq!((7, "unknown command: foo"), (request_id, reason.as_str()));
+        }
+        other => panic!("unexpected message: {other:?}"),
+    }
+
+    stop_server();
+}
+
+#[test]
+fn list_commands_round_trip() {
+    let _lock = SERVER_LOCK.lock();
+
+    static RECEIVED: Mutex<Option<(u64, u64, u64)>> = Mutex::new(None);
+
+    extern "C" fn list_cmds_cb(uuid_hi: u64, uuid_lo: u64, request_id: u64) {
+        *RECEIVED.lock() = Some((uuid_hi, uuid_lo, request_id));
+    }
+
+    const PROJECT_PATH: &str = "foo/bar/list-commands";
+    run_server_with(
+        PROJECT_PATH,
+        noop_cmd_cb,
+        list_cmds_cb,
+        noop_cancel_cmd_cb,
+        None,
+        Fakes::default(),
+    );
+    let mut conn = connect(PROJECT_PATH);
+
+    write_msg(&mut conn, &ClientMessage::ListCommands { request_id: 3 });
+    std::thread::sleep(Duration::from_millis(100));
+
+    let (uuid_hi, uuid_lo, request_id) = RECEIVED.lock().take().expect("No request received!");
+    let names = [CString::new("foo").unwrap(), CString::new("bar").unwrap()];
+    let name_ptrs: Vec<_> = names.iter().map(|s| s.as_ptr()).collect();
+    unsafe {
+        ucli_server::on_command_list(
+            uuid_hi,
+            uuid_lo,
+            request_id,
+            name_ptrs.as_ptr(),
+            name_ptrs.len() as i32,
+        );
+    }
+
+    match read_msg(&mut conn) {
+        Ok(ServerMessage::CommandList {
+            request_id,
+            commands,
+        }) => {
+            assert_eq!(3, request_id);
+            assert_eq!(vec!["foo", "bar"], commands);
+        }
+        other => panic!("unexpected message: {other:?}"),
+    }
+
+    stop_server();
+}
+
+#[test]
+fn unresponsive_editor_is_reported() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/unresponsive";
+    let options = ucli_server::ServerOptions {
+        unresponsive_timeout_ms: 100,
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+    let mut conn = connect(PROJECT_PATH);
+
+    let msg = ClientMessage::CommandRequest {
+        request_id: 1,
+        cmd: "freeze".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+
+    match read_msg(&mut conn) {
+        Ok(ServerMessage::EditorUnresponsive { .. }) => {}
+        other => panic!("unexpected message: {other:?}"),
+    }
+
+    stop_server();
+}
+
+#[test]
+fn cancel_command_reaches_unity() {
+    let _lock = SERVER_LOCK.lock();
+
+    static CANCELLED: Mutex<Option<u64>> = Mutex::new(None);
+
+    extern "C" fn cancel_cmd_cb(_: u64, _: u64, request_id: u64) {
+        *CANCELLED.lock() = Some(request_id);
+    }
+
+    const PROJECT_PATH: &str = "foo/bar/cancel-command";
+    run_server_with(
+        PROJECT_PATH,
+        noop_cmd_cb,
+        noop_list_cmds_cb,
+        cancel_cmd_cb,
+        None,
+        Fakes::default(),
+    );
+    let mut conn = connect(PROJECT_PATH);
+
+    let msg = ClientMessage::CommandRequest {
+        request_id: 5,
+        cmd: "foo".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+    write_msg(&mut conn, &ClientMessage::CancelCommand { request_id: 5 });
+    std::thread::sleep(Duration::from_millis(100));
+
+    assert_eq!(Some(5), CANCELLED.lock().take());
+
+    stop_server();
+}
+
+#[test]
+fn named_args_reach_unity() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/named-args";
+    run_server(PROJECT_PATH, recording_cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    let named_args = vec![
+        ("target".to_string(), "StandaloneLinux64".to_string()),
+        ("development".to_string(), "true".to_string()),
+    ];
+    let msg = ClientMessage::CommandRequest {
+        request_id: 1,
+        cmd: "build".to_string(),
+        args: vec![],
+        named_args: named_args.clone(),
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+    std::thread::sleep(Duration::from_millis(100));
+
+    let received = received_commands();
+    assert_eq!(named_args, received[0].named_args);
+
+    stop_server();
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn metrics_are_served() {
+    use std::io::{Read, Write};
+
+    let _lock = SERVER_LOCK.lock();
+
+    fn fetch_metrics(port: u16) -> String {
+        let mut conn = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        conn.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    let metrics_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    const PROJECT_PATH: &str = "foo/bar/metrics";
+    let options = ucli_server::ServerOptions {
+        metrics_port,
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+    let mut conn = connect(PROJECT_PATH);
+
+    let msg = ClientMessage::CommandRequest {
+        request_id: 1,
+        cmd: "foo".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+    std::thread::sleep(Duration::from_millis(100));
+    let log = CString::new("oops").unwrap();
+    unsafe {
+        ucli_server::on_unity_console_log(0, 0, 2, log.as_ptr(), std::ptr::null());
+    }
+
+    let response = fetch_metrics(metrics_port);
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("\nucli_commands_total 1\n"));
+    assert!(response.contains("\nucli_connections 1\n"));
+    assert!(response.contains("\nucli_console_logs_total{type=\"warning\"} 1\n"));
+
+    stop_server();
+}
+
+#[test]
+fn stats_count_commands_and_logs() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/stats";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    let msg = ClientMessage::CommandRequest {
+        request_id: 1,
+        cmd: "foo".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+    std::thread::sleep(Duration::from_millis(100));
+    let log = CString::new("oops").unwrap();
+    unsafe {
+        ucli_server::on_unity_console_log(0, 0, 0, log.as_ptr(), std::ptr::null());
+    }
+    ucli_server::on_command_finish(0, 0, 0, true, std::ptr::null());
+
+    let stats = ptr_to_string(ucli_server::stats_json());
+    assert!(stats.contains("\"commands_received\":1,"));
+    assert!(stats.contains("\"commands_succeeded\":1,"));
+    assert!(stats.contains("\"commands_failed\":0,"));
+    assert!(stats.contains("\"error\":1,"));
+    assert!(stats.contains("\"warning\":0,"));
+
+    stop_server();
+    assert!(ucli_server::stats_json().is_null());
+}
+
+#[test]
+fn invalid_utf8_from_unity_is_repaired_and_counted() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/invalid-utf8";
+    let options = ucli_server::ServerOptions {
+        strict_utf8: true,
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+    let mut conn = connect(PROJECT_PATH);
+    std::thread::sleep(Duration::from_millis(100));
+
+    let log = CString::new(b"caf\xe9 au lait".to_vec()).unwrap();
+    let stack_trace = CString::new("fine").unwrap();
+    unsafe {
+        ucli_server::on_unity_console_log(0, 0, 3, log.as_ptr(), stack_trace.as_ptr());
+    }
+    match read_msg(&mut conn) {
+        Ok(ServerMessage::UnityConsoleOutput {
+            log, stack_trace, ..
+        }) => {
+            assert_eq!("caf\u{FFFD} au lait", log);
+            assert_eq!("fine", stack_trace);
+        }
+        msg => panic!("unexpected message: {msg:?}"),
+    }
+
+    let stats = ptr_to_string(ucli_server::stats_json());
+    assert!(stats.contains("\"invalid_utf8_strings\":1}"), "{stats}");
+
+    stop_server();
+}
+
+#[test]
+fn stop_closes_connections_right_away() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/stop-closes";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+    let mut conn = connect(PROJECT_PATH);
+
+    let stopped_at = Instant::now();
+    ucli_server::stop();
+    let read = std::io::Read::read(&mut conn, &mut [0; 1]);
+    assert!(
+        matches!(read, Ok(0))
+            || matches!(&read, Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset),
+        "connection wasn't closed: {read:?}"
+    );
+    assert!(stopped_at.elapsed() < Duration::from_millis(250));
+
+    stop_server();
+}
+
+#[test]
+fn status_reflects_the_running_server() {
+    let _lock = SERVER_LOCK.lock();
+
+    assert!(ucli_server::server_status_json().is_null());
+
+    const PROJECT_PATH: &str = "foo/bar/status";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+    let port =
+        discover_port(PROJECT_PATH, Duration::from_millis(5000)).expect("Cannot find service!");
+
+    let status = ptr_to_string(ucli_server::server_status_json());
+    let session_name = ptr_to_string(ucli_server::session_name());
+    assert!(status.contains(&format!("\"port\":{port},")));
+    assert!(status.contains(&format!("\"session_name\":\"{session_name}\",")));
+    assert!(status.contains("\"connections\":0,"));
+    assert!(status.contains("\"uptime_secs\":"));
+
+    stop_server();
+    assert!(ucli_server::server_status_json().is_null());
+}
+
+#[test]
+fn connection_count_drops_when_a_client_leaves() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/connection-count";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+
+    // The server only notices a client leaving once its socket is closed, so the counts are
+    // polled for instead of read right away.
+    let wait_for = |stats_count: &str, status_count: &str| {
+        let deadline = Instant::now() + Duration::from_millis(1000);
+        loop {
+            let stats = ptr_to_string(ucli_server::stats_json());
+            let status = ptr_to_string(ucli_server::server_status_json());
+            if stats.contains(stats_count) && status.contains(status_count) {
+                break;
+            }
+            assert!(Instant::now() < deadline, "{stats} {status}");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    let conn = connect(PROJECT_PATH);
+    wait_for("\"active_connections\":1,", "\"connections\":1,");
+    drop(conn);
+    wait_for("\"active_connections\":0,", "\"connections\":0,");
+
+    stop_server();
+}
+
+#[test]
+fn connection_callbacks_fire_in_order() {
+    let _lock = SERVER_LOCK.lock();
+
+    static EVENTS: Mutex<Vec<(&str, u64, u64)>> = Mutex::new(Vec::new());
+
+    extern "C" fn connected_cb(uuid_hi: u64, uuid_lo: u64) {
+        EVENTS.lock().push(("connected", uuid_hi, uuid_lo));
+    }
+
+    extern "C" fn disconnected_cb(uuid_hi: u64, uuid_lo: u64) {
+        EVENTS.lock().push(("disconnected", uuid_hi, uuid_lo));
+    }
+
+    EVENTS.lock().clear();
+    const PROJECT_PATH: &str = "foo/bar/connection-callbacks";
+    let options = ucli_server::ServerOptions {
+        client_connected_callback: Some(connected_cb),
+        client_disconnected_callback: Some(disconnected_cb),
+        ..Default::default()
+    };
+    run_server(PROJECT_PATH, noop_cmd_cb, Some(&options));
+
+    let conn = connect(PROJECT_PATH);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(1, EVENTS.lock().len());
+    drop(conn);
+    std::thread::sleep(Duration::from_millis(100));
+
+    let events = EVENTS.lock().clone();
+    assert_eq!(2, events.len(), "{events:?}");
+    assert_eq!(("connected", "disconnected"), (events[0].0, events[1].0));
+    assert_eq!(
+        (events[0].1, events[0].2),
+        (events[1].1, events[1].2),
+        "both events must be about the same client"
+    );
+
+    stop_server();
+}
+
+#[test]
+fn closed_client_does_not_stop_broadcasts() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/closed-client";
+    run_server(PROJECT_PATH, noop_cmd_cb, None);
+    let closed = connect(PROJECT_PATH);
+    let mut live = connect(PROJECT_PATH);
+    std::thread::sleep(Duration::from_millis(100));
+    drop(closed);
+
+    for text in ["first", "second"] {
+        let log = CString::new(text).unwrap();
+        unsafe {
+            ucli_server::on_unity_console_log(0, 0, 3, log.as_ptr(), std::ptr::null());
+        }
+        match read_msg(&mut live) {
+            Ok(ServerMessage::UnityConsoleOutput { log, .. }) => assert_eq!(text, log),
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    stop_server();
+}
+
+#[test]
+fn stalled_client_does_not_block_others() {
+    let _lock = SERVER_LOCK.lock();
+
+    const PROJECT_PATH: &str = "foo/bar/stalled-client";
+    run_server(PROJECT_PATH, recording_cmd_cb, None);
+
+    let msg = ClientMessage::CommandRequest {
+        request_id: 1,
+        cmd: "foo".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    let mut stalled = connect(PROJECT_PATH);
+    write_msg(&mut stalled, &msg);
+    std::thread::sleep(Duration::from_millis(100));
+    let mut live = connect(PROJECT_PATH);
+    write_msg(&mut live, &msg);
+    std::thread::sleep(Duration::from_millis(100));
+    let (stalled_id, live_id) = match received_commands().as_slice() {
+        [stalled, live] => (stalled.uuid, live.uuid),
+        received => panic!("unexpected commands: {received:?}"),
+    };
+
+    // Far more than the socket buffers and the connection's queue can hold together.
+    let flood = CString::new("x".repeat(64 * 1024)).unwrap();
+    for _ in 0..2000 {
+        unsafe {
+            ucli_server::on_unity_console_log(
+                stalled_id.0,
+                stalled_id.1,
+                3,
+                flood.as_ptr(),
+                std::ptr::null(),
+            );
+        }
+    }
+    ucli_server::on_command_finish(stalled_id.0, stalled_id.1, 1, true, std::ptr::null());
+
+    let log = CString::new("still served").unwrap();
+    unsafe {
+        ucli_server::on_unity_console_log(live_id.0, live_id.1, 3, log.as_ptr(), std::ptr::null());
+    }
+    match read_msg(&mut live) {
+        Ok(ServerMessage::UnityConsoleOutput { log, .. }) => assert_eq!("still served", log),
+        msg => panic!("unexpected message: {msg:?}"),
+    }
+
+    let stats = ptr_to_string(ucli_server::stats_json());
+    assert!(!stats.contains("\"console_logs_dropped\":0,"), "{stats}");
+
+    // What was queued before the drops still arrives, ending with the command's result.
+    stalled
+        .set_read_timeout(Some(Duration::from_millis(5000)))
+        .unwrap();
+    // The dropped ones still took up their numbers, after the welcome's.
+    let mut next_seq = 1;
+    let mut missed = 0;
+    loop {
+        let envelope = ClientCodec::default().read(&mut stalled).unwrap();
+        missed += envelope.seq - next_seq;
+        next_seq = envelope.seq + 1;
+        match envelope.payload {
+            ServerMessage::UnityConsoleOutput { .. } => {}
+            ServerMessage::CommandFinished { is_success, .. } => {
+                assert!(is_success);
+                break;
+            }
+            msg => panic!("unexpected message: {msg:?}"),
+        }
+    }
+    assert!(missed > 0);
+
+    stop_server();
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket_round_trip() {
+    use std::os::unix::net::UnixStream;
+
+    let _lock = SERVER_LOCK.lock();
+
+    let socket_path = std::env::temp_dir().join(format!("ucli-test-{}.sock", std::process::id()));
+    let socket_path_cstr = CString::new(socket_path.to_str().unwrap()).unwrap();
+    let options = ucli_server::ServerOptions {
+        unix_socket_path: socket_path_cstr.as_ptr(),
+        ..Default::default()
+    };
+    run_server("foo/bar/unix-socket", recording_cmd_cb, Some(&options));
+
+    let deadline = Instant::now() + Duration::from_millis(1000);
+    while !socket_path.exists() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let mut conn = UnixStream::connect(&socket_path).unwrap();
+    expect_welcome(&mut conn);
+    let msg = ClientMessage::CommandRequest {
+        request_id: 1,
+        cmd: "foo".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+    std::thread::sleep(Duration::from_millis(100));
+
+    assert_eq!(vec!["foo".to_string()], received_command_names());
+
+    stop_server();
+    assert!(!socket_path.exists());
+}
+
+#[cfg(windows)]
+#[test]
+fn named_pipe_round_trip() {
+    let _lock = SERVER_LOCK.lock();
+
+    let pipe_name = format!("ucli-test-{}", std::process::id());
+    let pipe_name_cstr = CString::new(pipe_name.as_str()).unwrap();
+    let options = ucli_server::ServerOptions {
+        pipe_name: pipe_name_cstr.as_ptr(),
+        ..Default::default()
+    };
+    run_server("foo/bar/named-pipe", recording_cmd_cb, Some(&options));
+
+    let open_pipe = || {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(common::named_pipe_path(&pipe_name))
+    };
+    let deadline = Instant::now() + Duration::from_millis(1000);
+    let mut conn = loop {
+        match open_pipe() {
+            Ok(conn) => break conn,
+            Err(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+            Err(e) => panic!("failed to open the pipe: {e}"),
+        }
+    };
+    expect_welcome(&mut conn);
+    let msg = ClientMessage::CommandRequest {
+        request_id: 1,
+        cmd: "foo".to_string(),
+        args: vec![],
+        named_args: vec![],
+        cwd: None,
+        env: vec![],
+        stdin: None,
+    };
+    write_msg(&mut conn, &msg);
+    std::thread::sleep(Duration::from_millis(100));
+
+    assert_eq!(vec!["foo".to_string()], received_command_names());
+
+    stop_server();
+}
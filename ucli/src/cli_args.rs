@@ -1,35 +1,348 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use clap::{arg, ArgMatches, Command, ValueHint};
+use clap::{arg, ArgAction, ArgMatches, Command, ValueHint};
+use regex::Regex;
 
 #[derive(Debug, PartialEq)]
 pub enum CliArgs {
     ListSessions {
         discovery_args: DiscoveryArgs,
+        format: ListFormat,
     },
     Compile {
         discovery_args: DiscoveryArgs,
+        dry_run: bool,
+        all: bool,
+        env: Vec<(String, String)>,
+    },
+    /// `ucli play`, `ucli pause` and `ucli stop-play`.
+    PlayMode {
+        mode: PlayMode,
+        discovery_args: DiscoveryArgs,
+        dry_run: bool,
+    },
+    Build {
+        /// The build target, e.g. `android`, the editor's active one if not given.
+        target: Option<String>,
+        /// Where to build to, from `--build-path`.
+        build_path: Option<PathBuf>,
+        discovery_args: DiscoveryArgs,
+        dry_run: bool,
     },
     Run {
         command: String,
         args: Vec<String>,
+        named_args: Vec<(String, String)>,
+        /// Environment variables for the command, from `--env`.
+        env: Vec<(String, String)>,
         discovery_args: DiscoveryArgs,
+        dry_run: bool,
+        all: bool,
+        record: Option<PathBuf>,
+        output: Option<PathBuf>,
+        output_file: Option<PathBuf>,
+        /// Shell command to run once the command finished.
+        exec_on_finish: Option<String>,
+        /// Only print a one line summary instead of the console output.
+        summary: bool,
+        /// Send what is piped to ucli as the command's input.
+        stdin: bool,
+        /// How many sessions `--all` runs the command on at once.
+        parallel: usize,
+    },
+    /// Runs every command of a file, one after the other.
+    ExecFile {
+        path: PathBuf,
+        discovery_args: DiscoveryArgs,
+        /// Run the remaining commands after one failed, instead of stopping.
+        continue_on_error: bool,
+        output: Option<PathBuf>,
     },
     ListCommands {
         discovery_args: DiscoveryArgs,
+        record: Option<PathBuf>,
+        output: Option<PathBuf>,
+    },
+    Replay {
+        path: PathBuf,
+        output: Option<PathBuf>,
+    },
+    /// Prompts for commands to run on a session.
+    Repl {
+        discovery_args: DiscoveryArgs,
+        output: Option<PathBuf>,
+    },
+    Watch {
+        discovery_args: DiscoveryArgs,
+        grep: Option<String>,
+        count: Option<u64>,
+        /// How many console messages from before connecting to print first.
+        since: Option<u32>,
+        coalesce: bool,
+        format: WatchFormat,
+        /// How long to wait for a session that closed the connection while reloading its
+        /// scripts, zero to give up right away.
+        reconnect_timeout: Duration,
+        /// Tail a log file instead of connecting to a session, Unity's `Editor.log` if no path is
+        /// given. `None` if not asked for, in which case the log is only tailed when no session
+        /// is found.
+        log_file: Option<Option<PathBuf>>,
+        record: Option<PathBuf>,
+        output: Option<PathBuf>,
     },
 }
 
-#[derive(Debug, PartialEq)]
+impl CliArgs {
+    /// The file to also write the printed output to, if any.
+    pub fn output(&self) -> Option<&Path> {
+        match self {
+            Self::Run { output, .. }
+            | Self::ExecFile { output, .. }
+            | Self::ListCommands { output, .. }
+            | Self::Replay { output, .. }
+            | Self::Repl { output, .. }
+            | Self::Watch { output, .. } => output.as_deref(),
+            Self::ListSessions { .. }
+            | Self::Compile { .. }
+            | Self::PlayMode { .. }
+            | Self::Build { .. } => None,
+        }
+    }
+
+    /// Whether to collapse repeated console messages into a count.
+    pub fn coalesce(&self) -> bool {
+        matches!(self, Self::Watch { coalesce: true, .. })
+    }
+}
+
+/// What every subcommand takes, given before or after it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GlobalArgs {
+    /// Print without colors, like setting `NO_COLOR` does.
+    pub no_color: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct DiscoveryArgs {
     pub path: Option<PathBuf>,
     pub project: Option<String>,
     pub session: Option<String>,
+    pub project_regex: Option<NameRegex>,
+    pub session_regex: Option<NameRegex>,
+    pub session_id: Option<String>,
+    /// Prefix of the hostname a session runs on, matched case-insensitively.
+    pub host: Option<String>,
     pub discovery_timeout: Option<Duration>,
+    /// How many more times to browse, each for `discovery_timeout`, while nothing matches.
+    pub discovery_retries: u32,
+    /// How long a session may take to greet a new connection before it's given up on.
+    pub welcome_timeout: Option<Duration>,
+    /// CA certificates to verify TLS sessions with, instead of their advertised fingerprint.
+    pub tls_ca: Option<PathBuf>,
+    /// Which of a session's advertised addresses to connect to first.
+    pub interface: Option<AddressPreference>,
+    /// Unix domain socket of a session, which skips discovery altogether.
+    pub socket: Option<PathBuf>,
+    /// Windows named pipe of a session, which skips discovery altogether.
+    pub pipe: Option<String>,
+    /// Advertised properties a session must all have with these values, from `--match-prop`.
+    pub match_props: Vec<(String, String)>,
+    /// Which session to pick when several match.
+    pub select: SessionSelect,
+}
+
+impl DiscoveryArgs {
+    /// Starts from no filter at all, for callers not going through the command line.
+    pub fn builder() -> DiscoveryArgsBuilder {
+        DiscoveryArgsBuilder::default()
+    }
+}
+
+/// Builds [`DiscoveryArgs`] one field at a time, leaving the rest to their defaults.
+#[derive(Debug, Default)]
+pub struct DiscoveryArgsBuilder {
+    args: DiscoveryArgs,
 }
 
-pub fn get_cli_args() -> CliArgs {
-    parse_args(&cli().get_matches())
+impl DiscoveryArgsBuilder {
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.path = Some(path.into());
+        self
+    }
+
+    pub fn project(mut self, project: impl Into<String>) -> Self {
+        self.args.project = Some(project.into());
+        self
+    }
+
+    pub fn session(mut self, session: impl Into<String>) -> Self {
+        self.args.session = Some(session.into());
+        self
+    }
+
+    pub fn project_regex(mut self, regex: NameRegex) -> Self {
+        self.args.project_regex = Some(regex);
+        self
+    }
+
+    pub fn session_regex(mut self, regex: NameRegex) -> Self {
+        self.args.session_regex = Some(regex);
+        self
+    }
+
+    pub fn session_id(mut self, session_id: impl Into<String>) -> Self {
+        self.args.session_id = Some(session_id.into());
+        self
+    }
+
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.args.host = Some(host.into());
+        self
+    }
+
+    /// How long each browse lasts.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.args.discovery_timeout = Some(timeout);
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.args.discovery_retries = retries;
+        self
+    }
+
+    pub fn welcome_timeout(mut self, timeout: Duration) -> Self {
+        self.args.welcome_timeout = Some(timeout);
+        self
+    }
+
+    pub fn tls_ca(mut self, tls_ca: impl Into<PathBuf>) -> Self {
+        self.args.tls_ca = Some(tls_ca.into());
+        self
+    }
+
+    pub fn interface(mut self, interface: AddressPreference) -> Self {
+        self.args.interface = Some(interface);
+        self
+    }
+
+    pub fn socket(mut self, socket: impl Into<PathBuf>) -> Self {
+        self.args.socket = Some(socket.into());
+        self
+    }
+
+    pub fn pipe(mut self, pipe: impl Into<String>) -> Self {
+        self.args.pipe = Some(pipe.into());
+        self
+    }
+
+    /// Adds to the properties a session must advertise, rather than replacing them.
+    pub fn match_prop(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.args.match_props.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn select(mut self, select: SessionSelect) -> Self {
+        self.args.select = select;
+        self
+    }
+
+    pub fn build(self) -> DiscoveryArgs {
+        self.args
+    }
+
+    /// Applies `set` only if there is a `value`.
+    fn maybe<T>(self, value: Option<T>, set: impl FnOnce(Self, T) -> Self) -> Self {
+        match value {
+            Some(value) => set(self, value),
+            None => self,
+        }
+    }
+}
+
+/// A regex that project or session names must match somewhere, compiled once while parsing
+/// the arguments.
+#[derive(Debug, Clone)]
+pub struct NameRegex(pub Regex);
+
+impl PartialEq for NameRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// How `list-sessions` prints what it found.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ListFormat {
+    /// Tab separated, printed as soon as each session is found.
+    Plain,
+    /// Aligned columns with a header, printed once discovery is over.
+    Table,
+    /// One JSON array of every session, printed once discovery is over. Empty if none was
+    /// found.
+    Json,
+    /// Everything resolved while browsing, filters ignored, with all its advertised properties.
+    /// From the diagnostic `--list-addresses`, for tracking down why a session isn't found.
+    Raw,
+}
+
+/// Which session to pick when several match where a single one is needed.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum SessionSelect {
+    /// The one whose name sorts first.
+    First,
+    /// The one started last, by the start time it advertises.
+    Newest,
+    /// None of them, it's an error.
+    #[default]
+    Error,
+}
+
+/// How `watch` prints what Unity sends.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WatchFormat {
+    /// Rendered for people to read, like every other subcommand does.
+    Human,
+    /// One JSON object per message and line, flushed as soon as it is written, for log pipelines.
+    Ndjson,
+}
+
+/// The editor's play mode controls, sent as built-in commands which Unity finishes once the
+/// editor is in the new mode.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PlayMode {
+    Play,
+    Pause,
+    Stop,
+}
+
+impl PlayMode {
+    /// The name of its subcommand, which is also how it is described.
+    pub fn command(self) -> &'static str {
+        match self {
+            Self::Play => "play",
+            Self::Pause => "pause",
+            Self::Stop => "stop-play",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AddressPreference {
+    Loopback,
+    /// An IPv4 network and its prefix length.
+    Subnet(Ipv4Addr, u8),
+    /// A local network interface, whose subnet the address must be on.
+    Interface(String),
+}
+
+pub fn get_cli_args() -> (GlobalArgs, CliArgs) {
+    let matches = cli().get_matches();
+    (parse_global_args(&matches), parse_args(&matches))
 }
 
 fn cli() -> Command {
@@ -37,29 +350,187 @@ fn cli() -> Command {
         .about("A command line interface for Unity game engine")
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .arg(arg!(--"no-color" "Print without colors").global(true))
         .subcommand(
             Command::new("list-sessions")
                 .about("List available Unity sessions")
-                .args(session_discovery_args()),
+                .args(session_discovery_args())
+                .arg(
+                    arg!(--format[FORMAT] "How to print the sessions")
+                        .value_parser(["plain", "table", "json"])
+                        .default_value("plain"),
+                )
+                .arg(
+                    arg!(--"list-addresses" "Diagnostic: print every resolved session unfiltered, with its addresses and properties")
+                        .hide(true),
+                ),
         )
         .subcommand(
             Command::new("compile")
                 .about("Compiles project scripts")
-                .args(session_discovery_args()),
+                .args(session_discovery_args())
+                .arg(dry_run_arg())
+                .arg(all_arg())
+                .arg(env_arg()),
+        )
+        .subcommand(play_mode_command(PlayMode::Play, "Enters play mode"))
+        .subcommand(play_mode_command(
+            PlayMode::Pause,
+            "Pauses play mode, or resumes it if paused",
+        ))
+        .subcommand(play_mode_command(PlayMode::Stop, "Exits play mode"))
+        .subcommand(
+            Command::new("build")
+                .about("Builds the player, printing the progress and where it was built to")
+                .args(session_discovery_args())
+                .arg(dry_run_arg())
+                .arg(arg!(--target[TARGET] "What to build for, e.g. android, the active target if not given"))
+                .arg(
+                    arg!(--"build-path"[PATH] "Where to build to, the last used location if not given")
+                        .value_hint(ValueHint::AnyPath)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
         )
         .subcommand(
             Command::new("run")
                 .about("Run custom command")
                 .args(session_discovery_args())
+                .arg(dry_run_arg())
+                .arg(all_arg())
+                .arg(record_arg().conflicts_with("all"))
+                .arg(output_arg())
+                .arg(
+                    arg!(--"output-file"[PATH] "Write the command's result to a file instead of printing it")
+                        .value_hint(ValueHint::FilePath)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .conflicts_with("all"),
+                )
+                .arg(
+                    arg!(--"exec-on-finish"[CMD] "Run a shell command once the command finished, told how it ended by UCLI_SUCCESS and UCLI_RESULT")
+                        .conflicts_with_all(["all", "dry-run"]),
+                )
+                .arg(env_arg())
+                .arg(arg!(--summary "Only print the outcome with how many warnings and errors were logged"))
+                .arg(arg!(--stdin "Send everything read from stdin to the command as its input"))
+                .arg(
+                    arg!(--parallel[N] "With --all, how many sessions to run the command on at once")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .default_value("4")
+                        .requires("all"),
+                )
+                .arg(
+                    arg!(--arg[NAMED_ARG] "Named argument passed to the command, repeatable")
+                        .value_name("KEY=VALUE")
+                        .action(ArgAction::Append)
+                        .value_parser(parse_named_arg),
+                )
                 .arg(arg!(command: <cmd>))
-                .arg(arg!(args: [args] ...).trailing_var_arg(true))
+                .arg(arg!(args: [args] ...).trailing_var_arg(true).allow_hyphen_values(true))
                 .arg_required_else_help(true),
         )
+        .subcommand(
+            Command::new("exec-file")
+                .about("Run the commands of a file, one `command arg arg` per line, stopping at the first failure")
+                .args(session_discovery_args())
+                .arg(output_arg())
+                .arg(arg!(--"continue-on-error" "Run the remaining commands after one failed"))
+                .arg(
+                    arg!(file: <FILE>)
+                        .value_hint(ValueHint::FilePath)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
+        )
         .subcommand(
             Command::new("list-commands")
                 .about("List available custom commands")
-                .args(session_discovery_args()),
+                .args(session_discovery_args())
+                .arg(record_arg())
+                .arg(output_arg()),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Print the messages of a session recorded with --record")
+                .arg(output_arg())
+                .arg(
+                    arg!(path: <PATH>)
+                        .value_hint(ValueHint::FilePath)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
         )
+        .subcommand(
+            Command::new("repl")
+                .about("Run commands typed at a prompt, :commands lists them and :quit or Ctrl-D exits")
+                .args(session_discovery_args())
+                .arg(output_arg()),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Print what Unity logs until interrupted")
+                .args(session_discovery_args())
+                .arg(record_arg())
+                .arg(output_arg())
+                .arg(arg!(--grep[PATTERN] "Only print console messages containing PATTERN"))
+                .arg(
+                    arg!(-n --count[N] "Exit after printing N console messages")
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    arg!(--since[N] "Start with up to N console messages logged before connecting")
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(arg!(--coalesce "Print repeated console messages once, with a count"))
+                .arg(
+                    arg!(--format[FORMAT] "How to print what Unity sends")
+                        .value_parser(["human", "ndjson"]),
+                )
+                .arg(
+                    arg!(--"reconnect-timeout"[ms] "How long to wait for a session closing the connection while it reloads its scripts, 0 to exit instead")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("60000"),
+                )
+                .arg(
+                    arg!(--"log-file"[PATH] "Tail a log file instead, Unity's Editor.log by default")
+                        .num_args(0..=1)
+                        .value_hint(ValueHint::FilePath)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .conflicts_with_all(["since", "record"]),
+                ),
+        )
+}
+
+fn play_mode_command(mode: PlayMode, about: &'static str) -> Command {
+    Command::new(mode.command())
+        .about(about)
+        .args(session_discovery_args())
+        .arg(dry_run_arg())
+}
+
+fn dry_run_arg() -> clap::Arg {
+    arg!(--"dry-run" "Print the session and command that would be used, without running it")
+}
+
+fn env_arg() -> clap::Arg {
+    arg!(--env[ENV] "Environment variable passed to the command, repeatable")
+        .value_name("KEY=VALUE")
+        .action(ArgAction::Append)
+        .value_parser(parse_env_var)
+}
+
+fn all_arg() -> clap::Arg {
+    arg!(--all "Target every matching session instead of a single one")
+        .conflicts_with_all(["socket", "pipe"])
+}
+
+fn record_arg() -> clap::Arg {
+    arg!(--record[PATH] "Also write every message from Unity to a file, for `ucli replay`")
+        .value_hint(ValueHint::FilePath)
+        .value_parser(clap::value_parser!(PathBuf))
+}
+
+fn output_arg() -> clap::Arg {
+    arg!(--output[PATH] "Also append the printed output, without colors, to a file")
+        .value_hint(ValueHint::FilePath)
+        .value_parser(clap::value_parser!(PathBuf))
 }
 
 fn session_discovery_args() -> Vec<clap::Arg> {
@@ -69,17 +540,75 @@ fn session_discovery_args() -> Vec<clap::Arg> {
             .value_parser(clap::value_parser!(PathBuf)),
         arg!(--project[NAME]),
         arg!(--session[NAME]),
+        arg!(--"project-regex"[REGEX] "Only consider projects whose name matches REGEX")
+            .value_parser(parse_name_regex)
+            .conflicts_with("project"),
+        arg!(--"session-regex"[REGEX] "Only consider sessions whose name matches REGEX")
+            .value_parser(parse_name_regex)
+            .conflicts_with("session"),
+        arg!(--"session-id"[ID]),
+        arg!(--host[NAME] "Only consider sessions on a host whose name starts with NAME"),
         arg!(--"discovery-timeout"[ms]).value_parser(clap::value_parser!(u64)),
+        arg!(--"discovery-retries"[N]).value_parser(clap::value_parser!(u32)),
+        arg!(--"welcome-timeout"[ms] "Give up on a session that doesn't answer within ms")
+            .value_parser(clap::value_parser!(u64)),
+        arg!(--"tls-ca"[PEM])
+            .value_hint(ValueHint::FilePath)
+            .value_parser(clap::value_parser!(PathBuf)),
+        arg!(--interface[IFACE])
+            .value_name("loopback|SUBNET|IFACE")
+            .value_parser(parse_address_preference),
+        arg!(--socket[PATH])
+            .value_hint(ValueHint::FilePath)
+            .value_parser(clap::value_parser!(PathBuf)),
+        arg!(--pipe[NAME]).conflicts_with("socket"),
+        arg!(--"match-prop"[PROP] "Only consider sessions advertising this property, repeatable")
+            .value_name("KEY=VALUE")
+            .action(ArgAction::Append)
+            .value_parser(parse_named_arg),
+        arg!(--select[POLICY] "Which session to pick when several match: the first by name, the newest, or none")
+            .value_parser(["first", "newest", "error"])
+            .default_value("error"),
     ]
 }
 
+fn parse_global_args(matches: &ArgMatches) -> GlobalArgs {
+    GlobalArgs {
+        no_color: matches.get_flag("no-color"),
+    }
+}
+
 fn parse_args(matches: &ArgMatches) -> CliArgs {
     match matches.subcommand() {
         Some(("list-sessions", sub_matches)) => CliArgs::ListSessions {
             discovery_args: parse_discovery_args(sub_matches),
+            format: match sub_matches.get_one::<String>("format").map(String::as_str) {
+                _ if sub_matches.get_flag("list-addresses") => ListFormat::Raw,
+                Some("table") => ListFormat::Table,
+                Some("json") => ListFormat::Json,
+                _ => ListFormat::Plain,
+            },
         },
         Some(("compile", sub_matches)) => CliArgs::Compile {
             discovery_args: parse_discovery_args(sub_matches),
+            dry_run: sub_matches.get_flag("dry-run"),
+            all: sub_matches.get_flag("all"),
+            env: parse_env(sub_matches),
+        },
+        Some((command @ ("play" | "pause" | "stop-play"), sub_matches)) => CliArgs::PlayMode {
+            mode: match command {
+                "play" => PlayMode::Play,
+                "pause" => PlayMode::Pause,
+                _ => PlayMode::Stop,
+            },
+            discovery_args: parse_discovery_args(sub_matches),
+            dry_run: sub_matches.get_flag("dry-run"),
+        },
+        Some(("build", sub_matches)) => CliArgs::Build {
+            target: sub_matches.get_one::<String>("target").cloned(),
+            build_path: sub_matches.get_one::<PathBuf>("build-path").cloned(),
+            discovery_args: parse_discovery_args(sub_matches),
+            dry_run: sub_matches.get_flag("dry-run"),
         },
         Some(("run", sub_matches)) => CliArgs::Run {
             command: sub_matches
@@ -88,36 +617,208 @@ fn parse_args(matches: &ArgMatches) -> CliArgs {
                 .unwrap(),
             args: sub_matches
                 .get_many::<String>("args")
-                .unwrap()
-                .map(String::to_owned)
-                .collect(),
+                .map_or_else(Vec::new, |args| args.map(String::to_owned).collect()),
+            named_args: sub_matches
+                .get_many::<(String, String)>("arg")
+                .map_or_else(Vec::new, |named_args| named_args.cloned().collect()),
+            env: parse_env(sub_matches),
             discovery_args: parse_discovery_args(sub_matches),
+            dry_run: sub_matches.get_flag("dry-run"),
+            all: sub_matches.get_flag("all"),
+            record: sub_matches.get_one::<PathBuf>("record").cloned(),
+            output: sub_matches.get_one::<PathBuf>("output").cloned(),
+            output_file: sub_matches.get_one::<PathBuf>("output-file").cloned(),
+            exec_on_finish: sub_matches.get_one::<String>("exec-on-finish").cloned(),
+            summary: sub_matches.get_flag("summary"),
+            stdin: sub_matches.get_flag("stdin"),
+            parallel: *sub_matches.get_one::<u32>("parallel").unwrap() as usize,
+        },
+        Some(("exec-file", sub_matches)) => CliArgs::ExecFile {
+            path: sub_matches.get_one::<PathBuf>("file").unwrap().to_owned(),
+            discovery_args: parse_discovery_args(sub_matches),
+            continue_on_error: sub_matches.get_flag("continue-on-error"),
+            output: sub_matches.get_one::<PathBuf>("output").cloned(),
         },
         Some(("list-commands", sub_matches)) => CliArgs::ListCommands {
             discovery_args: parse_discovery_args(sub_matches),
+            record: sub_matches.get_one::<PathBuf>("record").cloned(),
+            output: sub_matches.get_one::<PathBuf>("output").cloned(),
+        },
+        Some(("replay", sub_matches)) => CliArgs::Replay {
+            path: sub_matches.get_one::<PathBuf>("path").unwrap().to_owned(),
+            output: sub_matches.get_one::<PathBuf>("output").cloned(),
+        },
+        Some(("repl", sub_matches)) => CliArgs::Repl {
+            discovery_args: parse_discovery_args(sub_matches),
+            output: sub_matches.get_one::<PathBuf>("output").cloned(),
+        },
+        Some(("watch", sub_matches)) => CliArgs::Watch {
+            discovery_args: parse_discovery_args(sub_matches),
+            grep: sub_matches.get_one::<String>("grep").cloned(),
+            count: sub_matches.get_one::<u64>("count").copied(),
+            since: sub_matches.get_one::<u32>("since").copied(),
+            coalesce: sub_matches.get_flag("coalesce"),
+            format: match sub_matches.get_one::<String>("format").map(String::as_str) {
+                Some("ndjson") => WatchFormat::Ndjson,
+                _ => WatchFormat::Human,
+            },
+            reconnect_timeout: Duration::from_millis(
+                *sub_matches.get_one::<u64>("reconnect-timeout").unwrap(),
+            ),
+            log_file: sub_matches
+                .contains_id("log-file")
+                .then(|| sub_matches.get_one::<PathBuf>("log-file").cloned()),
+            record: sub_matches.get_one::<PathBuf>("record").cloned(),
+            output: sub_matches.get_one::<PathBuf>("output").cloned(),
         },
         _ => unreachable!(),
     }
 }
 
-fn parse_discovery_args(matches: &ArgMatches) -> DiscoveryArgs {
-    DiscoveryArgs {
-        path: matches
-            .get_one::<PathBuf>("path")
-            .map_or_else(|| std::env::current_dir().ok(), |p| Some(p.to_owned())),
-        project: matches.get_one::<String>("project").map(String::to_owned),
-        session: matches.get_one::<String>("session").map(String::to_owned),
-        discovery_timeout: matches
-            .get_one::<u64>("discovery-timeout")
-            .map(|v| Duration::from_millis(v.to_owned())),
+fn parse_named_arg(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .ok_or_else(|| format!("expected KEY=VALUE, found `{arg}`"))
+}
+
+/// Like [`parse_named_arg`], but the key must also be a portable variable name.
+fn parse_env_var(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = parse_named_arg(arg)?;
+    let mut chars = key.chars();
+    let is_valid_name = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_valid_name {
+        return Err(format!(
+            "`{key}` isn't a valid variable name, use letters, digits and `_`, not starting with a digit"
+        ));
+    }
+    Ok((key, value))
+}
+
+fn parse_env(matches: &ArgMatches) -> Vec<(String, String)> {
+    matches
+        .get_many::<(String, String)>("env")
+        .map_or_else(Vec::new, |env| env.cloned().collect())
+}
+
+fn parse_name_regex(arg: &str) -> Result<NameRegex, String> {
+    Regex::new(arg)
+        .map(NameRegex)
+        .map_err(|e| format!("invalid regex: {e}"))
+}
+
+fn parse_address_preference(arg: &str) -> Result<AddressPreference, String> {
+    if arg == "loopback" {
+        return Ok(AddressPreference::Loopback);
+    }
+    let Some((network, prefix_len)) = arg.split_once('/') else {
+        return Ok(AddressPreference::Interface(arg.to_owned()));
+    };
+    match (network.parse(), prefix_len.parse()) {
+        (Ok(network), Ok(prefix_len)) if prefix_len <= 32 => {
+            Ok(AddressPreference::Subnet(network, prefix_len))
+        }
+        _ => Err(format!(
+            "expected a subnet like 192.168.1.0/24, found `{arg}`"
+        )),
     }
 }
 
+fn parse_discovery_args(matches: &ArgMatches) -> DiscoveryArgs {
+    let builder = DiscoveryArgs::builder()
+        .maybe(
+            matches
+                .get_one::<PathBuf>("path")
+                .map_or_else(|| std::env::current_dir().ok(), |p| Some(p.to_owned())),
+            DiscoveryArgsBuilder::path,
+        )
+        .maybe(
+            matches.get_one::<String>("project").cloned(),
+            DiscoveryArgsBuilder::project,
+        )
+        .maybe(
+            matches.get_one::<String>("session").cloned(),
+            DiscoveryArgsBuilder::session,
+        )
+        .maybe(
+            matches.get_one::<NameRegex>("project-regex").cloned(),
+            DiscoveryArgsBuilder::project_regex,
+        )
+        .maybe(
+            matches.get_one::<NameRegex>("session-regex").cloned(),
+            DiscoveryArgsBuilder::session_regex,
+        )
+        .maybe(
+            matches.get_one::<String>("session-id").cloned(),
+            DiscoveryArgsBuilder::session_id,
+        )
+        .maybe(
+            matches.get_one::<String>("host").cloned(),
+            DiscoveryArgsBuilder::host,
+        )
+        .maybe(
+            matches
+                .get_one::<u64>("discovery-timeout")
+                .map(|v| Duration::from_millis(*v)),
+            DiscoveryArgsBuilder::timeout,
+        )
+        .maybe(
+            matches.get_one::<u32>("discovery-retries").copied(),
+            DiscoveryArgsBuilder::retries,
+        )
+        .maybe(
+            matches
+                .get_one::<u64>("welcome-timeout")
+                .map(|v| Duration::from_millis(*v)),
+            DiscoveryArgsBuilder::welcome_timeout,
+        )
+        .maybe(
+            matches.get_one::<PathBuf>("tls-ca").cloned(),
+            DiscoveryArgsBuilder::tls_ca,
+        )
+        .maybe(
+            matches.get_one::<AddressPreference>("interface").cloned(),
+            DiscoveryArgsBuilder::interface,
+        )
+        .maybe(
+            matches.get_one::<PathBuf>("socket").cloned(),
+            DiscoveryArgsBuilder::socket,
+        )
+        .maybe(
+            matches.get_one::<String>("pipe").cloned(),
+            DiscoveryArgsBuilder::pipe,
+        )
+        .select(
+            match matches.get_one::<String>("select").map(String::as_str) {
+                Some("first") => SessionSelect::First,
+                Some("newest") => SessionSelect::Newest,
+                _ => SessionSelect::Error,
+            },
+        );
+    matches
+        .get_many::<(String, String)>("match-prop")
+        .into_iter()
+        .flatten()
+        .fold(builder, |builder, (key, value)| {
+            builder.match_prop(key, value)
+        })
+        .build()
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{path::PathBuf, time::Duration};
+    use std::{
+        net::Ipv4Addr,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
 
-    use crate::cli_args::{cli, parse_args, CliArgs, DiscoveryArgs};
+    use crate::cli_args::{
+        cli, parse_args, parse_discovery_args, parse_global_args, AddressPreference, CliArgs,
+        DiscoveryArgs, GlobalArgs, ListFormat, PlayMode, SessionSelect, WatchFormat,
+    };
 
     #[test]
     fn parse_list_sessions_subcommand() {
@@ -130,13 +831,223 @@ mod tests {
                     path: Some(PathBuf::from("foo/bar/baz")),
                     project: None,
                     session: None,
+                    project_regex: None,
+                    session_regex: None,
+                    session_id: None,
+                    host: None,
                     discovery_timeout: None,
-                }
+                    discovery_retries: 0,
+                    welcome_timeout: None,
+                    tls_ca: None,
+                    interface: None,
+                    socket: None,
+                    pipe: None,
+                    match_props: vec![],
+                    select: SessionSelect::Error,
+                },
+                format: ListFormat::Plain,
             },
             parsed
         );
     }
 
+    #[test]
+    fn parse_no_color_before_or_after_the_subcommand() {
+        for args in [
+            vec!["ucli", "--no-color", "watch"],
+            vec!["ucli", "watch", "--no-color"],
+        ] {
+            let matches = cli().get_matches_from(args);
+            assert_eq!(GlobalArgs { no_color: true }, parse_global_args(&matches));
+        }
+        let matches = cli().get_matches_from(vec!["ucli", "watch"]);
+        assert_eq!(GlobalArgs::default(), parse_global_args(&matches));
+    }
+
+    #[test]
+    fn parse_table_format() {
+        let matches = cli().get_matches_from(vec!["ucli", "list-sessions", "--format=table"]);
+
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::ListSessions {
+                format: ListFormat::Table,
+                ..
+            }
+        ));
+        assert!(cli()
+            .try_get_matches_from(vec!["ucli", "list-sessions", "--format=grid"])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_json_format() {
+        let matches = cli().get_matches_from(vec!["ucli", "list-sessions", "--format", "json"]);
+
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::ListSessions {
+                format: ListFormat::Json,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn parse_list_addresses_flag() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "list-sessions",
+            "--format=table",
+            "--list-addresses",
+        ]);
+
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::ListSessions {
+                format: ListFormat::Raw,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn builder_sets_only_the_given_fields() {
+        let args = DiscoveryArgs::builder()
+            .path("foo/bar")
+            .project("baz")
+            .session("qux")
+            .timeout(Duration::from_millis(300))
+            .build();
+
+        assert_eq!(
+            DiscoveryArgs {
+                path: Some(PathBuf::from("foo/bar")),
+                project: Some("baz".to_owned()),
+                session: Some("qux".to_owned()),
+                discovery_timeout: Some(Duration::from_millis(300)),
+                ..DiscoveryArgs::default()
+            },
+            args
+        );
+        assert_eq!(DiscoveryArgs::default(), DiscoveryArgs::builder().build());
+    }
+
+    #[test]
+    fn parsed_discovery_args_match_the_builder() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "list-sessions",
+            "--path=foo",
+            "--session=bar",
+            "--discovery-timeout=200",
+            "--discovery-retries=2",
+            "--welcome-timeout=100",
+            "--pipe=baz",
+        ]);
+        let (_, sub_matches) = matches.subcommand().unwrap();
+
+        assert_eq!(
+            DiscoveryArgs::builder()
+                .path("foo")
+                .session("bar")
+                .timeout(Duration::from_millis(200))
+                .retries(2)
+                .welcome_timeout(Duration::from_millis(100))
+                .pipe("baz")
+                .build(),
+            parse_discovery_args(sub_matches)
+        );
+    }
+
+    #[test]
+    fn parse_match_props() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "list-sessions",
+            "--match-prop",
+            "platform=Android",
+            "--match-prop=license=pro",
+        ]);
+        let (_, sub_matches) = matches.subcommand().unwrap();
+
+        assert_eq!(
+            DiscoveryArgs::builder()
+                .match_prop("platform", "Android")
+                .match_prop("license", "pro")
+                .build()
+                .match_props,
+            parse_discovery_args(sub_matches).match_props
+        );
+    }
+
+    #[test]
+    fn parse_host() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "list-sessions",
+            "--host=my-laptop",
+            "--project=Game",
+        ]);
+        let (_, sub_matches) = matches.subcommand().unwrap();
+        let parsed = parse_discovery_args(sub_matches);
+
+        assert_eq!(Some("my-laptop".to_owned()), parsed.host);
+        assert_eq!(Some("Game".to_owned()), parsed.project);
+    }
+
+    #[test]
+    fn parse_discovery_retries_arg() {
+        let matches =
+            cli().get_matches_from(vec!["ucli", "list-sessions", "--discovery-retries", "3"]);
+
+        match parse_args(&matches) {
+            CliArgs::ListSessions { discovery_args, .. } => {
+                assert_eq!(3, discovery_args.discovery_retries)
+            }
+            parsed => panic!("unexpected arguments: {parsed:?}"),
+        }
+        assert!(cli()
+            .try_get_matches_from(vec!["ucli", "list-sessions", "--discovery-retries=-1"])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_name_regex_args() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "list-sessions",
+            "--session-regex",
+            "^game-(client|server)$",
+        ]);
+
+        match parse_args(&matches) {
+            CliArgs::ListSessions { discovery_args, .. } => {
+                let regex = discovery_args.session_regex.unwrap().0;
+                assert!(regex.is_match("game-client"));
+                assert!(!regex.is_match("game-tools"));
+            }
+            parsed => panic!("unexpected arguments: {parsed:?}"),
+        }
+    }
+
+    #[test]
+    fn invalid_or_conflicting_name_regex_is_rejected() {
+        let invalid =
+            cli().try_get_matches_from(vec!["ucli", "list-sessions", "--project-regex", "("]);
+        assert!(invalid.unwrap_err().to_string().contains("invalid regex"));
+
+        let conflicting = cli().try_get_matches_from(vec![
+            "ucli",
+            "list-sessions",
+            "--session",
+            "game",
+            "--session-regex",
+            "game",
+        ]);
+        assert!(conflicting.is_err());
+    }
+
     #[test]
     fn parse_compile_command() {
         let matches = cli().get_matches_from(vec!["ucli", "compile"]);
@@ -148,13 +1059,96 @@ mod tests {
                     path: std::env::current_dir().ok(),
                     project: None,
                     session: None,
+                    project_regex: None,
+                    session_regex: None,
+                    session_id: None,
+                    host: None,
                     discovery_timeout: None,
-                }
+                    discovery_retries: 0,
+                    welcome_timeout: None,
+                    tls_ca: None,
+                    interface: None,
+                    socket: None,
+                    pipe: None,
+                    match_props: vec![],
+                    select: SessionSelect::Error,
+                },
+                dry_run: false,
+                all: false,
+                env: vec![],
             },
             parsed
         );
     }
 
+    #[test]
+    fn parse_play_mode_commands() {
+        for (subcommand, expected) in [
+            ("play", PlayMode::Play),
+            ("pause", PlayMode::Pause),
+            ("stop-play", PlayMode::Stop),
+        ] {
+            let matches = cli().get_matches_from(vec!["ucli", subcommand, "--project", "Game"]);
+
+            match parse_args(&matches) {
+                CliArgs::PlayMode {
+                    mode,
+                    discovery_args,
+                    dry_run: false,
+                } => {
+                    assert_eq!(expected, mode);
+                    assert_eq!(subcommand, mode.command());
+                    assert_eq!(Some("Game".to_owned()), discovery_args.project);
+                }
+                parsed => panic!("unexpected args: {parsed:?}"),
+            }
+        }
+
+        let matches = cli().get_matches_from(vec!["ucli", "pause", "--dry-run"]);
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::PlayMode {
+                mode: PlayMode::Pause,
+                dry_run: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn parse_build_command() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "build",
+            "--target",
+            "android",
+            "--build-path=Builds/game.apk",
+        ]);
+
+        match parse_args(&matches) {
+            CliArgs::Build {
+                target,
+                build_path,
+                dry_run: false,
+                ..
+            } => {
+                assert_eq!(Some("android".to_owned()), target);
+                assert_eq!(Some(PathBuf::from("Builds/game.apk")), build_path);
+            }
+            parsed => panic!("unexpected args: {parsed:?}"),
+        }
+
+        let matches = cli().get_matches_from(vec!["ucli", "build"]);
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::Build {
+                target: None,
+                build_path: None,
+                ..
+            }
+        ));
+    }
+
     #[test]
     fn parse_run_command() {
         let matches = cli().get_matches_from(vec![
@@ -176,21 +1170,105 @@ mod tests {
         assert_eq!(
             CliArgs::Run {
                 command: "foo".to_owned(),
-                args: vec!["--bar", "baz", "--", "foo/bar"]
+                args: ["--bar", "baz", "--", "foo/bar"]
                     .iter()
                     .map(|s| s.to_string())
                     .collect(),
+                named_args: vec![],
+                env: vec![],
                 discovery_args: DiscoveryArgs {
                     path: std::env::current_dir().ok(),
                     project: None,
                     session: Some(String::from("foo-bar")),
+                    project_regex: None,
+                    session_regex: None,
+                    session_id: None,
+                    host: None,
                     discovery_timeout: Some(Duration::from_millis(500)),
-                }
+                    discovery_retries: 0,
+                    welcome_timeout: None,
+                    tls_ca: None,
+                    interface: None,
+                    socket: None,
+                    pipe: None,
+                    match_props: vec![],
+                    select: SessionSelect::Error,
+                },
+                dry_run: false,
+                all: false,
+                record: None,
+                output: None,
+                output_file: None,
+                exec_on_finish: None,
+                summary: false,
+                stdin: false,
+                parallel: 4,
             },
             parsed
         );
     }
 
+    #[test]
+    fn parse_run_command_with_named_args() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "run",
+            "--arg",
+            "target=StandaloneLinux64",
+            "--arg=scenes=a.unity,b.unity",
+            "build",
+            "--fast",
+        ]);
+        let parsed = parse_args(&matches);
+
+        assert_eq!(
+            CliArgs::Run {
+                command: "build".to_owned(),
+                args: vec!["--fast".to_owned()],
+                named_args: vec![
+                    ("target".to_owned(), "StandaloneLinux64".to_owned()),
+                    ("scenes".to_owned(), "a.unity,b.unity".to_owned()),
+                ],
+                env: vec![],
+                discovery_args: DiscoveryArgs {
+                    path: std::env::current_dir().ok(),
+                    project: None,
+                    session: None,
+                    project_regex: None,
+                    session_regex: None,
+                    session_id: None,
+                    host: None,
+                    discovery_timeout: None,
+                    discovery_retries: 0,
+                    welcome_timeout: None,
+                    tls_ca: None,
+                    interface: None,
+                    socket: None,
+                    pipe: None,
+                    match_props: vec![],
+                    select: SessionSelect::Error,
+                },
+                dry_run: false,
+                all: false,
+                record: None,
+                output: None,
+                output_file: None,
+                exec_on_finish: None,
+                summary: false,
+                stdin: false,
+                parallel: 4,
+            },
+            parsed
+        );
+    }
+
+    #[test]
+    fn named_arg_without_value_is_rejected() {
+        let result = cli().try_get_matches_from(vec!["ucli", "run", "--arg", "target", "build"]);
+
+        assert!(result.is_err());
+    }
+
     #[test]
     fn parse_list_command_command() {
         let matches = cli().get_matches_from(vec![
@@ -207,10 +1285,607 @@ mod tests {
                     path: std::env::current_dir().ok(),
                     project: Some(String::from("My Unity Project")),
                     session: None,
+                    project_regex: None,
+                    session_regex: None,
+                    session_id: None,
+                    host: None,
                     discovery_timeout: None,
-                }
+                    discovery_retries: 0,
+                    welcome_timeout: None,
+                    tls_ca: None,
+                    interface: None,
+                    socket: None,
+                    pipe: None,
+                    match_props: vec![],
+                    select: SessionSelect::Error,
+                },
+                record: None,
+                output: None,
+            },
+            parsed
+        );
+    }
+
+    #[test]
+    fn parse_session_id_discovery_arg() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "compile",
+            "--session-id",
+            "67e55044-10b1-426f-9247-bb680e5fe0c8",
+        ]);
+        let parsed = parse_args(&matches);
+
+        assert_eq!(
+            CliArgs::Compile {
+                discovery_args: DiscoveryArgs {
+                    path: std::env::current_dir().ok(),
+                    project: None,
+                    session: None,
+                    project_regex: None,
+                    session_regex: None,
+                    session_id: Some(String::from("67e55044-10b1-426f-9247-bb680e5fe0c8")),
+                    host: None,
+                    discovery_timeout: None,
+                    discovery_retries: 0,
+                    welcome_timeout: None,
+                    tls_ca: None,
+                    interface: None,
+                    socket: None,
+                    pipe: None,
+                    match_props: vec![],
+                    select: SessionSelect::Error,
+                },
+                dry_run: false,
+                all: false,
+                env: vec![],
+            },
+            parsed
+        );
+    }
+
+    #[test]
+    fn parse_tls_ca_discovery_arg() {
+        let matches =
+            cli().get_matches_from(vec!["ucli", "list-commands", "--tls-ca", "certs/ca.pem"]);
+        let parsed = parse_args(&matches);
+
+        assert_eq!(
+            CliArgs::ListCommands {
+                discovery_args: DiscoveryArgs {
+                    path: std::env::current_dir().ok(),
+                    project: None,
+                    session: None,
+                    project_regex: None,
+                    session_regex: None,
+                    session_id: None,
+                    host: None,
+                    discovery_timeout: None,
+                    discovery_retries: 0,
+                    welcome_timeout: None,
+                    tls_ca: Some(PathBuf::from("certs/ca.pem")),
+                    interface: None,
+                    socket: None,
+                    pipe: None,
+                    match_props: vec![],
+                    select: SessionSelect::Error,
+                },
+                record: None,
+                output: None,
+            },
+            parsed
+        );
+    }
+
+    #[test]
+    fn parse_dry_run_flag() {
+        let matches = cli().get_matches_from(vec!["ucli", "run", "--dry-run", "foo", "bar"]);
+        let parsed = parse_args(&matches);
+
+        assert_eq!(
+            CliArgs::Run {
+                command: "foo".to_owned(),
+                args: vec!["bar".to_owned()],
+                named_args: vec![],
+                env: vec![],
+                discovery_args: DiscoveryArgs {
+                    path: std::env::current_dir().ok(),
+                    project: None,
+                    session: None,
+                    project_regex: None,
+                    session_regex: None,
+                    session_id: None,
+                    host: None,
+                    discovery_timeout: None,
+                    discovery_retries: 0,
+                    welcome_timeout: None,
+                    tls_ca: None,
+                    interface: None,
+                    socket: None,
+                    pipe: None,
+                    match_props: vec![],
+                    select: SessionSelect::Error,
+                },
+                dry_run: true,
+                all: false,
+                record: None,
+                output: None,
+                output_file: None,
+                exec_on_finish: None,
+                summary: false,
+                stdin: false,
+                parallel: 4,
+            },
+            parsed
+        );
+    }
+
+    #[test]
+    fn parse_all_flag() {
+        let matches = cli().get_matches_from(vec!["ucli", "compile", "--all", "--project", "Game"]);
+        let parsed = parse_args(&matches);
+
+        assert_eq!(
+            CliArgs::Compile {
+                discovery_args: DiscoveryArgs {
+                    path: std::env::current_dir().ok(),
+                    project: Some(String::from("Game")),
+                    session: None,
+                    project_regex: None,
+                    session_regex: None,
+                    session_id: None,
+                    host: None,
+                    discovery_timeout: None,
+                    discovery_retries: 0,
+                    welcome_timeout: None,
+                    tls_ca: None,
+                    interface: None,
+                    socket: None,
+                    pipe: None,
+                    match_props: vec![],
+                    select: SessionSelect::Error,
+                },
+                dry_run: false,
+                all: true,
+                env: vec![],
+            },
+            parsed
+        );
+    }
+
+    #[test]
+    fn parse_interface_preference() {
+        let parse = |value: &str| {
+            let matches =
+                cli().get_matches_from(vec!["ucli", "list-sessions", "--interface", value]);
+            match parse_args(&matches) {
+                CliArgs::ListSessions { discovery_args, .. } => discovery_args.interface,
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(Some(AddressPreference::Loopback), parse("loopback"));
+        assert_eq!(
+            Some(AddressPreference::Subnet(Ipv4Addr::new(192, 168, 1, 0), 24)),
+            parse("192.168.1.0/24")
+        );
+        assert_eq!(
+            Some(AddressPreference::Interface("eth0".to_owned())),
+            parse("eth0")
+        );
+    }
+
+    #[test]
+    fn invalid_subnet_is_rejected() {
+        let result =
+            cli().try_get_matches_from(vec!["ucli", "list-sessions", "--interface", "10.0.0.0/33"]);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_socket_discovery_arg() {
+        let matches =
+            cli().get_matches_from(vec!["ucli", "list-commands", "--socket", "/tmp/ucli.sock"]);
+        let parsed = parse_args(&matches);
+
+        assert_eq!(
+            CliArgs::ListCommands {
+                discovery_args: DiscoveryArgs {
+                    path: std::env::current_dir().ok(),
+                    project: None,
+                    session: None,
+                    project_regex: None,
+                    session_regex: None,
+                    session_id: None,
+                    host: None,
+                    discovery_timeout: None,
+                    discovery_retries: 0,
+                    welcome_timeout: None,
+                    tls_ca: None,
+                    interface: None,
+                    socket: Some(PathBuf::from("/tmp/ucli.sock")),
+                    pipe: None,
+                    match_props: vec![],
+                    select: SessionSelect::Error,
+                },
+                record: None,
+                output: None,
+            },
+            parsed
+        );
+    }
+
+    #[test]
+    fn socket_conflicts_with_all() {
+        let result =
+            cli().try_get_matches_from(vec!["ucli", "compile", "--all", "--socket", "ucli.sock"]);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_pipe_discovery_arg() {
+        let matches = cli().get_matches_from(vec!["ucli", "list-commands", "--pipe", "ucli-game"]);
+        let parsed = parse_args(&matches);
+
+        assert_eq!(
+            CliArgs::ListCommands {
+                discovery_args: DiscoveryArgs {
+                    path: std::env::current_dir().ok(),
+                    project: None,
+                    session: None,
+                    project_regex: None,
+                    session_regex: None,
+                    session_id: None,
+                    host: None,
+                    discovery_timeout: None,
+                    discovery_retries: 0,
+                    welcome_timeout: None,
+                    tls_ca: None,
+                    interface: None,
+                    socket: None,
+                    pipe: Some("ucli-game".to_owned()),
+                    match_props: vec![],
+                    select: SessionSelect::Error,
+                },
+                record: None,
+                output: None,
+            },
+            parsed
+        );
+    }
+
+    #[test]
+    fn parse_record_arg() {
+        let matches =
+            cli().get_matches_from(vec!["ucli", "list-commands", "--record", "session.jsonl"]);
+
+        match parse_args(&matches) {
+            CliArgs::ListCommands { record, .. } => {
+                assert_eq!(Some(PathBuf::from("session.jsonl")), record)
+            }
+            parsed => panic!("unexpected arguments: {parsed:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_env_args() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "run",
+            "build",
+            "--env",
+            "BUILD_NUMBER=42",
+            "--env=BRANCH=release/1.2",
+            "--env",
+            "EMPTY=",
+        ]);
+
+        match parse_args(&matches) {
+            CliArgs::Run { env, .. } => assert_eq!(
+                vec![
+                    ("BUILD_NUMBER".to_owned(), "42".to_owned()),
+                    ("BRANCH".to_owned(), "release/1.2".to_owned()),
+                    ("EMPTY".to_owned(), String::new()),
+                ],
+                env
+            ),
+            parsed => panic!("unexpected arguments: {parsed:?}"),
+        }
+
+        let matches = cli().get_matches_from(vec!["ucli", "compile", "--env", "CI=1"]);
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::Compile { env, .. } if env == vec![("CI".to_owned(), "1".to_owned())]
+        ));
+    }
+
+    #[test]
+    fn malformed_env_args_are_rejected() {
+        let error = |env: &str| {
+            cli()
+                .try_get_matches_from(vec!["ucli", "run", "build", "--env", env])
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(error("BUILD_NUMBER").contains("expected KEY=VALUE"));
+        assert!(error("=42").contains("isn't a valid variable name"));
+        assert!(error("1ST=a").contains("isn't a valid variable name"));
+        assert!(error("BUILD-NUMBER=42").contains("isn't a valid variable name"));
+    }
+
+    #[test]
+    fn parse_summary_flag() {
+        let matches = cli().get_matches_from(vec!["ucli", "run", "build", "--summary"]);
+
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::Run { summary: true, .. }
+        ));
+    }
+
+    #[test]
+    fn parse_parallel_arg() {
+        let matches = cli().get_matches_from(vec!["ucli", "run", "build", "--all", "--parallel=2"]);
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::Run { parallel: 2, .. }
+        ));
+
+        let matches = cli().get_matches_from(vec!["ucli", "run", "build", "--all"]);
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::Run { parallel: 4, .. }
+        ));
+
+        for args in [
+            vec!["ucli", "run", "build", "--all", "--parallel=0"],
+            vec!["ucli", "run", "build", "--parallel=2"],
+        ] {
+            assert!(cli().try_get_matches_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_stdin_flag() {
+        let matches = cli().get_matches_from(vec!["ucli", "run", "eval", "--stdin"]);
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::Run { stdin: true, .. }
+        ));
+
+        let matches = cli().get_matches_from(vec!["ucli", "run", "eval"]);
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::Run { stdin: false, .. }
+        ));
+    }
+
+    #[test]
+    fn parse_exec_on_finish_arg() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "run",
+            "build",
+            "--exec-on-finish",
+            "./upload.sh build/",
+        ]);
+
+        match parse_args(&matches) {
+            CliArgs::Run { exec_on_finish, .. } => {
+                assert_eq!(Some("./upload.sh build/"), exec_on_finish.as_deref())
+            }
+            parsed => panic!("unexpected arguments: {parsed:?}"),
+        }
+        assert!(cli()
+            .try_get_matches_from(vec![
+                "ucli",
+                "run",
+                "build",
+                "--all",
+                "--exec-on-finish=true"
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_output_file_arg() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "run",
+            "list-scenes",
+            "--output-file",
+            "scenes.json",
+        ]);
+
+        match parse_args(&matches) {
+            CliArgs::Run { output_file, .. } => {
+                assert_eq!(Some(PathBuf::from("scenes.json")), output_file)
+            }
+            parsed => panic!("unexpected arguments: {parsed:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_watch_count_arg() {
+        let matches =
+            cli().get_matches_from(vec!["ucli", "watch", "-n", "50", "--grep", "NullReference"]);
+
+        match parse_args(&matches) {
+            CliArgs::Watch { grep, count, .. } => {
+                assert_eq!(Some("NullReference"), grep.as_deref());
+                assert_eq!(Some(50), count);
+            }
+            parsed => panic!("unexpected arguments: {parsed:?}"),
+        }
+        assert!(cli()
+            .try_get_matches_from(vec!["ucli", "watch", "--count", "0"])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_watch_since_arg() {
+        let matches = cli().get_matches_from(vec!["ucli", "watch", "--since", "100"]);
+
+        match parse_args(&matches) {
+            CliArgs::Watch {
+                since,
+                count,
+                coalesce,
+                ..
+            } => {
+                assert_eq!(Some(100), since);
+                assert_eq!(None, count);
+                assert!(!coalesce);
+            }
+            parsed => panic!("unexpected arguments: {parsed:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_watch_log_file_arg() {
+        let log_file = |args: Vec<&str>| match parse_args(&cli().get_matches_from(args)) {
+            CliArgs::Watch { log_file, .. } => log_file,
+            parsed => panic!("unexpected arguments: {parsed:?}"),
+        };
+
+        assert_eq!(None, log_file(vec!["ucli", "watch"]));
+        assert_eq!(Some(None), log_file(vec!["ucli", "watch", "--log-file"]));
+        assert_eq!(
+            Some(Some(PathBuf::from("Player.log"))),
+            log_file(vec!["ucli", "watch", "--log-file", "Player.log"])
+        );
+        assert!(cli()
+            .try_get_matches_from(vec!["ucli", "watch", "--log-file", "--since", "10"])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_watch_format_arg() {
+        let format = |args: Vec<&str>| match parse_args(&cli().get_matches_from(args)) {
+            CliArgs::Watch { format, .. } => format,
+            parsed => panic!("unexpected arguments: {parsed:?}"),
+        };
+
+        assert_eq!(WatchFormat::Human, format(vec!["ucli", "watch"]));
+        assert_eq!(
+            WatchFormat::Ndjson,
+            format(vec!["ucli", "watch", "--format", "ndjson"])
+        );
+        assert!(cli()
+            .try_get_matches_from(vec!["ucli", "watch", "--format", "json"])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_watch_reconnect_timeout_arg() {
+        let reconnect_timeout = |args: Vec<&str>| match parse_args(&cli().get_matches_from(args)) {
+            CliArgs::Watch {
+                reconnect_timeout, ..
+            } => reconnect_timeout,
+            parsed => panic!("unexpected arguments: {parsed:?}"),
+        };
+
+        assert_eq!(
+            Duration::from_secs(60),
+            reconnect_timeout(vec!["ucli", "watch"])
+        );
+        assert_eq!(
+            Duration::ZERO,
+            reconnect_timeout(vec!["ucli", "watch", "--reconnect-timeout", "0"])
+        );
+    }
+
+    #[test]
+    fn parse_select_arg() {
+        let select = |args: Vec<&str>| {
+            let matches = cli().get_matches_from(args);
+            parse_discovery_args(matches.subcommand_matches("run").unwrap()).select
+        };
+
+        assert_eq!(SessionSelect::Error, select(vec!["ucli", "run", "foo"]));
+        assert_eq!(
+            SessionSelect::First,
+            select(vec!["ucli", "run", "--select", "first", "foo"])
+        );
+        assert_eq!(
+            SessionSelect::Newest,
+            select(vec!["ucli", "run", "--select=newest", "foo"])
+        );
+        assert!(cli()
+            .try_get_matches_from(vec!["ucli", "run", "--select", "last", "foo"])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_replay_subcommand() {
+        let matches = cli().get_matches_from(vec!["ucli", "replay", "session.jsonl"]);
+        let parsed = parse_args(&matches);
+
+        assert_eq!(
+            CliArgs::Replay {
+                path: PathBuf::from("session.jsonl"),
+                output: None,
             },
             parsed
         );
     }
+
+    #[test]
+    fn parse_exec_file_subcommand() {
+        let matches = cli().get_matches_from(vec!["ucli", "exec-file", "setup.ucli"]);
+        match parse_args(&matches) {
+            CliArgs::ExecFile {
+                path,
+                continue_on_error: false,
+                ..
+            } => assert_eq!(PathBuf::from("setup.ucli"), path),
+            parsed => panic!("unexpected args: {parsed:?}"),
+        }
+
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "exec-file",
+            "--continue-on-error",
+            "setup.ucli",
+        ]);
+        assert!(matches!(
+            parse_args(&matches),
+            CliArgs::ExecFile {
+                continue_on_error: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn parse_repl_subcommand() {
+        let matches = cli().get_matches_from(vec!["ucli", "repl", "--session", "brave-otter"]);
+
+        match parse_args(&matches) {
+            CliArgs::Repl {
+                discovery_args,
+                output: None,
+            } => assert_eq!(Some("brave-otter".to_owned()), discovery_args.session),
+            parsed => panic!("unexpected args: {parsed:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_output_arg() {
+        let matches = cli().get_matches_from(vec![
+            "ucli",
+            "replay",
+            "session.jsonl",
+            "--output",
+            "session.log",
+        ]);
+
+        assert_eq!(
+            Some(Path::new("session.log")),
+            parse_args(&matches).output()
+        );
+    }
 }
@@ -0,0 +1,512 @@
+use std::{
+    fs::File,
+    io::{self, LineWriter, Read, Write},
+    net::{Shutdown, SocketAddrV4, TcpStream},
+    path::Path,
+    time::Duration,
+};
+
+use common::{
+    ClientCodec, ClientMessage, CodecError, Compression, Envelope, ServerMessage, UnityLogType,
+    PROTOCOL_VERSION,
+};
+
+use crate::{recording::Recorder, service_discovery::UnityService};
+
+/// Keeps an unreachable address, e.g. a stale link-local one, from stalling the next attempt.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+enum Transport {
+    Plain(TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixStream),
+    #[cfg(windows)]
+    Pipe(std::fs::File),
+    #[cfg(feature = "tls")]
+    Tls(Box<crate::tls::TlsStream>),
+}
+
+impl Transport {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+            // Pipes have no read timeout, so reads wait for the next message and Ctrl-C is only
+            // noticed once it arrives.
+            #[cfg(windows)]
+            Self::Pipe(_) => {
+                let _ = timeout;
+                Ok(())
+            }
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.sock.set_read_timeout(timeout),
+        }
+    }
+
+    /// Tells the other end nothing more will be written, so that it sees the end of the stream
+    /// even while this end is still open.
+    fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.shutdown(Shutdown::Write),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.shutdown(Shutdown::Write),
+            // A pipe client can't close only its end of the pipe, the server notices once the
+            // handle is dropped.
+            #[cfg(windows)]
+            Self::Pipe(_) => Ok(()),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()?;
+                stream.sock.shutdown(Shutdown::Write)
+            }
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+            #[cfg(windows)]
+            Self::Pipe(pipe) => pipe.read(buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+            #[cfg(windows)]
+            Self::Pipe(pipe) => pipe.write(buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+            #[cfg(windows)]
+            Self::Pipe(pipe) => pipe.flush(),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A connection to a single Unity session.
+pub struct UnityClient {
+    transport: Transport,
+    codec: ClientCodec,
+    /// Bytes of a message that didn't fully arrive before a `recv_timeout` gave up.
+    read_buf: Vec<u8>,
+    /// What arrived instead of the welcome, or after a gap that is reported first, to be
+    /// received next.
+    pending: Option<ServerMessage>,
+    /// The number the session should give its next message, to notice the ones it dropped.
+    next_seq: u64,
+    /// How many messages were sent, which is the number of the next one.
+    sent: u64,
+    recorder: Option<Recorder<LineWriter<File>>>,
+}
+
+impl UnityClient {
+    /// Connects to one of `service`'s addresses. Speaks TLS to sessions advertising a certificate
+    /// fingerprint, trusting `tls_ca` if given.
+    pub fn connect(
+        service: &UnityService,
+        address: SocketAddrV4,
+        tls_ca: Option<&Path>,
+    ) -> io::Result<Self> {
+        let stream = TcpStream::connect_timeout(&address.into(), CONNECT_TIMEOUT)?;
+        let transport = match service.tls_fingerprint {
+            None => Transport::Plain(stream),
+            #[cfg(feature = "tls")]
+            Some(ref fingerprint) => Transport::Tls(Box::new(crate::tls::connect(
+                stream,
+                &service.hostname,
+                fingerprint,
+                tls_ca,
+            )?)),
+            #[cfg(not(feature = "tls"))]
+            Some(_) => {
+                let _ = tls_ca;
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the session only accepts TLS, but ucli was built without the `tls` feature",
+                ));
+            }
+        };
+
+        Ok(Self::new(transport))
+    }
+
+    /// Connects to a session listening on a Unix domain socket, which is only supported on Unix.
+    pub fn connect_unix(path: &Path) -> io::Result<Self> {
+        #[cfg(unix)]
+        let transport = std::os::unix::net::UnixStream::connect(path).map(Transport::Unix);
+        #[cfg(not(unix))]
+        let transport = {
+            let _ = path;
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix domain sockets are only supported on Unix platforms",
+            ))
+        };
+        transport.map(Self::new)
+    }
+
+    /// Connects to a session listening on a named pipe, which is only supported on Windows.
+    pub fn connect_pipe(name: &str) -> io::Result<Self> {
+        #[cfg(windows)]
+        let transport = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(common::named_pipe_path(name))
+            .map(Transport::Pipe);
+        #[cfg(not(windows))]
+        let transport = {
+            let _ = name;
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "named pipes are only supported on Windows",
+            ))
+        };
+        transport.map(Self::new)
+    }
+
+    fn new(transport: Transport) -> Self {
+        Self {
+            transport,
+            codec: ClientCodec::new(),
+            read_buf: Vec::new(),
+            pending: None,
+            next_seq: 0,
+            sent: 0,
+            recorder: None,
+        }
+    }
+
+    /// Records every message received from now on.
+    pub fn record_to(&mut self, recorder: Recorder<LineWriter<File>>) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Stops recording, handing back the recorder e.g. to go on with on a new connection.
+    pub fn take_recorder(&mut self) -> Option<Recorder<LineWriter<File>>> {
+        self.recorder.take()
+    }
+
+    /// Says hello, offering every compression this build supports, then waits for the session to
+    /// greet the connection, so a server that accepted it but died before answering fails within
+    /// `timeout` instead of leaving every later read hanging. Frames are compressed the way the
+    /// welcome says from then on.
+    ///
+    /// Anything else arriving first, like a rejection, is kept to be received as usual. A server
+    /// speaking another protocol version fails with [`CodecError::VersionMismatch`].
+    pub fn wait_for_welcome(&mut self, timeout: Duration) -> Result<(), CodecError> {
+        self.send(&ClientMessage::Hello {
+            compression: Compression::supported(),
+        })?;
+        match self.recv_timeout(timeout)? {
+            Some(ServerMessage::Welcome {
+                protocol_version, ..
+            }) if protocol_version != PROTOCOL_VERSION => Err(CodecError::VersionMismatch {
+                expected: PROTOCOL_VERSION,
+                found: protocol_version,
+            }),
+            Some(ServerMessage::Welcome { compression, .. }) => {
+                self.codec.set_compression(compression);
+                Ok(())
+            }
+            Some(msg) => {
+                self.pending = Some(msg);
+                Ok(())
+            }
+            None => Err(CodecError::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "server not responding",
+            ))),
+        }
+    }
+
+    pub fn send(&mut self, msg: &ClientMessage) -> Result<(), CodecError> {
+        let envelope = Envelope::new(self.sent, msg.clone());
+        self.codec.write(&envelope, &mut self.transport)?;
+        self.sent += 1;
+        Ok(self.transport.flush()?)
+    }
+
+    /// Flushes what was sent and ends the connection, so that the session lets go of it right
+    /// away instead of when this process exits. Dropping the client does the same.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.transport.flush()?;
+        self.transport.shutdown()
+    }
+
+    /// Waits for the next message however long it takes, which only tests can afford, as Ctrl-C
+    /// wouldn't be noticed meanwhile.
+    #[cfg(test)]
+    pub fn recv(&mut self) -> Result<ServerMessage, CodecError> {
+        loop {
+            if let Some(msg) = self.recv_inner(None)? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Returns `None` if no whole message arrived within `timeout`, which must not be zero.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<ServerMessage>, CodecError> {
+        self.recv_inner(Some(timeout))
+    }
+
+    fn recv_inner(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<ServerMessage>, CodecError> {
+        if let Some(msg) = self.pending.take() {
+            return Ok(Some(msg));
+        }
+        self.transport.set_read_timeout(timeout)?;
+
+        let mut chunk = [0_u8; 4096];
+        loop {
+            if let Some(Envelope {
+                seq, payload: msg, ..
+            }) = self.codec.read_buffered(&mut self.read_buf)?
+            {
+                if let Some(ref mut recorder) = self.recorder {
+                    recorder.record(&msg)?;
+                }
+                let lost = seq.saturating_sub(self.next_seq);
+                self.next_seq = seq + 1;
+                if lost > 0 {
+                    self.pending = Some(msg);
+                    return Ok(Some(ServerMessage::Notice {
+                        level: UnityLogType::Warning,
+                        text: format!("{lost} messages lost, the session dropped them."),
+                    }));
+                }
+                return Ok(Some(msg));
+            }
+            match self.transport.read(&mut chunk) {
+                Ok(0) => return Err(CodecError::ConnectionClosed),
+                Ok(n) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for UnityClient {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        time::{Duration, Instant},
+    };
+
+    use common::{
+        ClientMessage, CodecError, Compression, Envelope, ServerMessage, SyncHeteroCodec,
+        PROTOCOL_VERSION,
+    };
+
+    use crate::client::{Transport, UnityClient};
+
+    /// Writes what a session would, numbering the messages from `first_seq` on.
+    fn send_as_server_from(stream: &mut TcpStream, first_seq: u64, msgs: &[ServerMessage]) {
+        let codec = SyncHeteroCodec::<Envelope<ServerMessage>, ClientMessage>::new();
+        for (seq, msg) in (first_seq..).zip(msgs) {
+            codec
+                .write(&Envelope::new(seq, msg.clone()), stream)
+                .unwrap();
+        }
+        stream.flush().unwrap();
+    }
+
+    fn send_as_server(stream: &mut TcpStream, msgs: &[ServerMessage]) {
+        send_as_server_from(stream, 0, msgs);
+    }
+
+    fn client_of(listener: &TcpListener) -> UnityClient {
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        UnityClient::new(Transport::Plain(stream))
+    }
+
+    #[test]
+    fn silent_server_is_not_responding() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = client_of(&listener);
+        let _accepted = listener.accept().unwrap();
+
+        let started = Instant::now();
+        match client.wait_for_welcome(Duration::from_millis(100)) {
+            Err(CodecError::Io(e)) => assert_eq!("server not responding", e.to_string()),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn messages_before_the_welcome_are_kept() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = client_of(&listener);
+        let (mut accepted, _) = listener.accept().unwrap();
+        send_as_server(
+            &mut accepted,
+            &[ServerMessage::Rejected {
+                reason: "full".to_owned(),
+            }],
+        );
+
+        client.wait_for_welcome(Duration::from_secs(1)).unwrap();
+        assert!(matches!(
+            client.recv().unwrap(),
+            ServerMessage::Rejected { reason } if reason == "full"
+        ));
+    }
+
+    #[test]
+    fn welcome_is_consumed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = client_of(&listener);
+        let (mut accepted, _) = listener.accept().unwrap();
+        send_as_server(
+            &mut accepted,
+            &[ServerMessage::welcome(), ServerMessage::IsBusy],
+        );
+
+        client.wait_for_welcome(Duration::from_secs(1)).unwrap();
+        assert!(matches!(client.recv().unwrap(), ServerMessage::IsBusy));
+    }
+
+    #[test]
+    fn welcome_of_another_protocol_version_is_a_mismatch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = client_of(&listener);
+        let (mut accepted, _) = listener.accept().unwrap();
+        send_as_server(
+            &mut accepted,
+            &[ServerMessage::Welcome {
+                protocol_version: PROTOCOL_VERSION + 1,
+                compression: Compression::None,
+            }],
+        );
+
+        match client.wait_for_welcome(Duration::from_secs(1)) {
+            Err(CodecError::VersionMismatch { expected, found }) => {
+                assert_eq!((PROTOCOL_VERSION, PROTOCOL_VERSION + 1), (expected, found))
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn frames_after_the_welcome_are_compressed_as_it_says() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = client_of(&listener);
+        let (mut accepted, _) = listener.accept().unwrap();
+        send_as_server(
+            &mut accepted,
+            &[ServerMessage::Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                compression: Compression::Zstd,
+            }],
+        );
+        let mut codec = SyncHeteroCodec::<Envelope<ServerMessage>, Envelope<ClientMessage>>::new();
+        codec.set_compression(Compression::Zstd);
+        codec
+            .write(&Envelope::new(1, ServerMessage::IsBusy), &mut accepted)
+            .unwrap();
+
+        client.wait_for_welcome(Duration::from_secs(1)).unwrap();
+        assert!(matches!(client.recv().unwrap(), ServerMessage::IsBusy));
+        client
+            .send(&ClientMessage::RequestBacklog { count: 3 })
+            .unwrap();
+
+        let hello = SyncHeteroCodec::<(), Envelope<ClientMessage>>::new()
+            .read(&mut accepted)
+            .unwrap();
+        assert!(matches!(
+            hello.payload,
+            ClientMessage::Hello { compression } if compression == Compression::supported()
+        ));
+        assert!(matches!(
+            codec.read(&mut accepted).unwrap().payload,
+            ClientMessage::RequestBacklog { count: 3 }
+        ));
+    }
+
+    #[test]
+    fn gaps_are_reported_as_lost_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = client_of(&listener);
+        let (mut accepted, _) = listener.accept().unwrap();
+        send_as_server(
+            &mut accepted,
+            &[ServerMessage::welcome(), ServerMessage::IsBusy],
+        );
+        send_as_server_from(&mut accepted, 5, &[ServerMessage::AssemblyReloaded]);
+
+        client.wait_for_welcome(Duration::from_secs(1)).unwrap();
+        assert!(matches!(client.recv().unwrap(), ServerMessage::IsBusy));
+        assert!(matches!(
+            client.recv().unwrap(),
+            ServerMessage::Notice { text, .. } if text == "3 messages lost, the session dropped them."
+        ));
+        assert!(matches!(
+            client.recv().unwrap(),
+            ServerMessage::AssemblyReloaded
+        ));
+    }
+
+    #[test]
+    fn closing_ends_the_stream_for_the_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = client_of(&listener);
+        let (mut accepted, _) = listener.accept().unwrap();
+        accepted
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        client.close().unwrap();
+        assert_eq!(0, accepted.read(&mut [0; 1]).unwrap());
+        drop(client);
+
+        let client = client_of(&listener);
+        let (mut accepted, _) = listener.accept().unwrap();
+        accepted
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        drop(client);
+        assert_eq!(0, accepted.read(&mut [0; 1]).unwrap());
+    }
+}
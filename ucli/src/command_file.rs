@@ -0,0 +1,68 @@
+use crate::words::split_words;
+
+/// A command read from a file given to `ucli exec-file`.
+#[derive(Debug, PartialEq)]
+pub struct CommandLine {
+    /// Where in the file it was, starting at 1.
+    pub line: usize,
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+/// Parses a file of commands, one `command arg arg` per line split by [`split_words`], skipping
+/// the lines left without any.
+pub fn parse_command_file(text: &str) -> Result<Vec<CommandLine>, String> {
+    let mut commands = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let mut words = split_words(line).map_err(|e| format!("line {}: {e}", i + 1))?;
+        if words.is_empty() {
+            continue;
+        }
+        let command = words.remove(0);
+        commands.push(CommandLine {
+            line: i + 1,
+            command,
+            args: words,
+        });
+    }
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command_file::{parse_command_file, CommandLine};
+
+    fn command(line: usize, command: &str, args: &[&str]) -> CommandLine {
+        CommandLine {
+            line,
+            command: command.to_owned(),
+            args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let text = "# Rebuild everything\n\
+                    \n\
+                    clean-cache\n   \t\n\
+                    bake-lights Main fast # the quick way\n\
+                    tag v#1\n";
+
+        assert_eq!(
+            Ok(vec![
+                command(3, "clean-cache", &[]),
+                command(5, "bake-lights", &["Main", "fast"]),
+                command(6, "tag", &["v#1"]),
+            ]),
+            parse_command_file(text)
+        );
+    }
+
+    #[test]
+    fn errors_are_reported_with_their_line() {
+        assert_eq!(
+            Err("line 2: unterminated double quote".to_owned()),
+            parse_command_file("ok\nlog \"oops\n")
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// Where Unity writes the editor's log on this platform, if it has a known place.
+pub fn default_editor_log_path() -> Option<PathBuf> {
+    editor_log_path(std::env::consts::OS, std::env::var_os)
+}
+
+/// Where Unity writes the editor's log on `os`, as named by [`std::env::consts::OS`], looking up
+/// the environment with `var`.
+fn editor_log_path(os: &str, var: impl Fn(&'static str) -> Option<OsString>) -> Option<PathBuf> {
+    let (base, dirs): (_, &[&str]) = match os {
+        "macos" => (var("HOME")?, &["Library", "Logs", "Unity"]),
+        "windows" => (var("LOCALAPPDATA")?, &["Unity", "Editor"]),
+        "linux" => (var("HOME")?, &[".config", "unity3d"]),
+        _ => return None,
+    };
+    let mut path = PathBuf::from(base);
+    path.extend(dirs);
+    path.push("Editor.log");
+    Some(path)
+}
+
+/// Reads the lines appended to a log file, like `tail -f`.
+pub struct LogFollower {
+    reader: BufReader<File>,
+    /// Where the next read starts.
+    position: u64,
+    /// The start of a line whose end wasn't written yet.
+    partial: String,
+}
+
+impl LogFollower {
+    /// Starts at the end of the file, so only lines written from now on are read.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let position = file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            reader: BufReader::new(file),
+            position,
+            partial: String::new(),
+        })
+    }
+
+    /// Returns the lines completed since the last call, without their line endings.
+    ///
+    /// Unity starts the log over when the editor restarts, so a file that got shorter is read
+    /// again from its start.
+    pub fn read_lines(&mut self) -> io::Result<Vec<String>> {
+        if self.reader.get_ref().metadata()?.len() < self.position {
+            self.position = self.reader.seek(SeekFrom::Start(0))?;
+            self.partial.clear();
+        }
+
+        let mut lines = Vec::new();
+        loop {
+            let read = self.reader.read_line(&mut self.partial)?;
+            if read == 0 {
+                return Ok(lines);
+            }
+            self.position += read as u64;
+            if self.partial.ends_with('\n') {
+                let line = self.partial.trim_end_matches(['\r', '\n']).to_owned();
+                self.partial.clear();
+                lines.push(line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, fs::OpenOptions, io::Write, path::PathBuf};
+
+    use crate::editor_log::{editor_log_path, LogFollower};
+
+    fn env(key: &str) -> Option<OsString> {
+        match key {
+            "HOME" => Some(OsString::from("/home/dev")),
+            "LOCALAPPDATA" => Some(OsString::from(r"C:\Users\dev\AppData\Local")),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn editor_log_path_per_os() {
+        assert_eq!(
+            Some(PathBuf::from("/home/dev/Library/Logs/Unity/Editor.log")),
+            editor_log_path("macos", env)
+        );
+        assert_eq!(
+            Some(
+                PathBuf::from(r"C:\Users\dev\AppData\Local")
+                    .join("Unity")
+                    .join("Editor")
+                    .join("Editor.log")
+            ),
+            editor_log_path("windows", env)
+        );
+        assert_eq!(
+            Some(PathBuf::from("/home/dev/.config/unity3d/Editor.log")),
+            editor_log_path("linux", env)
+        );
+        assert_eq!(None, editor_log_path("freebsd", env));
+        assert_eq!(None, editor_log_path("macos", |_| None));
+    }
+
+    #[test]
+    fn follower_reads_only_new_complete_lines() {
+        let path = std::env::temp_dir().join(format!("ucli-editor-{}.log", std::process::id()));
+        std::fs::write(&path, "before\n").unwrap();
+        let mut follower = LogFollower::open(&path).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+
+        assert!(follower.read_lines().unwrap().is_empty());
+        write!(file, "first\r\nsec").unwrap();
+        assert_eq!(vec!["first"], follower.read_lines().unwrap());
+        writeln!(file, "ond").unwrap();
+        assert_eq!(vec!["second"], follower.read_lines().unwrap());
+
+        std::fs::write(&path, "restarted\n").unwrap();
+        assert_eq!(vec!["restarted"], follower.read_lines().unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,58 @@
+use std::process::Command;
+
+/// The command given to `--exec-on-finish`, run by the platform's shell once the Unity command
+/// finished. It learns how it ended from `UCLI_SUCCESS`, `1` or `0`, and `UCLI_RESULT`, the
+/// message Unity finished it with.
+pub fn finish_hook(cmd: &str, is_success: bool, result: &str) -> Command {
+    let mut hook = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    hook.arg(cmd)
+        .env("UCLI_SUCCESS", if is_success { "1" } else { "0" })
+        .env("UCLI_RESULT", result);
+    hook
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::finish_hook::finish_hook;
+
+    #[cfg(unix)]
+    #[test]
+    fn hook_sees_how_the_command_ended() {
+        let output = finish_hook(
+            "echo \"$UCLI_SUCCESS $UCLI_RESULT\"",
+            true,
+            "Built 3 scenes",
+        )
+        .output()
+        .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(b"1 Built 3 scenes\n", output.stdout.as_slice());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn hook_sees_how_the_command_ended() {
+        let output = finish_hook("echo %UCLI_SUCCESS% %UCLI_RESULT%", false, "failed")
+            .output()
+            .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(b"0 failed\r\n", output.stdout.as_slice());
+    }
+
+    #[test]
+    fn hook_exit_status_is_reported() {
+        let status = finish_hook("exit 3", true, "").status().unwrap();
+
+        assert_eq!(Some(3), status.code());
+    }
+}
@@ -1,20 +1,1957 @@
-use cli_args::CliArgs;
+use std::{
+    cmp::Reverse,
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
+
+use cli_args::{
+    CliArgs, DiscoveryArgs, GlobalArgs, ListFormat, PlayMode, SessionSelect, WatchFormat,
+};
+use client::UnityClient;
+use command_file::parse_command_file;
+use common::{Builtin, ClientMessage, CodecError, ServerMessage, UnityLogType};
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use editor_log::{default_editor_log_path, LogFollower};
+use finish_hook::finish_hook;
+use message_json::{message_json, MessageSource};
+use recording::{read_recording, Recorder};
+use repl::{parse_repl_line, ReplLine};
+use result_stream::ResultStream;
+use rustyline::{error::ReadlineError, DefaultEditor};
+use service_discovery::{
+    describe_resolved, discover_all_services_with, discover_service, discover_service_stream,
+    discover_service_with, resolved_services, Discovery, MdnsBrowser, ServiceBrowser, UnityService,
+};
+use table::render_table;
+use terminal::{print_loop, LogCounts, TerminalWriter};
+use watch::WatchFilter;
 
 pub mod cli_args;
+mod client;
+mod command_file;
+mod editor_log;
+mod finish_hook;
+mod message_json;
+mod recording;
+mod repl;
+mod result_stream;
 mod service_discovery;
+mod suggestion;
+mod table;
 mod terminal;
+#[cfg(feature = "tls")]
+mod tls;
+mod watch;
+mod words;
+
+const COMMAND_REQUEST_ID: u64 = 1;
+const LIST_COMMANDS_REQUEST_ID: u64 = 2;
+
+/// How often a waiting subcommand checks whether the user pressed Ctrl-C.
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a session may take to greet a new connection unless `--welcome-timeout` says
+/// otherwise.
+const DEFAULT_WELCOME_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub fn run(global_args: GlobalArgs, args: CliArgs) -> ExitCode {
+    let text_sink = match args.output().map(open_output).transpose() {
+        Ok(text_sink) => text_sink,
+        Err(e) => {
+            eprintln!("Failed to open the output file: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let (terminal, printer) = print_loop(
+        std::io::stdout(),
+        std::io::stderr(),
+        text_sink,
+        args.coalesce(),
+        global_args.no_color,
+    );
+    let interrupts = handle_interrupts();
 
-pub fn run(args: CliArgs) {
-    match args {
-        CliArgs::ListSessions { discovery_args } => {}
-        CliArgs::Compile { discovery_args } => {}
+    let is_success = match args {
+        CliArgs::ListSessions {
+            discovery_args,
+            format,
+        } => list_sessions(&terminal, discovery_args, format),
+        CliArgs::Compile {
+            discovery_args,
+            dry_run,
+            all,
+            env,
+        } => run_builtin(
+            &terminal,
+            &interrupts,
+            &Invocation::compile(env),
+            discovery_args,
+            dry_run,
+            all,
+        ),
+        CliArgs::PlayMode {
+            mode,
+            discovery_args,
+            dry_run,
+        } => run_builtin(
+            &terminal,
+            &interrupts,
+            &Invocation::play_mode(mode),
+            discovery_args,
+            dry_run,
+            false,
+        ),
+        CliArgs::Build {
+            target,
+            build_path,
+            discovery_args,
+            dry_run,
+        } => run_builtin(
+            &terminal,
+            &interrupts,
+            &Invocation::build(target, build_path.as_deref()),
+            discovery_args,
+            dry_run,
+            false,
+        ),
         CliArgs::Run {
             command,
             args,
+            named_args,
+            env,
+            discovery_args,
+            dry_run,
+            all,
+            record,
+            output_file,
+            exec_on_finish,
+            summary,
+            stdin,
+            parallel,
+            ..
+        } => match stdin.then(read_stdin).transpose() {
+            Err(e) => {
+                terminal.write_error(format!("Failed to read stdin: {e}"));
+                false
+            }
+            Ok(stdin) => {
+                let invocation = Invocation {
+                    command,
+                    args,
+                    named_args,
+                    env,
+                    stdin,
+                    builtin: None,
+                };
+                if dry_run {
+                    print_dry_run(
+                        &terminal,
+                        &MdnsBrowser,
+                        discovery_args,
+                        all,
+                        &invocation.describe(),
+                    )
+                } else if all {
+                    run_command_on_all(
+                        &terminal,
+                        &interrupts,
+                        &invocation,
+                        discovery_args,
+                        summary,
+                        parallel,
+                    )
+                } else {
+                    let options = RunOptions {
+                        output_file: output_file.as_deref(),
+                        on_finish: exec_on_finish.as_deref(),
+                        summary,
+                    };
+                    run_command(
+                        &terminal,
+                        &interrupts,
+                        &invocation,
+                        discovery_args,
+                        record.as_deref(),
+                        options,
+                    )
+                }
+            }
+        },
+        CliArgs::ExecFile {
+            path,
+            discovery_args,
+            continue_on_error,
+            ..
+        } => exec_file(
+            &terminal,
+            &interrupts,
+            &path,
+            discovery_args,
+            continue_on_error,
+        ),
+        CliArgs::ListCommands {
+            discovery_args,
+            record,
+            ..
+        } => list_commands(&terminal, &interrupts, discovery_args, record.as_deref()),
+        CliArgs::Replay { path, .. } => replay(&terminal, &path),
+        CliArgs::Repl { discovery_args, .. } => repl(&terminal, &interrupts, discovery_args),
+        CliArgs::Watch {
+            discovery_args,
+            grep,
+            count,
+            since,
+            format,
+            reconnect_timeout,
+            log_file,
+            record,
+            ..
+        } => watch(
+            &terminal,
+            &interrupts,
+            discovery_args,
+            log_file,
+            record.as_deref(),
+            WatchOptions {
+                since,
+                filter: WatchFilter::new(grep, count),
+                format,
+                reconnect_timeout,
+            },
+        ),
+    };
+
+    drop(terminal);
+    let _ = printer.join();
+
+    if is_success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Appends to `path`, so that several runs can share a log.
+fn open_output(path: &Path) -> std::io::Result<Box<dyn std::io::Write + Send>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    Ok(Box::new(file))
+}
+
+fn read_stdin() -> std::io::Result<Vec<u8>> {
+    let mut input = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut input)?;
+    Ok(input)
+}
+
+/// Input longer than this is sent in several messages, keeping each well below the frame limit.
+const STDIN_CHUNK_LEN: usize = 1024 * 1024;
+
+/// A command for Unity, as given to `ucli run`.
+#[derive(Clone)]
+struct Invocation {
+    command: String,
+    args: Vec<String>,
+    named_args: Vec<(String, String)>,
+    env: Vec<(String, String)>,
+    /// What was piped to `ucli run --stdin`.
+    stdin: Option<Vec<u8>>,
+    /// Set for what the editor does by itself, which is asked for as such instead of as a custom
+    /// command. The rest only describes it then.
+    builtin: Option<Builtin>,
+}
+
+impl Invocation {
+    /// Something Unity does on its own, rather than a command registered by the project,
+    /// described as `command` with `named_args`.
+    fn builtin(builtin: Builtin, command: &str, named_args: Vec<(String, String)>) -> Self {
+        Self {
+            command: command.to_owned(),
+            args: vec![],
+            named_args,
+            env: vec![],
+            stdin: None,
+            builtin: Some(builtin),
+        }
+    }
+
+    fn play_mode(mode: PlayMode) -> Self {
+        let builtin = match mode {
+            PlayMode::Play => Builtin::Play,
+            PlayMode::Pause => Builtin::Pause,
+            PlayMode::Stop => Builtin::StopPlay,
+        };
+        Self::builtin(builtin, mode.command(), vec![])
+    }
+
+    fn compile(env: Vec<(String, String)>) -> Self {
+        Self {
+            env: env.clone(),
+            ..Self::builtin(Builtin::Compile { env }, "compile", vec![])
+        }
+    }
+
+    /// What `ucli build` asks for. Unity builds for the active target into the last used
+    /// location for whatever isn't given.
+    fn build(target: Option<String>, path: Option<&Path>) -> Self {
+        let path = path.map(|path| path.display().to_string());
+        let named_args = [
+            target.clone().map(|target| ("target".to_owned(), target)),
+            path.clone().map(|path| ("path".to_owned(), path)),
+        ];
+        Self::builtin(
+            Builtin::Build { target, path },
+            "build",
+            named_args.into_iter().flatten().collect(),
+        )
+    }
+
+    fn describe(&self) -> String {
+        let mut description = std::iter::once(self.command.as_str())
+            .chain(self.args.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if !self.named_args.is_empty() {
+            let named_args: Vec<_> = self
+                .named_args
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect();
+            description.push_str(&format!(" ({})", named_args.join(", ")));
+        }
+        if !self.env.is_empty() {
+            let env: Vec<_> = self.env.iter().map(|(k, v)| format!("{k}={v}")).collect();
+            description.push_str(&format!(" [env: {}]", env.join(" ")));
+        }
+        if let Some(ref stdin) = self.stdin {
+            description.push_str(&format!(" [stdin: {} bytes]", stdin.len()));
+        }
+        description
+    }
+
+    /// The messages to send for the command: its input in chunks, if it is too long to fit in
+    /// the request, then the request itself.
+    fn to_requests(&self) -> Vec<ClientMessage> {
+        let cwd = std::env::current_dir()
+            .ok()
+            .and_then(|dir| dir.into_os_string().into_string().ok());
+        if let Some(ref builtin) = self.builtin {
+            return vec![ClientMessage::BuiltinRequest {
+                request_id: COMMAND_REQUEST_ID,
+                builtin: builtin.clone(),
+                cwd,
+            }];
+        }
+        let stdin = self.stdin.as_deref().unwrap_or_default();
+        // Whatever doesn't fill a whole chunk goes in the request.
+        let ahead = stdin.len().saturating_sub(1) / STDIN_CHUNK_LEN * STDIN_CHUNK_LEN;
+        let chunks = stdin[..ahead]
+            .chunks(STDIN_CHUNK_LEN)
+            .map(|data| ClientMessage::StdinChunk {
+                request_id: COMMAND_REQUEST_ID,
+                data: data.to_vec(),
+            });
+        let request = ClientMessage::CommandRequest {
+            request_id: COMMAND_REQUEST_ID,
+            cmd: self.command.clone(),
+            args: self.args.clone(),
+            named_args: self.named_args.clone(),
+            cwd,
+            env: self.env.clone(),
+            stdin: self.stdin.as_ref().map(|_| stdin[ahead..].to_vec()),
+        };
+        chunks.chain(std::iter::once(request)).collect()
+    }
+}
+
+/// How often Ctrl-C was pressed since [`rearm_interrupts`] last ran.
+static INTERRUPT_PRESSES: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs the Ctrl-C handler. The first press is delivered through the returned receiver, so
+/// that the running subcommand can wind down, and the second one exits right away.
+fn handle_interrupts() -> Receiver<()> {
+    let (tx, rx) = crossbeam::channel::bounded(1);
+    let _ = ctrlc::set_handler(move || {
+        if INTERRUPT_PRESSES.fetch_add(1, Ordering::Relaxed) > 0 {
+            terminal::reset_colors();
+            std::process::exit(130);
+        }
+        let _ = tx.try_send(());
+    });
+    rx
+}
+
+/// Forgets the presses of Ctrl-C so far, so that the next one is delivered through `interrupts`
+/// again instead of exiting.
+fn rearm_interrupts(interrupts: &Receiver<()>) {
+    while interrupts.try_recv().is_ok() {}
+    INTERRUPT_PRESSES.store(0, Ordering::Relaxed);
+}
+
+enum Event {
+    Message(ServerMessage),
+    Interrupted,
+}
+
+/// Waits for the next message from Unity, or for the user to press Ctrl-C.
+fn next_event(client: &mut UnityClient, interrupts: &Receiver<()>) -> Result<Event, CodecError> {
+    loop {
+        if interrupts.try_recv().is_ok() {
+            return Ok(Event::Interrupted);
+        }
+        if let Some(msg) = client.recv_timeout(INTERRUPT_POLL_INTERVAL)? {
+            return Ok(Event::Message(msg));
+        }
+    }
+}
+
+/// Picks the single session `browser` hears of matching `discovery_args`, or explains why there is
+/// none.
+fn resolve_session(
+    terminal: &TerminalWriter,
+    browser: &dyn ServiceBrowser,
+    discovery_args: DiscoveryArgs,
+) -> Option<UnityService> {
+    let select = discovery_args.select;
+    pick_session(
+        terminal,
+        discover_service_with(browser, discovery_args),
+        select,
+    )
+}
+
+/// Picks the single session that was discovered, or one of several as `select` says, or
+/// explains why there is none.
+fn pick_session(
+    terminal: &TerminalWriter,
+    discovery: Discovery,
+    select: SessionSelect,
+) -> Option<UnityService> {
+    let Discovery {
+        mut services,
+        filtered_out,
+    } = discovery;
+    match services.len() {
+        0 => {
+            write_none_found(terminal, filtered_out);
+            None
+        }
+        1 => Some(services.remove(0)),
+        _ => match select_session(services, select) {
+            Ok(service) => Some(service),
+            Err(e) => {
+                terminal.write_error(e);
+                None
+            }
+        },
+    }
+}
+
+/// Picks one of several matching sessions as `select` says. Ties between sessions started at the
+/// same time go to the first by name, so the pick doesn't depend on the order they were found in.
+fn select_session(
+    mut services: Vec<UnityService>,
+    select: SessionSelect,
+) -> Result<UnityService, String> {
+    services.sort_by(|a, b| a.session_name.cmp(&b.session_name));
+    match select {
+        SessionSelect::First => Ok(services.remove(0)),
+        SessionSelect::Newest => {
+            if let Some(unknown) = services.iter().find(|s| s.started_at.is_none()) {
+                return Err(format!(
+                    "Multiple Unity sessions found, but {} doesn't advertise when it started, pick one with --session instead.",
+                    unknown.session_name
+                ));
+            }
+            let (newest, _) = services
+                .iter()
+                .enumerate()
+                .max_by_key(|(i, service)| (service.started_at, Reverse(*i)))
+                .unwrap();
+            Ok(services.remove(newest))
+        }
+        SessionSelect::Error => {
+            let names: Vec<_> = services.iter().map(|s| s.session_name.as_str()).collect();
+            Err(format!(
+                "Multiple Unity sessions found, pick one with --session or --select, or use --all: {}",
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+/// Like [`resolve_session`], but keeps every matching session.
+fn resolve_all_sessions(
+    terminal: &TerminalWriter,
+    browser: &dyn ServiceBrowser,
+    discovery_args: DiscoveryArgs,
+) -> Option<Vec<UnityService>> {
+    let Discovery {
+        services,
+        filtered_out,
+    } = discover_all_services_with(browser, discovery_args);
+    if services.is_empty() {
+        write_none_found(terminal, filtered_out);
+        return None;
+    }
+    Some(services)
+}
+
+fn write_none_found(terminal: &TerminalWriter, filtered_out: usize) {
+    match filtered_out {
+        0 => terminal.write_error("No Unity session found."),
+        1 => terminal.write_error("Found a Unity session, but it doesn't match the filters."),
+        n => terminal.write_error(format!(
+            "Found {n} Unity sessions, but none of them match the filters."
+        )),
+    }
+}
+
+fn connect(
+    terminal: &TerminalWriter,
+    service: &UnityService,
+    tls_ca: Option<&Path>,
+    welcome_timeout: Duration,
+) -> Option<UnityClient> {
+    for address in &service.addresses {
+        match greeted(
+            UnityClient::connect(service, *address, tls_ca),
+            welcome_timeout,
+        ) {
+            Ok(client) => return Some(client),
+            Err(e) => terminal.write_error(format!(
+                "Failed to connect to {} ({address}): {e}",
+                service.session_name
+            )),
+        }
+    }
+    None
+}
+
+/// Waits for the session behind a new connection to greet it.
+fn greeted(
+    client: std::io::Result<UnityClient>,
+    welcome_timeout: Duration,
+) -> Result<UnityClient, CodecError> {
+    let mut client = client?;
+    client.wait_for_welcome(welcome_timeout)?;
+    Ok(client)
+}
+
+/// Connects to the single session matching `discovery_args`, recording what it sends to `record`
+/// if given.
+fn connect_to_session(
+    terminal: &TerminalWriter,
+    discovery_args: DiscoveryArgs,
+    record: Option<&Path>,
+) -> Option<UnityClient> {
+    let client = open_session(terminal, discovery_args)?;
+    start_recording(terminal, client, record)
+}
+
+/// Records what `client` receives to `record`, if given.
+fn start_recording(
+    terminal: &TerminalWriter,
+    mut client: UnityClient,
+    record: Option<&Path>,
+) -> Option<UnityClient> {
+    if let Some(path) = record {
+        match Recorder::create(path) {
+            Ok(recorder) => client.record_to(recorder),
+            Err(e) => {
+                terminal.write_error(format!("Failed to create {}: {e}", path.display()));
+                return None;
+            }
+        }
+    }
+    Some(client)
+}
+
+fn open_session(terminal: &TerminalWriter, discovery_args: DiscoveryArgs) -> Option<UnityClient> {
+    let welcome_timeout = welcome_timeout_of(&discovery_args);
+    if let Some(ref socket) = discovery_args.socket {
+        return match greeted(UnityClient::connect_unix(socket), welcome_timeout) {
+            Ok(client) => Some(client),
+            Err(e) => {
+                terminal.write_error(format!("Failed to connect to {}: {e}", socket.display()));
+                None
+            }
+        };
+    }
+    if let Some(ref pipe) = discovery_args.pipe {
+        return match greeted(UnityClient::connect_pipe(pipe), welcome_timeout) {
+            Ok(client) => Some(client),
+            Err(e) => {
+                terminal.write_error(format!("Failed to connect to pipe {pipe}: {e}"));
+                None
+            }
+        };
+    }
+
+    let tls_ca = discovery_args.tls_ca.clone();
+    let service = resolve_session(terminal, &MdnsBrowser, discovery_args)?;
+    connect(terminal, &service, tls_ca.as_deref(), welcome_timeout)
+}
+
+fn welcome_timeout_of(discovery_args: &DiscoveryArgs) -> Duration {
+    discovery_args
+        .welcome_timeout
+        .unwrap_or(DEFAULT_WELCOME_TIMEOUT)
+}
+
+/// Prints where `command` would be sent, without connecting to the sessions `browser` hears of.
+fn print_dry_run(
+    terminal: &TerminalWriter,
+    browser: &dyn ServiceBrowser,
+    discovery_args: DiscoveryArgs,
+    all: bool,
+    command: &str,
+) -> bool {
+    if let Some(ref socket) = discovery_args.socket {
+        terminal.write_message(format!("Socket:  {}\nCommand: {command}", socket.display()));
+        return true;
+    }
+    if let Some(ref pipe) = discovery_args.pipe {
+        terminal.write_message(format!("Pipe:    {pipe}\nCommand: {command}"));
+        return true;
+    }
+
+    let services = if all {
+        resolve_all_sessions(terminal, browser, discovery_args)
+    } else {
+        resolve_session(terminal, browser, discovery_args).map(|service| vec![service])
+    };
+    let Some(services) = services else {
+        return false;
+    };
+
+    for service in services {
+        terminal.write_message(format!(
+            "Session: {} ({})\nProject: {} ({})\nUnity:   {}\nCommand: {command}",
+            service.session_name,
+            service.address(),
+            service.project,
+            service.path.display(),
+            service.unity_version,
+        ));
+    }
+    true
+}
+
+fn run_command(
+    terminal: &TerminalWriter,
+    interrupts: &Receiver<()>,
+    invocation: &Invocation,
+    discovery_args: DiscoveryArgs,
+    record: Option<&Path>,
+    options: RunOptions,
+) -> bool {
+    let Some(mut client) = connect_to_session(terminal, discovery_args, record) else {
+        return false;
+    };
+    execute(terminal, interrupts, &mut client, invocation, options)
+}
+
+/// Runs the commands of the file at `path` one after the other on the same session, like
+/// [`run_command`] runs one, then says how many succeeded. Stops at the first one that fails
+/// unless `continue_on_error`, and fails if any did.
+fn exec_file(
+    terminal: &TerminalWriter,
+    interrupts: &Receiver<()>,
+    path: &Path,
+    discovery_args: DiscoveryArgs,
+    continue_on_error: bool,
+) -> bool {
+    let commands = match std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| parse_command_file(&text))
+    {
+        Ok(commands) => commands,
+        Err(e) => {
+            terminal.write_error(format!("Failed to read {}: {e}", path.display()));
+            return false;
+        }
+    };
+    let Some(mut client) = connect_to_session(terminal, discovery_args, None) else {
+        return false;
+    };
+
+    let (mut succeeded, mut failed) = (0, 0);
+    for command in &commands {
+        let invocation = Invocation {
+            command: command.command.clone(),
+            args: command.args.clone(),
+            named_args: vec![],
+            env: vec![],
+            stdin: None,
+            builtin: None,
+        };
+        terminal.write_message(format!("[line {}] {}", command.line, invocation.describe()));
+        if execute(
+            terminal,
+            interrupts,
+            &mut client,
+            &invocation,
+            RunOptions::default(),
+        ) {
+            succeeded += 1;
+        } else {
+            failed += 1;
+            if !continue_on_error {
+                break;
+            }
+        }
+    }
+
+    let skipped = commands.len() - succeeded - failed;
+    let mut summary = format!("{succeeded} succeeded, {failed} failed");
+    if skipped > 0 {
+        summary.push_str(&format!(", {skipped} skipped"));
+    }
+    if failed == 0 {
+        terminal.write_message(summary);
+    } else {
+        terminal.write_error(summary);
+    }
+    failed == 0
+}
+
+/// How many sessions a built-in command given `--all` runs on at once, like `run --all` does
+/// unless told otherwise.
+const BUILTIN_PARALLEL: usize = 4;
+
+/// Runs a built-in command like [`run_command`] does without any of its options, on every
+/// matching session if `all` is set, or only says what it would run.
+fn run_builtin(
+    terminal: &TerminalWriter,
+    interrupts: &Receiver<()>,
+    invocation: &Invocation,
+    discovery_args: DiscoveryArgs,
+    dry_run: bool,
+    all: bool,
+) -> bool {
+    if dry_run {
+        return print_dry_run(
+            terminal,
+            &MdnsBrowser,
+            discovery_args,
+            all,
+            &invocation.describe(),
+        );
+    }
+    if all {
+        return run_command_on_all(
+            terminal,
+            interrupts,
+            invocation,
             discovery_args,
-        } => {}
-        _ => {
-            todo!()
+            false,
+            BUILTIN_PARALLEL,
+        );
+    }
+    run_command(
+        terminal,
+        interrupts,
+        invocation,
+        discovery_args,
+        None,
+        RunOptions::default(),
+    )
+}
+
+/// Runs the command on every matching session at once. Fails if it failed on any of them.
+fn run_command_on_all(
+    terminal: &TerminalWriter,
+    interrupts: &Receiver<()>,
+    invocation: &Invocation,
+    discovery_args: DiscoveryArgs,
+    summary: bool,
+    parallel: usize,
+) -> bool {
+    let tls_ca = discovery_args.tls_ca.clone();
+    let welcome_timeout = welcome_timeout_of(&discovery_args);
+    let Some(services) = resolve_all_sessions(terminal, &MdnsBrowser, discovery_args) else {
+        return false;
+    };
+
+    let sessions: Vec<_> = services
+        .into_iter()
+        .map(|service| {
+            let terminal = terminal.with_label(service.session_name.trim_end_matches('.'));
+            (terminal, service)
+        })
+        .collect();
+    let invocation = invocation.clone();
+    run_in_parallel(
+        interrupts,
+        sessions,
+        parallel,
+        move |(terminal, service): (TerminalWriter, UnityService), interrupts| {
+            connect(&terminal, &service, tls_ca.as_deref(), welcome_timeout).is_some_and(
+                |mut client| {
+                    let options = RunOptions {
+                        summary,
+                        ..RunOptions::default()
+                    };
+                    execute(&terminal, interrupts, &mut client, &invocation, options)
+                },
+            )
+        },
+    )
+}
+
+/// Calls `run` for every session on a thread of its own, at most `parallel` at once, and returns
+/// whether every call succeeded. Ctrl-C is passed on to the running calls, and the sessions
+/// still waiting for their turn are skipped.
+fn run_in_parallel<T, F>(
+    interrupts: &Receiver<()>,
+    sessions: Vec<T>,
+    parallel: usize,
+    run: F,
+) -> bool
+where
+    T: Send + 'static,
+    F: Fn(T, &Receiver<()>) -> bool + Send + Sync + 'static,
+{
+    let run = std::sync::Arc::new(run);
+    let (slot_tx, slot_rx) = crossbeam::channel::bounded(parallel);
+    for _ in 0..parallel {
+        let _ = slot_tx.send(());
+    }
+
+    let (done_tx, done_rx) = crossbeam::channel::unbounded();
+    let mut session_interrupts = Vec::with_capacity(sessions.len());
+    for session in sessions {
+        let (interrupt_tx, interrupt_rx) = crossbeam::channel::bounded(1);
+        session_interrupts.push(interrupt_tx);
+
+        let run = run.clone();
+        let (slot_tx, slot_rx) = (slot_tx.clone(), slot_rx.clone());
+        let done_tx = done_tx.clone();
+        std::thread::spawn(move || {
+            crossbeam::channel::select! {
+                recv(interrupt_rx) -> _ => {
+                    let _ = done_tx.send(false);
+                    return;
+                }
+                recv(slot_rx) -> _ => {}
+            }
+            let is_success = run(session, &interrupt_rx);
+            let _ = slot_tx.send(());
+            let _ = done_tx.send(is_success);
+        });
+    }
+    drop(done_tx);
+
+    let mut is_success = true;
+    loop {
+        crossbeam::channel::select! {
+            recv(interrupts) -> _ => {
+                for interrupt_tx in &session_interrupts {
+                    let _ = interrupt_tx.try_send(());
+                }
+            }
+            recv(done_rx) -> done => match done {
+                Ok(done) => is_success &= done,
+                Err(_) => return is_success,
+            },
+        }
+    }
+}
+
+/// How `ucli run` handles what a command sends back.
+#[derive(Clone, Copy, Default)]
+struct RunOptions<'a> {
+    /// Where to write the command's results, instead of printing them.
+    output_file: Option<&'a Path>,
+    /// Shell command to run once the command finished, which has to succeed as well.
+    on_finish: Option<&'a str>,
+    /// Print one line with the outcome and how many warnings and errors were logged, instead of
+    /// the console output.
+    summary: bool,
+}
+
+/// Sends the command and waits for it to finish, handling its output as `options` say.
+fn execute(
+    terminal: &TerminalWriter,
+    interrupts: &Receiver<()>,
+    client: &mut UnityClient,
+    invocation: &Invocation,
+    options: RunOptions,
+) -> bool {
+    let RunOptions {
+        output_file,
+        on_finish,
+        summary,
+    } = options;
+    for request in invocation.to_requests() {
+        if let Err(e) = client.send(&request) {
+            terminal.write_error(format!("Failed to send the command: {e}"));
+            return false;
+        }
+    }
+
+    let mut results_saved = true;
+    let mut stream: Option<ResultStream<Box<dyn Write>>> = None;
+    let mut counts = LogCounts::default();
+    loop {
+        match next_event(client, interrupts) {
+            Ok(Event::Interrupted) => {
+                terminal.write_message("Cancelling the command, press Ctrl-C again to quit...");
+                let cancel = ClientMessage::CancelCommand {
+                    request_id: COMMAND_REQUEST_ID,
+                };
+                if let Err(e) = client.send(&cancel) {
+                    terminal.write_error(format!("Failed to cancel the command: {e}"));
+                    return false;
+                }
+            }
+            Ok(Event::Message(ServerMessage::CommandFinished {
+                request_id,
+                is_success,
+                msg,
+            })) => {
+                let result = msg.clone().unwrap_or_default();
+                if !summary {
+                    terminal.write_server_msg(ServerMessage::CommandFinished {
+                        request_id,
+                        is_success,
+                        msg,
+                    });
+                }
+                let is_complete = stream.as_ref().is_none_or(|s| s.is_finished());
+                if !is_complete {
+                    terminal.write_error("The command finished before its whole result arrived.");
+                }
+                let is_success = is_success && results_saved && is_complete;
+                if summary {
+                    let line = counts.summary(&invocation.command, is_success);
+                    if is_success {
+                        terminal.write_message(line);
+                    } else {
+                        terminal.write_error(line);
+                    }
+                }
+                return match on_finish {
+                    Some(cmd) => run_finish_hook(terminal, cmd, is_success, &result) && is_success,
+                    None => is_success,
+                };
+            }
+            Ok(Event::Message(ServerMessage::ResultChunk {
+                seq, data, is_last, ..
+            })) => {
+                if !receive_chunk(terminal, output_file, &mut stream, seq, &data, is_last) {
+                    return false;
+                }
+            }
+            Ok(Event::Message(msg @ ServerMessage::CommandResult { .. })) => match output_file {
+                Some(path) => results_saved &= save_result(terminal, path, &msg),
+                None => terminal.write_server_msg(msg),
+            },
+            Ok(Event::Message(msg @ ServerMessage::CommandRejected { .. })) => {
+                terminal.write_server_msg(msg);
+                if invocation.builtin.is_none() {
+                    suggest_command(terminal, interrupts, client, &invocation.command);
+                }
+                return false;
+            }
+            Ok(Event::Message(msg @ ServerMessage::Rejected { .. })) => {
+                terminal.write_server_msg(msg);
+                return false;
+            }
+            Ok(Event::Message(msg @ ServerMessage::UnityConsoleOutput { .. })) => {
+                counts.count(&msg);
+                if !summary {
+                    terminal.write_server_msg(msg);
+                }
+            }
+            Ok(Event::Message(msg)) => terminal.write_server_msg(msg),
+            Err(e) => {
+                terminal.write_error(format!("Lost connection to Unity: {e}"));
+                return false;
+            }
+        }
+    }
+}
+
+/// Runs the `--exec-on-finish` command and waits for it, returning whether it succeeded.
+fn run_finish_hook(terminal: &TerminalWriter, cmd: &str, is_success: bool, result: &str) -> bool {
+    match finish_hook(cmd, is_success, result).status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            terminal.write_error(format!("`{cmd}` failed with {status}."));
+            false
+        }
+        Err(e) => {
+            terminal.write_error(format!("Failed to run `{cmd}`: {e}"));
+            false
+        }
+    }
+}
+
+/// Writes the payload of a `CommandResult` to `path`, and returns whether it could.
+fn save_result(terminal: &TerminalWriter, path: &Path, msg: &ServerMessage) -> bool {
+    let ServerMessage::CommandResult {
+        payload,
+        content_type,
+        ..
+    } = msg
+    else {
+        return true;
+    };
+    match std::fs::write(path, payload) {
+        Ok(()) => {
+            terminal.write_message(format!(
+                "Wrote the result ({content_type}, {} bytes) to {}",
+                payload.len(),
+                path.display()
+            ));
+            true
         }
+        Err(e) => {
+            terminal.write_error(format!("Failed to write {}: {e}", path.display()));
+            false
+        }
+    }
+}
+
+/// Writes a chunk of a streamed result to `output_file`, or prints it if not given. Returns whether
+/// it could, which it can't if a chunk went missing.
+fn receive_chunk(
+    terminal: &TerminalWriter,
+    output_file: Option<&Path>,
+    stream: &mut Option<ResultStream<Box<dyn Write>>>,
+    seq: u32,
+    data: &[u8],
+    is_last: bool,
+) -> bool {
+    if stream.is_none() {
+        let sink: Box<dyn Write> = match output_file {
+            Some(path) => match File::create(path) {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    terminal.write_error(format!("Failed to create {}: {e}", path.display()));
+                    return false;
+                }
+            },
+            None => Box::new(terminal.clone()),
+        };
+        *stream = Some(ResultStream::new(sink));
+    }
+    let Some(stream) = stream else {
+        return false;
+    };
+    if let Err(e) = stream.push(seq, data, is_last) {
+        terminal.write_error(format!("Failed to receive the result: {e}"));
+        return false;
+    }
+    if let Some(path) = output_file.filter(|_| is_last) {
+        terminal.write_message(format!(
+            "Wrote the result ({} bytes) to {}",
+            stream.received(),
+            path.display()
+        ));
+    }
+    true
+}
+
+fn suggest_command(
+    terminal: &TerminalWriter,
+    interrupts: &Receiver<()>,
+    client: &mut UnityClient,
+    command: &str,
+) {
+    let request = ClientMessage::ListCommands {
+        request_id: LIST_COMMANDS_REQUEST_ID,
+    };
+    if client.send(&request).is_err() {
+        return;
+    }
+
+    loop {
+        match next_event(client, interrupts) {
+            Ok(Event::Message(ServerMessage::CommandList {
+                request_id: LIST_COMMANDS_REQUEST_ID,
+                commands,
+            })) => {
+                if let Some(suggestion) = suggestion::closest_match(command, &commands) {
+                    terminal.write_message(format!(
+                        "Unknown command '{command}'; did you mean '{suggestion}'?"
+                    ));
+                }
+                return;
+            }
+            Ok(Event::Message(_)) => {}
+            Ok(Event::Interrupted) | Err(_) => return,
+        }
+    }
+}
+
+/// Prints each session as soon as it is discovered.
+fn list_sessions(
+    terminal: &TerminalWriter,
+    discovery_args: DiscoveryArgs,
+    format: ListFormat,
+) -> bool {
+    if format == ListFormat::Raw {
+        let mut resolved = false;
+        for (info, mismatch) in resolved_services(discovery_args) {
+            terminal.write_message(describe_resolved(&info, mismatch.as_deref()));
+            resolved = true;
+        }
+        if !resolved {
+            terminal.write_message("Nothing was resolved.");
+        }
+        return true;
+    }
+
+    let mut rows = Vec::new();
+    let mut sessions = Vec::new();
+    for service in discover_service_stream(discovery_args) {
+        if format == ListFormat::Json {
+            sessions.push(service.to_json());
+            continue;
+        }
+        let compatibility = if service.is_compatible() {
+            ""
+        } else {
+            " (incompatible)"
+        };
+        let address = service.address();
+        let row = vec![
+            service.session_name.trim_end_matches('.').to_owned(),
+            service.project,
+            service.unity_version,
+            format!("{address}{compatibility}"),
+        ];
+        if format == ListFormat::Plain {
+            terminal.write_message(row.join("\t"));
+        }
+        rows.push(row);
+    }
+
+    if format == ListFormat::Json {
+        terminal.write_message(serde_json::Value::Array(sessions).to_string());
+    } else if rows.is_empty() {
+        terminal.write_message("No Unity session found.");
+    } else if format == ListFormat::Table {
+        terminal.write_message(render_table(
+            &["SESSION", "PROJECT", "UNITY", "ADDRESS"],
+            &rows,
+        ));
+    }
+    true
+}
+
+/// Where `watch` reads what Unity logs from.
+enum WatchSource {
+    Session(Box<WatchedSession>),
+    LogFile(PathBuf),
+}
+
+/// A connection to the session, what to tell about it, and how to find it again.
+struct WatchedSession {
+    client: UnityClient,
+    source: MessageSource,
+    reconnect_args: DiscoveryArgs,
+}
+
+impl WatchSource {
+    fn session(client: UnityClient, source: MessageSource, reconnect_args: DiscoveryArgs) -> Self {
+        Self::Session(Box::new(WatchedSession {
+            client,
+            source,
+            reconnect_args,
+        }))
+    }
+}
+
+/// What `watch` prints, and how.
+struct WatchOptions {
+    /// How many console messages from before connecting to print first.
+    since: Option<u32>,
+    filter: WatchFilter,
+    format: WatchFormat,
+    /// How long to wait for a session that closed the connection while reloading its scripts,
+    /// zero to give up right away.
+    reconnect_timeout: Duration,
+}
+
+/// Prints a message `watch` accepted in `format`, as one line flushed right away for `ndjson`.
+fn write_watched(
+    terminal: &TerminalWriter,
+    format: WatchFormat,
+    source: &MessageSource,
+    msg: ServerMessage,
+) {
+    match format {
+        WatchFormat::Human => terminal.write_server_msg(msg),
+        WatchFormat::Ndjson => {
+            let line = format!("{}\n", message_json(&msg, SystemTime::now(), source));
+            // Raw output is flushed after every write.
+            let _ = terminal.clone().write_all(line.as_bytes());
+        }
+    }
+}
+
+/// Connects to the session to watch, or settles for a log file when asked to, or when no session
+/// is found and the editor's log exists.
+fn watch_source(
+    terminal: &TerminalWriter,
+    discovery_args: DiscoveryArgs,
+    log_file: Option<Option<PathBuf>>,
+    record: Option<&Path>,
+    format: WatchFormat,
+) -> Option<WatchSource> {
+    match log_file {
+        Some(Some(path)) => return Some(WatchSource::LogFile(path)),
+        Some(None) => {
+            let Some(path) = default_editor_log_path() else {
+                terminal.write_error(
+                    "Unity's Editor.log has no known location on this platform, pass its path to --log-file.",
+                );
+                return None;
+            };
+            return Some(WatchSource::LogFile(path));
+        }
+        None => {}
+    }
+    let mut reconnect_args = discovery_args.clone();
+    if discovery_args.socket.is_some() || discovery_args.pipe.is_some() {
+        return connect_to_session(terminal, discovery_args, record)
+            .map(|client| WatchSource::session(client, MessageSource::default(), reconnect_args));
+    }
+
+    let tls_ca = discovery_args.tls_ca.clone();
+    let welcome_timeout = welcome_timeout_of(&discovery_args);
+    let select = discovery_args.select;
+    let discovery = discover_service(discovery_args);
+    if discovery.services.is_empty() {
+        if let Some(path) = default_editor_log_path().filter(|path| path.is_file()) {
+            write_none_found(terminal, discovery.filtered_out);
+            // Kept out of the JSON lines, which go to stdout as well.
+            if format == WatchFormat::Human {
+                terminal.write_message(format!("Tailing {} instead.", path.display()));
+            }
+            return Some(WatchSource::LogFile(path));
+        }
+    }
+    let service = pick_session(terminal, discovery, select)?;
+    let client = connect(terminal, &service, tls_ca.as_deref(), welcome_timeout)?;
+    if service.session_id.is_some() {
+        reconnect_args.session_id = service.session_id.clone();
+    }
+    let source = MessageSource {
+        session: Some(service.session_name.trim_end_matches('.').to_owned()),
+        session_id: service.session_id,
+    };
+    start_recording(terminal, client, record)
+        .map(|client| WatchSource::session(client, source, reconnect_args))
+}
+
+/// How often [`reconnect`] tries again.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+enum Reconnection {
+    Connected(UnityClient),
+    Interrupted,
+    TimedOut,
+}
+
+/// Connects to the session matching `discovery_args` again, trying until `timeout` passes or the
+/// user presses Ctrl-C. Failed attempts aren't reported, as the session is expected to be gone
+/// for a while.
+fn reconnect(
+    interrupts: &Receiver<()>,
+    discovery_args: &DiscoveryArgs,
+    timeout: Duration,
+) -> Reconnection {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(client) = reopen_session(discovery_args) {
+            return Reconnection::Connected(client);
+        }
+        if Instant::now() >= deadline {
+            return Reconnection::TimedOut;
+        }
+        if !matches!(
+            interrupts.recv_timeout(RECONNECT_INTERVAL),
+            Err(RecvTimeoutError::Timeout)
+        ) {
+            return Reconnection::Interrupted;
+        }
+    }
+}
+
+/// Like [`open_session`], but quiet about failing.
+fn reopen_session(discovery_args: &DiscoveryArgs) -> Option<UnityClient> {
+    let welcome_timeout = welcome_timeout_of(discovery_args);
+    if let Some(ref socket) = discovery_args.socket {
+        return greeted(UnityClient::connect_unix(socket), welcome_timeout).ok();
+    }
+    if let Some(ref pipe) = discovery_args.pipe {
+        return greeted(UnityClient::connect_pipe(pipe), welcome_timeout).ok();
+    }
+    let mut services = discover_service(discovery_args.clone()).services;
+    if services.len() != 1 {
+        return None;
+    }
+    let service = services.remove(0);
+    service.addresses.iter().find_map(|address| {
+        let client = UnityClient::connect(&service, *address, discovery_args.tls_ca.as_deref());
+        greeted(client, welcome_timeout).ok()
+    })
+}
+
+/// Prints what Unity sends until the user presses Ctrl-C, or the filter has printed enough.
+/// Starts with up to `since` console messages from before connecting, if given.
+fn watch(
+    terminal: &TerminalWriter,
+    interrupts: &Receiver<()>,
+    discovery_args: DiscoveryArgs,
+    log_file: Option<Option<PathBuf>>,
+    record: Option<&Path>,
+    options: WatchOptions,
+) -> bool {
+    let WatchOptions {
+        since,
+        mut filter,
+        format,
+        reconnect_timeout,
+    } = options;
+    let WatchedSession {
+        mut client,
+        source,
+        reconnect_args,
+    } = match watch_source(terminal, discovery_args, log_file, record, format) {
+        Some(WatchSource::Session(session)) => *session,
+        Some(WatchSource::LogFile(path)) => {
+            return tail_log_file(terminal, interrupts, &path, filter, format)
+        }
+        None => return false,
+    };
+    if let Some(count) = since {
+        if let Err(e) = client.send(&ClientMessage::RequestBacklog { count }) {
+            terminal.write_error(format!("Failed to request the backlog: {e}"));
+            return false;
+        }
+    }
+
+    // Between the session saying it reloads its scripts and that it's done.
+    let mut is_reloading = false;
+    loop {
+        match next_event(&mut client, interrupts) {
+            Ok(Event::Message(msg @ ServerMessage::Rejected { .. })) => {
+                write_watched(terminal, format, &source, msg);
+                return false;
+            }
+            Ok(Event::Message(msg)) => {
+                match msg {
+                    ServerMessage::AssemblyReloading => is_reloading = true,
+                    ServerMessage::AssemblyReloaded => is_reloading = false,
+                    _ => {}
+                }
+                if filter.accept(&msg) {
+                    write_watched(terminal, format, &source, msg);
+                }
+                if filter.is_done() {
+                    return true;
+                }
+            }
+            Ok(Event::Interrupted) => return true,
+            Err(e) if is_reloading && !reconnect_timeout.is_zero() => {
+                // Kept out of the JSON lines, which go to stdout as well.
+                if format == WatchFormat::Human {
+                    terminal.write_message(
+                        "Unity closed the connection while reloading, reconnecting...",
+                    );
+                }
+                match reconnect(interrupts, &reconnect_args, reconnect_timeout) {
+                    Reconnection::Connected(mut reconnected) => {
+                        if let Some(recorder) = client.take_recorder() {
+                            reconnected.record_to(recorder);
+                        }
+                        client = reconnected;
+                        is_reloading = false;
+                        if format == WatchFormat::Human {
+                            terminal.write_message("Reconnected.");
+                        }
+                    }
+                    Reconnection::Interrupted => return true,
+                    Reconnection::TimedOut => {
+                        terminal.write_error(format!(
+                            "Lost connection to Unity: {e}, and it didn't come back within {} ms.",
+                            reconnect_timeout.as_millis()
+                        ));
+                        return false;
+                    }
+                }
+            }
+            Err(e) => {
+                terminal.write_error(format!("Lost connection to Unity: {e}"));
+                return false;
+            }
+        }
+    }
+}
+
+/// Prints the lines appended to `path` as console messages, until interrupted.
+fn tail_log_file(
+    terminal: &TerminalWriter,
+    interrupts: &Receiver<()>,
+    path: &Path,
+    mut filter: WatchFilter,
+    format: WatchFormat,
+) -> bool {
+    let mut follower = match LogFollower::open(path) {
+        Ok(follower) => follower,
+        Err(e) => {
+            terminal.write_error(format!("Failed to open {}: {e}", path.display()));
+            return false;
+        }
+    };
+
+    loop {
+        let lines = match follower.read_lines() {
+            Ok(lines) => lines,
+            Err(e) => {
+                terminal.write_error(format!("Failed to read {}: {e}", path.display()));
+                return false;
+            }
+        };
+        for log in lines {
+            let msg = ServerMessage::UnityConsoleOutput {
+                log_type: UnityLogType::Log,
+                log,
+                stack_trace: String::new(),
+            };
+            if filter.accept(&msg) {
+                write_watched(terminal, format, &MessageSource::default(), msg);
+            }
+            if filter.is_done() {
+                return true;
+            }
+        }
+        if !matches!(
+            interrupts.recv_timeout(INTERRUPT_POLL_INTERVAL),
+            Err(RecvTimeoutError::Timeout)
+        ) {
+            return true;
+        }
+    }
+}
+
+fn list_commands(
+    terminal: &TerminalWriter,
+    interrupts: &Receiver<()>,
+    discovery_args: DiscoveryArgs,
+    record: Option<&Path>,
+) -> bool {
+    let Some(mut client) = connect_to_session(terminal, discovery_args, record) else {
+        return false;
+    };
+    request_command_list(terminal, interrupts, &mut client)
+}
+
+/// Asks for the custom commands of the session and prints them.
+fn request_command_list(
+    terminal: &TerminalWriter,
+    interrupts: &Receiver<()>,
+    client: &mut UnityClient,
+) -> bool {
+    let request = ClientMessage::ListCommands {
+        request_id: LIST_COMMANDS_REQUEST_ID,
+    };
+    if let Err(e) = client.send(&request) {
+        terminal.write_error(format!("Failed to request the command list: {e}"));
+        return false;
+    }
+
+    loop {
+        match next_event(client, interrupts) {
+            Ok(Event::Message(msg @ ServerMessage::CommandList { .. })) => {
+                terminal.write_server_msg(msg);
+                return true;
+            }
+            Ok(Event::Message(msg @ ServerMessage::Rejected { .. })) => {
+                terminal.write_server_msg(msg);
+                return false;
+            }
+            Ok(Event::Message(msg)) => terminal.write_server_msg(msg),
+            Ok(Event::Interrupted) => {
+                terminal.write_error("Interrupted.");
+                return false;
+            }
+            Err(e) => {
+                terminal.write_error(format!("Lost connection to Unity: {e}"));
+                return false;
+            }
+        }
+    }
+}
+
+/// Prompts for commands to run on the session, one at a time, until `:quit` or Ctrl-D. Ctrl-C
+/// cancels the running command, or drops what was typed at the prompt.
+fn repl(
+    terminal: &TerminalWriter,
+    interrupts: &Receiver<()>,
+    discovery_args: DiscoveryArgs,
+) -> bool {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            terminal.write_error(format!("Failed to start the prompt: {e}"));
+            return false;
+        }
+    };
+    let Some(mut client) = connect_to_session(terminal, discovery_args, None) else {
+        return false;
+    };
+
+    loop {
+        terminal.wait_emitted();
+        let line = match editor.readline("ucli> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return true,
+            Err(e) => {
+                terminal.write_error(format!("Failed to read the prompt: {e}"));
+                return false;
+            }
+        };
+        let _ = editor.add_history_entry(line.as_str());
+
+        // Ctrl-C at the prompt doesn't reach the handler, but a press while the last command
+        // wound down would still count towards exiting.
+        rearm_interrupts(interrupts);
+        match parse_repl_line(&line) {
+            Ok(Some(ReplLine::Quit)) => return true,
+            Ok(Some(ReplLine::Commands)) => {
+                request_command_list(terminal, interrupts, &mut client);
+            }
+            Ok(Some(ReplLine::Run { command, args })) => {
+                let invocation = Invocation {
+                    command,
+                    args,
+                    named_args: vec![],
+                    env: vec![],
+                    stdin: None,
+                    builtin: None,
+                };
+                execute(
+                    terminal,
+                    interrupts,
+                    &mut client,
+                    &invocation,
+                    RunOptions::default(),
+                );
+            }
+            Ok(None) => {}
+            Err(e) => terminal.write_error(e),
+        }
+    }
+}
+
+/// Prints the messages of a `--record`ed session as if they were just received.
+fn replay(terminal: &TerminalWriter, path: &Path) -> bool {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            terminal.write_error(format!("Failed to open {}: {e}", path.display()));
+            return false;
+        }
+    };
+
+    for msg in read_recording(std::io::BufReader::new(file)) {
+        match msg {
+            Ok(msg) => terminal.write_server_msg(msg),
+            Err(e) => {
+                terminal.write_error(format!("Failed to read {}: {e}", path.display()));
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, SocketAddrV4, TcpListener},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use common::{
+        Builtin, ClientMessage, Envelope, ServerMessage, SyncHeteroCodec, MDNS_SERVICE_NAME,
+        PROJECT_NAME_PROP_KEY, PROJECT_PATH_PROP_KEY, SESSION_ID_PROP_KEY, UNITY_VERSION_PROP_KEY,
+    };
+    use mdns_sd::{ServiceEvent, ServiceInfo};
+
+    use crate::{
+        cli_args::{DiscoveryArgs, PlayMode, SessionSelect, WatchFormat},
+        connect, print_dry_run, run_in_parallel, select_session,
+        service_discovery::{BrowseEvents, ServiceBrowser, UnityService},
+        terminal::print_loop,
+        watch,
+        watch::WatchFilter,
+        Invocation, WatchOptions, STDIN_CHUNK_LEN,
+    };
+
+    /// Hears of a single session, which resolves right away.
+    struct ResolvingBrowser(ServiceInfo);
+
+    impl ServiceBrowser for ResolvingBrowser {
+        fn browse(&self, _: Instant) -> BrowseEvents {
+            let mut event = Some(ServiceEvent::ServiceResolved(self.0.clone()));
+            Box::new(move || event.take())
+        }
+    }
+
+    #[test]
+    fn dry_run_does_not_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let props = [
+            (PROJECT_PATH_PROP_KEY, "foo/bar/dry-run"),
+            (PROJECT_NAME_PROP_KEY, "My Unity Project"),
+            (UNITY_VERSION_PROP_KEY, "2023.5.30"),
+            (SESSION_ID_PROP_KEY, "dry-run-session"),
+        ];
+        let info = ServiceInfo::new(
+            MDNS_SERVICE_NAME,
+            "dry-run",
+            "localhost.local.",
+            "127.0.0.1",
+            listener.local_addr().unwrap().port(),
+            &props[..],
+        )
+        .unwrap();
+        let browser = ResolvingBrowser(info);
+
+        let (terminal, printer) = print_loop(std::io::sink(), std::io::sink(), None, false, false);
+        let discovery_args = DiscoveryArgs {
+            path: None,
+            project: None,
+            session: None,
+            project_regex: None,
+            session_regex: None,
+            session_id: Some("dry-run-session".to_owned()),
+            host: None,
+            discovery_timeout: Some(Duration::from_millis(5000)),
+            discovery_retries: 0,
+            welcome_timeout: None,
+            tls_ca: None,
+            interface: None,
+            socket: None,
+            pipe: None,
+            match_props: vec![],
+            select: SessionSelect::Error,
+        };
+        let is_success = print_dry_run(&terminal, &browser, discovery_args, false, "foo bar");
+        drop(terminal);
+        printer.join().unwrap();
+
+        assert!(is_success);
+        assert_eq!(
+            std::io::ErrorKind::WouldBlock,
+            listener.accept().unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn connect_falls_back_to_next_address() {
+        let local_address = |listener: &TcpListener| {
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port())
+        };
+        let dead_address = local_address(&TcpListener::bind("127.0.0.1:0").unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_address = local_address(&listener);
+        let session = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            SyncHeteroCodec::<Envelope<ServerMessage>, ClientMessage>::new()
+                .write(&Envelope::from(ServerMessage::welcome()), &mut stream)
+                .unwrap();
+            stream
+        });
+        let service = UnityService {
+            addresses: vec![dead_address, live_address],
+            hostname: "localhost.local.".to_owned(),
+            path: PathBuf::from("foo/bar/baz"),
+            project: "My Unity Project".to_owned(),
+            unity_version: "2023.5.30".to_owned(),
+            session_name: "foo-bar.".to_owned(),
+            session_id: None,
+            protocol_version: 0,
+            tls_fingerprint: None,
+            started_at: None,
+            properties: HashMap::new(),
+        };
+
+        let (terminal, printer) = print_loop(std::io::sink(), std::io::sink(), None, false, false);
+        let client = connect(&terminal, &service, None, Duration::from_secs(1));
+        drop(terminal);
+        printer.join().unwrap();
+
+        assert!(client.is_some());
+        assert!(session.join().is_ok());
+    }
+
+    fn candidate(session_name: &str, started_at: Option<u64>) -> UnityService {
+        UnityService {
+            addresses: vec![SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4242)],
+            hostname: "localhost.local.".to_owned(),
+            path: PathBuf::from("foo/bar/baz"),
+            project: "My Unity Project".to_owned(),
+            unity_version: "2023.5.30".to_owned(),
+            session_name: session_name.to_owned(),
+            session_id: None,
+            protocol_version: 0,
+            tls_fingerprint: None,
+            started_at,
+            properties: HashMap::new(),
+        }
+    }
+
+    fn selected(
+        candidates: &[(&str, Option<u64>)],
+        select: SessionSelect,
+    ) -> Result<String, String> {
+        let candidates = candidates
+            .iter()
+            .map(|(name, started_at)| candidate(name, *started_at))
+            .collect();
+        select_session(candidates, select).map(|service| service.session_name)
+    }
+
+    #[test]
+    fn first_selects_the_lowest_name() {
+        let candidates = [
+            ("quiet-lynx.", Some(3)),
+            ("brave-otter.", None),
+            ("calm-heron.", Some(1)),
+        ];
+
+        assert_eq!(
+            Ok("brave-otter.".to_owned()),
+            selected(&candidates, SessionSelect::First)
+        );
+    }
+
+    #[test]
+    fn newest_selects_the_latest_start() {
+        let candidates = [
+            ("brave-otter.", Some(1_700_000_000_000)),
+            ("quiet-lynx.", Some(1_700_000_300_000)),
+            ("calm-heron.", Some(1_700_000_200_000)),
+        ];
+        assert_eq!(
+            Ok("quiet-lynx.".to_owned()),
+            selected(&candidates, SessionSelect::Newest)
+        );
+
+        let tied = [("quiet-lynx.", Some(5)), ("brave-otter.", Some(5))];
+        assert_eq!(
+            Ok("brave-otter.".to_owned()),
+            selected(&tied, SessionSelect::Newest)
+        );
+
+        let unknown = [("quiet-lynx.", Some(5)), ("brave-otter.", None)];
+        assert_eq!(
+            Err("Multiple Unity sessions found, but brave-otter. doesn't advertise when it started, pick one with --session instead.".to_owned()),
+            selected(&unknown, SessionSelect::Newest)
+        );
+    }
+
+    #[test]
+    fn error_selects_nothing_and_lists_the_candidates() {
+        let candidates = [("quiet-lynx.", Some(3)), ("brave-otter.", Some(1))];
+
+        assert_eq!(
+            Err("Multiple Unity sessions found, pick one with --session or --select, or use --all: brave-otter., quiet-lynx.".to_owned()),
+            selected(&candidates, SessionSelect::Error)
+        );
+    }
+
+    #[test]
+    fn build_asks_only_for_what_was_given() {
+        assert_eq!("build", Invocation::build(None, None).describe());
+        assert_eq!(
+            "build (target=android, path=Builds/game.apk)",
+            Invocation::build(
+                Some("android".to_owned()),
+                Some(Path::new("Builds/game.apk"))
+            )
+            .describe()
+        );
+        assert_eq!(
+            "build (path=Builds)",
+            Invocation::build(None, Some(Path::new("Builds"))).describe()
+        );
+        assert!(matches!(
+            Invocation::build(Some("android".to_owned()), None).to_requests()[..],
+            [ClientMessage::BuiltinRequest {
+                builtin: Builtin::Build {
+                    target: Some(ref target),
+                    path: None,
+                },
+                ..
+            }] if target == "android"
+        ));
+    }
+
+    #[test]
+    fn play_mode_is_asked_for_as_a_builtin() {
+        let requests = Invocation::play_mode(PlayMode::Stop).to_requests();
+
+        assert!(matches!(
+            requests[..],
+            [ClientMessage::BuiltinRequest {
+                builtin: Builtin::StopPlay,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn compile_is_asked_for_with_its_env() {
+        let env = vec![("CI".to_owned(), "1".to_owned())];
+        let invocation = Invocation::compile(env.clone());
+
+        assert_eq!("compile [env: CI=1]", invocation.describe());
+        match &invocation.to_requests()[..] {
+            [ClientMessage::BuiltinRequest {
+                builtin: Builtin::Compile { env: sent },
+                ..
+            }] => assert_eq!(&env, sent),
+            requests => panic!("unexpected requests: {requests:?}"),
+        }
+    }
+
+    #[test]
+    fn long_stdin_is_sent_ahead_in_chunks() {
+        let requests = |stdin: Option<Vec<u8>>| {
+            Invocation {
+                command: "eval".to_owned(),
+                args: vec![],
+                named_args: vec![],
+                env: vec![],
+                stdin,
+                builtin: None,
+            }
+            .to_requests()
+        };
+        let sent = |requests: Vec<ClientMessage>| {
+            let mut sent = (Vec::new(), None);
+            for request in requests {
+                match request {
+                    ClientMessage::StdinChunk { data, .. } => sent.0.push(data.len()),
+                    ClientMessage::CommandRequest { stdin, .. } => {
+                        sent.1 = stdin.map(|stdin| stdin.len())
+                    }
+                    request => panic!("unexpected request: {request:?}"),
+                }
+            }
+            sent
+        };
+
+        assert_eq!((vec![], None), sent(requests(None)));
+        assert_eq!((vec![], Some(0)), sent(requests(Some(vec![]))));
+        assert_eq!(
+            (vec![], Some(STDIN_CHUNK_LEN)),
+            sent(requests(Some(vec![0; STDIN_CHUNK_LEN])))
+        );
+        assert_eq!(
+            (vec![STDIN_CHUNK_LEN, STDIN_CHUNK_LEN], Some(1)),
+            sent(requests(Some(vec![0; 2 * STDIN_CHUNK_LEN + 1])))
+        );
+    }
+
+    #[test]
+    fn at_most_parallel_sessions_run_at_once() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let most_active = Arc::new(AtomicUsize::new(0));
+        let ran = Arc::new(AtomicUsize::new(0));
+        let (_interrupt_tx, interrupts) = crossbeam::channel::bounded(1);
+
+        let is_success = {
+            let (active, most_active, ran) = (active.clone(), most_active.clone(), ran.clone());
+            run_in_parallel(&interrupts, (0..10).collect(), 3, move |session, _| {
+                let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+                most_active.fetch_max(now_active, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                active.fetch_sub(1, Ordering::SeqCst);
+                ran.fetch_add(1, Ordering::SeqCst);
+                session != 7
+            })
+        };
+
+        assert!(!is_success);
+        assert_eq!(10, ran.load(Ordering::SeqCst));
+        assert_eq!(3, most_active.load(Ordering::SeqCst));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn watch_reconnects_when_a_reload_closes_the_connection() {
+        let path = std::env::temp_dir().join(format!("ucli-reload-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let session = std::thread::spawn(move || {
+            let codec = SyncHeteroCodec::<Envelope<ServerMessage>, ClientMessage>::new();
+            let accept = |msgs: Vec<ServerMessage>| {
+                let (mut stream, _) = listener.accept().unwrap();
+                for (seq, msg) in (0..).zip(msgs) {
+                    codec.write(&Envelope::new(seq, msg), &mut stream).unwrap();
+                }
+                stream
+            };
+            drop(accept(vec![
+                ServerMessage::welcome(),
+                ServerMessage::AssemblyReloading,
+            ]));
+            accept(vec![
+                ServerMessage::welcome(),
+                ServerMessage::AssemblyReloaded,
+                ServerMessage::UnityConsoleOutput {
+                    log_type: common::UnityLogType::Log,
+                    log: "back".to_owned(),
+                    stack_trace: String::new(),
+                },
+            ])
+        });
+
+        let (terminal, printer) = print_loop(std::io::sink(), std::io::sink(), None, false, false);
+        let (_interrupt, interrupts) = crossbeam::channel::bounded(1);
+        let is_success = watch(
+            &terminal,
+            &interrupts,
+            DiscoveryArgs::builder().socket(&path).build(),
+            None,
+            None,
+            WatchOptions {
+                since: None,
+                filter: WatchFilter::new(None, Some(1)),
+                format: WatchFormat::Human,
+                reconnect_timeout: Duration::from_secs(5),
+            },
+        );
+        drop(terminal);
+        printer.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert!(is_success);
+        assert!(session.join().is_ok());
     }
 }
@@ -1,6 +1,8 @@
+use std::process::ExitCode;
+
 use ucli::{cli_args::get_cli_args, run};
 
-pub fn main() {
-    let args = get_cli_args();
-    run(args);
+pub fn main() -> ExitCode {
+    let (global_args, args) = get_cli_args();
+    run(global_args, args)
 }
@@ -0,0 +1,95 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::ServerMessage;
+use serde_json::Value;
+
+/// Version of the objects [`message_json`] builds, bumped whenever a field is renamed, removed or
+/// changes meaning, but not when one is added.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The session messages printed as JSON came from, named like `list-sessions --format json`
+/// does. Unknown when connected through a socket or pipe, or tailing a log file.
+#[derive(Clone, Debug, Default)]
+pub struct MessageSource {
+    pub session: Option<String>,
+    pub session_id: Option<String>,
+}
+
+/// `msg` as the object every machine-readable output of ucli prints for it: its `type` and fields
+/// as recordings have them, `request_id` included for the messages about a request, along with
+/// `schema_version`, `received_at_ms` since the Unix epoch, and where it came from.
+pub fn message_json(msg: &ServerMessage, received_at: SystemTime, source: &MessageSource) -> Value {
+    let mut json = serde_json::to_value(msg).expect("messages always serialize");
+    let received_at_ms = received_at
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    if let Some(fields) = json.as_object_mut() {
+        fields.insert("schema_version".to_owned(), SCHEMA_VERSION.into());
+        fields.insert("received_at_ms".to_owned(), received_at_ms.into());
+        fields.insert("session".to_owned(), source.session.clone().into());
+        fields.insert("session_id".to_owned(), source.session_id.clone().into());
+    }
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use common::{ServerMessage, UnityLogType};
+
+    use crate::message_json::{message_json, MessageSource};
+
+    #[test]
+    fn lines_snapshot() {
+        let received_at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let source = MessageSource {
+            session: Some("brave-otter".to_owned()),
+            session_id: Some("7f3c".to_owned()),
+        };
+        let lines: Vec<_> = [
+            ServerMessage::UnityConsoleOutput {
+                log_type: UnityLogType::Warning,
+                log: "Missing \"Player\" tag".to_owned(),
+                stack_trace: String::new(),
+            },
+            ServerMessage::CompilationStarted,
+            ServerMessage::CommandRejected {
+                request_id: 1,
+                reason: "no such command".to_owned(),
+            },
+        ]
+        .iter()
+        .map(|msg| message_json(msg, received_at, &source).to_string())
+        .collect();
+
+        assert_eq!(
+            vec![
+                "{\"log\":\"Missing \\\"Player\\\" tag\",\"log_type\":\"Warning\",\
+                 \"received_at_ms\":1700000000123,\"schema_version\":1,\
+                 \"session\":\"brave-otter\",\"session_id\":\"7f3c\",\"stack_trace\":\"\",\
+                 \"type\":\"UnityConsoleOutput\"}",
+                "{\"received_at_ms\":1700000000123,\"schema_version\":1,\
+                 \"session\":\"brave-otter\",\"session_id\":\"7f3c\",\
+                 \"type\":\"CompilationStarted\"}",
+                "{\"reason\":\"no such command\",\"received_at_ms\":1700000000123,\
+                 \"request_id\":1,\"schema_version\":1,\"session\":\"brave-otter\",\
+                 \"session_id\":\"7f3c\",\"type\":\"CommandRejected\"}",
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn unknown_source_is_null() {
+        let json = message_json(
+            &ServerMessage::AssemblyReloaded,
+            UNIX_EPOCH,
+            &MessageSource::default(),
+        );
+
+        assert_eq!(serde_json::Value::Null, json["session"]);
+        assert_eq!(serde_json::Value::Null, json["session_id"]);
+        assert_eq!(0, json["received_at_ms"]);
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, LineWriter, Write},
+    path::Path,
+};
+
+use common::ServerMessage;
+
+/// Writes messages as JSON, one per line, so that a recording cut short by Ctrl-C stays readable.
+pub struct Recorder<W> {
+    dst: W,
+}
+
+impl Recorder<LineWriter<File>> {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(LineWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(dst: W) -> Self {
+        Self { dst }
+    }
+
+    pub fn record(&mut self, msg: &ServerMessage) -> io::Result<()> {
+        serde_json::to_writer(&mut self.dst, msg)?;
+        writeln!(self.dst)
+    }
+}
+
+/// Reads back the messages written by a [`Recorder`].
+pub fn read_recording<R: BufRead>(src: R) -> impl Iterator<Item = io::Result<ServerMessage>> {
+    src.lines()
+        .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|line| serde_json::from_str(&line?).map_err(io::Error::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{ServerMessage, UnityLogType};
+
+    use crate::recording::{read_recording, Recorder};
+
+    #[test]
+    fn recording_round_trip() {
+        let messages = vec![
+            ServerMessage::CompilationStarted,
+            ServerMessage::UnityConsoleOutput {
+                log_type: UnityLogType::Warning,
+                log: "line one\nline two".to_owned(),
+                stack_trace: String::new(),
+            },
+            ServerMessage::CommandFinished {
+                request_id: 1,
+                is_success: true,
+                msg: Some("done".to_owned()),
+            },
+        ];
+
+        let mut recorder = Recorder::new(Vec::new());
+        for msg in &messages {
+            recorder.record(msg).unwrap();
+        }
+        let replayed: Vec<_> = read_recording(&recorder.dst[..])
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(format!("{messages:?}"), format!("{replayed:?}"));
+    }
+
+    #[test]
+    fn garbage_line_is_an_error() {
+        let mut replayed = read_recording(&b"{\"IsBusy\"\n"[..]);
+
+        assert!(replayed.next().unwrap().is_err());
+    }
+}
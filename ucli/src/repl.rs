@@ -0,0 +1,70 @@
+use crate::words::split_words;
+
+/// What a line typed at the prompt of `ucli repl` asks for.
+#[derive(Debug, PartialEq)]
+pub enum ReplLine {
+    /// `:quit`, ends the REPL like Ctrl-D does.
+    Quit,
+    /// `:commands`, lists the custom commands of the session.
+    Commands,
+    Run {
+        command: String,
+        args: Vec<String>,
+    },
+}
+
+/// Parses a line typed at the prompt, split by [`split_words`] unless it starts with `:`. `None`
+/// if there is nothing to do.
+pub fn parse_repl_line(line: &str) -> Result<Option<ReplLine>, String> {
+    match line.trim() {
+        ":quit" => return Ok(Some(ReplLine::Quit)),
+        ":commands" => return Ok(Some(ReplLine::Commands)),
+        unknown if unknown.starts_with(':') => {
+            return Err(format!(
+                "Unknown `{unknown}`, use :commands to list the commands or :quit to exit."
+            ))
+        }
+        _ => {}
+    }
+    let mut words = split_words(line)?;
+    if words.is_empty() {
+        return Ok(None);
+    }
+    let command = words.remove(0);
+    Ok(Some(ReplLine::Run {
+        command,
+        args: words,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repl::{parse_repl_line, ReplLine};
+
+    #[test]
+    fn lines_are_commands_with_args() {
+        assert_eq!(
+            Ok(Some(ReplLine::Run {
+                command: "open".to_owned(),
+                args: vec!["Assets/My Scene.unity".to_owned(), "additive".to_owned()],
+            })),
+            parse_repl_line("open 'Assets/My Scene.unity' additive")
+        );
+        assert_eq!(Ok(None), parse_repl_line("   "));
+        assert_eq!(Ok(None), parse_repl_line("# just a note"));
+        assert_eq!(
+            Err("unterminated double quote".to_owned()),
+            parse_repl_line("open \"Assets")
+        );
+    }
+
+    #[test]
+    fn colon_lines_control_the_repl() {
+        assert_eq!(Ok(Some(ReplLine::Quit)), parse_repl_line(":quit"));
+        assert_eq!(Ok(Some(ReplLine::Commands)), parse_repl_line(" :commands "));
+        assert_eq!(
+            Err("Unknown `:help`, use :commands to list the commands or :quit to exit.".to_owned()),
+            parse_repl_line(":help")
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    cmp::Ordering,
+    fmt::{self, Display},
+    io::{self, Write},
+};
+
+/// Writes a result streamed in `ResultChunk`s to a sink as the chunks arrive, so that it never
+/// has to be held in memory at once.
+pub struct ResultStream<W> {
+    sink: W,
+    next_seq: u32,
+    received: u64,
+    is_finished: bool,
+}
+
+#[derive(Debug)]
+pub enum StreamError {
+    /// Chunks before `found` never arrived.
+    Missing {
+        expected: u32,
+        found: u32,
+    },
+    /// A chunk that was already written arrived again.
+    Repeated {
+        seq: u32,
+    },
+    AfterLast {
+        seq: u32,
+    },
+    Io(io::Error),
+}
+
+impl Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing { expected, found } => {
+                write!(f, "expected chunk {expected}, but chunk {found} arrived")
+            }
+            Self::Repeated { seq } => write!(f, "chunk {seq} arrived twice or out of order"),
+            Self::AfterLast { seq } => write!(f, "chunk {seq} arrived after the last one"),
+            Self::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl<W: Write> ResultStream<W> {
+    pub fn new(sink: W) -> Self {
+        Self {
+            sink,
+            next_seq: 0,
+            received: 0,
+            is_finished: false,
+        }
+    }
+
+    /// Writes `data` if it is the chunk expected next.
+    pub fn push(&mut self, seq: u32, data: &[u8], is_last: bool) -> Result<(), StreamError> {
+        if self.is_finished {
+            return Err(StreamError::AfterLast { seq });
+        }
+        match seq.cmp(&self.next_seq) {
+            Ordering::Less => return Err(StreamError::Repeated { seq }),
+            Ordering::Greater => {
+                return Err(StreamError::Missing {
+                    expected: self.next_seq,
+                    found: seq,
+                })
+            }
+            Ordering::Equal => {}
+        }
+        self.sink.write_all(data).map_err(StreamError::Io)?;
+        self.next_seq += 1;
+        self.received += data.len() as u64;
+        if is_last {
+            self.sink.flush().map_err(StreamError::Io)?;
+            self.is_finished = true;
+        }
+        Ok(())
+    }
+
+    /// Whether the last chunk was written.
+    pub fn is_finished(&self) -> bool {
+        self.is_finished
+    }
+
+    /// How many bytes were written so far.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::result_stream::{ResultStream, StreamError};
+
+    #[test]
+    fn chunks_are_written_in_order() {
+        let mut stream = ResultStream::new(Vec::new());
+
+        stream.push(0, b"hello ", false).unwrap();
+        assert!(!stream.is_finished());
+        stream.push(1, b"world", true).unwrap();
+
+        assert!(stream.is_finished());
+        assert_eq!(11, stream.received());
+        assert_eq!(b"hello world", stream.sink.as_slice());
+    }
+
+    #[test]
+    fn missing_chunks_are_detected() {
+        let mut stream = ResultStream::new(Vec::new());
+
+        stream.push(0, b"a", false).unwrap();
+
+        assert!(matches!(
+            stream.push(2, b"c", true),
+            Err(StreamError::Missing {
+                expected: 1,
+                found: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn repeated_and_late_chunks_are_detected() {
+        let mut stream = ResultStream::new(Vec::new());
+
+        stream.push(0, b"a", false).unwrap();
+        assert!(matches!(
+            stream.push(0, b"a", false),
+            Err(StreamError::Repeated { seq: 0 })
+        ));
+
+        stream.push(1, b"b", true).unwrap();
+        assert!(matches!(
+            stream.push(2, b"c", true),
+            Err(StreamError::AfterLast { seq: 2 })
+        ));
+        assert_eq!(b"ab", stream.sink.as_slice());
+    }
+}
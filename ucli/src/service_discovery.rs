@@ -1,118 +1,1022 @@
 use std::{
-    net::SocketAddrV4,
-    path::PathBuf,
-    str::FromStr,
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddrV4},
+    path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 
 use common::{
-    MDNS_SERVICE_NAME, PROJECT_NAME_PROP_KEY, PROJECT_PATH_PROP_KEY, UNITY_VERSION_PROP_KEY,
+    glob_matches, is_glob, BUILTIN_PROP_KEYS, MDNS_SERVICE_NAME, PROJECT_NAME_PROP_KEY,
+    PROJECT_PATH_PROP_KEY, PROTOCOL_VERSION, PROTOCOL_VERSION_PROP_KEY, SESSION_ID_PROP_KEY,
+    STARTED_AT_PROP_KEY, TLS_FINGERPRINT_PROP_KEY, UNITY_VERSION_PROP_KEY,
 };
+use if_addrs::IfAddr;
 use mdns_sd::{IPMulticastTTLOption, ServiceDaemon, ServiceEvent, ServiceInfo};
 
-use crate::cli_args::DiscoveryArgs;
+use crate::cli_args::{AddressPreference, DiscoveryArgs, NameRegex};
 
 pub struct UnityService {
-    address: SocketAddrV4,
-    hostname: String,
-    path: PathBuf,
-    project: String,
-    unity_version: String,
-    session_name: String,
+    /// Every advertised address, best first. Never empty.
+    pub addresses: Vec<SocketAddrV4>,
+    pub hostname: String,
+    pub path: PathBuf,
+    pub project: String,
+    pub unity_version: String,
+    pub session_name: String,
+    /// Missing on servers that predate the property.
+    pub session_id: Option<String>,
+    /// `0` when the server doesn't advertise it, i.e. it predates the property.
+    pub protocol_version: u32,
+    /// Set when the session only accepts TLS connections.
+    pub tls_fingerprint: Option<String>,
+    /// When the session started, in milliseconds since the Unix epoch. Missing on servers that
+    /// predate the property.
+    pub started_at: Option<u64>,
+    /// The extra properties Unity advertised, e.g. the target platform, without the built-in ones.
+    pub properties: HashMap<String, String>,
 }
 
-pub fn discover_service(args: DiscoveryArgs) -> Vec<UnityService> {
-    let daemon = ServiceDaemon::new(IPMulticastTTLOption::LinkLocal).unwrap();
-    let receiver = daemon.browse(MDNS_SERVICE_NAME).unwrap();
-    let mut services = Vec::new();
+impl UnityService {
+    /// The address to try first.
+    pub fn address(&self) -> SocketAddrV4 {
+        self.addresses[0]
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+
+    /// The session as `ucli list-sessions --format json` prints it.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "session": self.session_name.trim_end_matches('.'),
+            "session_id": self.session_id,
+            "project": self.project,
+            "path": self.path.to_string_lossy(),
+            "unity_version": self.unity_version,
+            "hostname": self.hostname,
+            "addresses": self.addresses.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "protocol_version": self.protocol_version,
+            "compatible": self.is_compatible(),
+            "tls_fingerprint": self.tls_fingerprint,
+            "started_at_ms": self.started_at,
+            "properties": self.properties,
+        })
+    }
+
+    /// Identifies the session across the resolutions of its different network interfaces.
+    fn key(&self) -> ServiceKey {
+        match self.session_id {
+            Some(ref session_id) => ServiceKey::SessionId(session_id.clone()),
+            // mDNS keeps instance names unique on the network.
+            None => ServiceKey::Name(self.session_name.clone()),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum ServiceKey {
+    SessionId(String),
+    Name(String),
+}
+
+/// Yields the next event of a browse each call, and `None` once it is over.
+pub type BrowseEvents = Box<dyn FnMut() -> Option<ServiceEvent>>;
+
+/// Where discovery hears about sessions from, so that it can be fed scripted events instead of
+/// multicast DNS.
+pub trait ServiceBrowser {
+    /// Starts browsing for Unity sessions, until `deadline`.
+    fn browse(&self, deadline: Instant) -> BrowseEvents;
+}
+
+/// Browses the local network over multicast DNS, which every discovery does unless given another
+/// [`ServiceBrowser`].
+pub struct MdnsBrowser;
+
+impl ServiceBrowser for MdnsBrowser {
+    fn browse(&self, deadline: Instant) -> BrowseEvents {
+        let daemon = ServiceDaemon::new(IPMulticastTTLOption::LinkLocal).unwrap();
+        let receiver = daemon.browse(MDNS_SERVICE_NAME).unwrap();
+        Box::new(move || {
+            // Browsing lasts as long as the daemon does.
+            let _ = &daemon;
+            receiver.recv_deadline(deadline).ok()
+        })
+    }
+}
+
+/// Yields every match as soon as it resolves, until the discovery timeout.
+pub fn discover_service_stream(args: DiscoveryArgs) -> impl Iterator<Item = UnityService> {
+    let mut seen = HashSet::new();
+    browse(&MdnsBrowser, args)
+        .filter(move |(_, service)| seen.insert(service.key()))
+        .map(|(_, service)| service)
+}
+
+/// What a discovery found, retries included.
+pub struct Discovery {
+    pub services: Vec<UnityService>,
+    /// How many sessions were found but didn't match the filters, to tell that apart from not
+    /// finding any session.
+    pub filtered_out: usize,
+}
+
+/// Stops at the first exact match, otherwise returns every partial match.
+pub fn discover_service(args: DiscoveryArgs) -> Discovery {
+    discover_service_with(&MdnsBrowser, args)
+}
+
+/// Like [`discover_service`], hearing about sessions from `browser`.
+pub fn discover_service_with(browser: &dyn ServiceBrowser, args: DiscoveryArgs) -> Discovery {
+    with_retries(args.discovery_retries, || {
+        collect_discovery(browse(browser, args.clone()), true)
+    })
+}
 
+/// Returns every match, exact or not, that `browser` hears of before the timeout.
+pub fn discover_all_services_with(browser: &dyn ServiceBrowser, args: DiscoveryArgs) -> Discovery {
+    with_retries(args.discovery_retries, || {
+        collect_discovery(browse(browser, args.clone()), false)
+    })
+}
+
+/// Runs `attempt` again while it finds no match, up to `retries` more times.
+fn with_retries(retries: u32, mut attempt: impl FnMut() -> Discovery) -> Discovery {
+    let mut discovery = attempt();
+    for _ in 0..retries {
+        if !discovery.services.is_empty() {
+            break;
+        }
+        let filtered_out = discovery.filtered_out;
+        discovery = attempt();
+        discovery.filtered_out = discovery.filtered_out.max(filtered_out);
+    }
+    discovery
+}
+
+/// Every service resolved until the discovery timeout, unfiltered, with why it doesn't match
+/// `args` if it doesn't. For telling why a session isn't found.
+pub fn resolved_services(
+    args: DiscoveryArgs,
+) -> impl Iterator<Item = (ServiceInfo, Option<String>)> {
+    let mut next_event = browse_events(&MdnsBrowser, &args);
+    std::iter::from_fn(move || loop {
+        if let ServiceEvent::ServiceResolved(info) = next_event()? {
+            let mismatch = check_service(&info, &args).err();
+            return Some((info, mismatch));
+        }
+    })
+}
+
+/// `info` as `list-sessions --list-addresses` prints it, with every advertised property.
+pub fn describe_resolved(info: &ServiceInfo, mismatch: Option<&str>) -> String {
+    let mut addresses: Vec<_> = info.get_addresses().iter().collect();
+    addresses.sort();
+    let mut lines = vec![
+        match mismatch {
+            Some(reason) => format!("{} (filtered out: {reason})", info.get_fullname()),
+            None => info.get_fullname().to_owned(),
+        },
+        format!("  hostname: {}", info.get_hostname()),
+        format!("  port: {}", info.get_port()),
+        format!(
+            "  addresses: {}",
+            addresses
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    ];
+    lines.extend(
+        info.get_properties()
+            .iter()
+            .map(|prop| format!("  {}={}", prop.key(), prop.val_str())),
+    );
+    lines.join("\n")
+}
+
+fn browse(browser: &dyn ServiceBrowser, args: DiscoveryArgs) -> Matches<BrowseEvents> {
+    let next_event = browse_events(browser, &args);
+    matching_services(args, next_event)
+}
+
+fn browse_events(browser: &dyn ServiceBrowser, args: &DiscoveryArgs) -> BrowseEvents {
     let deadline = Instant::now() + args.discovery_timeout.unwrap_or(Duration::from_millis(100));
-    while let Ok(event) = receiver.recv_deadline(deadline) {
-        if let ServiceEvent::ServiceResolved(info) = event {
-            match filter_service(&info, &args) {
-                Some((true, service)) => {
-                    return vec![service];
-                }
-                Some((false, service)) => {
-                    services.push(service);
+    browser.browse(deadline)
+}
+
+/// Filters the services resolved from `next_event` until it runs dry. A session resolved again,
+/// e.g. through another network interface, is yielded again with the addresses of every
+/// resolution so far, ranked together.
+fn matching_services<F>(args: DiscoveryArgs, next_event: F) -> Matches<F>
+where
+    F: FnMut() -> Option<ServiceEvent>,
+{
+    Matches {
+        args,
+        next_event,
+        seen: HashMap::new(),
+        filtered_out: HashSet::new(),
+    }
+}
+
+struct Matches<F> {
+    args: DiscoveryArgs,
+    next_event: F,
+    /// The addresses of every session yielded so far, best first.
+    seen: HashMap<ServiceKey, Vec<SocketAddrV4>>,
+    /// Full names of the services that didn't match.
+    filtered_out: HashSet<String>,
+}
+
+impl<F> Iterator for Matches<F>
+where
+    F: FnMut() -> Option<ServiceEvent>,
+{
+    type Item = (bool, UnityService);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let ServiceEvent::ServiceResolved(info) = (self.next_event)()? {
+                match filter_service(&info, &self.args) {
+                    Some((is_exact_match, mut service)) => {
+                        let addresses = self.seen.entry(service.key()).or_default();
+                        for address in service.addresses.drain(..) {
+                            if !addresses.contains(&address) {
+                                addresses.push(address);
+                            }
+                        }
+                        let preference = address_preference(&service.path, &self.args);
+                        addresses.sort_by_key(|address| {
+                            (
+                                address_rank(address.ip(), preference.as_ref()),
+                                *address.ip(),
+                            )
+                        });
+                        service.addresses = addresses.clone();
+                        return Some((is_exact_match, service));
+                    }
+                    None => {
+                        self.filtered_out.insert(info.get_fullname().to_owned());
+                    }
                 }
-                None => {}
             }
         }
     }
+}
+
+fn collect_discovery<F>(mut matches: Matches<F>, stop_at_exact_match: bool) -> Discovery
+where
+    F: FnMut() -> Option<ServiceEvent>,
+{
+    let services = collect_services(matches.by_ref(), stop_at_exact_match);
+    Discovery {
+        services,
+        filtered_out: matches.filtered_out.len(),
+    }
+}
+
+fn collect_services(
+    found: impl Iterator<Item = (bool, UnityService)>,
+    stop_at_exact_match: bool,
+) -> Vec<UnityService> {
+    let mut services: Vec<UnityService> = Vec::new();
+    for (is_exact_match, service) in found {
+        if is_exact_match && stop_at_exact_match {
+            return vec![service];
+        }
+        // A session resolved again comes with the addresses of its earlier resolutions too.
+        match services.iter_mut().find(|seen| seen.key() == service.key()) {
+            Some(seen) => *seen = service,
+            None => services.push(service),
+        }
+    }
 
     services
 }
 
-fn filter_service(info: &ServiceInfo, args: &DiscoveryArgs) -> Option<(bool, UnityService)> {
-    let address = if let Some(ip) = info.get_addresses().iter().next() {
-        SocketAddrV4::new(ip.to_owned(), info.get_port())
+/// Lower is better: addresses matching `preference` first, then LAN, then anything else. Loopback
+/// only reaches this machine and link-local addresses are the most likely to be stale or
+/// unroutable, so both are picked last unless asked for.
+fn address_rank(ip: &Ipv4Addr, preference: Option<&AddressPreference>) -> (bool, u8) {
+    let is_preferred = preference.is_some_and(|preference| is_preferred(ip, preference));
+    let rank = if ip.is_private() {
+        0
+    } else if ip.is_loopback() {
+        2
+    } else if ip.is_link_local() {
+        3
     } else {
-        return None;
+        1
     };
+    (!is_preferred, rank)
+}
 
-    let path = if let Some(path) = info.get_property_val_str(PROJECT_PATH_PROP_KEY) {
-        if let Ok(path) = PathBuf::from_str(path) {
-            path
-        } else {
-            return None;
+/// The addresses to pick first for the session of the project at `path`, if any.
+fn address_preference(path: &Path, args: &DiscoveryArgs) -> Option<AddressPreference> {
+    // A project found on this machine is most likely opened by a local editor.
+    args.interface
+        .clone()
+        .or_else(|| path.exists().then_some(AddressPreference::Loopback))
+}
+
+fn is_preferred(ip: &Ipv4Addr, preference: &AddressPreference) -> bool {
+    match preference {
+        AddressPreference::Loopback => ip.is_loopback(),
+        AddressPreference::Subnet(network, prefix_len) => {
+            let netmask = u32::MAX
+                .checked_shl(32 - u32::from(*prefix_len))
+                .unwrap_or(0);
+            is_on_subnet(ip, network, netmask.into())
         }
-    } else {
-        return None;
-    };
+        AddressPreference::Interface(name) => if_addrs::get_if_addrs()
+            .unwrap_or_default()
+            .into_iter()
+            .any(|interface| match interface.addr {
+                IfAddr::V4(ref addr) if interface.name == *name => {
+                    is_on_subnet(ip, &addr.ip, addr.netmask)
+                }
+                _ => false,
+            }),
+    }
+}
 
-    let project = if let Some(project) = info.get_property_val_str(PROJECT_NAME_PROP_KEY) {
-        project.to_owned()
-    } else {
-        return None;
+fn is_on_subnet(ip: &Ipv4Addr, network: &Ipv4Addr, netmask: Ipv4Addr) -> bool {
+    let netmask = u32::from(netmask);
+    u32::from(*ip) & netmask == u32::from(*network) & netmask
+}
+
+fn filter_service(info: &ServiceInfo, args: &DiscoveryArgs) -> Option<(bool, UnityService)> {
+    check_service(info, args).ok()
+}
+
+/// Like [`filter_service`], but tells why a service doesn't match, for the diagnostic
+/// `list-sessions --list-addresses`.
+fn check_service(info: &ServiceInfo, args: &DiscoveryArgs) -> Result<(bool, UnityService), String> {
+    let property = |key: &str| {
+        info.get_property_val_str(key)
+            .ok_or_else(|| format!("doesn't advertise `{key}`"))
     };
+    let path = PathBuf::from(property(PROJECT_PATH_PROP_KEY)?);
 
-    let unity_version =
-        if let Some(unity_version) = info.get_property_val_str(UNITY_VERSION_PROP_KEY) {
-            unity_version.to_owned()
-        } else {
-            return None;
-        };
+    let preference = address_preference(&path, args);
+    let mut addresses: Vec<_> = info.get_addresses().iter().copied().collect();
+    if addresses.is_empty() {
+        return Err("advertises no address".to_owned());
+    }
+    addresses.sort_by_key(|ip| (address_rank(ip, preference.as_ref()), *ip));
+    let addresses = addresses
+        .into_iter()
+        .map(|ip| SocketAddrV4::new(ip, info.get_port()))
+        .collect();
+
+    let project = property(PROJECT_NAME_PROP_KEY)?.to_owned();
+    let unity_version = property(UNITY_VERSION_PROP_KEY)?.to_owned();
 
     let session_name = info.get_fullname().replace(MDNS_SERVICE_NAME, "");
 
+    let session_id = info
+        .get_property_val_str(SESSION_ID_PROP_KEY)
+        .map(str::to_owned);
+
+    let protocol_version = info
+        .get_property_val_str(PROTOCOL_VERSION_PROP_KEY)
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+
+    let tls_fingerprint = info
+        .get_property_val_str(TLS_FINGERPRINT_PROP_KEY)
+        .map(str::to_owned);
+
+    let started_at = info
+        .get_property_val_str(STARTED_AT_PROP_KEY)
+        .and_then(|v| v.parse().ok());
+
+    let properties = info
+        .get_properties()
+        .iter()
+        .filter(|prop| !BUILTIN_PROP_KEYS.contains(&prop.key()))
+        .map(|prop| (prop.key().to_owned(), prop.val_str().to_owned()))
+        .collect();
+
     let service = UnityService {
-        address,
+        addresses,
         hostname: info.get_hostname().to_owned(),
         path,
         project,
         unity_version,
         session_name,
+        session_id,
+        protocol_version,
+        tls_fingerprint,
+        started_at,
+        properties,
     };
 
+    // Built-in properties may be matched too, e.g. `unity-version=2022.3.10f1`.
+    for (key, value) in &args.match_props {
+        match property(key)? {
+            advertised if advertised == value => {}
+            advertised => return Err(format!("advertises `{key}={advertised}`, not `{value}`")),
+        }
+    }
+
+    if let Some(ref host_arg) = args.host {
+        if !service
+            .hostname
+            .to_lowercase()
+            .starts_with(&host_arg.to_lowercase())
+        {
+            return Err(format!(
+                "host `{}` doesn't start with `{host_arg}`",
+                service.hostname.trim_end_matches('.')
+            ));
+        }
+    }
+
+    if let Some(ref session_id_arg) = args.session_id {
+        if service.session_id.as_ref() == Some(session_id_arg) {
+            return Ok((true, service));
+        } else {
+            return Err(format!("session id isn't `{session_id_arg}`"));
+        }
+    }
+
     if let Some(ref path_arg) = args.path {
         if let (Ok(path_arg), Ok(path)) = (
             std::fs::canonicalize(path_arg),
             std::fs::canonicalize(&service.path),
         ) {
             if path_arg == path {
-                return Some((true, service));
+                return Ok((true, service));
             } else {
-                return None;
+                return Err(format!("project path isn't {}", path_arg.display()));
             }
         }
     }
 
+    let doesnt_match = |what: &str, name: &str, filter: &str| {
+        Err(format!("{what} `{name}` doesn't match `{filter}`"))
+    };
     if let Some(ref project_arg) = args.project {
-        if !&service.project.starts_with(project_arg) {
-            return None;
-        } else {
-            return Some((&service.project == project_arg, service));
+        return match name_matches(&service.project, project_arg) {
+            Some(is_exact) => Ok((is_exact, service)),
+            None => doesnt_match("project", &service.project, project_arg),
+        };
+    }
+    if let Some(NameRegex(ref regex)) = args.project_regex {
+        if !regex.is_match(&service.project) {
+            return doesnt_match("project", &service.project, regex.as_str());
         }
+        return Ok((false, service));
     }
 
+    let session_name = service.session_name.trim_end_matches('.');
     if let Some(ref session_arg) = args.session {
-        if !&service.session_name.starts_with(session_arg) {
-            return None;
-        } else {
-            return Some((&service.session_name == session_arg, service));
+        return match name_matches(&service.session_name, session_arg) {
+            Some(is_exact) => Ok((is_exact, service)),
+            None => doesnt_match("session", session_name, session_arg),
+        };
+    }
+    if let Some(NameRegex(ref regex)) = args.session_regex {
+        if !regex.is_match(session_name) {
+            return doesnt_match("session", session_name, regex.as_str());
         }
+        return Ok((false, service));
     }
 
-    Some((false, service))
+    Ok((false, service))
+}
+
+/// Whether `name` matches `filter`, and if so whether exactly. Filters with wildcards must match
+/// the whole name and are never exact, since they are meant to pick several sessions; others
+/// match names they are a prefix of.
+fn name_matches(name: &str, filter: &str) -> Option<bool> {
+    if is_glob(filter) {
+        glob_matches(filter, name.trim_end_matches('.')).then_some(false)
+    } else {
+        name.starts_with(filter).then(|| name == filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        collections::{HashMap, VecDeque},
+        time::Instant,
+    };
+
+    use common::{
+        MDNS_SERVICE_NAME, PROJECT_NAME_PROP_KEY, PROJECT_PATH_PROP_KEY, PROTOCOL_VERSION_PROP_KEY,
+        SESSION_ID_PROP_KEY, STARTED_AT_PROP_KEY, UNITY_VERSION_PROP_KEY,
+    };
+    use mdns_sd::{ServiceEvent, ServiceInfo};
+    use regex::Regex;
+
+    use crate::{
+        cli_args::{AddressPreference, DiscoveryArgs, NameRegex, SessionSelect},
+        service_discovery::{
+            check_service, collect_discovery, collect_services, describe_resolved,
+            discover_all_services_with, discover_service_with, filter_service, matching_services,
+            name_matches, with_retries, BrowseEvents, ServiceBrowser,
+        },
+    };
+
+    fn no_filter() -> DiscoveryArgs {
+        DiscoveryArgs {
+            path: None,
+            project: None,
+            session: None,
+            project_regex: None,
+            session_regex: None,
+            session_id: None,
+            host: None,
+            discovery_timeout: None,
+            discovery_retries: 0,
+            welcome_timeout: None,
+            tls_ca: None,
+            interface: None,
+            socket: None,
+            pipe: None,
+            match_props: vec![],
+            select: SessionSelect::Error,
+        }
+    }
+
+    fn service_info(extra_props: &[(&str, &str)]) -> ServiceInfo {
+        named_service_info("foo-bar", extra_props)
+    }
+
+    fn named_service_info(instance_name: &str, extra_props: &[(&str, &str)]) -> ServiceInfo {
+        service_info_at(instance_name, "127.0.0.1", extra_props)
+    }
+
+    fn service_info_at(
+        instance_name: &str,
+        addresses: &str,
+        extra_props: &[(&str, &str)],
+    ) -> ServiceInfo {
+        let mut props = vec![
+            (PROJECT_PATH_PROP_KEY, "foo/bar/baz"),
+            (PROJECT_NAME_PROP_KEY, "My Unity Project"),
+            (UNITY_VERSION_PROP_KEY, "2023.5.30"),
+        ];
+        props.retain(|(key, _)| extra_props.iter().all(|(extra_key, _)| extra_key != key));
+        props.extend_from_slice(extra_props);
+        ServiceInfo::new(
+            MDNS_SERVICE_NAME,
+            instance_name,
+            "localhost.local.",
+            addresses,
+            4242,
+            &props[..],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn resolved_services_are_described_with_every_property() {
+        let info = service_info_at("foo-bar", "192.168.1.7,127.0.0.1", &[("extra", "1")]);
+
+        assert_eq!(
+            format!(
+                "foo-bar.{MDNS_SERVICE_NAME} (filtered out: no reason)\n  \
+                 hostname: localhost.local.\n  \
+                 port: 4242\n  \
+                 addresses: 127.0.0.1, 192.168.1.7\n  \
+                 {PROJECT_PATH_PROP_KEY}=foo/bar/baz\n  \
+                 {PROJECT_NAME_PROP_KEY}=My Unity Project\n  \
+                 {UNITY_VERSION_PROP_KEY}=2023.5.30\n  \
+                 extra=1"
+            ),
+            describe_resolved(&info, Some("no reason"))
+        );
+        assert!(
+            describe_resolved(&info, None).starts_with(&format!("foo-bar.{MDNS_SERVICE_NAME}\n"))
+        );
+    }
+
+    #[test]
+    fn session_filter_globs() {
+        let matches = |session: &str| {
+            let args = DiscoveryArgs {
+                session: Some(session.to_owned()),
+                ..no_filter()
+            };
+            ["game-client", "game-server", "tools"]
+                .into_iter()
+                .filter(|name| filter_service(&named_service_info(name, &[]), &args).is_some())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["game-client", "game-server"], matches("game-*"));
+        assert_eq!(vec!["tools"], matches("t?ols"));
+        assert_eq!(vec!["game-server"], matches("*server"));
+        assert!(matches("game-?").is_empty());
+        // Without wildcards, names are still matched by prefix.
+        assert_eq!(vec!["game-client", "game-server"], matches("game"));
+    }
+
+    #[test]
+    fn session_filter_regex() {
+        let args = DiscoveryArgs {
+            session_regex: Some(NameRegex(Regex::new("^game-.*-[0-9]+$").unwrap())),
+            ..no_filter()
+        };
+        let matching: Vec<_> = ["game-client-1", "game-client", "tools-2"]
+            .into_iter()
+            .filter(|name| filter_service(&named_service_info(name, &[]), &args).is_some())
+            .collect();
+
+        assert_eq!(vec!["game-client-1"], matching);
+    }
+
+    #[test]
+    fn only_literal_filters_match_exactly() {
+        assert_eq!(Some(true), name_matches("My Project", "My Project"));
+        assert_eq!(Some(false), name_matches("My Project", "My"));
+        assert_eq!(Some(false), name_matches("My Project", "My*"));
+        assert_eq!(Some(false), name_matches("My Project", "My Proj?ct"));
+        assert_eq!(None, name_matches("My Project", "Other*"));
+    }
+
+    #[test]
+    fn parse_protocol_version_prop() {
+        let info = service_info(&[(PROTOCOL_VERSION_PROP_KEY, "42")]);
+        let (_, service) = filter_service(&info, &no_filter()).unwrap();
+
+        assert_eq!(42, service.protocol_version);
+    }
+
+    #[test]
+    fn parse_started_at_prop() {
+        let info = service_info(&[(STARTED_AT_PROP_KEY, "1700000000123")]);
+        let (_, service) = filter_service(&info, &no_filter()).unwrap();
+        assert_eq!(Some(1_700_000_000_123), service.started_at);
+
+        let info = service_info(&[(STARTED_AT_PROP_KEY, "yesterday")]);
+        let (_, service) = filter_service(&info, &no_filter()).unwrap();
+        assert_eq!(None, service.started_at);
+    }
+
+    #[test]
+    fn service_as_json() {
+        let info = service_info(&[(SESSION_ID_PROP_KEY, "1234")]);
+        let (_, service) = filter_service(&info, &no_filter()).unwrap();
+
+        assert_eq!(
+            serde_json::json!({
+                "session": "foo-bar",
+                "session_id": "1234",
+                "project": "My Unity Project",
+                "path": "foo/bar/baz",
+                "unity_version": "2023.5.30",
+                "hostname": "localhost.local.",
+                "addresses": ["127.0.0.1:4242"],
+                "protocol_version": 0,
+                "compatible": false,
+                "tls_fingerprint": null,
+                "started_at_ms": null,
+                "properties": {},
+            }),
+            service.to_json()
+        );
+    }
+
+    #[test]
+    fn extra_properties_are_kept() {
+        let info = service_info(&[("platform", "Android"), (SESSION_ID_PROP_KEY, "1234")]);
+        let (_, service) = filter_service(&info, &no_filter()).unwrap();
+
+        assert_eq!(
+            HashMap::from([("platform".to_owned(), "Android".to_owned())]),
+            service.properties
+        );
+        assert_eq!(
+            serde_json::json!({ "platform": "Android" }),
+            service.to_json()["properties"]
+        );
+    }
+
+    #[test]
+    fn services_are_filtered_by_property() {
+        let matches = |props: &[(&str, &str)]| {
+            let args = DiscoveryArgs {
+                match_props: props
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+                ..no_filter()
+            };
+            filter_service(&service_info(&[("platform", "Android")]), &args).is_some()
+        };
+
+        assert!(matches(&[("platform", "Android")]));
+        assert!(!matches(&[("platform", "iOS")]));
+        assert!(!matches(&[("license", "pro")]));
+        assert!(matches(&[(UNITY_VERSION_PROP_KEY, "2023.5.30")]));
+    }
+
+    #[test]
+    fn every_property_filter_must_match() {
+        let info = service_info(&[("platform", "Android"), ("license", "pro")]);
+        let check = |props: &[(&str, &str)]| {
+            let args = DiscoveryArgs {
+                match_props: props
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+                ..no_filter()
+            };
+            check_service(&info, &args).err()
+        };
+
+        assert_eq!(None, check(&[("platform", "Android"), ("license", "pro")]));
+        assert_eq!(
+            Some("advertises `license=pro`, not `personal`".to_owned()),
+            check(&[("platform", "Android"), ("license", "personal")])
+        );
+        assert_eq!(
+            Some("doesn't advertise `region`".to_owned()),
+            check(&[("region", "eu"), ("license", "personal")])
+        );
+    }
+
+    #[test]
+    fn services_are_filtered_by_host_prefix() {
+        let matches = |host: &str, project: Option<&str>| {
+            let args = DiscoveryArgs {
+                host: Some(host.to_owned()),
+                project: project.map(str::to_owned),
+                ..no_filter()
+            };
+            filter_service(&service_info(&[]), &args).is_some()
+        };
+
+        assert!(matches("localhost", None));
+        assert!(matches("LocalHost.local", None));
+        assert!(matches("local", Some("My Unity")));
+        assert!(!matches("local", Some("Other")));
+        assert!(!matches("my-laptop", None));
+        assert!(!matches("host", None));
+    }
+
+    #[test]
+    fn mismatches_are_explained() {
+        let reason = |info: &ServiceInfo, args: DiscoveryArgs| check_service(info, &args).err();
+
+        assert_eq!(
+            Some(format!("doesn't advertise `{PROJECT_NAME_PROP_KEY}`")),
+            reason(
+                &ServiceInfo::new(
+                    MDNS_SERVICE_NAME,
+                    "foo-bar",
+                    "localhost.local.",
+                    "127.0.0.1",
+                    4242,
+                    &[(PROJECT_PATH_PROP_KEY, "foo/bar/baz")][..],
+                )
+                .unwrap(),
+                no_filter()
+            )
+        );
+        assert_eq!(
+            Some("session `foo-bar` doesn't match `game-*`".to_owned()),
+            reason(
+                &service_info(&[]),
+                DiscoveryArgs {
+                    session: Some("game-*".to_owned()),
+                    ..no_filter()
+                }
+            )
+        );
+        assert_eq!(
+            Some("host `localhost.local` doesn't start with `my-laptop`".to_owned()),
+            reason(
+                &service_info(&[]),
+                DiscoveryArgs {
+                    host: Some("my-laptop".to_owned()),
+                    ..no_filter()
+                }
+            )
+        );
+        assert_eq!(
+            Some("session id isn't `1234`".to_owned()),
+            reason(
+                &service_info(&[]),
+                DiscoveryArgs {
+                    session_id: Some("1234".to_owned()),
+                    ..no_filter()
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn missing_protocol_version_prop_is_unknown() {
+        let info = service_info(&[]);
+        let (_, service) = filter_service(&info, &no_filter()).unwrap();
+
+        assert_eq!(0, service.protocol_version);
+        assert!(!service.is_compatible());
+    }
+
+    #[test]
+    fn stream_yields_services_in_resolution_order() {
+        let mut events = vec![
+            ServiceEvent::SearchStarted(MDNS_SERVICE_NAME.to_owned()),
+            ServiceEvent::ServiceResolved(named_service_info("first", &[])),
+            ServiceEvent::ServiceFound(MDNS_SERVICE_NAME.to_owned(), "second".to_owned()),
+            ServiceEvent::ServiceResolved(named_service_info("second", &[])),
+        ]
+        .into_iter();
+        let sessions: Vec<_> = matching_services(no_filter(), || events.next())
+            .map(|(_, service)| service.session_name)
+            .collect();
+
+        assert_eq!(vec!["first.", "second."], sessions);
+    }
+
+    #[test]
+    fn collecting_stops_at_exact_match() {
+        let mut events = vec![
+            ServiceEvent::ServiceResolved(named_service_info("first", &[])),
+            ServiceEvent::ServiceResolved(named_service_info("second", &[])),
+            ServiceEvent::ServiceResolved(named_service_info("third", &[])),
+        ]
+        .into_iter();
+        let args = DiscoveryArgs {
+            session: Some("second.".to_owned()),
+            ..no_filter()
+        };
+        let services = collect_services(matching_services(args, || events.next()), true);
+
+        assert_eq!(1, services.len());
+        assert_eq!("second.", services[0].session_name);
+        assert!(events.next().is_some());
+    }
+
+    #[test]
+    fn discovery_is_retried_until_something_matches() {
+        let mut attempts = vec![
+            vec![],
+            vec![ServiceEvent::ServiceResolved(service_info(&[]))],
+            vec![ServiceEvent::ServiceResolved(service_info(&[]))],
+        ]
+        .into_iter();
+        let mut attempt_count = 0;
+        let discovery = with_retries(5, || {
+            attempt_count += 1;
+            let mut events = attempts.next().unwrap().into_iter();
+            collect_discovery(matching_services(no_filter(), move || events.next()), true)
+        });
+
+        assert_eq!(2, attempt_count);
+        assert_eq!(1, discovery.services.len());
+    }
+
+    /// Yields the scripted events of one browse per call, and nothing once they run out.
+    struct ScriptedBrowser(RefCell<VecDeque<Vec<ServiceEvent>>>);
+
+    impl ScriptedBrowser {
+        fn new(browses: Vec<Vec<ServiceEvent>>) -> Self {
+            Self(RefCell::new(browses.into()))
+        }
+    }
+
+    impl ServiceBrowser for ScriptedBrowser {
+        fn browse(&self, _: Instant) -> BrowseEvents {
+            let mut events = self
+                .0
+                .borrow_mut()
+                .pop_front()
+                .unwrap_or_default()
+                .into_iter();
+            Box::new(move || events.next())
+        }
+    }
+
+    #[test]
+    fn discovery_browses_again_until_a_session_matches() {
+        let browser = ScriptedBrowser::new(vec![
+            vec![ServiceEvent::ServiceResolved(named_service_info(
+                "other",
+                &[],
+            ))],
+            vec![
+                ServiceEvent::ServiceResolved(named_service_info("other", &[])),
+                ServiceEvent::ServiceResolved(named_service_info("foo-bar", &[])),
+                ServiceEvent::ServiceResolved(named_service_info("foo-bar", &[])),
+            ],
+            vec![ServiceEvent::ServiceResolved(named_service_info(
+                "foo-bar",
+                &[],
+            ))],
+        ]);
+        let args = DiscoveryArgs {
+            session: Some("foo".to_owned()),
+            discovery_retries: 5,
+            ..no_filter()
+        };
+
+        let discovery = discover_service_with(&browser, args);
+
+        assert_eq!(1, discovery.services.len());
+        assert_eq!("foo-bar.", discovery.services[0].session_name);
+        assert_eq!(1, discovery.filtered_out);
+        assert_eq!(1, browser.0.borrow().len());
+    }
+
+    #[test]
+    fn discovery_of_all_sessions_gives_up_after_the_retries() {
+        let browser = ScriptedBrowser::new((0..4).map(|_| vec![]).collect());
+        let args = DiscoveryArgs {
+            discovery_retries: 2,
+            ..no_filter()
+        };
+
+        let discovery = discover_all_services_with(&browser, args);
+
+        assert!(discovery.services.is_empty());
+        assert_eq!(1, browser.0.borrow().len());
+    }
+
+    #[test]
+    fn filtered_out_sessions_are_counted() {
+        let mut attempt_count = 0;
+        let discovery = with_retries(2, || {
+            attempt_count += 1;
+            let mut events = vec![
+                ServiceEvent::ServiceResolved(named_service_info("first", &[])),
+                ServiceEvent::ServiceResolved(named_service_info("first", &[])),
+                ServiceEvent::ServiceResolved(named_service_info("second", &[])),
+            ]
+            .into_iter();
+            let args = DiscoveryArgs {
+                session: Some("third".to_owned()),
+                ..no_filter()
+            };
+            collect_discovery(matching_services(args, move || events.next()), true)
+        });
+
+        assert_eq!(3, attempt_count);
+        assert!(discovery.services.is_empty());
+        assert_eq!(2, discovery.filtered_out);
+    }
+
+    #[test]
+    fn same_session_resolved_twice_is_collected_once_with_every_address() {
+        let session_id = [(SESSION_ID_PROP_KEY, "e1c6e5b4")];
+        let mut events = vec![
+            ServiceEvent::ServiceResolved(service_info_at("foo-bar", "169.254.3.4", &session_id)),
+            ServiceEvent::ServiceResolved(named_service_info("other", &[])),
+            ServiceEvent::ServiceResolved(service_info_at("foo-bar", "192.168.1.20", &session_id)),
+        ]
+        .into_iter();
+        let services = collect_services(matching_services(no_filter(), || events.next()), false);
+
+        assert_eq!(2, services.len());
+        let addresses: Vec<_> = services[0]
+            .addresses
+            .iter()
+            .map(|address| address.ip().to_string())
+            .collect();
+        assert_eq!(vec!["192.168.1.20", "169.254.3.4"], addresses);
+    }
+
+    #[test]
+    fn link_local_address_is_picked_last() {
+        let info = service_info_at("foo-bar", "169.254.3.4,192.168.1.20", &[]);
+        let (_, service) = filter_service(&info, &no_filter()).unwrap();
+
+        assert_eq!("192.168.1.20", service.address().ip().to_string());
+    }
+
+    #[test]
+    fn preferred_subnet_is_picked_first() {
+        let info = service_info_at("foo-bar", "192.168.1.20,10.0.0.7,127.0.0.1", &[]);
+        let args = DiscoveryArgs {
+            interface: Some(AddressPreference::Subnet("10.0.0.0".parse().unwrap(), 8)),
+            ..no_filter()
+        };
+        let (_, service) = filter_service(&info, &args).unwrap();
+
+        assert_eq!("10.0.0.7", service.address().ip().to_string());
+    }
+
+    #[test]
+    fn loopback_is_preferred_for_local_projects() {
+        let project = std::env::current_dir().unwrap();
+        let info = service_info_at(
+            "foo-bar",
+            "192.168.1.20,127.0.0.1",
+            &[(PROJECT_PATH_PROP_KEY, project.to_str().unwrap())],
+        );
+        let (_, service) = filter_service(&info, &no_filter()).unwrap();
+
+        assert_eq!("127.0.0.1", service.address().ip().to_string());
+    }
 }
@@ -0,0 +1,51 @@
+/// Returns the candidate closest to `input`, if it is close enough to be a likely typo.
+pub fn closest_match<'a>(input: &str, candidates: &'a [String]) -> Option<&'a str> {
+    let threshold = input.chars().count().max(3) / 3;
+    candidates
+        .iter()
+        .map(|candidate| (levenshtein(input, candidate), candidate))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev_row: Vec<usize> = (0..=b.len()).collect();
+    let mut row = vec![0; b.len() + 1];
+
+    for (i, a_char) in a.chars().enumerate() {
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = prev_row[j] + usize::from(a_char != *b_char);
+            row[j + 1] = substitution.min(prev_row[j + 1] + 1).min(row[j] + 1);
+        }
+        std::mem::swap(&mut prev_row, &mut row);
+    }
+
+    prev_row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::suggestion::{closest_match, levenshtein};
+
+    #[test]
+    fn levenshtein_distance() {
+        assert_eq!(0, levenshtein("foo", "foo"));
+        assert_eq!(1, levenshtein("foo", "food"));
+        assert_eq!(1, levenshtein("foo", "fo"));
+        assert_eq!(1, levenshtein("foo", "fao"));
+        assert_eq!(3, levenshtein("kitten", "sitting"));
+        assert_eq!(3, levenshtein("", "bar"));
+    }
+
+    #[test]
+    fn suggest_closest_command() {
+        let commands = vec!["food".to_string(), "build".to_string(), "fooba".to_string()];
+
+        assert_eq!(Some("food"), closest_match("foo", &commands));
+        assert_eq!(Some("build"), closest_match("buld", &commands));
+        assert_eq!(None, closest_match("import-assets", &commands));
+    }
+}
@@ -0,0 +1,58 @@
+/// Lays `rows` out under `headers` in columns as wide as their widest cell, two spaces apart.
+/// Lines don't end with spaces.
+pub fn render_table<S: AsRef<str>>(headers: &[&str], rows: &[Vec<S>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.as_ref().chars().count());
+        }
+    }
+
+    let render_line = |cells: Vec<&str>| {
+        let line = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        line.trim_end().to_owned()
+    };
+    std::iter::once(render_line(headers.to_vec()))
+        .chain(
+            rows.iter()
+                .map(|row| render_line(row.iter().map(AsRef::as_ref).collect())),
+        )
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::table::render_table;
+
+    #[test]
+    fn columns_fit_their_widest_cell() {
+        let rows = vec![
+            vec!["my-game", "Game", "2022.3.1f1"],
+            vec!["a-much-longer-session", "Tools", "6000.0.0b1"],
+        ];
+
+        assert_eq!(
+            "SESSION                PROJECT  UNITY\n\
+             my-game                Game     2022.3.1f1\n\
+             a-much-longer-session  Tools    6000.0.0b1",
+            render_table(&["SESSION", "PROJECT", "UNITY"], &rows)
+        );
+    }
+
+    #[test]
+    fn headers_can_be_the_widest() {
+        let rows = vec![vec!["a".to_owned(), "ünïcode".to_owned(), "".to_owned()]];
+
+        assert_eq!(
+            "NAME  PROJECT_NAME  NOTE\n\
+             a     ünïcode",
+            render_table(&["NAME", "PROJECT_NAME", "NOTE"], &rows)
+        );
+    }
+}
@@ -1,63 +1,1024 @@
-use std::io::Write;
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    io::{self, IsTerminal, Write},
+    sync::Arc,
+    thread::JoinHandle,
+};
 
-use crossbeam::channel::{Receiver, Sender};
+use crossbeam::channel::Sender;
 use crossterm::{
-    style::{Color, SetForegroundColor},
+    style::{Color, ResetColor, SetForegroundColor, Stylize},
     ExecutableCommand,
 };
 
-use common::ServerMessage;
+use common::{ServerMessage, UnityLogType};
 
 #[derive(Clone)]
 pub struct TerminalWriter {
-    inner: Sender<Output>,
+    inner: Sender<Queued>,
+    meta: OutputMeta,
+}
+
+/// What a [`TerminalWriter`] hands to the thread of [`sink_loop`].
+enum Queued {
+    Output(Output, OutputMeta),
+    /// Answered once everything queued before it was emitted.
+    Barrier(Sender<()>),
 }
 
 impl TerminalWriter {
+    /// A writer prefixing everything it prints with `[label]`, to tell sessions apart.
+    pub fn with_label(&self, label: &str) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            meta: OutputMeta {
+                session_label: Some(label.into()),
+            },
+        }
+    }
+
     pub fn write_server_msg(&self, item: ServerMessage) {
-        self.inner.send(Output::ServerMessage(item)).unwrap();
+        self.send(Output::ServerMessage(item));
     }
+
+    pub fn write_message<S: Into<String>>(&self, msg: S) {
+        self.send(Output::Message(msg.into()));
+    }
+
+    pub fn write_error<S: Into<String>>(&self, msg: S) {
+        self.send(Output::Error(msg.into()));
+    }
+
+    /// Waits until everything written so far was emitted, e.g. before prompting the user so the
+    /// prompt doesn't end up between the lines still being printed.
+    pub fn wait_emitted(&self) {
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        self.inner.send(Queued::Barrier(tx)).unwrap();
+        let _ = rx.recv();
+    }
+
+    fn send(&self, output: Output) {
+        self.inner
+            .send(Queued::Output(output, self.meta.clone()))
+            .unwrap();
+    }
+}
+
+/// Where everything written to a [`TerminalWriter`] ends up, on the thread [`sink_loop`] spawns.
+pub trait OutputSink: Send {
+    /// Called for every output, in the order they were written.
+    fn emit(&mut self, output: &Output, meta: &OutputMeta);
+
+    /// Called once every writer is gone, before the thread exits.
+    fn finish(&mut self) {}
+}
+
+/// What the sink is told about an output besides the output itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OutputMeta {
+    /// The session the output is about, when there are several.
+    pub session_label: Option<Arc<str>>,
 }
 
-enum Output {
+/// Everything a [`TerminalWriter`] can be given to write.
+#[derive(Debug)]
+pub enum Output {
     ServerMessage(ServerMessage),
+    /// Client side message for the user.
+    Message(String),
+    /// Client side failure.
+    Error(String),
+    /// Bytes to print as they are, like a streamed result.
+    Raw(Vec<u8>),
+}
+
+fn print_colored<W: Write, D: Display>(dst: &mut W, colored: bool, color: Color, text: D) {
+    if !colored {
+        writeln!(dst, "{text}").unwrap();
+        return;
+    }
+    dst.execute(SetForegroundColor(color)).unwrap();
+    write!(dst, "{text}").unwrap();
+    dst.execute(ResetColor).unwrap();
+    writeln!(dst).unwrap();
+}
+
+/// Prints every line of `stack_trace` indented and dimmed, to group it under its message. Unity
+/// ends lines with CRLF on Windows, and often leaves blank lines at the end.
+fn print_stack_trace<W: Write>(dst: &mut W, colored: bool, stack_trace: &str) {
+    for line in stack_trace.lines().filter(|line| !line.trim().is_empty()) {
+        print_colored(dst, colored, Color::DarkGrey, format_args!("  {line}"));
+    }
+}
+
+/// The colors session labels are printed in, none of them used for the messages themselves so
+/// labels don't read as warnings or errors.
+const LABEL_PALETTE: [Color; 10] = [
+    Color::Cyan,
+    Color::Magenta,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::DarkCyan,
+    Color::DarkMagenta,
+    Color::DarkGreen,
+    Color::DarkYellow,
+    Color::DarkBlue,
+];
+
+/// The color of `label`, picked from [`LABEL_PALETTE`] by an FNV-1a hash of it, so a session
+/// keeps its color across runs and the sessions watched together mostly get different ones.
+fn label_color(label: &str) -> Color {
+    let hash = label.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    LABEL_PALETTE[(hash % LABEL_PALETTE.len() as u64) as usize]
+}
+
+/// `[label]`, with the label in its color if `colored`.
+fn label_prefix(label: &str, colored: bool) -> String {
+    if colored {
+        format!("[{}]", label.with(label_color(label)))
+    } else {
+        format!("[{label}]")
+    }
+}
+
+/// Writes `text` to `dst` with every line prefixed by `[label]`.
+fn write_labeled<W: Write>(dst: &mut W, label: &str, colored: bool, text: &[u8]) {
+    let prefix = label_prefix(label, colored);
+    for line in text.split_inclusive(|b| *b == b'\n') {
+        write!(dst, "{prefix} ").unwrap();
+        dst.write_all(line).unwrap();
+    }
+    dst.flush().unwrap();
 }
 
 impl Output {
-    fn print_to_console<T: Write, U: Write>(&self, stdout: &mut T, stderr: &mut U) {
+    /// Prints the output with every line prefixed by its session label, if it has one. `colored`
+    /// is off for sinks that must stay plain text, like files.
+    fn print_labeled<T: Write, U: Write>(
+        &self,
+        meta: &OutputMeta,
+        stdout: &mut T,
+        stderr: &mut U,
+        colored: bool,
+    ) {
+        match meta.session_label {
+            None => self.print_to_console(stdout, stderr, colored),
+            Some(ref label) => {
+                let (mut out, mut err) = (Vec::new(), Vec::new());
+                self.print_to_console(&mut out, &mut err, colored);
+                write_labeled(stdout, label, colored, &out);
+                write_labeled(stderr, label, colored, &err);
+            }
+        }
+    }
+
+    fn print_to_console<T: Write, U: Write>(&self, stdout: &mut T, stderr: &mut U, colored: bool) {
         match self {
             Self::ServerMessage(ServerMessage::IsBusy) => {
-                todo!();
+                print_colored(stderr, colored, Color::Yellow, "Unity is busy.");
             }
             Self::ServerMessage(ServerMessage::UnityConsoleOutput {
                 log_type,
                 log,
                 stack_trace,
+            }) => match log_type {
+                UnityLogType::Error | UnityLogType::Assert | UnityLogType::Exception => {
+                    print_colored(stderr, colored, Color::Red, log);
+                    print_stack_trace(stderr, colored, stack_trace);
+                }
+                UnityLogType::Warning => {
+                    print_colored(stdout, colored, Color::Yellow, log);
+                }
+                UnityLogType::Log | UnityLogType::Unknown => {
+                    writeln!(stdout, "{log}").unwrap();
+                }
+            },
+            Self::ServerMessage(ServerMessage::CompilationStarted) => {
+                writeln!(stdout, "Compiling scripts...").unwrap();
+            }
+            Self::ServerMessage(ServerMessage::Compiling) => {}
+            Self::ServerMessage(ServerMessage::CompilationFinished {}) => {
+                writeln!(stdout, "Compilation finished.").unwrap();
+            }
+            Self::ServerMessage(
+                ServerMessage::AssemblyUnloaded | ServerMessage::Welcome { .. },
+            ) => {}
+            Self::ServerMessage(ServerMessage::AssemblyReloading) => {
+                writeln!(stdout, "Reloading assemblies...").unwrap();
+            }
+            Self::ServerMessage(ServerMessage::AssemblyReloaded) => {
+                writeln!(stdout, "Assemblies reloaded.").unwrap();
+            }
+            Self::ServerMessage(ServerMessage::CommandFinished {
+                is_success, msg, ..
+            }) => {
+                if *is_success {
+                    print_colored(
+                        stdout,
+                        colored,
+                        Color::Green,
+                        msg.as_deref().unwrap_or("Command finished."),
+                    );
+                } else {
+                    print_colored(
+                        stderr,
+                        colored,
+                        Color::Red,
+                        msg.as_deref().unwrap_or("Command failed."),
+                    );
+                }
+            }
+            Self::ServerMessage(ServerMessage::Rejected { reason }) => {
+                print_colored(stderr, colored, Color::Red, reason);
+            }
+            Self::ServerMessage(ServerMessage::CommandRejected { reason, .. }) => {
+                print_colored(stderr, colored, Color::Magenta, reason);
+                writeln!(
+                    stderr,
+                    "Run `ucli list-commands` to see the available commands."
+                )
+                .unwrap();
+            }
+            Self::ServerMessage(ServerMessage::CommandList { commands, .. }) => {
+                for command in commands {
+                    writeln!(stdout, "{command}").unwrap();
+                }
+            }
+            Self::ServerMessage(ServerMessage::CommandResult {
+                payload,
+                content_type,
+                ..
             }) => {
-                todo!();
+                if is_textual(content_type) {
+                    writeln!(stdout, "{}", String::from_utf8_lossy(payload)).unwrap();
+                } else {
+                    writeln!(
+                        stdout,
+                        "[{} bytes of {content_type}, use `--output-file` to save them]",
+                        payload.len()
+                    )
+                    .unwrap();
+                }
+            }
+            Self::ServerMessage(ServerMessage::Notice { level, text }) => {
+                let color = match level {
+                    UnityLogType::Error | UnityLogType::Assert | UnityLogType::Exception => {
+                        Color::Red
+                    }
+                    UnityLogType::Warning => Color::Yellow,
+                    UnityLogType::Log | UnityLogType::Unknown => Color::Cyan,
+                };
+                print_colored(stderr, colored, color, text);
+            }
+            Self::ServerMessage(ServerMessage::EditorUnresponsive { idle_secs }) => {
+                print_colored(
+                    stderr,
+                    colored,
+                    Color::Yellow,
+                    format!("Unity hasn't responded for {idle_secs}s, the editor may be frozen."),
+                );
             }
-            Self::ServerMessage(ServerMessage::CommandFinished { is_success, msg }) => {
-                todo!();
+            Self::Message(msg) => {
+                writeln!(stdout, "{msg}").unwrap();
             }
-            _ => {
-                todo!();
+            Self::Error(msg) => {
+                print_colored(stderr, colored, Color::Red, msg);
+            }
+            Self::ServerMessage(ServerMessage::ResultChunk { data, .. }) | Self::Raw(data) => {
+                stdout.write_all(data).unwrap();
+                stdout.flush().unwrap();
             }
         }
-        stdout.execute(SetForegroundColor(Color::Red)).unwrap();
     }
 }
 
+/// How many warnings and errors a command logged, for `ucli run --summary`.
+#[derive(Default)]
+pub struct LogCounts {
+    warnings: u32,
+    errors: u32,
+}
+
+impl LogCounts {
+    pub fn count(&mut self, msg: &ServerMessage) {
+        let ServerMessage::UnityConsoleOutput { log_type, .. } = msg else {
+            return;
+        };
+        match log_type {
+            UnityLogType::Error | UnityLogType::Assert | UnityLogType::Exception => {
+                self.errors += 1;
+            }
+            UnityLogType::Warning => self.warnings += 1,
+            UnityLogType::Log | UnityLogType::Unknown => {}
+        }
+    }
+
+    /// e.g. `build: success (3 warnings, 0 errors)`.
+    pub fn summary(&self, command: &str, is_success: bool) -> String {
+        let plural = |n: u32, noun: &str| match n {
+            1 => format!("1 {noun}"),
+            n => format!("{n} {noun}s"),
+        };
+        format!(
+            "{command}: {} ({}, {})",
+            if is_success { "success" } else { "failure" },
+            plural(self.warnings, "warning"),
+            plural(self.errors, "error")
+        )
+    }
+}
+
+/// Whether a result of the MIME type `content_type` can be printed as text.
+fn is_textual(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(essence, "application/json" | "application/xml")
+}
+
+/// Writing to the terminal prints to stdout as is, to stream results to it.
+impl Write for TerminalWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(Output::Raw(buf.to_vec()));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Puts both streams back to the default colors, for when the process exits without waiting
+/// for the printing thread.
+pub fn reset_colors() {
+    let _ = std::io::stdout().execute(ResetColor);
+    let _ = std::io::stderr().execute(ResetColor);
+}
+
+/// Collapses runs of identical console messages into the first one and a count of how many
+/// times it was seen.
+#[derive(Default)]
+struct Coalescer {
+    last: Option<(Option<Arc<str>>, UnityLogType, String)>,
+    seen: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Step {
+    /// Print the output, after ending the previous message's run if it repeated.
+    Print(Option<Repeats>),
+    /// The previous message again.
+    Repeat(Repeats),
+}
+
+#[derive(Debug, PartialEq)]
+struct Repeats {
+    session_label: Option<Arc<str>>,
+    seen: u64,
+    /// Whether the message went to stderr, where its count goes too.
+    to_stderr: bool,
+}
+
+impl Repeats {
+    fn line(&self, colored: bool) -> String {
+        match self.session_label {
+            Some(ref label) => format!(
+                "{} (repeated {} times)",
+                label_prefix(label, colored),
+                self.seen
+            ),
+            None => format!("(repeated {} times)", self.seen),
+        }
+    }
+}
+
+impl Coalescer {
+    fn push(&mut self, output: &Output, meta: &OutputMeta) -> Step {
+        let Output::ServerMessage(ServerMessage::UnityConsoleOutput { log_type, log, .. }) = output
+        else {
+            return Step::Print(self.finish());
+        };
+        let key = (meta.session_label.clone(), log_type.clone(), log.clone());
+        if self.last.as_ref() == Some(&key) {
+            self.seen += 1;
+            return Step::Repeat(Repeats {
+                session_label: key.0,
+                seen: self.seen,
+                to_stderr: is_error(log_type),
+            });
+        }
+        let repeats = self.finish();
+        self.last = Some(key);
+        self.seen = 1;
+        Step::Print(repeats)
+    }
+
+    /// Ends the current run, and returns how often its message was seen if more than once.
+    fn finish(&mut self) -> Option<Repeats> {
+        let (session_label, log_type, _) = self.last.take()?;
+        let seen = std::mem::take(&mut self.seen);
+        (seen > 1).then_some(Repeats {
+            session_label,
+            seen,
+            to_stderr: is_error(&log_type),
+        })
+    }
+}
+
+/// Whether console messages of `log_type` are printed to stderr.
+fn is_error(log_type: &UnityLogType) -> bool {
+    matches!(
+        log_type,
+        UnityLogType::Error | UnityLogType::Assert | UnityLogType::Exception
+    )
+}
+
+/// Prints to the terminal, copying everything without colors to `text_sink` if given.
+struct ConsoleSink<T, U> {
+    stdout: T,
+    stderr: U,
+    text_sink: Option<Box<dyn Write + Send>>,
+    coalescer: Option<Coalescer>,
+    /// Off when the user asked for no colors with `--no-color` or `NO_COLOR`.
+    colored: bool,
+    /// Whether stdout and stderr are terminals, on which repeat counts are updated in place.
+    /// Anywhere else, only the final count is printed.
+    are_terminals: (bool, bool),
+}
+
+impl<T: Write, U: Write> ConsoleSink<T, U> {
+    /// The stream `repeats` are counted on, and whether it is a terminal.
+    fn repeats_stream(&mut self, repeats: &Repeats) -> (&mut dyn Write, bool) {
+        if repeats.to_stderr {
+            (&mut self.stderr, self.are_terminals.1)
+        } else {
+            (&mut self.stdout, self.are_terminals.0)
+        }
+    }
+
+    /// Updates the count of `repeats` in place, if its stream is a terminal.
+    fn count_repeats(&mut self, repeats: &Repeats) {
+        let line = repeats.line(self.colored);
+        if let (stream, true) = self.repeats_stream(repeats) {
+            write!(stream, "\r{line}").unwrap();
+            stream.flush().unwrap();
+        }
+    }
+
+    /// Moves past the in place count of `repeats`, or prints its final count where it can't be
+    /// updated in place, and copies that count to `text_sink`.
+    fn end_repeats(&mut self, repeats: &Repeats) {
+        let line = repeats.line(self.colored);
+        match self.repeats_stream(repeats) {
+            (stream, true) => writeln!(stream).unwrap(),
+            (stream, false) => writeln!(stream, "{line}").unwrap(),
+        }
+        if let Some(ref mut sink) = self.text_sink {
+            let _ = writeln!(sink, "{}", repeats.line(false)).and_then(|_| sink.flush());
+        }
+    }
+}
+
+impl<T: Write + Send, U: Write + Send> OutputSink for ConsoleSink<T, U> {
+    fn emit(&mut self, output: &Output, meta: &OutputMeta) {
+        match self.coalescer.as_mut().map(|c| c.push(output, meta)) {
+            Some(Step::Repeat(repeats)) => {
+                self.count_repeats(&repeats);
+                return;
+            }
+            Some(Step::Print(Some(repeats))) => self.end_repeats(&repeats),
+            Some(Step::Print(None)) | None => {}
+        }
+        output.print_labeled(meta, &mut self.stdout, &mut self.stderr, self.colored);
+        if let Some(ref mut sink) = self.text_sink {
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            output.print_labeled(meta, &mut out, &mut err, false);
+            // Losing the copy shouldn't stop the terminal output.
+            let _ = sink
+                .write_all(&out)
+                .and_then(|_| sink.write_all(&err))
+                .and_then(|_| sink.flush());
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(repeats) = self.coalescer.as_mut().and_then(Coalescer::finish) {
+            self.end_repeats(&repeats);
+        }
+    }
+}
+
+/// Whether to print colors given `--no-color` and the value of `NO_COLOR`, which disables them
+/// when set to anything but an empty string.
+fn colors_wanted(no_color_flag: bool, no_color: Option<OsString>) -> bool {
+    !no_color_flag && no_color.is_none_or(|value| value.is_empty())
+}
+
+/// Spawns the thread printing everything sent through the returned writer, and copying it
+/// without colors to `text_sink` if given. The thread exits, and the handle can be joined, once
+/// every clone of the writer is dropped.
+///
+/// With `coalesce`, consecutive identical console messages are printed once, followed by a count
+/// of their repeats that is updated in place when the process's own stream is a terminal. With
+/// `no_color`, nothing is colored.
 pub fn print_loop<T: Write + Send + 'static, U: Write + Send + 'static>(
-    mut stdout: T,
-    mut stderr: U,
-) -> TerminalWriter {
-    let (tx, rx) = crossbeam::channel::unbounded::<Output>();
+    stdout: T,
+    stderr: U,
+    text_sink: Option<Box<dyn Write + Send>>,
+    coalesce: bool,
+    no_color: bool,
+) -> (TerminalWriter, JoinHandle<()>) {
+    sink_loop(Box::new(ConsoleSink {
+        stdout,
+        stderr,
+        text_sink,
+        coalescer: coalesce.then(Coalescer::default),
+        colored: colors_wanted(no_color, std::env::var_os("NO_COLOR")),
+        are_terminals: (
+            std::io::stdout().is_terminal(),
+            std::io::stderr().is_terminal(),
+        ),
+    }))
+}
 
-    std::thread::spawn(move || {
-        while let Ok(output) = rx.recv() {
-            output.print_to_console(&mut stdout, &mut stderr);
+/// Like [`print_loop`], but hands everything to `sink` instead of printing it.
+pub fn sink_loop(mut sink: Box<dyn OutputSink>) -> (TerminalWriter, JoinHandle<()>) {
+    let (tx, rx) = crossbeam::channel::unbounded();
+
+    let handle = std::thread::spawn(move || {
+        while let Ok(queued) = rx.recv() {
+            match queued {
+                Queued::Output(output, meta) => sink.emit(&output, &meta),
+                Queued::Barrier(done) => {
+                    let _ = done.send(());
+                }
+            }
         }
+        sink.finish();
     });
 
-    TerminalWriter { inner: tx }
+    (
+        TerminalWriter {
+            inner: tx,
+            meta: OutputMeta::default(),
+        },
+        handle,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsString,
+        sync::{Arc, Mutex},
+    };
+
+    use crossterm::style::{Color, Stylize};
+
+    use common::{ServerMessage, UnityLogType};
+
+    use crate::terminal::{
+        colors_wanted, label_color, print_loop, sink_loop, Coalescer, ConsoleSink, LogCounts,
+        Output, OutputMeta, OutputSink, Repeats, Step, LABEL_PALETTE,
+    };
+
+    fn meta(session_label: Option<&str>) -> OutputMeta {
+        OutputMeta {
+            session_label: session_label.map(Arc::from),
+        }
+    }
+
+    fn render(session_label: Option<&str>, output: Output) -> (String, String) {
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        output.print_labeled(&meta(session_label), &mut stdout, &mut stderr, true);
+        (
+            String::from_utf8(stdout).unwrap(),
+            String::from_utf8(stderr).unwrap(),
+        )
+    }
+
+    /// Renders `msg` with and without colors, returning both streams for each.
+    fn render_msg(msg: ServerMessage) -> [(String, String); 2] {
+        let output = Output::ServerMessage(msg);
+        [false, true].map(|colored| {
+            let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+            output.print_to_console(&mut stdout, &mut stderr, colored);
+            (
+                String::from_utf8(stdout).unwrap(),
+                String::from_utf8(stderr).unwrap(),
+            )
+        })
+    }
+
+    fn console_output(log_type: UnityLogType, log: &str) -> ServerMessage {
+        ServerMessage::UnityConsoleOutput {
+            log_type,
+            log: log.to_owned(),
+            stack_trace: String::new(),
+        }
+    }
+
+    fn out(text: &str) -> (String, String) {
+        (text.to_owned(), String::new())
+    }
+
+    fn err(text: &str) -> (String, String) {
+        (String::new(), text.to_owned())
+    }
+
+    #[test]
+    fn messages_render_exactly() {
+        assert_eq!(
+            [err("oops\n"), err("\x1b[38;5;9moops\x1b[0m\n")],
+            render_msg(console_output(UnityLogType::Error, "oops"))
+        );
+        assert_eq!(
+            [out("careful\n"), out("\x1b[38;5;11mcareful\x1b[0m\n")],
+            render_msg(console_output(UnityLogType::Warning, "careful"))
+        );
+        assert_eq!(
+            [out("hello\n"), out("hello\n")],
+            render_msg(console_output(UnityLogType::Log, "hello"))
+        );
+        assert_eq!(
+            [
+                out("Command finished.\n"),
+                out("\x1b[38;5;10mCommand finished.\x1b[0m\n")
+            ],
+            render_msg(ServerMessage::CommandFinished {
+                request_id: 1,
+                is_success: true,
+                msg: None,
+            })
+        );
+        assert_eq!(
+            [
+                err("No scenes to build\n"),
+                err("\x1b[38;5;9mNo scenes to build\x1b[0m\n")
+            ],
+            render_msg(ServerMessage::CommandFinished {
+                request_id: 1,
+                is_success: false,
+                msg: Some("No scenes to build".to_owned()),
+            })
+        );
+        assert_eq!(
+            [
+                err("Unity is busy.\n"),
+                err("\x1b[38;5;11mUnity is busy.\x1b[0m\n")
+            ],
+            render_msg(ServerMessage::IsBusy)
+        );
+        assert_eq!(
+            [err("3 dropped\n"), err("\x1b[38;5;11m3 dropped\x1b[0m\n")],
+            render_msg(ServerMessage::Notice {
+                level: UnityLogType::Warning,
+                text: "3 dropped".to_owned(),
+            })
+        );
+        assert_eq!(
+            [
+                err("Backlog truncated\n"),
+                err("\x1b[38;5;14mBacklog truncated\x1b[0m\n")
+            ],
+            render_msg(ServerMessage::Notice {
+                level: UnityLogType::Log,
+                text: "Backlog truncated".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn no_color_disables_colors_unless_empty() {
+        assert!(colors_wanted(false, None));
+        assert!(colors_wanted(false, Some(OsString::new())));
+        assert!(!colors_wanted(false, Some(OsString::from("1"))));
+    }
+
+    #[test]
+    fn no_color_flag_disables_colors_whatever_the_env_says() {
+        assert!(!colors_wanted(true, None));
+        assert!(!colors_wanted(true, Some(OsString::new())));
+    }
+
+    #[test]
+    fn unlabeled_output_is_printed_as_is() {
+        let (stdout, stderr) = render(None, Output::Message("hello\nworld".to_owned()));
+
+        assert_eq!("hello\nworld\n", stdout);
+        assert!(stderr.is_empty());
+    }
+
+    #[test]
+    fn labeled_output_prefixes_every_line() {
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        Output::Message("hello\nworld".to_owned()).print_labeled(
+            &meta(Some("my-game")),
+            &mut stdout,
+            &mut stderr,
+            false,
+        );
+
+        assert_eq!(b"[my-game] hello\n[my-game] world\n", stdout.as_slice());
+        assert!(stderr.is_empty());
+    }
+
+    #[test]
+    fn sessions_get_stable_distinct_label_colors() {
+        assert_eq!(Color::Blue, label_color("brave-otter"));
+        assert_eq!(Color::DarkCyan, label_color("quiet-lynx"));
+        for label in ["brave-otter", "quiet-lynx", ""] {
+            assert!(LABEL_PALETTE.contains(&label_color(label)));
+        }
+
+        let (stdout, _) = render(Some("quiet-lynx"), Output::Message("hello".to_owned()));
+        assert_eq!(
+            format!("[{}] hello\n", "quiet-lynx".with(Color::DarkCyan)),
+            stdout
+        );
+    }
+
+    #[test]
+    fn labeled_colored_output_stays_on_one_line() {
+        let (stdout, stderr) = render(Some("my-game"), Output::Error("oops".to_owned()));
+
+        assert!(stdout.is_empty());
+        let label = format!("[{}] ", "my-game".with(label_color("my-game")));
+        assert!(stderr.starts_with(&label));
+        assert!(stderr.contains("oops"));
+        assert_eq!(1, stderr.lines().count());
+    }
+
+    #[test]
+    fn stack_traces_are_indented_under_their_message() {
+        let exception = || {
+            Output::ServerMessage(ServerMessage::UnityConsoleOutput {
+                log_type: UnityLogType::Exception,
+                log: "NullReferenceException".to_owned(),
+                stack_trace: "Player.Update () (at Assets/Player.cs:12)\r\n\
+                              UnityEngine.Debug:Log()\r\n\r\n"
+                    .to_owned(),
+            })
+        };
+
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        exception().print_to_console(&mut stdout, &mut stderr, false);
+        assert!(stdout.is_empty());
+        assert_eq!(
+            "NullReferenceException\n  \
+             Player.Update () (at Assets/Player.cs:12)\n  \
+             UnityEngine.Debug:Log()\n",
+            String::from_utf8(stderr).unwrap()
+        );
+
+        let (_, colored) = render(None, exception());
+        assert_eq!(3, colored.lines().count());
+        assert!(colored.contains("  UnityEngine.Debug:Log()"));
+    }
+
+    #[test]
+    fn summary_counts_warnings_and_errors() {
+        let mut counts = LogCounts::default();
+        assert_eq!(
+            "build: success (0 warnings, 0 errors)",
+            counts.summary("build", true)
+        );
+
+        for log_type in [
+            UnityLogType::Warning,
+            UnityLogType::Log,
+            UnityLogType::Exception,
+            UnityLogType::Warning,
+            UnityLogType::Assert,
+            UnityLogType::Warning,
+        ] {
+            counts.count(&ServerMessage::UnityConsoleOutput {
+                log_type,
+                log: String::new(),
+                stack_trace: String::new(),
+            });
+        }
+        counts.count(&ServerMessage::IsBusy);
+        assert_eq!(
+            "build: failure (3 warnings, 2 errors)",
+            counts.summary("build", false)
+        );
+
+        let mut counts = LogCounts::default();
+        counts.count(&ServerMessage::UnityConsoleOutput {
+            log_type: UnityLogType::Error,
+            log: String::new(),
+            stack_trace: String::new(),
+        });
+        assert_eq!(
+            "build: failure (0 warnings, 1 error)",
+            counts.summary("build", false)
+        );
+    }
+
+    #[test]
+    fn text_sink_gets_uncolored_output() {
+        let path = std::env::temp_dir().join(format!("ucli-output-{}.log", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+
+        let (terminal, printer) = print_loop(
+            std::io::sink(),
+            std::io::sink(),
+            Some(Box::new(file)),
+            false,
+            false,
+        );
+        terminal.write_error("oops");
+        terminal.write_server_msg(ServerMessage::UnityConsoleOutput {
+            log_type: UnityLogType::Warning,
+            log: "careful".to_owned(),
+            stack_trace: String::new(),
+        });
+        drop(terminal);
+        printer.join().unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!("oops\ncareful\n", text);
+    }
+
+    fn log(text: &str) -> Output {
+        Output::ServerMessage(ServerMessage::UnityConsoleOutput {
+            log_type: UnityLogType::Error,
+            log: text.to_owned(),
+            stack_trace: String::new(),
+        })
+    }
+
+    #[test]
+    fn identical_console_messages_are_coalesced() {
+        let mut coalescer = Coalescer::default();
+        let repeats = |seen| Repeats {
+            session_label: None,
+            seen,
+            to_stderr: true,
+        };
+
+        let unlabeled = meta(None);
+        assert_eq!(Step::Print(None), coalescer.push(&log("spam"), &unlabeled));
+        assert_eq!(
+            Step::Repeat(repeats(2)),
+            coalescer.push(&log("spam"), &unlabeled)
+        );
+        assert_eq!(
+            Step::Repeat(repeats(3)),
+            coalescer.push(&log("spam"), &unlabeled)
+        );
+        assert_eq!(
+            Step::Print(Some(repeats(3))),
+            coalescer.push(&log("other"), &unlabeled)
+        );
+        // Same text from another session isn't a repeat.
+        assert_eq!(
+            Step::Print(None),
+            coalescer.push(&log("other"), &meta(Some("b")))
+        );
+        assert_eq!(
+            Step::Print(None),
+            coalescer.push(&Output::Message("hi".to_owned()), &unlabeled)
+        );
+        assert_eq!(Step::Print(None), coalescer.push(&log("spam"), &unlabeled));
+        assert_eq!(None, coalescer.finish());
+    }
+
+    #[test]
+    fn repeats_are_counted_on_the_stream_of_their_message() {
+        let print_spam = |are_terminals| {
+            let mut sink = ConsoleSink {
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+                text_sink: None,
+                coalescer: Some(Coalescer::default()),
+                colored: false,
+                are_terminals,
+            };
+            for _ in 0..3 {
+                sink.emit(&log("spam"), &meta(None));
+            }
+            sink.finish();
+            (
+                String::from_utf8(sink.stdout).unwrap(),
+                String::from_utf8(sink.stderr).unwrap(),
+            )
+        };
+
+        assert_eq!(
+            (
+                String::new(),
+                "spam\n\r(repeated 2 times)\r(repeated 3 times)\n".to_owned()
+            ),
+            print_spam((true, true))
+        );
+        // Where the count can't be rewritten, it is printed once it is final.
+        assert_eq!(
+            (String::new(), "spam\n(repeated 3 times)\n".to_owned()),
+            print_spam((true, false))
+        );
+    }
+
+    #[test]
+    fn text_sink_gets_the_final_repeat_count() {
+        let path = std::env::temp_dir().join(format!("ucli-coalesce-{}.log", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+
+        let (terminal, printer) = print_loop(
+            std::io::sink(),
+            std::io::sink(),
+            Some(Box::new(file)),
+            true,
+            false,
+        );
+        for _ in 0..3 {
+            terminal.write_server_msg(ServerMessage::UnityConsoleOutput {
+                log_type: UnityLogType::Log,
+                log: "spam".to_owned(),
+                stack_trace: String::new(),
+            });
+        }
+        terminal.write_message("done");
+        drop(terminal);
+        printer.join().unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!("spam\n(repeated 3 times)\ndone\n", text);
+    }
+
+    #[test]
+    fn only_textual_results_are_printed() {
+        let result = |content_type: &str| {
+            Output::ServerMessage(ServerMessage::CommandResult {
+                request_id: 1,
+                payload: b"{\"ok\":true}".to_vec(),
+                content_type: content_type.to_owned(),
+            })
+        };
+
+        let (stdout, _) = render(None, result("application/json; charset=utf-8"));
+        assert_eq!("{\"ok\":true}\n", stdout);
+
+        let (stdout, _) = render(None, result("image/png"));
+        assert_eq!(
+            "[11 bytes of image/png, use `--output-file` to save them]\n",
+            stdout
+        );
+    }
+
+    /// Keeps everything it is given, for tests to look at.
+    struct CollectingSink(Arc<Mutex<Vec<(String, OutputMeta)>>>);
+
+    impl OutputSink for CollectingSink {
+        fn emit(&mut self, output: &Output, meta: &OutputMeta) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((format!("{output:?}"), meta.clone()));
+        }
+    }
+
+    #[test]
+    fn sinks_get_every_output_in_order() {
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let (terminal, printer) = sink_loop(Box::new(CollectingSink(collected.clone())));
+
+        terminal.write_server_msg(ServerMessage::IsBusy);
+        terminal.with_label("my-game").write_error("oops");
+        terminal.write_message("done");
+        drop(terminal);
+        printer.join().unwrap();
+
+        assert_eq!(
+            vec![
+                ("ServerMessage(IsBusy)".to_owned(), meta(None)),
+                (r#"Error("oops")"#.to_owned(), meta(Some("my-game"))),
+                (r#"Message("done")"#.to_owned(), meta(None)),
+            ],
+            *collected.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn waiting_returns_once_everything_was_emitted() {
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let (terminal, printer) = sink_loop(Box::new(CollectingSink(collected.clone())));
+
+        for i in 0..100 {
+            terminal.write_message(i.to_string());
+        }
+        terminal.wait_emitted();
+        assert_eq!(100, collected.lock().unwrap().len());
+
+        drop(terminal);
+        printer.join().unwrap();
+    }
 }
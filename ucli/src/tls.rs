@@ -0,0 +1,81 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, ClientConnection, RootCertStore, ServerName, StreamOwned,
+};
+
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Wraps `stream` in TLS. The server is verified against the CA certificates in `ca_path` when
+/// given, and pinned to the `fingerprint` it advertised otherwise.
+pub fn connect(
+    stream: TcpStream,
+    hostname: &str,
+    fingerprint: &str,
+    ca_path: Option<&Path>,
+) -> io::Result<TlsStream> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let config = match ca_path {
+        Some(ca_path) => builder
+            .with_root_certificates(load_roots(ca_path)?)
+            .with_no_client_auth(),
+        None => builder
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                fingerprint: fingerprint.to_owned(),
+            }))
+            .with_no_client_auth(),
+    };
+
+    let server_name = ServerName::try_from(hostname.trim_end_matches('.'))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let conn = ClientConnection::new(Arc::new(config), server_name).map_err(io::Error::other)?;
+
+    Ok(StreamOwned::new(conn, stream))
+}
+
+fn load_roots(ca_path: &Path) -> io::Result<RootCertStore> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(ca_path)?))?;
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no CA certificate found in `{}`", ca_path.display()),
+        ));
+    }
+
+    Ok(roots)
+}
+
+/// Trusts exactly the certificate a session advertised over mDNS.
+struct PinnedCertVerifier {
+    fingerprint: String,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if common::tls_fingerprint(&end_entity.0).eq_ignore_ascii_case(&self.fingerprint) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "the certificate doesn't match the advertised fingerprint".to_owned(),
+            ))
+        }
+    }
+}
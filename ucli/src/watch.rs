@@ -0,0 +1,92 @@
+use common::ServerMessage;
+
+/// Decides which messages `ucli watch` prints, and when it has printed enough of them.
+pub struct WatchFilter {
+    grep: Option<String>,
+    /// How many more console messages to print, if limited.
+    remaining: Option<u64>,
+}
+
+impl WatchFilter {
+    /// Console messages are only printed if they contain `grep`, and printing stops after
+    /// `count` of them.
+    pub fn new(grep: Option<String>, count: Option<u64>) -> Self {
+        Self {
+            grep,
+            remaining: count,
+        }
+    }
+
+    /// Whether to print `msg`. Only console messages are filtered and counted, the others are
+    /// always printed unless watching for a pattern.
+    pub fn accept(&mut self, msg: &ServerMessage) -> bool {
+        if self.is_done() {
+            return false;
+        }
+        let ServerMessage::UnityConsoleOutput { log, .. } = msg else {
+            return self.grep.is_none();
+        };
+        if self.grep.as_ref().is_some_and(|grep| !log.contains(grep)) {
+            return false;
+        }
+        if let Some(ref mut remaining) = self.remaining {
+            *remaining -= 1;
+        }
+        true
+    }
+
+    /// Whether the requested count of console messages was printed.
+    pub fn is_done(&self) -> bool {
+        self.remaining == Some(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{ServerMessage, UnityLogType};
+
+    use crate::watch::WatchFilter;
+
+    fn log(text: &str) -> ServerMessage {
+        ServerMessage::UnityConsoleOutput {
+            log_type: UnityLogType::Log,
+            log: text.to_owned(),
+            stack_trace: String::new(),
+        }
+    }
+
+    #[test]
+    fn stops_after_count_console_messages() {
+        let mut filter = WatchFilter::new(None, Some(2));
+
+        assert!(filter.accept(&log("a")));
+        assert!(filter.accept(&ServerMessage::CompilationStarted));
+        assert!(!filter.is_done());
+        assert!(filter.accept(&log("b")));
+
+        assert!(filter.is_done());
+        assert!(!filter.accept(&log("c")));
+    }
+
+    #[test]
+    fn only_matching_messages_are_counted() {
+        let mut filter = WatchFilter::new(Some("error".to_owned()), Some(1));
+
+        assert!(!filter.accept(&log("all good")));
+        assert!(!filter.accept(&ServerMessage::CompilationStarted));
+        assert!(!filter.is_done());
+        assert!(filter.accept(&log("an error happened")));
+
+        assert!(filter.is_done());
+    }
+
+    #[test]
+    fn unlimited_without_count() {
+        let mut filter = WatchFilter::new(None, None);
+
+        for _ in 0..1000 {
+            assert!(filter.accept(&log("spam")));
+        }
+        assert!(!filter.is_done());
+    }
+}
@@ -0,0 +1,123 @@
+/// Splits `line` into words like a shell would: single quotes keep everything up to the next one
+/// as is, and in double quotes, or outside of quotes, a backslash escapes the next character. A
+/// `#` starting a word comments out the rest of the line.
+///
+/// Meant for commands typed or written to a file as one line, not for the arguments of `ucli run`,
+/// which the shell already split.
+pub fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars();
+    // The word being read, `None` between words so that `''` still makes an empty one.
+    let mut word: Option<String> = None;
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '#' if word.is_none() => break,
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated single quote".to_owned()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => word.push(c),
+                            None => return Err("unterminated double quote".to_owned()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated double quote".to_owned()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("nothing to escape at the end of the line".to_owned()),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::words::split_words;
+
+    fn words(line: &str) -> Vec<String> {
+        split_words(line).unwrap()
+    }
+
+    #[test]
+    fn whitespace_separates_words() {
+        assert_eq!(vec!["bake", "Main", "fast"], words("  bake\tMain   fast "));
+        assert!(words("").is_empty());
+        assert!(words(" \t ").is_empty());
+    }
+
+    #[test]
+    fn empty_quotes_are_empty_words() {
+        assert_eq!(vec!["log", "", ""], words("log '' \"\""));
+        assert_eq!(vec!["a", "b"], words("a'' \"\"b"));
+    }
+
+    #[test]
+    fn quotes_keep_spaces_and_join_with_their_word() {
+        assert_eq!(
+            vec!["open", "Assets/My Scene.unity"],
+            words("open 'Assets/My Scene.unity'")
+        );
+        assert_eq!(
+            vec!["open", "Assets/My Scene.unity"],
+            words("open Assets/\"My Scene\".unity")
+        );
+        assert_eq!(vec!["its"], words("'it''s'"));
+    }
+
+    #[test]
+    fn quotes_nest_inside_the_other_kind() {
+        assert_eq!(vec!["say \"hi\"", "it's"], words("'say \"hi\"' \"it's\""));
+    }
+
+    #[test]
+    fn backslashes_escape_outside_single_quotes() {
+        assert_eq!(vec!["a b", "c\"d", "e\\f"], words(r#"a\ b c\"d "e\\f""#));
+        assert_eq!(vec!["say \"hi\""], words(r#""say \"hi\"""#));
+        assert_eq!(vec![r"C:\Unity\"], words(r"'C:\Unity\'"));
+    }
+
+    #[test]
+    fn hash_starting_a_word_is_a_comment() {
+        assert_eq!(vec!["tag", "v#1"], words("tag v#1 # the first"));
+        assert_eq!(vec!["tag", "#1", "#2"], words("tag '#1' \\#2"));
+        assert!(words("# nothing to run").is_empty());
+    }
+
+    #[test]
+    fn unterminated_quotes_are_errors() {
+        assert_eq!(
+            Err("unterminated single quote".to_owned()),
+            split_words("log 'oops")
+        );
+        assert_eq!(
+            Err("unterminated double quote".to_owned()),
+            split_words("log \"oops")
+        );
+        assert_eq!(
+            Err("unterminated double quote".to_owned()),
+            split_words("log \"oops\\")
+        );
+        assert_eq!(
+            Err("nothing to escape at the end of the line".to_owned()),
+            split_words("log oops\\")
+        );
+    }
+}